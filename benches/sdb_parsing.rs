@@ -1,11 +1,98 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use leybold_opc_rs::sdb;
+use std::path::PathBuf;
+
+use leybold_opc_rs::sdb::{AccessMode, ParamFlags, ParseMode, Sdb, TypeKind};
+use leybold_opc_rs::sdb_builder::{fixture, Member, SdbBuilder};
+use leybold_opc_rs::sdb_store::DEFAULT_SDB_FILE;
+
+/// The instrument's SDB if there is one in the working directory, otherwise the much
+/// smaller fixture.
+fn sdb_file() -> PathBuf {
+    let path = PathBuf::from(DEFAULT_SDB_FILE);
+    if path.exists() {
+        return path;
+    }
+    let path = std::env::temp_dir().join("sdb-fixture-bench.dat");
+    std::fs::write(&path, fixture()).unwrap();
+    path
+}
+
+/// An SDB of several megabytes, with many devices laid out like the gauges of the
+/// instruments.
+fn large_sdb_file() -> PathBuf {
+    let mut b = SdbBuilder::new(0x1a46e);
+    let int = b.add_type(TypeKind::Int, 2, "INT");
+    let real = b.add_type(TypeKind::Real, 4, "REAL");
+    let string = b.add_type(TypeKind::String, 81, "STRING");
+    let members = [
+        "Number",
+        "Value",
+        "WarningValue",
+        "ErrorValue",
+        "Name",
+        "Unit",
+    ]
+    .into_iter()
+    .zip([int, real, real, real, string, string])
+    .map(|(name, ty)| Member::new(name, ty))
+    .collect();
+    let parameter = b.add_struct("DATA", members);
+    let parameters = b.add_array("DATA", parameter, &[(1, 20)]);
+    let device = b.add_struct("DATA", vec![Member::new("Parameter", parameters)]);
+    let devices = b.add_array("DATA", device, &[(1, 400)]);
+    b.add_variable(
+        ".Device",
+        devices,
+        ParamFlags::GLOBAL,
+        AccessMode::Read,
+        0x10000,
+    );
+    let path = std::env::temp_dir().join("sdb-large-bench.dat");
+    std::fs::write(&path, b.build()).unwrap();
+    path
+}
 
 pub fn criterion_benchmark(c: &mut Criterion) {
+    let file = sdb_file();
     c.bench_function("read_sdb_file", |b| {
-        b.iter(|| black_box(sdb::read_sdb_file()))
+        b.iter(|| black_box(Sdb::from_file(&file)))
+    });
+    c.bench_function("read_sdb_file_lazy", |b| {
+        b.iter(|| black_box(Sdb::from_file_with(&file, ParseMode::Lazy)))
     });
+
+    let large = large_sdb_file();
+    c.bench_function("read_large_sdb_file", |b| {
+        b.iter(|| black_box(Sdb::from_file(&large)))
+    });
+    #[cfg(feature = "mmap")]
+    c.bench_function("read_large_sdb_mmap", |b| {
+        b.iter(|| black_box(Sdb::from_mmap(&large)))
+    });
+}
+
+/// Compares the names stored back to back in one buffer with one `CompactString` per
+/// name, which keeps up to 24 bytes inline and allocates longer names on the heap.
+/// Common path prefixes are still stored once per name.
+pub fn name_memory(_c: &mut Criterion) {
+    let sdb = Sdb::from_file(sdb_file()).unwrap();
+    let separate: usize = sdb
+        .parameters()
+        .map(|p| match p.name().len() {
+            len @ 25.. => 24 + len,
+            _ => 24,
+        })
+        .sum();
+    let shared = sdb.name_storage_size();
+    assert!(
+        shared < separate,
+        "{shared} bytes in one buffer, {separate} separately"
+    );
+    println!(
+        "parameter names: {shared} bytes in one buffer, {separate} bytes as separate strings ({:.0} % saved)",
+        100.0 * (1.0 - shared as f64 / separate as f64)
+    );
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(benches, criterion_benchmark, name_memory);
 criterion_main!(benches);
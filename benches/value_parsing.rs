@@ -0,0 +1,45 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use leybold_opc_rs::opc_values::{RawValue, Value};
+use leybold_opc_rs::sdb::{ParseMode, Sdb};
+use leybold_opc_rs::sdb_builder::fixture;
+use leybold_opc_rs::value_tree::ValueTree;
+
+/// Parses the value of `.Gauge`, an array of structs holding timers, arrays and a
+/// table of parameter structs, the most deeply nested value of the instruments.
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let sdb = Sdb::from_bytes(&fixture(), ParseMode::Full).unwrap();
+    let ty = sdb.param_by_name(".Gauge").unwrap().type_info();
+    let data = vec![0x41; ty.response_len()];
+    c.bench_function("parse_gauge_value", |b| {
+        b.iter(|| black_box(Value::parse(&data, &ty).unwrap()))
+    });
+    c.bench_function("parse_gauge_tree", |b| {
+        b.iter(|| black_box(ValueTree::parse(&data, &ty).unwrap()))
+    });
+    // Exporting it as JSON, through a parsed value and straight from the bytes.
+    let mut json = Vec::with_capacity(64 * 1024);
+    c.bench_function("gauge_json_via_value", |b| {
+        b.iter(|| {
+            json.clear();
+            let value = Value::parse(&data, &ty).unwrap();
+            serde_json::to_writer(&mut json, &value).unwrap();
+        })
+    });
+    c.bench_function("gauge_json_via_tree", |b| {
+        b.iter(|| {
+            json.clear();
+            let tree = ValueTree::parse(&data, &ty).unwrap();
+            serde_json::to_writer(&mut json, &tree).unwrap();
+        })
+    });
+    c.bench_function("gauge_json_raw", |b| {
+        b.iter(|| {
+            json.clear();
+            serde_json::to_writer(&mut json, &RawValue::new(&data, &ty)).unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+/// Controls which parameters a client is allowed to write.
+///
+/// Patterns are parameter names where `*` matches any sequence of characters,
+/// e.g. `.Gauge[*].Parameter[*].Value`.
+///
+/// ```toml
+/// [access]
+/// allow = [".CockpitUser"]
+///
+/// [access.clients.operator]
+/// allow = [".CockpitUser", ".HostRemote"]
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Refuse all writes, regardless of the allow lists.
+    pub read_only: bool,
+    /// Writable parameters for clients without their own entry. Empty means all parameters.
+    pub allow: Vec<String>,
+    /// Parameters which no client may write.
+    pub deny: Vec<String>,
+    /// Per-client rules, replacing the global `allow` list for that client.
+    pub clients: BTreeMap<String, ClientAccess>,
+}
+
+/// The rules of one client, named by the user writing, see [`current_user`].
+///
+/// [`current_user`]: crate::audit::current_user
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientAccess {
+    pub read_only: bool,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl AccessPolicy {
+    /// Whether every client may write every parameter, as without an `[access]` table.
+    pub fn is_open(&self) -> bool {
+        !self.read_only && self.allow.is_empty() && self.deny.is_empty() && self.clients.is_empty()
+    }
+
+    /// Fails unless `client` may write to `param`.
+    pub fn check_write(&self, client: &str, param: &str) -> Result<()> {
+        if self.read_only {
+            bail!("Writes are disabled, the access policy is read-only.");
+        }
+        if self.deny.iter().any(|p| glob_match(p, param)) {
+            bail!("Writing {param} is denied by the access policy.");
+        }
+        let allow = match self.clients.get(client) {
+            Some(c) if c.read_only => bail!("Client '{client}' is read-only."),
+            Some(c) if c.deny.iter().any(|p| glob_match(p, param)) => {
+                bail!("Client '{client}' is denied writing {param}.")
+            }
+            Some(c) => &c.allow,
+            None => &self.allow,
+        };
+        if !allow.is_empty() && !allow.iter().any(|p| glob_match(p, param)) {
+            bail!("Client '{client}' is not allowed to write {param}.");
+        }
+        Ok(())
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` matches any sequence of characters.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut text) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = rest.split('*').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return text.ends_with(part);
+        }
+        match text.find(part) {
+            Some(pos) => text = &text[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn test_access_policy() {
+    assert!(glob_match(
+        ".Gauge[*].Parameter[*].Value",
+        ".Gauge[1].Parameter[12].Value"
+    ));
+    assert!(!glob_match(
+        ".Gauge[*].Value",
+        ".Gauge[1].Parameter[1].Value2"
+    ));
+    assert!(glob_match("*", ".CockpitUser"));
+
+    let policy: AccessPolicy = toml::from_str(
+        r#"
+        allow = [".CockpitUser"]
+        deny = [".OPC*"]
+        [clients.operator]
+        allow = [".CockpitUser", ".HostRemote"]
+        "#,
+    )
+    .unwrap();
+    policy.check_write("someone", ".CockpitUser").unwrap();
+    assert!(policy.check_write("someone", ".HostRemote").is_err());
+    policy.check_write("operator", ".HostRemote").unwrap();
+    assert!(policy.check_write("operator", ".OPCCounter").is_err());
+}
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::clock::{Clock, SystemClock};
+use crate::sdb::{normalize_param_path, Sdb};
+use crate::template::Template;
+
+/// Fires when a parameter leaves its allowed range. The rules are checked against the
+/// values read while polling, so the parameter must be one of those read.
+///
+/// ```toml
+/// [[alerts]]
+/// name = "chamber-pressure"
+/// param = ".Gauge[1].Parameter[1].Value"
+/// above = 1e-3
+///
+/// [notify]
+/// webhook = "https://example.com/hooks/vacuum"
+/// webhook_template = '{{"text": "{param} is {value} at {ts}"}}'
+/// sendmail = "operator@example.com"
+/// desktop = true
+/// repeat_secs = 3600
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    pub name: String,
+    pub param: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+}
+
+impl AlertRule {
+    fn is_violated(&self, value: f64) -> bool {
+        self.above.is_some_and(|max| value > max) || self.below.is_some_and(|min| value < min)
+    }
+}
+
+/// Where alert notifications are sent.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// URL which receives every [`AlertEvent`] as a JSON POST, sent with `curl`.
+    pub webhook: Option<String>,
+    /// The body posted to the webhook instead of the JSON event, see [`Template`].
+    pub webhook_template: Option<Template>,
+    /// Address to mail alerts to, with the local `sendmail`.
+    pub sendmail: Option<String>,
+    /// Show desktop notifications with `notify-send`.
+    pub desktop: bool,
+    /// Notify again about an alert still firing after this many seconds. Zero
+    /// notifies only when the alert fires and recovers.
+    pub repeat_secs: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Recovered,
+}
+
+/// A change of an alert, or a reminder that it is still firing.
+#[derive(Clone, Debug, Serialize)]
+pub struct AlertEvent {
+    pub alert: String,
+    pub param: String,
+    pub value: f64,
+    pub state: AlertState,
+}
+
+impl AlertEvent {
+    pub fn summary(&self) -> String {
+        match self.state {
+            AlertState::Firing => format!("Alert {}: {} is {}", self.alert, self.param, self.value),
+            AlertState::Recovered => {
+                format!("Recovered {}: {} is {}", self.alert, self.param, self.value)
+            }
+        }
+    }
+}
+
+/// Evaluates the alert rules against polled values, and reports changes only.
+#[derive(Debug)]
+pub struct Alerts {
+    rules: Vec<AlertRule>,
+    repeat: Option<Duration>,
+    /// When each firing alert was last notified, by rule index.
+    firing: HashMap<usize, Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Alerts {
+    pub fn new(mut rules: Vec<AlertRule>, notify: &NotifyConfig) -> Self {
+        for rule in &mut rules {
+            rule.param = normalize_param_path(&rule.param);
+        }
+        Self {
+            rules,
+            repeat: (notify.repeat_secs > 0).then(|| Duration::from_secs(notify.repeat_secs)),
+            firing: HashMap::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Times the repeated notifications with `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Fails for rules whose parameter is missing from the SDB or isn't among those
+    /// `read`, since those rules would never fire.
+    pub fn check_params<'a>(
+        &self,
+        sdb: &Sdb,
+        read: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        let read: Vec<_> = read.into_iter().map(normalize_param_path).collect();
+        for rule in &self.rules {
+            let param = sdb
+                .param_by_name(&rule.param)
+                .with_context(|| format!("Alert {}", rule.name))?;
+            if !read.contains(&normalize_param_path(param.name())) {
+                bail!(
+                    "Alert {}: {} isn't read, add it to the parameters.",
+                    rule.name,
+                    param.name()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check(&mut self, param: &str, value: f64) -> Vec<AlertEvent> {
+        self.check_at(param, value, self.clock.now())
+    }
+
+    /// Checks the rules of `param`, which is matched like [`Sdb::param_by_name`] does.
+    ///
+    /// [`Sdb::param_by_name`]: crate::sdb::Sdb::param_by_name
+    pub fn check_at(&mut self, param: &str, value: f64, now: Instant) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+        let normalized = normalize_param_path(param);
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if rule.param != normalized {
+                continue;
+            }
+            let state = match (rule.is_violated(value), self.firing.get(&idx)) {
+                (true, None) => AlertState::Firing,
+                (true, Some(last)) if self.repeat.is_some_and(|r| now - *last >= r) => {
+                    AlertState::Firing
+                }
+                (false, Some(_)) => AlertState::Recovered,
+                _ => continue,
+            };
+            match state {
+                AlertState::Firing => self.firing.insert(idx, now),
+                AlertState::Recovered => self.firing.remove(&idx),
+            };
+            events.push(AlertEvent {
+                alert: rule.name.clone(),
+                param: param.to_string(),
+                value,
+                state,
+            });
+        }
+        events
+    }
+}
+
+/// Sends alert notifications from a thread of its own, so that slow destinations don't
+/// hold up polling. Dropping it waits for the notifications still queued.
+#[derive(Debug)]
+pub struct Notifier {
+    events: Option<Sender<AlertEvent>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    /// Without the `notify` feature, events are only logged.
+    pub fn start(config: NotifyConfig) -> Self {
+        #[cfg(feature = "notify")]
+        {
+            let (events, rx) = std::sync::mpsc::channel::<AlertEvent>();
+            let thread = std::thread::spawn(move || {
+                for event in rx {
+                    send::send(&config, &event);
+                }
+            });
+            Self {
+                events: Some(events),
+                thread: Some(thread),
+            }
+        }
+        #[cfg(not(feature = "notify"))]
+        {
+            let _ = config;
+            Self {
+                events: None,
+                thread: None,
+            }
+        }
+    }
+
+    /// Logs the event and queues it for every destination configured. Failures are
+    /// logged, so that one broken destination doesn't keep the others from being
+    /// notified.
+    pub fn notify(&self, event: AlertEvent) {
+        warn!("{}", event.summary());
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.events = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Notifications through curl, sendmail and notify-send, with the `notify` feature.
+#[cfg(feature = "notify")]
+mod send {
+    use std::io::Write;
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    use anyhow::{bail, Context, Result};
+    use tracing::{debug, warn};
+
+    use super::{AlertEvent, NotifyConfig};
+    use crate::template::{Fields, Template};
+
+    /// How long a command may take to send a notification before it is killed.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub(super) fn send(config: &NotifyConfig, event: &AlertEvent) {
+        if let Some(url) = &config.webhook {
+            if let Err(e) = post_webhook(url, config.webhook_template.as_ref(), event) {
+                warn!("Webhook notification failed: {e:#}");
+            }
+        }
+        if let Some(to) = &config.sendmail {
+            if let Err(e) = send_mail(to, event) {
+                warn!("Mail notification failed: {e:#}");
+            }
+        }
+        if config.desktop {
+            let sent = Command::new("notify-send")
+                .args(["--app-name=leybold-opc", &event.summary()])
+                .spawn()
+                .context("Failed to run notify-send")
+                .and_then(|child| wait(child, "notify-send"));
+            if let Err(e) = sent {
+                warn!("Desktop notification failed: {e:#}");
+            }
+        }
+    }
+
+    /// Waits for the command to succeed, killing it after [`TIMEOUT`].
+    fn wait(mut child: Child, name: &str) -> Result<()> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                if !status.success() {
+                    bail!("{name} exited with {status}");
+                }
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("{name} took longer than {} s", TIMEOUT.as_secs());
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn post_webhook(url: &str, template: Option<&Template>, event: &AlertEvent) -> Result<()> {
+        debug!("Posting alert to {url}");
+        let body = match template {
+            Some(template) => template.render(&Fields {
+                param: &event.param,
+                value: &event.value.to_string(),
+                unit: None,
+                ts: chrono::Utc::now(),
+            }),
+            None => serde_json::to_string(event)?,
+        };
+        let child = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--max-time", "10"])
+            .args([
+                "--header",
+                "Content-Type: application/json",
+                "--data",
+                &body,
+                url,
+            ])
+            .spawn()
+            .context("Failed to run curl")?;
+        wait(child, "curl")
+    }
+
+    fn send_mail(to: &str, event: &AlertEvent) -> Result<()> {
+        let mut child = Command::new("sendmail")
+            .args(["-t"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Failed to run sendmail")?;
+        #[cfg(feature = "exporters")]
+        let body = serde_json::to_string_pretty(event)?;
+        #[cfg(not(feature = "exporters"))]
+        let body = serde_json::to_string(event)?;
+        let mail = format!("To: {to}\nSubject: {}\n\n{body}\n", event.summary());
+        child.stdin.take().unwrap().write_all(mail.as_bytes())?;
+        wait(child, "sendmail")
+    }
+}
+
+#[test]
+fn test_alert_dedup() {
+    let rule = AlertRule {
+        name: "pressure".into(),
+        param: ".P".into(),
+        above: Some(1e-3),
+        below: None,
+    };
+    let notify = NotifyConfig {
+        repeat_secs: 60,
+        ..Default::default()
+    };
+    let mut alerts = Alerts::new(vec![rule], &notify);
+    let t0 = Instant::now();
+    let states = |events: Vec<AlertEvent>| events.iter().map(|e| e.state).collect::<Vec<_>>();
+    assert_eq!(states(alerts.check_at(".P", 1e-4, t0)), []);
+    assert_eq!(
+        states(alerts.check_at(".p", 1e-2, t0)),
+        [AlertState::Firing]
+    );
+    let t1 = t0 + Duration::from_secs(30);
+    assert_eq!(states(alerts.check_at(".P", 1e-2, t1)), []);
+    assert_eq!(states(alerts.check_at(".Q", 1e-2, t1)), []);
+    let t2 = t0 + Duration::from_secs(61);
+    assert_eq!(
+        states(alerts.check_at(".P", 1e-2, t2)),
+        [AlertState::Firing]
+    );
+    assert_eq!(
+        states(alerts.check_at(".P", 1e-5, t2)),
+        [AlertState::Recovered]
+    );
+}
+
+#[test]
+fn test_alert_params() {
+    let sdb = crate::sdb_builder::test_sdb();
+    let rule = |param: &str| AlertRule {
+        name: "pressure".into(),
+        param: param.into(),
+        above: Some(1e-3),
+        below: None,
+    };
+    let notify = NotifyConfig::default();
+    let read = [".Gauge[1].Parameter[1].Value"];
+    let alerts = Alerts::new(vec![rule("gauge.1.parameter.1.value")], &notify);
+    alerts.check_params(&sdb, read).unwrap();
+    let alerts = Alerts::new(vec![rule(".Gauge[2].Parameter[1].Value")], &notify);
+    assert!(alerts.check_params(&sdb, read).is_err());
+    let alerts = Alerts::new(vec![rule(".No.Such.Param")], &notify);
+    assert!(alerts.check_params(&sdb, read).is_err());
+}
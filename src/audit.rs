@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::access::AccessPolicy;
+use crate::config::Config;
+use crate::metadata::{MetadataOverlay, OutOfRange};
+use crate::opc_values::Value;
+
+/// One entry in the audit log. The log is stored as JSON, one entry per line.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    pub time: DateTime<Utc>,
+    /// The user or client which requested the write.
+    pub user: &'a str,
+    pub param: &'a str,
+    /// The value before the write, if it could be read.
+    pub old: Option<&'a Value>,
+    pub new: &'a Value,
+}
+
+/// The fields of an audit record needed to enforce the write rate limit.
+#[derive(Deserialize)]
+struct AuditRecordTime {
+    time: DateTime<Utc>,
+    param: String,
+}
+
+/// Append-only log of all parameter writes.
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self { path, file })
+    }
+
+    pub fn append(&mut self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data())
+            .with_context(|| format!("Failed to write to audit log {}", self.path.display()))
+    }
+
+    /// Returns the time of the last logged write for each parameter.
+    pub fn last_writes(&self) -> Result<HashMap<String, DateTime<Utc>>> {
+        let mut last = HashMap::new();
+        let reader = BufReader::new(File::open(&self.path)?);
+        for line in reader.lines() {
+            let line = line?;
+            // Skip damaged lines rather than refusing all writes.
+            if let Ok(rec) = serde_json::from_str::<AuditRecordTime>(&line) {
+                last.insert(rec.param, rec.time);
+            }
+        }
+        Ok(last)
+    }
+}
+
+/// Enforces a minimum interval between writes to the same parameter.
+#[derive(Clone, Debug, Default)]
+pub struct WriteRateLimiter {
+    min_interval: Duration,
+    last: HashMap<String, DateTime<Utc>>,
+}
+
+impl WriteRateLimiter {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Seeds the limiter with earlier writes, e.g. from [`AuditLog::last_writes`],
+    /// so that the limit also applies across separate program runs.
+    pub fn with_history(mut self, last: HashMap<String, DateTime<Utc>>) -> Self {
+        self.last = last;
+        self
+    }
+
+    /// Fails if the parameter was written less than the minimum interval before `now`.
+    pub fn check(&self, param: &str, now: DateTime<Utc>) -> Result<()> {
+        let Some(last) = self.last.get(param) else {
+            return Ok(());
+        };
+        let since = (now - *last).to_std().unwrap_or_default();
+        if since < self.min_interval {
+            bail!(
+                "Parameter {param} was written {:.1} s ago, the minimum interval between writes is {:.1} s.",
+                since.as_secs_f32(),
+                self.min_interval.as_secs_f32()
+            );
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, param: &str, now: DateTime<Utc>) {
+        self.last.insert(param.to_string(), now);
+    }
+}
+
+/// Applies the write rules from the config: the access policy and rate limit are checked
+/// before each write, and each completed write is recorded in the audit log.
+pub struct WriteGuard {
+    user: String,
+    access: AccessPolicy,
+    audit: Option<AuditLog>,
+    limiter: WriteRateLimiter,
+    /// The limits of the parameter values, unless turned off.
+    limits: Option<MetadataOverlay>,
+}
+
+impl WriteGuard {
+    /// `user` identifies the writing user or client, both in the audit log
+    /// and for the access policy.
+    pub fn new(config: &Config, user: impl Into<String>) -> Result<Self> {
+        let mut limiter = WriteRateLimiter::new(config.writes.min_interval());
+        let audit = match &config.writes.audit_log {
+            Some(path) => {
+                let log = AuditLog::open(path)?;
+                limiter = limiter.with_history(log.last_writes()?);
+                Some(log)
+            }
+            None => None,
+        };
+        Ok(Self {
+            user: user.into(),
+            access: config.access.clone(),
+            audit,
+            limiter,
+            limits: Some(config.metadata()?),
+        })
+    }
+
+    /// Whether to refuse values outside the min/max of the metadata overlay, on by default.
+    pub fn with_limits(mut self, check: bool) -> Self {
+        if !check {
+            self.limits = None;
+        }
+        self
+    }
+
+    /// True if writes are logged, in which case the caller should supply the old value.
+    pub fn wants_old_value(&self) -> bool {
+        self.audit.is_some()
+    }
+
+    /// The user or client the writes are made for.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Makes the following writes for `user`, e.g. for the clients of a server.
+    pub fn set_user(&mut self, user: impl Into<String>) {
+        self.user = user.into();
+    }
+
+    pub fn before_write(&self, param: &str, value: &Value) -> Result<()> {
+        self.access.check_write(&self.user, param)?;
+        self.check_limits(param, value)?;
+        self.limiter.check(param, Utc::now())
+    }
+
+    /// Fails with [`OutOfRange`] for values outside the limits of the parameter.
+    pub fn check_limits(&self, param: &str, value: &Value) -> Result<(), OutOfRange> {
+        match self.limits.as_ref().and_then(|limits| limits.get(param)) {
+            Some(meta) => meta.check_range(param, value),
+            None => Ok(()),
+        }
+    }
+
+    pub fn after_write(&mut self, param: &str, old: Option<&Value>, new: &Value) -> Result<()> {
+        let time = Utc::now();
+        self.limiter.record(param, time);
+        if let Some(log) = &mut self.audit {
+            log.append(&AuditRecord {
+                time,
+                user: &self.user,
+                param,
+                old,
+                new,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of the local user, for the audit log and the access policy. On Unix it is
+/// looked up by the uid, since `$USER` can be set to anything.
+pub fn current_user() -> String {
+    #[cfg(unix)]
+    {
+        let uid = unsafe { libc::getuid() };
+        user_name(uid).unwrap_or_else(|| uid.to_string())
+    }
+    #[cfg(not(unix))]
+    std::env::var("USERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+pub(crate) fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        // SAFETY: The buffer outlives the call, and its length is passed along.
+        let r = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        if r == libc::ERANGE && buf.len() < 1 << 16 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if r != 0 || found.is_null() {
+            return None;
+        }
+        // SAFETY: On success, pw_name points to a NUL terminated string in `buf`.
+        let name = unsafe { std::ffi::CStr::from_ptr(pwd.pw_name) };
+        return Some(name.to_string_lossy().into_owned());
+    }
+}
+
+#[test]
+fn test_write_rate_limit() {
+    let t0 = Utc::now();
+    let mut limiter = WriteRateLimiter::new(Duration::from_secs(10));
+    limiter.check(".CockpitUser", t0).unwrap();
+    limiter.record(".CockpitUser", t0);
+    assert!(limiter
+        .check(".CockpitUser", t0 + chrono::Duration::seconds(5))
+        .is_err());
+    limiter.check(".HostRemote", t0).unwrap();
+    limiter
+        .check(".CockpitUser", t0 + chrono::Duration::seconds(10))
+        .unwrap();
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn test_write_guard_limits() {
+    let path = std::env::temp_dir().join(format!("limits-{}.toml", std::process::id()));
+    std::fs::write(&path, "[\".P\"]\nmin = 0.0\nmax = 1100.0\n").unwrap();
+    let config = Config {
+        metadata: Some(path.clone()),
+        ..Default::default()
+    };
+    let guard = WriteGuard::new(&config, "test").unwrap();
+    std::fs::remove_file(path).unwrap();
+    guard.before_write(".P", &Value::Float(1000.0)).unwrap();
+    let e = guard
+        .before_write(".P", &Value::Float(f32::NAN))
+        .unwrap_err();
+    assert!(e.downcast_ref::<OutOfRange>().is_some());
+    let guard = guard.with_limits(false);
+    guard.before_write(".P", &Value::Float(2000.0)).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_user_name() {
+    assert_eq!(user_name(0).as_deref(), Some("root"));
+}
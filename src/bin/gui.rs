@@ -0,0 +1,276 @@
+//! A desktop GUI for browsing the SDB, watching and plotting values and writing parameters.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::Parser;
+use eframe::egui;
+use egui_plot::{Line, Plot, PlotPoints};
+
+use leybold_opc_rs::audit::{self, WriteGuard};
+use leybold_opc_rs::client::{Client, OpResult};
+use leybold_opc_rs::config::Config;
+use leybold_opc_rs::errors::device::ErrorCode;
+use leybold_opc_rs::host::Host;
+use leybold_opc_rs::opc_values::Value;
+use leybold_opc_rs::plc_connection::Connection;
+use leybold_opc_rs::sdb::{Parameter, ParseMode, Sdb};
+use leybold_opc_rs::sdb_store::{SdbStore, DEFAULT_SDB_FILE};
+use leybold_opc_rs::stats;
+
+#[derive(Parser, Debug)]
+#[clap(version, about)]
+struct GuiArgs {
+    /// The IP address or host name of the Vacvision unit.
+    #[clap(long, value_name = "HOST")]
+    ip: Option<Host>,
+    /// The SDB file describing the parameters of the instrument.
+    #[clap(long, value_name = "FILE", default_value = DEFAULT_SDB_FILE)]
+    sdb: std::path::PathBuf,
+    /// Time between reads, in seconds.
+    #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, default_value_t = 1.0)]
+    interval: f32,
+}
+
+struct WriteDialog {
+    param: Parameter<'static>,
+    text: String,
+    result: Option<String>,
+}
+
+struct GuiApp {
+    sdb: &'static Sdb,
+    ip: String,
+    client: Option<Client<'static>>,
+    filter: String,
+    watched: Vec<Parameter<'static>>,
+    values: Vec<Option<Value>>,
+    selected: Option<usize>,
+    interval: Duration,
+    next_read: Instant,
+    write: Option<WriteDialog>,
+    /// The access rules and audit log of the config file apply to writes from the GUI too.
+    guard: WriteGuard,
+    status: String,
+}
+
+impl GuiApp {
+    fn connect(&mut self) {
+        let r = self
+            .ip
+            .parse::<Host>()
+            .and_then(|host| Connection::connect(&host))
+            .and_then(|conn| Client::new(conn, self.sdb));
+        match r {
+            Ok(mut client) => {
+                client.set_history_capacity(3600);
+                self.status = format!("Connected to {}", client.capabilities().runtime);
+                self.client = Some(client);
+            }
+            Err(e) => self.status = format!("Connection failed: {e:#}"),
+        }
+    }
+
+    fn poll(&mut self) {
+        let Some(client) = &mut self.client else {
+            return;
+        };
+        if self.watched.is_empty() || Instant::now() < self.next_read {
+            return;
+        }
+        self.next_read = Instant::now() + self.interval;
+        match client.read_cached(&self.watched) {
+            Ok(values) => self.values = values.into_iter().map(Some).collect(),
+            Err(e) => self.status = format!("Read failed: {e:#}"),
+        }
+    }
+
+    fn watch(&mut self, param: Parameter<'static>) {
+        if !self.watched.contains(&param) {
+            self.watched.push(param);
+            self.values.push(None);
+            self.next_read = Instant::now();
+        }
+    }
+
+    fn sdb_browser(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Parameters");
+        ui.text_edit_singleline(&mut self.filter);
+        let filter = self.filter.to_lowercase();
+        let sdb = self.sdb;
+        let matches: Vec<_> = sdb
+            .visible_parameters()
+            .filter(|p| p.name().to_lowercase().contains(&filter))
+            .take(500)
+            .collect();
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for param in matches {
+                let label = param.to_string();
+                if ui.selectable_label(false, label).clicked() {
+                    self.watch(param);
+                }
+            }
+        });
+    }
+
+    fn value_table(&mut self, ui: &mut egui::Ui) {
+        let mut remove = None;
+        egui::Grid::new("values").striped(true).show(ui, |ui| {
+            for (i, (param, value)) in self.watched.iter().zip(&self.values).enumerate() {
+                if ui
+                    .selectable_label(self.selected == Some(i), param.name())
+                    .clicked()
+                {
+                    self.selected = Some(i);
+                }
+                match value {
+                    Some(value) => ui.label(format!("{value:?}")),
+                    None => ui.label("-"),
+                };
+                if ui.button("Write…").clicked() {
+                    self.write = Some(WriteDialog {
+                        param: param.clone(),
+                        text: String::new(),
+                        result: None,
+                    });
+                }
+                if ui.button("Remove").clicked() {
+                    remove = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(i) = remove {
+            self.watched.remove(i);
+            self.values.remove(i);
+            self.selected = None;
+        }
+    }
+
+    fn plot(&self, ui: &mut egui::Ui) {
+        let (Some(client), Some(param)) = (&self.client, self.selected.map(|i| &self.watched[i]))
+        else {
+            ui.label("Select a parameter to plot it.");
+            return;
+        };
+        let now = chrono::Utc::now();
+        let points: PlotPoints = client
+            .history(param)
+            .filter_map(|s| {
+                let t = (s.time - now).num_milliseconds() as f64 / 1000.0;
+                Some([t, s.value.as_f64()?])
+            })
+            .collect();
+        Plot::new("history")
+            .x_axis_label("seconds")
+            .show(ui, |plot| plot.line(Line::new(points).name(param.name())));
+    }
+
+    fn write_dialog(&mut self, ctx: &egui::Context) {
+        let Some(dialog) = &mut self.write else {
+            return;
+        };
+        let mut open = true;
+        let mut send = false;
+        egui::Window::new(format!("Write {}", dialog.param.name()))
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.label(format!("Type: {}", dialog.param.type_info()));
+                ui.text_edit_singleline(&mut dialog.text);
+                send = ui.button("Write").clicked();
+                if let Some(result) = &dialog.result {
+                    ui.label(result);
+                }
+            });
+        if send {
+            let r = match (&mut self.client, dialog.param.value_from_str(&dialog.text)) {
+                (None, _) => "Not connected.".to_string(),
+                (_, Err(e)) => format!("Invalid value: {e:#}"),
+                (Some(client), Ok(value)) => {
+                    let r = client
+                        .transaction()
+                        .guarded(&mut self.guard)
+                        .write(dialog.param.clone(), value)
+                        .execute();
+                    match r.map(|mut r| r.results.pop()) {
+                        Ok(Some(OpResult::Write(w))) if w.is_ok() => "Written.".into(),
+                        Ok(Some(OpResult::Write(w))) => {
+                            format!("Write failed with error code {}.", ErrorCode(w.error_code))
+                        }
+                        Ok(_) => "No write result.".into(),
+                        Err(e) => format!("Write failed: {e:#}"),
+                    }
+                }
+            };
+            dialog.result = Some(r);
+            self.next_read = Instant::now();
+        }
+        if !open {
+            self.write = None;
+        }
+    }
+}
+
+impl eframe::App for GuiApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // Reads block the UI thread, which is fine for the short responses of the instrument.
+        self.poll();
+
+        egui::TopBottomPanel::top("connection").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Instrument IP:");
+                ui.text_edit_singleline(&mut self.ip);
+                if ui.button("Connect").clicked() {
+                    self.connect();
+                }
+                ui.label(&self.status);
+            });
+        });
+        egui::SidePanel::left("sdb")
+            .default_width(320.0)
+            .show(ctx, |ui| self.sdb_browser(ui));
+        egui::CentralPanel::default().show(ctx, |ui| {
+            self.value_table(ui);
+            ui.separator();
+            self.plot(ui);
+        });
+        self.write_dialog(ctx);
+
+        ctx.request_repaint_after(self.next_read.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt().with_target(false).init();
+    let args = GuiArgs::parse();
+    let store = SdbStore::new(&args.sdb).with_parse_mode(ParseMode::Lazy);
+    // The SDB lives as long as the GUI, which needs 'static state.
+    let sdb: &'static Sdb = Box::leak(Box::new(store.load()?));
+    let mut app = GuiApp {
+        sdb,
+        ip: args
+            .ip
+            .as_ref()
+            .map(|ip| ip.to_string())
+            .unwrap_or_default(),
+        client: None,
+        filter: String::new(),
+        watched: Vec::new(),
+        values: Vec::new(),
+        selected: None,
+        interval: Duration::from_secs_f32(args.interval),
+        next_read: Instant::now(),
+        write: None,
+        guard: WriteGuard::new(&Config::load(None)?, audit::current_user())?,
+        status: "Not connected".into(),
+    };
+    if args.ip.is_some() {
+        app.connect();
+    }
+    eframe::run_native(
+        "Leybold OPC",
+        eframe::NativeOptions::default(),
+        Box::new(|_cc| Ok(Box::new(app))),
+    )
+    .map_err(|e| anyhow::anyhow!("{e}"))
+}
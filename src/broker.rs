@@ -0,0 +1,375 @@
+//! Sharing one connection to the instrument between processes, over a Unix socket.
+//!
+//! The instruments seem to serve one client at a time, so tools running side by side
+//! each connecting on their own get in each other's way. A [`Broker`] owns the
+//! connection, and the tools connect to it with [`Connection::connect_unix`] instead.
+//!
+//! The socket file can be opened by the owner and group of the broker. With a
+//! [`BrokerAccess`], the writes of each client are checked against the access policy
+//! for the user running it.
+
+use std::collections::HashMap;
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use binrw::{BinRead, Endian};
+use tracing::{debug, warn};
+
+use crate::access::AccessPolicy;
+use crate::packets::{Dialect, PacketCCHeader};
+use crate::plc_connection::{Connection, DeviceBusy, ACK_RESPONSE};
+use crate::sdb::Sdb;
+
+type Connect = dyn Fn() -> Result<Connection> + Send + Sync;
+
+/// How long a client downloading the SDB may take to ask for the next part, while the
+/// other clients wait.
+const DOWNLOAD_PART_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Passes the queries of the clients on its Unix socket to the instrument, one query
+/// at a time. The connection is made again when it fails.
+///
+/// The socket file is removed when the broker is dropped.
+pub struct Broker {
+    path: PathBuf,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    conn: Mutex<Option<Connection>>,
+    path: PathBuf,
+    dialect: Dialect,
+    connect: Box<Connect>,
+    access: Option<BrokerAccess>,
+}
+
+/// The access policy of a broker, with the parameters by address, for checking the
+/// writes of the clients. Packets the broker doesn't know are refused unless the policy
+/// lets everyone write everything, since they may change the instrument.
+#[derive(Clone, Debug)]
+pub struct BrokerAccess {
+    policy: AccessPolicy,
+    /// The names and sizes of the parameters at each address.
+    params: HashMap<u32, Vec<(String, usize)>>,
+}
+
+impl BrokerAccess {
+    pub fn new(policy: AccessPolicy, sdb: &Sdb) -> Self {
+        let mut params: HashMap<u32, Vec<_>> = HashMap::new();
+        for p in sdb.parameters() {
+            let size = p.type_info().response_len();
+            params
+                .entry(p.id())
+                .or_default()
+                .push((p.name().to_string(), size));
+        }
+        Self { policy, params }
+    }
+
+    /// Fails unless `user` may send the request. Every parameter a write may be
+    /// addressing is checked.
+    fn check(&self, user: &str, request: &[u8], endian: Endian) -> Result<()> {
+        let payload = &request[PacketCCHeader::LEN..];
+        match payload.first() {
+            Some(0x3c) => {}
+            Some(0x2e | 0x11 | 0x31 | 0x32 | 0x34) => return Ok(()),
+            _ if self.policy.is_open() => return Ok(()),
+            kind => bail!("Refusing a packet of unknown kind {kind:02x?} from '{user}'."),
+        }
+        // `3c 00`, count, then `00 03`, address, length and data per write.
+        let mut cur = Cursor::new(payload.get(2..).unwrap_or_default());
+        let count = u32::read_options(&mut cur, endian, ())?;
+        for _ in 0..count {
+            let (_, address, len) = <(u16, u32, u32)>::read_options(&mut cur, endian, ())?;
+            cur.set_position(cur.position() + len as u64);
+            let params = self.params.get(&address).into_iter().flatten();
+            let mut written = params.filter(|(_, size)| *size == len as usize).peekable();
+            if written.peek().is_none() {
+                bail!("Refusing a write of {len} bytes to {address:#x}, no such parameter.");
+            }
+            for (name, _) in written {
+                self.policy.check_write(user, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Broker {
+    /// Connects to the instrument with `connect` and listens on `path`. A socket file
+    /// left by a broker which is no longer running is replaced. Writes are checked with
+    /// `access` if given.
+    pub fn start(
+        path: impl Into<PathBuf>,
+        connect: impl Fn() -> Result<Connection> + Send + Sync + 'static,
+        access: Option<BrokerAccess>,
+    ) -> Result<Self> {
+        let path = path.into();
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                bail!("{} exists and is not a socket.", path.display());
+            }
+            if UnixStream::connect(&path).is_ok() {
+                bail!("A broker is already listening on {}.", path.display());
+            }
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to listen on {}", path.display()))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o660))?;
+        let conn = advertise(connect()?, &path)?;
+        let shared = Arc::new(Shared {
+            dialect: conn.dialect(),
+            conn: Mutex::new(Some(conn)),
+            connect: Box::new(connect),
+            path: path.clone(),
+            access,
+        });
+        let serving = shared.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = serving.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve(stream, &shared) {
+                        warn!("Broker client disconnected: {e:#}");
+                    }
+                });
+            }
+        });
+        debug!("Broker listening on {}", path.display());
+        Ok(Self { path, shared })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sends a keep-alive query if the connection's keep-alive interval has passed, see
+    /// [`Connection::keep_alive`]. The connection is made again on the next query if
+    /// this fails.
+    pub fn keep_alive(&self) -> Result<()> {
+        let mut conn = self.shared.conn.lock().unwrap();
+        if let Some(Err(e)) = conn.as_mut().map(Connection::keep_alive) {
+            *conn = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Records the broker in the session lock of the connection, so that other processes
+/// connecting to the instrument use the broker instead.
+fn advertise(conn: Connection, path: &Path) -> Result<Connection> {
+    if let Some(session) = conn.session() {
+        session.set_broker(path)?;
+    }
+    Ok(conn)
+}
+
+/// Passes on the queries of one client until it disconnects.
+///
+/// An SDB download is a sequence of queries, each asking for the next part, so the
+/// connection is kept for the client from the first query until the last part.
+fn serve(mut stream: UnixStream, shared: &Shared) -> Result<()> {
+    let user = match shared.access {
+        Some(_) => peer_user(&stream).context("Failed to identify the client")?,
+        None => String::new(),
+    };
+    let mut downloading: Option<MutexGuard<Option<Connection>>> = None;
+    loop {
+        stream.set_read_timeout(downloading.is_some().then_some(DOWNLOAD_PART_TIMEOUT))?;
+        let mut hdr_bytes = [0; PacketCCHeader::LEN];
+        match stream.read_exact(&mut hdr_bytes) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            r => r?,
+        }
+        // The broker acknowledges the responses to the instrument itself.
+        if hdr_bytes.starts_with(&[0x66, 0x66]) {
+            stream.write_all(&ACK_RESPONSE)?;
+            continue;
+        }
+        let hdr = PacketCCHeader::read_options(
+            &mut Cursor::new(&hdr_bytes),
+            shared.dialect.endian(),
+            (),
+        )?;
+        let mut request = hdr_bytes.to_vec();
+        request.resize(PacketCCHeader::LEN + hdr.payload_len as usize, 0);
+        stream.read_exact(&mut request[PacketCCHeader::LEN..])?;
+        // Refused requests end the client's connection, leaving the instrument untouched.
+        if let Some(access) = &shared.access {
+            access.check(&user, &request, shared.dialect.endian())?;
+        }
+
+        let mut conn = downloading
+            .take()
+            .unwrap_or_else(|| shared.conn.lock().unwrap());
+        if conn.is_none() {
+            let reconnected = (shared.connect)().context("Reconnecting to the instrument")?;
+            *conn = Some(advertise(reconnected, &shared.path)?);
+        }
+        let device = conn.as_mut().expect("connected above");
+        match device.query_raw(&request) {
+            Ok(response) => {
+                stream.write_all(&response)?;
+                if is_download(&request) && download_continues(&response) {
+                    downloading = Some(conn);
+                }
+            }
+            // Left unanswered, so that the client times out too.
+            Err(e) if e.is::<DeviceBusy>() => {
+                device.resync()?;
+            }
+            Err(e) => {
+                if e.chain().any(|e| e.is::<std::io::Error>()) {
+                    *conn = None;
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// The name of the user running the process at the other end of the socket.
+fn peer_user(stream: &UnixStream) -> std::io::Result<String> {
+    let uid = peer_uid(stream)?;
+    Ok(crate::audit::user_name(uid).unwrap_or_else(|| uid.to_string()))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` outlives the call, and its size is passed along.
+    let r = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    match r {
+        0 => Ok(cred.uid),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: Both pointers are valid for the call.
+    match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Whether the request asks for the SDB or its next part.
+fn is_download(request: &[u8]) -> bool {
+    matches!(request.get(PacketCCHeader::LEN), Some(0x31 | 0x32))
+}
+
+/// Whether a response to a download request has more parts to come.
+fn download_continues(response: &[u8]) -> bool {
+    let hdr = PacketCCHeader::LEN;
+    response
+        .get(hdr..hdr + 4)
+        .is_some_and(|continues| continues != [0; 4])
+}
+
+#[test]
+fn test_broker() {
+    use crate::client::Client;
+    use crate::opc_values::Value;
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, crate::sdb_builder::fixture()).unwrap();
+    let pressure = sdb.param_by_name(".Gauge[1].Parameter[1].Value").unwrap();
+    sim.set(&pressure, &Value::Float(1e-3)).unwrap();
+    let path = std::env::temp_dir().join(format!("broker-{}.sock", std::process::id()));
+
+    let addr = sim.addr();
+    let broker = Broker::start(&path, move || Connection::connect_addr(addr), None).unwrap();
+    assert!(Broker::start(&path, move || Connection::connect_addr(addr), None).is_err());
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+    let mut clients: Vec<_> = (0..2)
+        .map(|_| Client::new(Connection::connect_unix(&path).unwrap(), &sdb).unwrap())
+        .collect();
+    for client in &mut clients {
+        let values = client.read(std::slice::from_ref(&pressure)).unwrap();
+        assert_eq!(values, [Value::Float(1e-3)]);
+    }
+    // Downloads side by side get the whole SDB each, not parts of both.
+    let downloads: Vec<_> = (0..2)
+        .map(|_| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                crate::plc_connection::download_sdb(|| Connection::connect_unix(&path), 1, None)
+            })
+        })
+        .collect();
+    for download in downloads {
+        assert_eq!(
+            download.join().unwrap().unwrap(),
+            crate::sdb_builder::fixture()
+        );
+    }
+    drop(broker);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_broker_access() {
+    use crate::client::Client;
+    use crate::opc_values::Value;
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let operator = crate::access::ClientAccess {
+        allow: vec![".CockpitUser".into()],
+        ..Default::default()
+    };
+    let policy = AccessPolicy {
+        deny: vec![".HostRemote".into()],
+        clients: [(crate::audit::current_user(), operator)].into(),
+        ..Default::default()
+    };
+    let path = std::env::temp_dir().join(format!("broker-access-{}.sock", std::process::id()));
+    let addr = sim.addr();
+    let access = BrokerAccess::new(policy, &sdb);
+    let _broker =
+        Broker::start(&path, move || Connection::connect_addr(addr), Some(access)).unwrap();
+    let connect = || Client::new(Connection::connect_unix(&path).unwrap(), &sdb).unwrap();
+    let user_param = sdb.param_by_name(".CockpitUser").unwrap();
+    let written = connect()
+        .write_many(&[(user_param.clone(), Value::String("service".into()))])
+        .unwrap();
+    assert!(written[0].is_ok());
+    let counter = sdb.param_by_name(".OPCCounter").unwrap();
+    assert!(connect()
+        .write_many(&[(counter.clone(), Value::Int(1))])
+        .is_err());
+    assert_eq!(sim.get(&counter).unwrap(), Value::Int(0));
+    assert_eq!(
+        connect().read(&[user_param]).unwrap()[0],
+        Value::String("service".into())
+    );
+}
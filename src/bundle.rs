@@ -0,0 +1,94 @@
+//! Device description bundles: the SDB, its decoded description, the instrument
+//! identification and a snapshot of the values in one tar archive, for attaching to
+//! service tickets and firmware regression reports.
+
+use std::io::{self, Write};
+
+use anyhow::{ensure, Result};
+use chrono::Utc;
+
+const BLOCK: usize = 512;
+
+/// The files of an archive, written with [`Bundle::write_tar`].
+#[derive(Clone, Debug, Default)]
+pub struct Bundle {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Bundle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file. Fails for names longer than 100 bytes.
+    pub fn add(&mut self, name: &str, data: impl Into<Vec<u8>>) -> Result<&mut Self> {
+        ensure!(
+            name.len() <= 100,
+            "File name {name} too long for the archive."
+        );
+        self.files.push((name.to_string(), data.into()));
+        Ok(self)
+    }
+
+    /// Writes the files as a ustar archive, dated now.
+    pub fn write_tar(&self, out: &mut impl Write) -> io::Result<()> {
+        let mtime = Utc::now().timestamp().max(0) as u64;
+        for (name, data) in &self.files {
+            out.write_all(&tar_header(name, data.len(), mtime))?;
+            out.write_all(data)?;
+            out.write_all(&[0; BLOCK][..padding(data.len())])?;
+        }
+        // The end of the archive is marked by two empty blocks.
+        out.write_all(&[0; 2 * BLOCK])
+    }
+}
+
+fn padding(len: usize) -> usize {
+    len.next_multiple_of(BLOCK) - len
+}
+
+fn tar_header(name: &str, size: usize, mtime: u64) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{size:011o}\0").as_bytes());
+    field(136, format!("{mtime:011o}\0").as_bytes());
+    // The checksum is computed with its own field filled with spaces.
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+#[test]
+fn test_bundle_tar() {
+    let mut tar = vec![];
+    Bundle::new()
+        .add("values.json", "{}")
+        .unwrap()
+        .add("sdb.dat", vec![1; 600])
+        .unwrap()
+        .write_tar(&mut tar)
+        .unwrap();
+    assert_eq!(tar.len(), 2 * BLOCK + 3 * BLOCK + 2 * BLOCK);
+    let header = &tar[BLOCK * 2..BLOCK * 3];
+    assert!(header.starts_with(b"sdb.dat\0"));
+    assert_eq!(&header[124..136], b"00000001130\0");
+    let checksum = u32::from_str_radix(std::str::from_utf8(&header[148..154]).unwrap(), 8);
+    let mut unchecked = header.to_vec();
+    unchecked[148..156].fill(b' ');
+    assert_eq!(
+        checksum.unwrap(),
+        unchecked.iter().map(|&b| b as u32).sum::<u32>()
+    );
+    assert!(tar[tar.len() - 2 * BLOCK..].iter().all(|&b| b == 0));
+    assert!(Bundle::new().add(&"a".repeat(101), "").is_err());
+}
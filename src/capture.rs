@@ -0,0 +1,915 @@
+//! Decoding the protocol passively from packet captures, e.g. of a mirror port between
+//! an HMI and the instrument. Only classic pcap files are read, as written by
+//! `tcpdump -w`; pcapng files can be converted with `editcap -F pcap`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::io::{Cursor, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use binrw::{BinRead, Endian};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::errors::device::ErrorCode;
+use crate::opc_values::Value;
+use crate::packets::cc_payloads::{InstrumentVersionResponse, SdbDownload};
+use crate::packets::{PacketCC, PacketCCHeader};
+use crate::sdb::{Parameter, Sdb};
+
+/// The TCP port the instrument listens on.
+pub const PLC_PORT: u16 = 1202;
+
+const ACK_MAGIC: [u8; 2] = [0x66, 0x66];
+const ACK_LEN: usize = 24;
+
+/// One captured link layer frame.
+#[derive(Clone, Debug)]
+pub struct PcapRecord {
+    /// Capture time since the Unix epoch.
+    pub time: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Reads the records of a classic pcap file, from a file or a pipe.
+pub struct PcapReader<R> {
+    reader: R,
+    endian: Endian,
+    nanos: bool,
+    /// The largest record length.
+    snaplen: usize,
+    link_type: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut hdr = [0; 24];
+        reader
+            .read_exact(&mut hdr)
+            .context("Failed to read the pcap file header.")?;
+        let magic = u32::from_le_bytes(hdr[..4].try_into().unwrap());
+        let (endian, nanos) = match magic {
+            0xa1b2c3d4 => (Endian::Little, false),
+            0xa1b23c4d => (Endian::Little, true),
+            0xd4c3b2a1 => (Endian::Big, false),
+            0x4d3cb2a1 => (Endian::Big, true),
+            0x0a0d0d0a => bail!("pcapng files are not supported, convert with editcap -F pcap."),
+            _ => bail!("Not a pcap file, magic {magic:#010x}."),
+        };
+        let snaplen = read_u32(&hdr[16..], endian) as usize;
+        let link_type = read_u32(&hdr[20..], endian) & 0xffff;
+        Ok(Self {
+            reader,
+            endian,
+            nanos,
+            snaplen,
+            link_type,
+        })
+    }
+
+    /// Returns `None` at the end of the capture.
+    pub fn next_record(&mut self) -> Result<Option<PcapRecord>> {
+        let mut hdr = [0; 16];
+        match self.reader.read_exact(&mut hdr) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            r => r.context("Failed to read pcap record.")?,
+        }
+        let secs = read_u32(&hdr, self.endian) as u64;
+        let frac = read_u32(&hdr[4..], self.endian);
+        let len = read_u32(&hdr[8..], self.endian) as usize;
+        let time = match self.nanos {
+            true => Duration::new(secs, frac),
+            false => Duration::new(secs, frac.saturating_mul(1000)),
+        };
+        if len > self.snaplen {
+            bail!(
+                "Invalid pcap record of {len} bytes, the limit is {}.",
+                self.snaplen
+            );
+        }
+        // Read as it arrives rather than allocated up front, so that a corrupt length
+        // can't take more memory than the rest of the file.
+        let mut data = Vec::new();
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut data)
+            .context("Failed to read pcap record.")?;
+        if data.len() < len {
+            bail!("Truncated pcap record.");
+        }
+        Ok(Some(PcapRecord { time, data }))
+    }
+
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+}
+
+fn read_u32(b: &[u8], endian: Endian) -> u32 {
+    let b = b[..4].try_into().unwrap();
+    match endian {
+        Endian::Big => u32::from_be_bytes(b),
+        Endian::Little => u32::from_le_bytes(b),
+    }
+}
+
+fn read_u16(b: &[u8], endian: Endian) -> u16 {
+    let b = b[..2].try_into().unwrap();
+    match endian {
+        Endian::Big => u16::from_be_bytes(b),
+        Endian::Little => u16::from_le_bytes(b),
+    }
+}
+
+struct TcpSegment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+/// Finds the TCP segment in a link layer frame, for the link types tcpdump uses on
+/// Ethernet interfaces, the "any" interface and raw IP tunnels.
+fn parse_tcp(link_type: u32, frame: &[u8]) -> Option<TcpSegment<'_>> {
+    let (ethertype, ip) = match link_type {
+        // Ethernet, with an optional VLAN tag.
+        1 => {
+            let mut off = 12;
+            let mut ethertype = u16::from_be_bytes(frame.get(off..off + 2)?.try_into().ok()?);
+            if ethertype == 0x8100 {
+                off += 4;
+                ethertype = u16::from_be_bytes(frame.get(off..off + 2)?.try_into().ok()?);
+            }
+            (ethertype, frame.get(off + 2..)?)
+        }
+        // Raw IP.
+        101 | 228 | 229 => match frame.first()? >> 4 {
+            4 => (0x0800, frame),
+            6 => (0x86dd, frame),
+            _ => return None,
+        },
+        // Linux cooked capture.
+        113 => (
+            u16::from_be_bytes(frame.get(14..16)?.try_into().ok()?),
+            frame.get(16..)?,
+        ),
+        _ => return None,
+    };
+    let (src, dst, tcp) = match ethertype {
+        0x0800 => {
+            let ihl = (ip.first()? & 0x0f) as usize * 4;
+            let total = u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?) as usize;
+            if *ip.get(9)? != 6 {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            // Ethernet pads short frames, the IP length excludes the padding.
+            let tcp = ip.get(ihl..total.min(ip.len()))?;
+            (
+                IpAddr::from(Ipv4Addr::from(src)),
+                IpAddr::from(Ipv4Addr::from(dst)),
+                tcp,
+            )
+        }
+        0x86dd => {
+            let len = u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?) as usize;
+            // Extension headers are not followed, they are unusual on a local network.
+            if *ip.get(6)? != 6 {
+                return None;
+            }
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            let tcp = ip.get(40..(40 + len).min(ip.len()))?;
+            (
+                IpAddr::from(Ipv6Addr::from(src)),
+                IpAddr::from(Ipv6Addr::from(dst)),
+                tcp,
+            )
+        }
+        _ => return None,
+    };
+    let port = |off: usize| Some(u16::from_be_bytes(tcp.get(off..off + 2)?.try_into().ok()?));
+    let data_off = (tcp.get(12)? >> 4) as usize * 4;
+    Some(TcpSegment {
+        src: SocketAddr::new(src, port(0)?),
+        dst: SocketAddr::new(dst, port(2)?),
+        seq: u32::from_be_bytes(tcp.get(4..8)?.try_into().ok()?),
+        syn: tcp.get(13)? & 0x02 != 0,
+        payload: tcp.get(data_off..)?,
+    })
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    ToPlc,
+    FromPlc,
+}
+
+/// A complete packet of the protocol, or one of the "66 66" acknowledgements.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub time: Duration,
+    /// The address of the HMI side, which identifies the connection.
+    pub client: SocketAddr,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+impl Frame {
+    pub fn is_ack(&self) -> bool {
+        self.bytes.starts_with(&ACK_MAGIC)
+    }
+}
+
+#[derive(Default)]
+struct Stream {
+    next_seq: Option<u32>,
+    buf: Vec<u8>,
+    /// The buffer starts at a packet boundary.
+    synced: bool,
+}
+
+impl Stream {
+    fn push(&mut self, seg: &TcpSegment) {
+        if seg.syn {
+            *self = Self {
+                next_seq: Some(seg.seq.wrapping_add(1)),
+                buf: Vec::new(),
+                synced: true,
+            };
+            return;
+        }
+        if seg.payload.is_empty() {
+            return;
+        }
+        let next = *self.next_seq.get_or_insert(seg.seq);
+        let mut payload = seg.payload;
+        match seg.seq.wrapping_sub(next) as i32 {
+            0 => {}
+            gap if gap > 0 => {
+                warn!("{gap} bytes missing from the capture, skipping to the next packet.");
+                self.buf.clear();
+                self.synced = false;
+            }
+            overlap => {
+                // Retransmitted data.
+                let overlap = overlap.unsigned_abs() as usize;
+                if overlap >= payload.len() {
+                    return;
+                }
+                payload = &payload[overlap..];
+            }
+        }
+        self.buf.extend_from_slice(payload);
+        self.next_seq = Some(seg.seq.wrapping_add(seg.payload.len() as u32));
+    }
+
+    /// Splits the complete packets off the buffer. A capture started in the middle of
+    /// a connection is synchronized at the next header magic.
+    fn frames(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        loop {
+            if !self.synced {
+                match find_header(&self.buf) {
+                    Some(pos) => {
+                        self.buf.drain(..pos);
+                        self.synced = true;
+                    }
+                    None => {
+                        let keep = self.buf.len().min(3);
+                        self.buf.drain(..self.buf.len() - keep);
+                        return frames;
+                    }
+                }
+            }
+            let len = if self.buf.starts_with(&ACK_MAGIC) {
+                ACK_LEN
+            } else if let Some(endian) = header_endian(&self.buf) {
+                if self.buf.len() < PacketCCHeader::LEN {
+                    return frames;
+                }
+                PacketCCHeader::LEN + read_u16(&self.buf[6..], endian) as usize
+            } else if self.buf.len() < 4 {
+                return frames;
+            } else {
+                warn!("Lost packet boundaries in the captured stream.");
+                self.synced = false;
+                continue;
+            };
+            if self.buf.len() < len {
+                return frames;
+            }
+            frames.push(self.buf.drain(..len).collect());
+        }
+    }
+}
+
+/// The byte order of a packet, from its header magic.
+fn header_endian(bytes: &[u8]) -> Option<Endian> {
+    match bytes.get(..4)? {
+        [0xcc, 0xcc, 0x00, 0x01] => Some(Endian::Big),
+        [0x01, 0x00, 0xcc, 0xcc] => Some(Endian::Little),
+        _ => None,
+    }
+}
+
+fn find_header(bytes: &[u8]) -> Option<usize> {
+    (0..bytes.len().saturating_sub(3)).find(|&i| header_endian(&bytes[i..]).is_some())
+}
+
+/// Reassembles the TCP streams to and from the instrument into packets.
+pub struct Reassembler {
+    plc_port: u16,
+    link_type: u32,
+    streams: HashMap<(SocketAddr, SocketAddr), Stream>,
+}
+
+impl Reassembler {
+    pub fn new(link_type: u32, plc_port: u16) -> Self {
+        Self {
+            plc_port,
+            link_type,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Returns the packets completed by the captured frame, which is ignored if it
+    /// isn't TCP to or from the instrument port.
+    pub fn push(&mut self, record: &PcapRecord) -> Vec<Frame> {
+        let Some(seg) = parse_tcp(self.link_type, &record.data) else {
+            return Vec::new();
+        };
+        let (client, direction) = if seg.dst.port() == self.plc_port {
+            (seg.src, Direction::ToPlc)
+        } else if seg.src.port() == self.plc_port {
+            (seg.dst, Direction::FromPlc)
+        } else {
+            return Vec::new();
+        };
+        let stream = self.streams.entry((seg.src, seg.dst)).or_default();
+        stream.push(&seg);
+        stream
+            .frames()
+            .into_iter()
+            .map(|bytes| Frame {
+                time: record.time,
+                client,
+                direction,
+                bytes,
+            })
+            .collect()
+    }
+}
+
+/// A parameter named in a packet, with its value if the packet has one.
+#[derive(Clone, Debug)]
+pub struct ParamEntry<'sdb> {
+    pub id: u32,
+    /// `None` if the id is not in the SDB.
+    pub param: Option<Parameter<'sdb>>,
+    pub value: Option<Value>,
+}
+
+impl ParamEntry<'_> {
+    pub fn name(&self) -> String {
+        match &self.param {
+            Some(p) => p.name().to_string(),
+            None => format!("<id {:#x}>", self.id),
+        }
+    }
+}
+
+/// A decoded packet.
+#[derive(Clone, Debug)]
+pub struct Message<'sdb> {
+    pub time: Duration,
+    pub client: SocketAddr,
+    pub direction: Direction,
+    /// The command, e.g. `read`, with `-response` appended for the responses.
+    pub kind: String,
+    pub error_code: Option<u16>,
+    pub params: Vec<ParamEntry<'sdb>>,
+    /// Anything else understood about the packet.
+    pub detail: Option<String>,
+    /// The number of payload bytes understood, the rest is unexplained.
+    pub decoded_len: usize,
+}
+
+impl Display for Message<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let time = DateTime::<Utc>::from(UNIX_EPOCH + self.time);
+        let arrow = match self.direction {
+            Direction::ToPlc => "->",
+            Direction::FromPlc => "<-",
+        };
+        write!(
+            f,
+            "{} {} {arrow} {}",
+            time.format("%H:%M:%S%.3f"),
+            self.client,
+            self.kind
+        )?;
+        if let Some(code) = self.error_code.filter(|c| *c != 0) {
+            write!(f, " error {}", ErrorCode(code))?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, " {detail}")?;
+        }
+        for entry in &self.params {
+            match &entry.value {
+                Some(v) => write!(f, "\n    {} = {v:?}", entry.name())?,
+                None => write!(f, "\n    {}", entry.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the packets of the captured connections. Responses don't say what they
+/// respond to, so they are decoded by the last request on the same connection.
+pub struct Analyzer<'sdb> {
+    sdb: Option<&'sdb Sdb>,
+    pending: HashMap<SocketAddr, (&'static str, Vec<ParamEntry<'sdb>>)>,
+}
+
+impl<'sdb> Analyzer<'sdb> {
+    /// Without an SDB the parameters are shown by id, and read responses are not decoded.
+    pub fn new(sdb: Option<&'sdb Sdb>) -> Self {
+        Self {
+            sdb,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn decode(&mut self, frame: &Frame) -> Message<'sdb> {
+        let mut msg = Message {
+            time: frame.time,
+            client: frame.client,
+            direction: frame.direction,
+            kind: "unknown".into(),
+            error_code: None,
+            params: Vec::new(),
+            detail: None,
+            decoded_len: 0,
+        };
+        if frame.is_ack() {
+            msg.kind = "ack".into();
+            return msg;
+        }
+        let endian = header_endian(&frame.bytes).expect("frame without header magic");
+        let payload = &frame.bytes[PacketCCHeader::LEN..];
+        match frame.direction {
+            Direction::ToPlc => {
+                let (kind, params, decoded_len) = match self.decode_request(payload, endian) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Failed to decode request: {e:#}");
+                        (command_name(payload, endian), Vec::new(), 0)
+                    }
+                };
+                msg.kind = kind.into();
+                msg.decoded_len = decoded_len;
+                msg.params.clone_from(&params);
+                if kind == "read" {
+                    msg.detail = Some(format!("{} parameters", params.len()));
+                }
+                self.pending.insert(frame.client, (kind, params));
+            }
+            Direction::FromPlc => {
+                let (kind, params) = self
+                    .pending
+                    .remove(&frame.client)
+                    .unwrap_or(("unknown", Vec::new()));
+                msg.kind = format!("{kind}-response");
+                if let Err(e) = decode_response(kind, &frame.bytes, endian, params, &mut msg) {
+                    msg.detail = Some(format!("{e:#}"));
+                }
+            }
+        }
+        msg
+    }
+
+    fn decode_request(
+        &self,
+        payload: &[u8],
+        endian: Endian,
+    ) -> Result<(&'static str, Vec<ParamEntry<'sdb>>, usize)> {
+        let kind = command_name(payload, endian);
+        let mut r = Cursor::new(payload.get(2..).unwrap_or_default());
+        let mut params = Vec::new();
+        match kind {
+            "read" => {
+                for _ in 0..u32::read_options(&mut r, endian, ())? {
+                    let _magic = u16::read_options(&mut r, endian, ())?;
+                    let id = u32::read_options(&mut r, endian, ())?;
+                    let len = u32::read_options(&mut r, endian, ())?;
+                    params.push(self.entry(id, Some(len as usize)));
+                }
+                self.check_sdb_id(u32::read_options(&mut r, endian, ())?);
+            }
+            "write" => {
+                for _ in 0..u32::read_options(&mut r, endian, ())? {
+                    let _magic = u16::read_options(&mut r, endian, ())?;
+                    let id = u32::read_options(&mut r, endian, ())?;
+                    let len = u32::read_options(&mut r, endian, ())? as usize;
+                    let left = r.get_ref().len() - r.position() as usize;
+                    if len > left {
+                        bail!("Write of {len} bytes in {left} bytes of request.");
+                    }
+                    let mut data = vec![0; len];
+                    r.read_exact(&mut data)?;
+                    let mut entry = self.entry(id, None);
+                    entry.value = entry
+                        .param
+                        .as_ref()
+                        .and_then(|p| Value::parse_endian(&data, &p.type_info(), endian).ok());
+                    params.push(entry);
+                }
+                self.check_sdb_id(u32::read_options(&mut r, endian, ())?);
+            }
+            // The SDB commands have no arguments, only a constant file name.
+            "unknown" => return Ok((kind, params, 0)),
+            _ => return Ok((kind, params, payload.len())),
+        }
+        Ok((kind, params, 2 + r.position() as usize))
+    }
+
+    /// Arrays and structs share the id of their first element, the response length
+    /// of a read tells them apart.
+    fn entry(&self, id: u32, response_len: Option<usize>) -> ParamEntry<'sdb> {
+        let param = self.sdb.and_then(|sdb| {
+            let mut candidates = sdb.params_by_id(id).peekable();
+            let first = candidates.peek().cloned();
+            candidates
+                .find(|p| response_len.is_none_or(|len| p.type_info().response_len() == len))
+                .or(first)
+        });
+        ParamEntry {
+            id,
+            param,
+            value: None,
+        }
+    }
+
+    fn check_sdb_id(&self, id: u32) {
+        if let Some(sdb) = self.sdb.filter(|sdb| sdb.sdb_id() != id) {
+            warn!(
+                "The HMI uses SDB {id:#x}, not {:#x}, names may be wrong.",
+                sdb.sdb_id()
+            );
+        }
+    }
+}
+
+fn command_name(payload: &[u8], endian: Endian) -> &'static str {
+    match (
+        payload.get(..2).map(|b| read_u16(b, endian)),
+        payload.first(),
+    ) {
+        (Some(0x2e00), _) => "read",
+        (Some(0x3c00), _) => "write",
+        (_, Some(0x11)) => "version",
+        (_, Some(0x34)) => "sdb-version",
+        (_, Some(0x31)) => "sdb-download",
+        (_, Some(0x32)) => "sdb-download-continue",
+        _ => "unknown",
+    }
+}
+
+fn decode_response<'sdb>(
+    kind: &str,
+    bytes: &[u8],
+    endian: Endian,
+    mut params: Vec<ParamEntry<'sdb>>,
+    msg: &mut Message<'sdb>,
+) -> Result<()> {
+    let payload = &bytes[PacketCCHeader::LEN..];
+    let error_code = payload.get(..2).map(|b| read_u16(b, endian));
+    match kind {
+        "read" => {
+            msg.error_code = error_code;
+            // The values are decoded by hand, the packet decoder expects well-formed
+            // responses to queries it sent itself.
+            let mut data = payload.get(6..).unwrap_or_default();
+            for entry in &mut params {
+                let Some(param) = &entry.param else { break };
+                let ty = param.type_info();
+                let len = ty.response_len();
+                if data.first() != Some(&1) || data.len() < 1 + len {
+                    break;
+                }
+                entry.value = Value::parse_endian(&data[1..1 + len], &ty, endian).ok();
+                data = &data[1 + len..];
+            }
+            msg.params = params;
+            msg.decoded_len = payload.len() - data.len();
+        }
+        "write" => {
+            msg.error_code = error_code;
+            msg.params = params;
+            msg.decoded_len = payload.len().min(2);
+        }
+        "version" => {
+            let r = PacketCC::<InstrumentVersionResponse>::read_options(
+                &mut Cursor::new(bytes),
+                endian,
+                (),
+            )?;
+            msg.error_code = Some(r.payload.error_code);
+            msg.decoded_len = payload.len();
+            msg.detail = Some(format!(
+                "{} (SDB {:#x})",
+                r.payload.description(),
+                r.payload.sdb_version
+            ));
+        }
+        "sdb-version" => {
+            msg.error_code = error_code;
+            if let Some(size) = payload.get(2..6) {
+                msg.detail = Some(format!("SDB size {}", read_u32(size, endian)));
+                msg.decoded_len = 6;
+            }
+        }
+        "sdb-download" | "sdb-download-continue" => {
+            let r = PacketCC::<SdbDownload>::read_options(&mut Cursor::new(bytes), endian, ())?;
+            msg.decoded_len = 6 + r.payload.sdb_part.len();
+            msg.detail = Some(format!(
+                "{} bytes{}",
+                r.payload.sdb_part.len(),
+                if r.payload.continues {
+                    ", continues"
+                } else {
+                    ""
+                }
+            ));
+        }
+        _ => msg.detail = Some(format!("{} bytes", payload.len())),
+    }
+    Ok(())
+}
+
+/// A packet in an [`AnnotatedCapture`].
+#[derive(Clone, Debug, Serialize)]
+pub struct AnnotatedPacket {
+    pub time: DateTime<Utc>,
+    pub kind: String,
+    pub header: PacketCCHeader,
+    /// Header fields with values which this crate doesn't send or expect.
+    pub unexplained_header: Vec<String>,
+    pub error_code: Option<u16>,
+    pub params: Vec<AnnotatedParam>,
+    pub detail: Option<String>,
+    /// Offset in the payload of the first byte not understood.
+    pub unresolved_offset: usize,
+    /// The payload bytes from `unresolved_offset` on, in hex.
+    pub unresolved: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AnnotatedParam {
+    pub id: u32,
+    pub name: Option<String>,
+    pub value: Option<Value>,
+}
+
+/// A request with the response to it, if one was captured.
+#[derive(Clone, Debug, Serialize)]
+pub struct Exchange {
+    pub client: SocketAddr,
+    pub request: AnnotatedPacket,
+    pub response: Option<AnnotatedPacket>,
+}
+
+/// A report of everything decoded from a capture, and what is still unexplained.
+#[derive(Debug, Default, Serialize)]
+pub struct AnnotatedCapture {
+    pub exchanges: Vec<Exchange>,
+    /// Responses without a request, e.g. at the start of the capture.
+    pub unmatched_responses: Vec<AnnotatedPacket>,
+    pub acks: usize,
+    /// How often each value of the header fields not fully understood was seen,
+    /// by field and packet kind.
+    pub header_values: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+/// Collects the frames of a capture into an [`AnnotatedCapture`].
+pub struct Annotator<'sdb> {
+    analyzer: Analyzer<'sdb>,
+    pending: HashMap<SocketAddr, AnnotatedPacket>,
+    report: AnnotatedCapture,
+}
+
+impl<'sdb> Annotator<'sdb> {
+    pub fn new(sdb: Option<&'sdb Sdb>) -> Self {
+        Self {
+            analyzer: Analyzer::new(sdb),
+            pending: HashMap::new(),
+            report: AnnotatedCapture::default(),
+        }
+    }
+
+    pub fn push(&mut self, frame: &Frame) -> Result<()> {
+        if frame.is_ack() {
+            self.report.acks += 1;
+            return Ok(());
+        }
+        let msg = self.analyzer.decode(frame);
+        let endian = header_endian(&frame.bytes).expect("frame without header magic");
+        let header = PacketCCHeader::read_options(&mut Cursor::new(&frame.bytes), endian, ())?;
+        let payload = &frame.bytes[PacketCCHeader::LEN..];
+        let request = self.pending.remove(&frame.client);
+        let mut packet = AnnotatedPacket {
+            time: DateTime::from(UNIX_EPOCH + frame.time),
+            kind: msg.kind,
+            header,
+            unexplained_header: Vec::new(),
+            error_code: msg.error_code,
+            params: msg
+                .params
+                .iter()
+                .map(|e| AnnotatedParam {
+                    id: e.id,
+                    name: e.param.as_ref().map(|p| p.name().to_string()),
+                    value: e.value.clone(),
+                })
+                .collect(),
+            detail: msg.detail,
+            unresolved_offset: msg.decoded_len,
+            unresolved: payload[msg.decoded_len.min(payload.len())..]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        };
+        self.explain_header(&mut packet, frame.direction, request.as_ref());
+        match (frame.direction, request) {
+            (Direction::ToPlc, unanswered) => {
+                if let Some(request) = unanswered {
+                    self.report.exchanges.push(Exchange {
+                        client: frame.client,
+                        request,
+                        response: None,
+                    });
+                }
+                self.pending.insert(frame.client, packet);
+            }
+            (Direction::FromPlc, Some(request)) => self.report.exchanges.push(Exchange {
+                client: frame.client,
+                request,
+                response: Some(packet),
+            }),
+            (Direction::FromPlc, None) => self.report.unmatched_responses.push(packet),
+        }
+        Ok(())
+    }
+
+    /// Notes the header fields which differ from what [`PacketCCHeader::new_cmd`] sends,
+    /// and counts the values of the fields whose meaning is a guess.
+    fn explain_header(
+        &mut self,
+        packet: &mut AnnotatedPacket,
+        direction: Direction,
+        request: Option<&AnnotatedPacket>,
+    ) {
+        let hdr = packet.header;
+        let marker = match direction {
+            Direction::ToPlc => 0x23,
+            Direction::FromPlc => 0x27,
+        };
+        let len2 = if hdr.len2 == hdr.payload_len {
+            "payload_len".to_string()
+        } else if request.is_some_and(|r| r.header.payload_len == hdr.len2) {
+            "request payload_len".to_string()
+        } else {
+            hdr.len2.to_string()
+        };
+        let fields = [
+            (
+                "u16_zero",
+                format!("{:#06x}", hdr.u16_zero),
+                hdr.u16_zero != 0,
+            ),
+            (
+                "u64_8_f",
+                format!("{:#018x}", hdr.u64_8_f),
+                hdr.u64_8_f != 0,
+            ),
+            (
+                "one_if_data_poll_maybe",
+                hdr.one_if_data_poll_maybe.to_string(),
+                hdr.one_if_data_poll_maybe > 1,
+            ),
+            ("u8_14", format!("{:#04x}", hdr.u8_14), hdr.u8_14 != 0),
+            (
+                "len2",
+                len2,
+                direction == Direction::ToPlc && hdr.len2 != hdr.payload_len,
+            ),
+            ("b17", format!("{:#04x}", hdr.b17), hdr.b17 != marker),
+        ];
+        for (field, value, unexpected) in fields {
+            if unexpected {
+                packet.unexplained_header.push(format!("{field} = {value}"));
+            }
+            *self
+                .report
+                .header_values
+                .entry(format!("{field} ({})", packet.kind))
+                .or_default()
+                .entry(value)
+                .or_default() += 1;
+        }
+    }
+
+    /// Returns the report, with the requests still waiting for a response.
+    pub fn finish(mut self) -> AnnotatedCapture {
+        for (client, request) in self.pending.drain() {
+            self.report.exchanges.push(Exchange {
+                client,
+                request,
+                response: None,
+            });
+        }
+        self.report
+            .exchanges
+            .sort_by_key(|e| (e.request.time, e.client));
+        self.report
+    }
+}
+
+#[test]
+fn test_analyze_capture() {
+    use crate::packets::ParamQuerySetBuilder;
+    use binrw::BinWrite;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let param = sdb.param_by_name(".CockpitUser").unwrap();
+    let pkt = [param.clone()]
+        .into_iter()
+        .collect::<ParamQuerySetBuilder>()
+        .into_query_packet();
+    let mut request = Vec::new();
+    pkt.write_options(&mut Cursor::new(&mut request), Endian::Big, ())
+        .unwrap();
+
+    // Ethernet + IPv4 + TCP frames, the request split across two segments.
+    let tcp_frame = |seq: u32, payload: &[u8]| {
+        let mut f = vec![0; 14];
+        f[12..14].copy_from_slice(&[0x08, 0x00]);
+        let mut ip = vec![
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1,
+        ];
+        let total = (20 + 20 + payload.len()) as u16;
+        ip[2..4].copy_from_slice(&total.to_be_bytes());
+        f.extend(ip);
+        let mut tcp = vec![0; 20];
+        tcp[0..2].copy_from_slice(&50000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&PLC_PORT.to_be_bytes());
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+        tcp[12] = 5 << 4;
+        f.extend(tcp);
+        f.extend_from_slice(payload);
+        f
+    };
+    let mut pcap = Vec::new();
+    pcap.extend(0xa1b2c3d4u32.to_le_bytes());
+    pcap.extend([2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 1, 0, 0, 0]);
+    for (seq, part) in [(100, &request[..10]), (110, &request[10..])] {
+        let frame = tcp_frame(seq, part);
+        pcap.extend(1_700_000_000u32.to_le_bytes());
+        pcap.extend(0u32.to_le_bytes());
+        pcap.extend((frame.len() as u32).to_le_bytes());
+        pcap.extend((frame.len() as u32).to_le_bytes());
+        pcap.extend(frame);
+    }
+
+    let mut reader = PcapReader::new(pcap.as_slice()).unwrap();
+    let mut reassembler = Reassembler::new(reader.link_type(), PLC_PORT);
+    let mut analyzer = Analyzer::new(Some(&sdb));
+    let mut messages = Vec::new();
+    while let Some(record) = reader.next_record().unwrap() {
+        for frame in reassembler.push(&record) {
+            messages.push(analyzer.decode(&frame));
+        }
+    }
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].kind, "read");
+    assert_eq!(messages[0].direction, Direction::ToPlc);
+    assert_eq!(messages[0].params[0].param.as_ref(), Some(&param));
+    assert_eq!(messages[0].decoded_len, request.len() - PacketCCHeader::LEN);
+
+    // Record lengths beyond the snaplen, or the end of the file, are errors.
+    for len in [u32::MAX, 1000] {
+        let mut corrupt = pcap[..24].to_vec();
+        corrupt.extend([0; 8]);
+        corrupt.extend(len.to_le_bytes());
+        corrupt.extend(len.to_le_bytes());
+        let mut reader = PcapReader::new(corrupt.as_slice()).unwrap();
+        assert!(reader.next_record().is_err());
+    }
+}
@@ -0,0 +1,1478 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{debug, warn};
+
+use crate::audit::WriteGuard;
+use crate::clock::Clock;
+use crate::coalesce::ReadPlan;
+use crate::errors::device::ErrorCode;
+use crate::history::{History, Sample};
+use crate::opc_values::{EncodeOpcValue, Value};
+use crate::packets::cc_payloads::{InstrumentVersionQuery, SdbVersionQuery};
+use crate::packets::{
+    DeviceStatus, Dialect, PacketCC, ParamQuerySetBuilder, ParamStatus, ParamWrite,
+    ParamsReadQuery, PayloadParamWrite, RawReadQuery,
+};
+use crate::plc_connection::{Connection, DeviceBusy, EncodedQuery};
+use crate::sdb::{Parameter, Sdb, TypeKind};
+
+/// The largest response payload known to be accepted by all runtimes.
+pub const DEFAULT_MAX_RESPONSE_LEN: usize = 0x300;
+
+/// The largest response length tried by [`Client::probe_max_response_len`].
+pub const PROBE_RESPONSE_LEN_LIMIT: usize = 0x2000;
+
+/// The number of parameter sets [`Client::read_cached`] keeps encoded queries for.
+pub const QUERY_CACHE_CAPACITY: usize = 64;
+
+type ReadQuery<'sdb> = EncodedQuery<'sdb, ParamsReadQuery<'sdb>>;
+
+/// The encoded read queries of a parameter set, with the plan they were built from.
+struct ReadQueries<'sdb> {
+    plan: ReadPlan<'sdb>,
+    packets: Vec<ReadQuery<'sdb>>,
+}
+
+bitflags::bitflags! {
+    /// Optional protocol features of the connected runtime, for applications to adapt
+    /// to the firmware generation.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct ProtocolFeatures: u32 {
+        /// File download, which the SDB is read with.
+        const FILE_API = 1 << 0;
+        /// Read responses beyond [`DEFAULT_MAX_RESPONSE_LEN`], as found by
+        /// [`Client::probe_max_response_len`].
+        const LARGE_PAYLOAD = 1 << 1;
+    }
+}
+
+/// What the connected runtime supports, determined from its version response and by
+/// probing.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// The runtime description reported by the instrument.
+    pub runtime: String,
+    pub sdb_version: u32,
+    /// The second, so far unexplained, word of the version response.
+    pub version_word: u32,
+    /// The dialect of the connection. Responses with the markers of other dialects are
+    /// anomalies, see [`Connection::set_strict`].
+    pub dialect: Dialect,
+    /// Upper limit for the response payload of one parameter read query. The version
+    /// response doesn't tell, so this is [`DEFAULT_MAX_RESPONSE_LEN`] until found with
+    /// [`Client::probe_max_response_len`].
+    pub max_response_len: usize,
+    pub features: ProtocolFeatures,
+}
+
+impl Capabilities {
+    /// Queries the instrument version and derives the capabilities from it, then probes
+    /// for the optional features. A response marker other than the dialect's is reported
+    /// as an anomaly by the connection, and fails in strict mode, rather than being taken
+    /// for a dialect of its own.
+    pub fn negotiate(conn: &mut Connection) -> Result<Self> {
+        let r = conn.query(&InstrumentVersionQuery::pkt())?;
+        let version = r.payload;
+        if let Some(code @ 1..) = version.error_code() {
+            bail!("Version query failed with error code {}.", ErrorCode(code));
+        }
+        let caps = Self {
+            runtime: version.description(),
+            sdb_version: version.sdb_version,
+            version_word: version.u32_0,
+            dialect: conn.dialect(),
+            max_response_len: DEFAULT_MAX_RESPONSE_LEN,
+            features: probe_features(conn),
+        };
+        debug!("Negotiated {caps:?}");
+        Ok(caps)
+    }
+}
+
+/// Probes with queries which don't change the runtime's state. A feature is missing
+/// when its query fails in any way, e.g. is answered with an error code.
+fn probe_features(conn: &mut Connection) -> ProtocolFeatures {
+    let mut features = ProtocolFeatures::empty();
+    match conn.query(&SdbVersionQuery::pkt()) {
+        Ok(r) if r.payload.error_code == 0 => features |= ProtocolFeatures::FILE_API,
+        Ok(r) => debug!(
+            "No file API, error code {}.",
+            ErrorCode(r.payload.error_code)
+        ),
+        Err(e) => {
+            debug!("No file API: {e:#}");
+            // Don't take a late answer for the response to the next query.
+            if is_timeout(&e) {
+                let _ = conn.resync();
+            }
+        }
+    }
+    features
+}
+
+/// A poll interval which backs off while the device is busy, and recovers gradually.
+#[derive(Clone, Debug)]
+pub struct AdaptiveInterval {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl AdaptiveInterval {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            current: base,
+        }
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Records the outcome of a poll and returns the interval until the next one. Busy
+    /// responses double the interval. Slow answers don't, since a slow link isn't a busy
+    /// device.
+    pub fn record(&mut self, busy: bool) -> Duration {
+        if busy {
+            self.current = (self.current * 2).min(self.max);
+        } else {
+            self.current = self.current.mul_f64(0.9).max(self.base);
+        }
+        self.current
+    }
+}
+
+/// Tunes the response size limit of reads to the link. The limit grows while larger
+/// queries raise the throughput, steps back when they lower it, and halves when a read
+/// fails. Sizes which were slower or failed aren't tried again.
+#[derive(Clone, Debug)]
+pub struct AdaptiveBatching {
+    min: usize,
+    /// The largest size to try, lowered by failures and slowdowns.
+    ceiling: usize,
+    current: usize,
+    /// The size and throughput, in bytes per second, of the previous read.
+    previous: Option<(usize, f64)>,
+}
+
+impl AdaptiveBatching {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.min(max);
+        Self {
+            min,
+            ceiling: max,
+            current: DEFAULT_MAX_RESPONSE_LEN.clamp(min, max),
+            previous: None,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Records a read of `bytes` which took `elapsed`, or failed, and returns the
+    /// limit for the next read.
+    pub fn record(&mut self, bytes: usize, elapsed: Duration, failed: bool) -> usize {
+        if failed {
+            self.ceiling = (self.current - 1).max(self.min);
+            self.current = (self.current / 2).max(self.min);
+            self.previous = None;
+            return self.current;
+        }
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(1e-6);
+        match self.previous {
+            // Allow for some jitter before deciding that larger queries are slower.
+            Some((len, previous)) if len < self.current && rate < previous * 0.9 => {
+                self.ceiling = len;
+                self.current = len;
+                self.previous = None;
+            }
+            _ => {
+                self.previous = Some((self.current, rate));
+                self.current = (self.current + self.current / 4).min(self.ceiling);
+            }
+        }
+        self.current
+    }
+}
+
+/// Decides when the poll loop polls next and when it stops, after a number of polls or
+/// at a deadline.
+#[derive(Debug)]
+pub struct PollSchedule {
+    clock: Arc<dyn Clock>,
+    /// `None` polls once.
+    interval: Option<AdaptiveInterval>,
+    count: Option<u64>,
+    started: Instant,
+    deadline: Option<Instant>,
+    jitter: Option<Jitter>,
+    polls: u64,
+    busy: u64,
+}
+
+impl PollSchedule {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        interval: Option<AdaptiveInterval>,
+        count: Option<u64>,
+        duration: Option<Duration>,
+    ) -> Self {
+        let started = clock.now();
+        Self {
+            clock,
+            interval,
+            count,
+            started,
+            deadline: duration.map(|d| started + d),
+            jitter: None,
+            polls: 0,
+            busy: 0,
+        }
+    }
+
+    /// Spreads the waits between polls randomly by up to `max` either way.
+    pub fn with_jitter(mut self, max: Duration) -> Self {
+        self.jitter = Some(Jitter::new(max));
+        self
+    }
+
+    pub fn is_polling(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.count.is_some_and(|n| self.polls >= n)
+            || self.deadline.is_some_and(|d| self.clock.now() >= d)
+    }
+
+    /// Records a successful poll, and returns how long to wait for the next one, or
+    /// `None` when polling is done.
+    pub fn polled(&mut self) -> Option<Duration> {
+        self.polls += 1;
+        let now = self.clock.now();
+        if self.is_done() {
+            return None;
+        }
+        let mut d = self.interval.as_mut()?.record(false);
+        if let Some(jitter) = &mut self.jitter {
+            d = jitter.apply(d);
+        }
+        // Don't wait past the deadline.
+        Some(self.deadline.map_or(d, |end| d.min(end - now)))
+    }
+
+    /// Records a poll answered busy, and returns the slowed down interval.
+    pub fn busy(&mut self) -> Duration {
+        self.busy += 1;
+        match &mut self.interval {
+            Some(interval) => interval.record(true),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn polls(&self) -> u64 {
+        self.polls
+    }
+
+    pub fn busy_polls(&self) -> u64 {
+        self.busy
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now() - self.started
+    }
+}
+
+/// Random offsets for the waits between polls, so that instances started together
+/// don't keep polling in step.
+#[derive(Clone, Debug)]
+pub struct Jitter {
+    max: Duration,
+    /// Xorshift state, never zero.
+    state: u64,
+}
+
+impl Jitter {
+    /// Offsets of up to `max` either way, seeded from the time and the process id.
+    pub fn new(max: Duration) -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self {
+            max,
+            state: (nanos ^ (std::process::id() as u64) << 32) | 1,
+        }
+    }
+
+    /// `wait` offset by a random amount, but not below zero.
+    pub fn apply(&mut self, wait: Duration) -> Duration {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let offset = self.max.mul_f64(self.state as f64 / u64::MAX as f64 * 2.0);
+        (wait + offset).saturating_sub(self.max)
+    }
+}
+
+/// The outcome of writing one parameter.
+#[derive(Clone, Debug)]
+pub struct WriteResult<'sdb> {
+    pub param: Parameter<'sdb>,
+    /// The error code reported by the instrument, zero on success.
+    pub error_code: u16,
+}
+
+impl WriteResult<'_> {
+    pub fn is_ok(&self) -> bool {
+        self.error_code == 0
+    }
+}
+
+/// What a write does when the instrument doesn't answer in time, leaving open
+/// whether the values were written.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Fail with the timeout.
+    #[default]
+    Fail,
+    /// Read the values back. Those not written fail with [`WriteNotApplied`], they
+    /// are never sent twice.
+    AtMostOnce,
+    /// Read the values back, and write those not written again, as often as the
+    /// connection's [`RetryPolicy`](crate::plc_connection::RetryPolicy) retries, but
+    /// at least once.
+    AtLeastOnce,
+}
+
+impl std::str::FromStr for WritePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "at-most-once" => Ok(Self::AtMostOnce),
+            "at-least-once" => Ok(Self::AtLeastOnce),
+            _ => bail!("Unknown write policy '{s}', expected fail, at-most-once or at-least-once."),
+        }
+    }
+}
+
+/// Writes which timed out and, read back, turned out not to have been applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteNotApplied {
+    pub params: Vec<String>,
+}
+
+impl std::fmt::Display for WriteNotApplied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Write timed out and wasn't applied to {}.",
+            self.params.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for WriteNotApplied {}
+
+/// A write refused before it was sent, by the access rules or rate limit of the
+/// [`WriteGuard`]. Returned as the context of the refusal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteRejected {
+    pub param: String,
+}
+
+impl std::fmt::Display for WriteRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Write to {} rejected", self.param)
+    }
+}
+
+impl std::error::Error for WriteRejected {}
+
+/// A read query answered with an error code instead of values, as too large reads are.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReadRefused {
+    pub error_code: u16,
+}
+
+impl std::fmt::Display for ReadRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Read query failed with error code {}.",
+            ErrorCode(self.error_code)
+        )
+    }
+}
+
+impl std::error::Error for ReadRefused {}
+
+/// A value answered with a status not known to be followed by the value, so the read
+/// has no value for `param` or the parameters after it. Strict mode fails on the status
+/// before this.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValueMissing {
+    pub param: String,
+    pub status: ParamStatus,
+}
+
+impl std::fmt::Display for ValueMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No value for {}, answered with status {}.",
+            self.param, self.status
+        )
+    }
+}
+
+impl std::error::Error for ValueMissing {}
+
+/// A long string read or written in chunks which failed after `done` of its `len`
+/// bytes. Returned as the context of the failure; a write leaves the first `done`
+/// bytes written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialTransfer {
+    pub param: String,
+    pub write: bool,
+    pub done: usize,
+    pub len: usize,
+}
+
+impl std::fmt::Display for PartialTransfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = if self.write { "Wrote" } else { "Read" };
+        write!(
+            f,
+            "{what} {} of {} bytes of {}",
+            self.done, self.len, self.param
+        )
+    }
+}
+
+impl std::error::Error for PartialTransfer {}
+
+/// A sequence of reads and writes, see [`Client::transaction`].
+///
+/// Consecutive reads are sent as one read query and consecutive writes as one
+/// write packet, the order between reads and writes is kept.
+///
+/// A transaction can be executed repeatedly, e.g. once per poll. The read queries are
+/// serialized on the first execution and sent as they are after that.
+pub struct Transaction<'c, 'sdb> {
+    client: &'c mut Client<'sdb>,
+    guard: Option<&'c mut WriteGuard>,
+    write_policy: Option<WritePolicy>,
+    steps: Vec<Step<'sdb>>,
+}
+
+/// Consecutive operations of the same kind, sent together.
+enum Step<'sdb> {
+    Read {
+        params: Vec<Parameter<'sdb>>,
+        /// Built on the first execution.
+        queries: Option<ReadQueries<'sdb>>,
+    },
+    Write(Vec<(Parameter<'sdb>, Value)>),
+}
+
+/// The result of one operation of a transaction.
+#[derive(Clone, Debug)]
+pub enum OpResult<'sdb> {
+    Read(Parameter<'sdb>, Value),
+    Write(WriteResult<'sdb>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TransactionResult<'sdb> {
+    /// One result per operation, in the order they were added.
+    pub results: Vec<OpResult<'sdb>>,
+    /// The instrument timestamp of the first read, if there were any reads.
+    pub timestamp: Option<Duration>,
+}
+
+impl TransactionResult<'_> {
+    pub fn failed_writes(&self) -> impl Iterator<Item = &WriteResult<'_>> + '_ {
+        self.results.iter().filter_map(|r| match r {
+            OpResult::Write(w) if !w.is_ok() => Some(w),
+            _ => None,
+        })
+    }
+}
+
+impl<'c, 'sdb> Transaction<'c, 'sdb> {
+    pub fn read(mut self, param: Parameter<'sdb>) -> Self {
+        match self.steps.last_mut() {
+            Some(Step::Read { params, queries }) => {
+                params.push(param);
+                *queries = None;
+            }
+            _ => self.steps.push(Step::Read {
+                params: vec![param],
+                queries: None,
+            }),
+        }
+        self
+    }
+
+    pub fn write(mut self, param: Parameter<'sdb>, value: Value) -> Self {
+        match self.steps.last_mut() {
+            Some(Step::Write(batch)) => batch.push((param, value)),
+            _ => self.steps.push(Step::Write(vec![(param, value)])),
+        }
+        self
+    }
+
+    /// Applies the access rules, rate limit and audit log of the guard to the writes.
+    pub fn guarded(mut self, guard: &'c mut WriteGuard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// The write policy of this transaction, instead of the client's.
+    pub fn write_policy(mut self, policy: WritePolicy) -> Self {
+        self.write_policy = Some(policy);
+        self
+    }
+
+    /// The client, for queries outside of the transaction.
+    pub fn client(&mut self) -> &mut Client<'sdb> {
+        self.client
+    }
+
+    pub fn execute(&mut self) -> Result<TransactionResult<'sdb>> {
+        let mut result = TransactionResult::default();
+        let mut steps = std::mem::take(&mut self.steps);
+        let r = steps
+            .iter_mut()
+            .try_for_each(|step| self.execute_step(step, &mut result));
+        self.steps = steps;
+        r.map(|_| result)
+    }
+
+    fn execute_step(
+        &mut self,
+        step: &mut Step<'sdb>,
+        result: &mut TransactionResult<'sdb>,
+    ) -> Result<()> {
+        match step {
+            Step::Read { params, queries } => {
+                if queries.is_none() {
+                    *queries = Some(self.client.encoded_read_packets(params)?);
+                }
+                let max_len = self.client.capabilities.max_response_len;
+                let r = self.client.query_tuned(params, queries.as_ref().unwrap());
+                if self.client.capabilities.max_response_len != max_len {
+                    // Split up again at the new limit.
+                    *queries = None;
+                }
+                let (values, timestamp) = r?;
+                self.client.record_history(params, &values, timestamp);
+                result.timestamp = result.timestamp.or(timestamp);
+                let reads = params.iter().cloned().zip(values);
+                result
+                    .results
+                    .extend(reads.map(|(p, v)| OpResult::Read(p, v)));
+            }
+            Step::Write(batch) => {
+                let writes = self.write_batch(batch)?;
+                result
+                    .results
+                    .extend(writes.into_iter().map(OpResult::Write));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_batch(
+        &mut self,
+        batch: &[(Parameter<'sdb>, Value)],
+    ) -> Result<Vec<WriteResult<'sdb>>> {
+        let policy = self.write_policy.unwrap_or(self.client.write_policy);
+        let Some(guard) = self.guard.as_deref_mut() else {
+            return self.client.write_with_policy(batch, policy);
+        };
+        for (param, value) in batch {
+            guard
+                .before_write(param.name(), value)
+                .with_context(|| WriteRejected {
+                    param: param.name().to_string(),
+                })?;
+        }
+        let old = if guard.wants_old_value() {
+            let params: Vec<_> = batch.iter().map(|(p, _)| p.clone()).collect();
+            self.client.read(&params)?.into_iter().map(Some).collect()
+        } else {
+            vec![None; batch.len()]
+        };
+        let results = self.client.write_with_policy(batch, policy)?;
+        for ((result, (_, value)), old) in results.iter().zip(batch).zip(&old) {
+            if result.is_ok() {
+                guard.after_write(result.param.name(), old.as_ref(), value)?;
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// An instrument connection together with the SDB describing its parameters.
+pub struct Client<'sdb> {
+    conn: Connection,
+    sdb: &'sdb Sdb,
+    capabilities: Capabilities,
+    /// Encoded read queries by parameter set, see [`Client::read_cached`].
+    query_cache: HashMap<u64, (Vec<Parameter<'sdb>>, ReadQueries<'sdb>)>,
+    history: Option<History<'sdb>>,
+    batching: Option<AdaptiveBatching>,
+    write_policy: WritePolicy,
+}
+
+impl<'sdb> Client<'sdb> {
+    /// Wraps the connection, negotiating the capabilities of the instrument.
+    pub fn new(mut conn: Connection, sdb: &'sdb Sdb) -> Result<Self> {
+        let capabilities = Capabilities::negotiate(&mut conn)?;
+        conn.set_dialect(capabilities.dialect);
+        Ok(Self {
+            conn,
+            sdb,
+            capabilities,
+            query_cache: HashMap::new(),
+            history: None,
+            batching: None,
+            write_policy: WritePolicy::default(),
+        })
+    }
+
+    /// How writes handle timeouts, see [`Client::write_with_policy`].
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn sdb(&self) -> &'sdb Sdb {
+        self.sdb
+    }
+
+    /// The response size limit used to split up reads.
+    pub fn max_response_len(&self) -> usize {
+        self.capabilities.max_response_len
+    }
+
+    pub fn set_max_response_len(&mut self, len: usize) {
+        self.capabilities.max_response_len = len;
+    }
+
+    /// Finds the largest read response the instrument accepts, by reading parameter
+    /// sets of different sizes, and uses it for the following reads.
+    ///
+    /// The instrument is expected to answer too large queries with an error code. If it
+    /// drops the connection instead, the error is returned and the connection is unusable.
+    pub fn probe_max_response_len(&mut self) -> Result<usize> {
+        // Search over the size budget, while remembering the largest response seen.
+        let (mut good, mut bad) = (0, PROBE_RESPONSE_LEN_LIMIT + 1);
+        let mut best = 0;
+        let mut len = DEFAULT_MAX_RESPONSE_LEN;
+        while bad - good > 16 {
+            let accepted = self.probe_read(len)?;
+            debug!("Probing response length {len:#x}: accepted {accepted:?}");
+            match accepted {
+                Some(actual) => {
+                    good = len;
+                    best = best.max(actual);
+                }
+                None => bad = len,
+            }
+            len = (good + bad) / 2;
+        }
+        if best == 0 {
+            bail!("The instrument refused even a {DEFAULT_MAX_RESPONSE_LEN:#x} byte response.");
+        }
+        self.capabilities.max_response_len = best;
+        self.capabilities.features.set(
+            ProtocolFeatures::LARGE_PAYLOAD,
+            best > DEFAULT_MAX_RESPONSE_LEN,
+        );
+        Ok(best)
+    }
+
+    /// Reads a set of parameters with a total response length close to, but not above,
+    /// `len`. Returns the actual length if the instrument accepted the query.
+    fn probe_read(&mut self, len: usize) -> Result<Option<usize>> {
+        let mut query = ParamQuerySetBuilder::new(self.sdb);
+        for param in self.sdb.scalar_parameters() {
+            if query.estimated_response_len() + param.wire_cost().response > len {
+                break;
+            }
+            query.add_param(param)?;
+        }
+        let total = query.estimated_response_len();
+        let r = self.conn.query(&query.into_query_packet())?;
+        Ok((r.payload.error_code == 0).then_some(total))
+    }
+
+    /// Keeps the last `capacity` values read of every parameter, see [`Client::history`].
+    /// A capacity of zero turns the history off.
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history = (capacity > 0).then(|| History::new(capacity));
+    }
+
+    /// Tunes the response size limit to the measured throughput of the reads, instead
+    /// of keeping it fixed.
+    pub fn set_adaptive_batching(&mut self, batching: Option<AdaptiveBatching>) {
+        if let Some(b) = &batching {
+            self.capabilities.max_response_len = b.current();
+        }
+        self.batching = batching;
+    }
+
+    fn tune_batching(&mut self, params: &[Parameter], elapsed: Duration, failed: bool) {
+        let Some(batching) = &mut self.batching else {
+            return;
+        };
+        let bytes = params.iter().map(|p| p.wire_cost().total()).sum();
+        let len = batching.record(bytes, elapsed, failed);
+        if len != self.capabilities.max_response_len {
+            debug!("Adjusting the read size limit to {len:#x}.");
+            self.capabilities.max_response_len = len;
+        }
+    }
+
+    /// The recent values read of the parameter, oldest first. Empty unless enabled
+    /// with [`Client::set_history_capacity`].
+    pub fn history(
+        &self,
+        param: &Parameter<'sdb>,
+    ) -> impl DoubleEndedIterator<Item = &Sample> + '_ {
+        self.history
+            .as_ref()
+            .map(|h| h.get(param))
+            .into_iter()
+            .flatten()
+    }
+
+    fn record_history(
+        &mut self,
+        params: &[Parameter<'sdb>],
+        values: &[Value],
+        device_time: Option<Duration>,
+    ) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        let time = chrono::Utc::now();
+        for (param, value) in params.iter().zip(values) {
+            let sample = Sample {
+                time,
+                device_time,
+                value: value.clone(),
+            };
+            history.record(param, sample);
+        }
+    }
+
+    /// Access to the underlying connection, for sending raw packets.
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+
+    /// Reads the parameters, split over as many queries as the response size limit requires.
+    pub fn read(&mut self, params: &[Parameter<'sdb>]) -> Result<Vec<Value>> {
+        Ok(self.read_timed(params)?.0)
+    }
+
+    /// Like [`Client::read`], also returning the instrument timestamp of the first response.
+    fn read_timed(&mut self, params: &[Parameter<'sdb>]) -> Result<(Vec<Value>, Option<Duration>)> {
+        let queries = self.encoded_read_packets(params)?;
+        let r = self.query_tuned(params, &queries)?;
+        self.record_history(params, &r.0, r.1);
+        Ok(r)
+    }
+
+    /// Sends the read queries of `params`, tuning the batching to the outcome. A read
+    /// refused while the size limit is adaptive shrinks the limit, and is split and
+    /// sent again, until the instrument accepts it or the limit is at its minimum.
+    fn query_tuned(
+        &mut self,
+        params: &[Parameter<'sdb>],
+        queries: &ReadQueries<'sdb>,
+    ) -> Result<(Vec<Value>, Option<Duration>)> {
+        let mut resplit = None;
+        loop {
+            let max_len = self.capabilities.max_response_len;
+            let queries = resplit.as_ref().unwrap_or(queries);
+            let started = Instant::now();
+            let r = Self::query_read_packets(&mut self.conn, self.sdb, max_len, queries);
+            self.tune_batching(params, started.elapsed(), r.is_err());
+            let refused = r.as_ref().is_err_and(|e| e.is::<ReadRefused>());
+            if !refused || self.capabilities.max_response_len == max_len {
+                return r;
+            }
+            debug!(
+                "Read refused at {max_len:#x} bytes, retrying at {:#x}.",
+                self.capabilities.max_response_len
+            );
+            resplit = Some(self.encoded_read_packets(params)?);
+        }
+    }
+
+    /// Like [`Client::read`], but keeps the encoded queries for the parameter set, so that
+    /// reading the same set again skips planning, building and serializing the queries.
+    ///
+    /// Meant for polling a few fixed sets, the cache is cleared when it holds
+    /// [`QUERY_CACHE_CAPACITY`] sets.
+    pub fn read_cached(&mut self, params: &[Parameter<'sdb>]) -> Result<Vec<Value>> {
+        Ok(self.read_cached_timed(params)?.0)
+    }
+
+    /// Like [`Client::read_cached`], also returning the instrument timestamp of the first
+    /// response.
+    pub fn read_cached_timed(
+        &mut self,
+        params: &[Parameter<'sdb>],
+    ) -> Result<(Vec<Value>, Option<Duration>)> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        params.hash(&mut hasher);
+        self.capabilities.max_response_len.hash(&mut hasher);
+        let key = hasher.finish();
+        if !matches!(self.query_cache.get(&key), Some((cached, _)) if cached == params) {
+            if self.query_cache.len() >= QUERY_CACHE_CAPACITY {
+                debug!("Query cache full, clearing it.");
+                self.query_cache.clear();
+            }
+            let queries = self.encoded_read_packets(params)?;
+            self.query_cache.insert(key, (params.to_vec(), queries));
+        }
+        let (cached, queries) = self.query_cache.remove(&key).unwrap();
+        let r = self.query_tuned(params, &queries);
+        self.query_cache.insert(key, (cached, queries));
+        let (values, timestamp) = r?;
+        self.record_history(params, &values, timestamp);
+        Ok((values, timestamp))
+    }
+
+    /// Sends the read queries, returning the values of the parameters planned for in order.
+    /// Long strings are not in the queries, they are read in chunks here. Members of
+    /// compound parameters are read as planned by [`ReadPlan`].
+    fn query_read_packets(
+        conn: &mut Connection,
+        sdb: &Sdb,
+        max_len: usize,
+        queries: &ReadQueries<'sdb>,
+    ) -> Result<(Vec<Value>, Option<Duration>)> {
+        let plan = &queries.plan;
+        let (values, timestamp) =
+            Self::query_fetched(conn, sdb, max_len, &plan.fetched, &queries.packets)?;
+        Ok((plan.values(values)?, timestamp))
+    }
+
+    fn query_fetched(
+        conn: &mut Connection,
+        sdb: &Sdb,
+        max_len: usize,
+        params: &[Parameter<'sdb>],
+        packets: &[ReadQuery<'sdb>],
+    ) -> Result<(Vec<Value>, Option<Duration>)> {
+        let mut timestamp = None;
+        let mut values = Vec::new();
+        for packet in packets {
+            let r = conn.query_encoded(packet)?;
+            if r.payload.error_code != 0 {
+                return Err(ReadRefused {
+                    error_code: r.payload.error_code,
+                }
+                .into());
+            }
+            timestamp.get_or_insert(r.payload.timestamp);
+            let payload = r.payload;
+            for (i, (param, value)) in payload.query_set.0.iter().zip(payload.data).enumerate() {
+                values.push(value.ok_or_else(|| ValueMissing {
+                    param: param.name().to_string(),
+                    status: payload.statuses[i],
+                })?);
+            }
+        }
+        if !params
+            .iter()
+            .any(|p| is_chunked(p, max_len) || p.wire_cost().response > max_len)
+        {
+            return Ok((values, timestamp));
+        }
+        let mut regular = values.into_iter();
+        let mut merged = Vec::with_capacity(params.len());
+        for param in params {
+            merged.push(match is_chunked(param, max_len) {
+                true => Self::read_chunked(conn, sdb, param, max_len)?,
+                false => assemble(param, max_len, &mut regular)?,
+            });
+        }
+        Ok((merged, timestamp))
+    }
+
+    /// Reads the value of the parameter in chunks of at most `max_len` bytes.
+    fn read_chunked(
+        conn: &mut Connection,
+        sdb: &Sdb,
+        param: &Parameter,
+        max_len: usize,
+    ) -> Result<Value> {
+        check_chunk_addressing(sdb, param)?;
+        let ty = param.type_info();
+        let len = ty.response_len();
+        let mut data = Vec::with_capacity(len);
+        for offset in (0..len).step_by(max_len) {
+            let chunk_len = (len - offset).min(max_len);
+            let query = RawReadQuery::new(sdb, &[(param.id() + offset as u32, chunk_len as u32)]);
+            let r = conn.query(&query).and_then(|r| match r.payload.error_code {
+                0 => Ok(r),
+                code => bail!("Read query failed with error code {}.", ErrorCode(code)),
+            });
+            let r = r.with_context(|| PartialTransfer {
+                param: param.name().to_string(),
+                write: false,
+                done: offset,
+                len,
+            })?;
+            data.extend(r.payload.chunks.concat());
+        }
+        Ok(Value::parse_endian(&data, &ty, conn.dialect().endian())?)
+    }
+
+    /// The read queries for the parameters, split as required by the response size limit.
+    /// Parameters exceeding the limit on their own are read by their elements, see
+    /// [`ParamQuerySetBuilder::add_split`], and fail with
+    /// [`ParamTooLarge`](crate::packets::ParamTooLarge) if they have none.
+    fn read_packets(
+        &self,
+        params: &[Parameter<'sdb>],
+    ) -> Result<Vec<PacketCC<'sdb, ParamsReadQuery<'sdb>>>> {
+        let max_len = self.capabilities.max_response_len;
+        let mut split = ParamQuerySetBuilder::new(self.sdb).max_response_len(max_len);
+        for param in params.iter().filter(|p| !is_chunked(p, max_len)) {
+            split.add_split(param.clone())?;
+        }
+        let regular = split.params();
+        let mut packets = vec![];
+        let mut rest = regular;
+        while !rest.is_empty() {
+            let mut query = ParamQuerySetBuilder::new(self.sdb).max_response_len(max_len);
+            for param in rest {
+                let len = query.estimated_response_len() + param.wire_cost().response;
+                if !query.is_empty() && len > max_len {
+                    break;
+                }
+                query.add_param(param.clone())?;
+            }
+            rest = &rest[query.len()..];
+            packets.push(query.into_query_packet());
+        }
+        Ok(packets)
+    }
+
+    fn encoded_read_packets(&self, params: &[Parameter<'sdb>]) -> Result<ReadQueries<'sdb>> {
+        let plan = ReadPlan::new(params, self.capabilities.max_response_len);
+        if plan.fetched.len() < params.len() {
+            debug!(
+                "Reading {} parameters for {} requested.",
+                plan.fetched.len(),
+                params.len()
+            );
+        }
+        let packets = self.read_packets(&plan.fetched)?.into_iter();
+        let packets = packets
+            .map(|p| self.conn.encode(p))
+            .collect::<Result<_>>()?;
+        Ok(ReadQueries { plan, packets })
+    }
+
+    /// Starts a sequence of reads and writes, executed in order with as few
+    /// round trips as possible.
+    ///
+    /// ```no_run
+    /// # use leybold_opc_rs::{client::Client, sdb::Parameter};
+    /// # fn f<'s>(client: &mut Client<'s>, p: Parameter<'s>) -> anyhow::Result<()> {
+    /// use leybold_opc_rs::opc_values::Value;
+    /// let result = client
+    ///     .transaction()
+    ///     .read(p.clone())
+    ///     .write(p.clone(), Value::String("service".into()))
+    ///     .read(p)
+    ///     .execute()?;
+    /// # Ok(()) }
+    /// ```
+    pub fn transaction(&mut self) -> Transaction<'_, 'sdb> {
+        Transaction {
+            client: self,
+            guard: None,
+            write_policy: None,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Writes all the values in a single packet.
+    ///
+    /// The instrument only reports one status for the whole packet, so if it reports an
+    /// error, every write of the batch has that error code. Nothing is sent again.
+    ///
+    /// Timeouts are handled by the client's [`WritePolicy`].
+    pub fn write_many(
+        &mut self,
+        writes: &[(Parameter<'sdb>, Value)],
+    ) -> Result<Vec<WriteResult<'sdb>>> {
+        self.write_with_policy(writes, self.write_policy)
+    }
+
+    /// Writes the values like [`Client::write_many`]. If the instrument doesn't answer
+    /// in time, the values are read back to find out which were written, and `policy`
+    /// decides what happens to the others.
+    pub fn write_with_policy(
+        &mut self,
+        writes: &[(Parameter<'sdb>, Value)],
+        policy: WritePolicy,
+    ) -> Result<Vec<WriteResult<'sdb>>> {
+        let mut results = vec![None; writes.len()];
+        // The indices of the writes not known to be applied.
+        let mut pending: Vec<usize> = (0..writes.len()).collect();
+        let mut retries = 0;
+        loop {
+            let batch: Vec<_> = pending.iter().map(|&i| writes[i].clone()).collect();
+            let e = match self.write_once(&batch) {
+                Ok(written) => {
+                    for (&i, r) in pending.iter().zip(written) {
+                        results[i] = Some(r);
+                    }
+                    break;
+                }
+                Err(e) if policy != WritePolicy::Fail && is_timeout(&e) => e,
+                Err(e) => return Err(e),
+            };
+            warn!("Write timed out, reading the values back: {e:#}");
+            self.conn.resync()?;
+            let params: Vec<_> = batch.iter().map(|(p, _)| p.clone()).collect();
+            let current = self.read(&params)?;
+            let mut unapplied = Vec::new();
+            for ((&i, (param, value)), current) in pending.iter().zip(&batch).zip(&current) {
+                let ty = param.type_info();
+                if value.opc_encode(&ty)? == current.opc_encode(&ty)? {
+                    results[i] = Some(WriteResult {
+                        param: param.clone(),
+                        error_code: 0,
+                    });
+                } else {
+                    unapplied.push(i);
+                }
+            }
+            pending = unapplied;
+            if pending.is_empty() {
+                break;
+            }
+            let max_retries = self.conn.retry_policy().retries.max(1);
+            if policy == WritePolicy::AtMostOnce || retries == max_retries {
+                let params = pending.iter().map(|&i| writes[i].0.name().to_string());
+                return Err(e.context(WriteNotApplied {
+                    params: params.collect(),
+                }));
+            }
+            retries += 1;
+            debug!("Writing {} values again, retry {retries}.", pending.len());
+        }
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    fn write_once(
+        &mut self,
+        writes: &[(Parameter<'sdb>, Value)],
+    ) -> Result<Vec<WriteResult<'sdb>>> {
+        let max_len = self.capabilities.max_response_len;
+        if writes.iter().any(|(p, _)| is_chunked(p, max_len)) {
+            // Chunked writes take several packets, so every write gets its own status.
+            let mut results = Vec::with_capacity(writes.len());
+            for (param, value) in writes {
+                let error_code = if is_chunked(param, max_len) {
+                    self.write_chunked(param, value)?
+                } else {
+                    self.write_packet(&[ParamWrite::new(param, value)?])?
+                };
+                results.push(WriteResult {
+                    param: param.clone(),
+                    error_code,
+                });
+            }
+            return Ok(results);
+        }
+        let packets = writes
+            .iter()
+            .map(|(param, value)| ParamWrite::new(param, value))
+            .collect::<Result<Vec<_>>>()?;
+        let code = self.write_packet(&packets)?;
+        if code != 0 && writes.len() > 1 {
+            debug!(
+                "Batch write of {} values failed with {}.",
+                writes.len(),
+                ErrorCode(code)
+            );
+        }
+        let results = writes.iter().map(|(param, _)| WriteResult {
+            param: param.clone(),
+            error_code: code,
+        });
+        Ok(results.collect())
+    }
+
+    /// Writes the value in chunks, stopping at the first chunk which fails. A refused
+    /// first chunk is its error code, later failures are errors with [`PartialTransfer`].
+    fn write_chunked(&mut self, param: &Parameter, value: &Value) -> Result<u16> {
+        check_chunk_addressing(self.sdb, param)?;
+        let data = value.opc_encode(&param.type_info())?;
+        let max_len = self.capabilities.max_response_len;
+        for (i, chunk) in data.chunks(max_len).enumerate() {
+            let address = param.id() + (i * max_len) as u32;
+            let r = self.write_packet(&[ParamWrite::raw(address, chunk.to_vec())]);
+            let e = match r {
+                Ok(0) => continue,
+                Ok(code) if i == 0 => return Ok(code),
+                Ok(code) => anyhow!("Write failed with error code {}.", ErrorCode(code)),
+                Err(e) => e,
+            };
+            return Err(e.context(PartialTransfer {
+                param: param.name().to_string(),
+                write: true,
+                done: i * max_len,
+                len: data.len(),
+            }));
+        }
+        Ok(0)
+    }
+
+    fn write_packet(&mut self, params: &[ParamWrite]) -> Result<u16> {
+        let r = self
+            .conn
+            .query(&PacketCC::new(PayloadParamWrite::new(self.sdb, params)))?;
+        Ok(r.payload.error_code().unwrap_or(0))
+    }
+}
+
+/// A query which got no response in time.
+fn is_timeout(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<DeviceBusy>()
+        .is_some_and(|busy| busy.error_code.is_none())
+}
+
+/// Takes the value of `param` from the values read, putting it together from the
+/// values of its elements if it was too large to read at once.
+fn assemble(
+    param: &Parameter,
+    max_len: usize,
+    values: &mut impl Iterator<Item = Value>,
+) -> Result<Value> {
+    if param.wire_cost().response <= max_len {
+        return values
+            .next()
+            .context("Too few values in the read responses.");
+    }
+    let elements = param.elements();
+    let parts = elements
+        .iter()
+        .map(|e| assemble(e, max_len, values))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(match param.value_kind() {
+        TypeKind::Data => {
+            let names = elements
+                .iter()
+                .map(|e| e.name()[param.name().len() + 1..].to_string());
+            Value::Struct(names.zip(parts).collect())
+        }
+        _ => Value::Array(parts),
+    })
+}
+
+/// Strings longer than one response are read and written in chunks, at addresses
+/// within the parameter. Parameter ids are the addresses of the values, as the ids of
+/// consecutive struct members show.
+fn is_chunked(param: &Parameter, max_response_len: usize) -> bool {
+    param.value_kind() == TypeKind::String && param.type_info().response_len() > max_response_len
+}
+
+/// Checks that the bytes of `param` are at `param.id()` and the following addresses:
+/// the members of the struct containing it must be at the offsets of its type layout,
+/// and no other parameter may start within the value.
+fn check_chunk_addressing(sdb: &Sdb, param: &Parameter) -> Result<()> {
+    let end = param.id() as usize + param.type_info().response_len();
+    if let Some(other) = sdb
+        .parameters()
+        .find(|p| p.id() > param.id() && (p.id() as usize) < end)
+    {
+        bail!(
+            "Can't address {} in chunks, {} starts within it.",
+            param.name(),
+            other.name()
+        );
+    }
+    let Some((parent, _)) = param.name().rsplit_once('.') else {
+        return Ok(());
+    };
+    let Ok(parent) = sdb.param_by_name(parent) else {
+        return Ok(());
+    };
+    for member in parent.type_info().struct_info().into_iter().flatten() {
+        let name = format!("{}.{}", parent.name(), member.name);
+        let Ok(p) = sdb.param_by_name(&name) else {
+            continue;
+        };
+        if p.id() as usize != parent.id() as usize + member.offset {
+            bail!(
+                "Can't address {} in chunks, {name} isn't at offset {} of {}.",
+                param.name(),
+                member.offset,
+                parent.name()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_adaptive_interval() {
+    let ms = Duration::from_millis;
+    let mut interval = AdaptiveInterval::new(ms(100), ms(1000));
+    assert_eq!(interval.record(false), ms(100));
+    assert_eq!(interval.record(true), ms(200));
+    assert_eq!(interval.record(true), ms(400));
+    // Slow but successful polls recover like any other.
+    assert_eq!(interval.record(false), ms(360));
+    for _ in 0..5 {
+        interval.record(true);
+    }
+    assert_eq!(interval.current(), ms(1000));
+    for _ in 0..50 {
+        interval.record(false);
+    }
+    assert_eq!(interval.current(), ms(100));
+}
+
+#[test]
+fn test_poll_schedule() {
+    use crate::clock::MockClock;
+    let ms = Duration::from_millis;
+    let clock = Arc::new(MockClock::new());
+    let interval = AdaptiveInterval::new(ms(100), ms(1000));
+    let mut schedule = PollSchedule::new(clock.clone(), Some(interval), None, Some(ms(250)));
+    clock.advance(ms(10));
+    assert_eq!(schedule.polled(), Some(ms(100)));
+    clock.advance(ms(100));
+    assert_eq!(schedule.busy(), ms(200));
+    clock.advance(ms(50));
+    // Clamped to the deadline.
+    assert_eq!(schedule.polled(), Some(ms(90)));
+    clock.advance(ms(90));
+    assert!(schedule.is_done());
+    assert_eq!((schedule.polls(), schedule.busy_polls()), (2, 1));
+
+    let interval = AdaptiveInterval::new(ms(100), ms(1000));
+    let mut jittered =
+        PollSchedule::new(clock.clone(), Some(interval), None, None).with_jitter(ms(20));
+    let waits: Vec<_> = (0..20).map(|_| jittered.polled().unwrap()).collect();
+    assert!(waits.iter().all(|w| (ms(80)..=ms(120)).contains(w)));
+    assert!(waits.iter().any(|w| *w != waits[0]));
+
+    let mut once = PollSchedule::new(clock.clone(), None, Some(3), None);
+    assert_eq!(once.polled(), None);
+    assert!(!once.is_done());
+}
+
+#[test]
+fn test_adaptive_batching() {
+    let ms = Duration::from_millis;
+    let mut batching = AdaptiveBatching::new(0x100, 0x800);
+    assert_eq!(batching.record(1000, ms(100), false), 0x3c0);
+    assert_eq!(batching.record(1000, ms(90), false), 0x4b0);
+    // Slower than at the previous size.
+    assert_eq!(batching.record(1000, ms(120), false), 0x3c0);
+    assert_eq!(batching.record(1000, ms(90), false), 0x3c0);
+    assert_eq!(batching.record(0, ms(0), true), 0x1e0);
+    assert_eq!(batching.record(1000, ms(90), false), 0x258);
+}
+
+#[test]
+fn test_adaptive_batching_poll() {
+    use crate::sim::{SimulatedPlc, SIM_MAX_RESPONSE_LEN};
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let params: Vec<_> = sdb.scalar_parameters().collect();
+    let total: usize = params.iter().map(|p| p.wire_cost().response).sum();
+    assert!(total > SIM_MAX_RESPONSE_LEN);
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    // Larger than the simulator accepts, so that the limit grows into refusals.
+    client.set_adaptive_batching(Some(AdaptiveBatching::new(0x100, 0x2000)));
+    let mut transaction = client.transaction();
+    for param in &params {
+        transaction = transaction.read(param.clone());
+    }
+    // Refused reads shrink the limit and are sent again within the same poll.
+    for _ in 0..20 {
+        let result = transaction.execute().unwrap();
+        assert_eq!(result.results.len(), params.len());
+    }
+}
+
+#[test]
+fn test_chunked_strings() {
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    client.set_max_response_len(0x20);
+    let name = sdb.param_by_name(".Gauge[1].Parameter[1].Name").unwrap();
+    let unit = sdb.param_by_name(".Gauge[1].Parameter[1].Unit").unwrap();
+    assert!(is_chunked(&name, 0x20));
+    check_chunk_addressing(&sdb, &name).unwrap();
+
+    let long = Value::String("A name longer than one response of 32 bytes".into());
+    let mbar = Value::String("mbar".into());
+    let written = client
+        .write_many(&[(name.clone(), long.clone()), (unit.clone(), mbar.clone())])
+        .unwrap();
+    assert!(written.iter().all(WriteResult::is_ok));
+    assert_eq!(sim.get(&name).unwrap(), long);
+    // The neighbouring member isn't overwritten by the chunks.
+    assert_eq!(client.read(&[name, unit]).unwrap(), [long, mbar]);
+}
+
+#[test]
+fn test_split_reads() {
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    let frequencies = sdb.param_by_name(".OPCPumpFrequency").unwrap();
+    let elements = frequencies.elements();
+    assert_eq!(elements[2].name(), ".OPCPumpFrequency[3]");
+    for (element, f) in elements.iter().zip([1.0, 2.0, 3.0]) {
+        sim.set(element, &Value::Float(f)).unwrap();
+    }
+    let value = client
+        .read(std::slice::from_ref(&frequencies))
+        .unwrap()
+        .remove(0);
+    assert_eq!(
+        value,
+        Value::Array(vec![
+            Value::Float(1.0),
+            Value::Float(2.0),
+            Value::Float(3.0)
+        ])
+    );
+    // Too small for the array, which is read by its elements.
+    client.set_max_response_len(elements[0].wire_cost().response);
+    assert_eq!(
+        client.read(std::slice::from_ref(&frequencies)).unwrap(),
+        [value]
+    );
+    let timer = sdb.param_by_name(".Gauge[1].DegasTimer").unwrap();
+    let Value::Struct(members) = &client.read(&[timer]).unwrap()[0] else {
+        panic!("The timer isn't a struct.");
+    };
+    assert_eq!(members[5].0, "ET");
+}
+
+#[test]
+fn test_value_missing() {
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    let counter = sdb.param_by_name(".OPCCounter").unwrap();
+    let frequency = sdb.param_by_name(".OPCPumpFrequency[1]").unwrap();
+    sim.set(&frequency, &Value::Float(1.0)).unwrap();
+    sim.set_status(&counter, 3);
+    let e = client.read(&[counter, frequency.clone()]).unwrap_err();
+    let missing = e.downcast_ref::<ValueMissing>().unwrap();
+    assert_eq!(missing.param, ".OPCCounter");
+    assert_eq!(missing.status, ParamStatus::Unknown(3));
+    // The value after it isn't decoded from bytes that may not be its own.
+    let mut query = ParamQuerySetBuilder::new(&sdb);
+    query.add(".OPCCounter").unwrap();
+    query.add(".OPCPumpFrequency[1]").unwrap();
+    let r = client
+        .connection()
+        .query(&query.into_query_packet())
+        .unwrap();
+    assert_eq!(r.payload.data, [None, None]);
+    assert_eq!(r.payload.statuses, [ParamStatus::Unknown(3)]);
+}
+
+#[test]
+fn test_write_policy() {
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let mut conn = Connection::connect_addr(sim.addr()).unwrap();
+    conn.set_read_timeout(Duration::from_millis(100)).unwrap();
+    let mut client = Client::new(conn, &sdb).unwrap();
+    let param = sdb.param_by_name(".Gauge[1].Parameter[1].Value").unwrap();
+    let write = |v| [(param.clone(), Value::Float(v))];
+
+    sim.lose_writes(1, true);
+    let r = client.write_with_policy(&write(1.0), WritePolicy::AtMostOnce);
+    assert!(r.unwrap()[0].is_ok());
+
+    sim.lose_writes(1, false);
+    let e = client
+        .write_with_policy(&write(2.0), WritePolicy::AtMostOnce)
+        .unwrap_err();
+    assert!(e.downcast_ref::<WriteNotApplied>().is_some());
+    assert_eq!(sim.get(&param).unwrap(), Value::Float(1.0));
+
+    sim.lose_writes(1, false);
+    let r = client.write_with_policy(&write(3.0), WritePolicy::AtLeastOnce);
+    assert!(r.unwrap()[0].is_ok());
+    assert_eq!(sim.get(&param).unwrap(), Value::Float(3.0));
+
+    sim.lose_writes(1, true);
+    let e = client.write_many(&write(4.0)).unwrap_err();
+    assert!(is_timeout(&e));
+}
+
+#[test]
+fn test_negotiate_marker() {
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let foreign = Dialect::Custom {
+        endian: binrw::Endian::Big,
+        command_marker: 0x23,
+        response_marker: 0x28,
+    };
+    let connect = || {
+        let mut conn = Connection::connect_addr(sim.addr()).unwrap();
+        conn.set_dialect(foreign);
+        conn
+    };
+    // The simulator's marker is reported, not taken over.
+    let client = Client::new(connect(), &sdb).unwrap();
+    assert_eq!(client.capabilities().dialect, foreign);
+    let mut strict = connect();
+    strict.set_strict(true);
+    assert!(Client::new(strict, &sdb).is_err());
+}
@@ -0,0 +1,73 @@
+//! The time source of polling, replaceable so that timing logic can be tested without
+//! waiting.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    /// Blocks the thread for up to `timeout`, like [`std::thread::park_timeout`].
+    fn park_timeout(&self, timeout: Duration);
+
+    /// Blocks the thread for `duration`, like [`std::thread::sleep`].
+    fn sleep(&self, duration: Duration) {
+        let end = self.now() + duration;
+        loop {
+            let now = self.now();
+            if now >= end {
+                return;
+            }
+            self.park_timeout(end - now);
+        }
+    }
+}
+
+/// The real time.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn park_timeout(&self, timeout: Duration) {
+        std::thread::park_timeout(timeout);
+    }
+}
+
+/// A clock which only moves when told to, or when parked. Parking returns at once,
+/// with the clock advanced by the timeout.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.now.lock().unwrap() += d;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn park_timeout(&self, timeout: Duration) {
+        self.advance(timeout);
+    }
+}
@@ -0,0 +1,768 @@
+//! Combines the reads of concurrent consumers into one query per tick, so that the
+//! slow instrument link isn't queried once per consumer, and queues their writes
+//! in between.
+//!
+//! [`ReadPlan`] avoids reading the same bytes twice when a set of parameters holds
+//! both a struct or array and some of its members.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{debug, warn};
+
+use crate::audit::WriteGuard;
+use crate::client::{Client, OpResult, WriteRejected};
+use crate::errors::device::ErrorCode;
+use crate::events::{ConnectionEvent, ConnectionEvents};
+use crate::opc_values::Value;
+use crate::plc_connection::Connection;
+use crate::sdb::{normalize_param_path, Parameter, ParseMode, Sdb, TypeKind};
+use crate::sdb_store::SdbStore;
+
+type ConnectFn = dyn Fn() -> Result<Connection> + Send + Sync;
+
+/// Called with the outcome of a write, on the worker thread.
+type WriteCallback = Box<dyn FnOnce(Result<()>) + Send>;
+
+struct ReadRequest {
+    names: Vec<String>,
+    reply: Sender<Result<Vec<Value>>>,
+}
+
+struct WriteRequest {
+    /// The client to check the access policy for, instead of the user of the guard.
+    client: Option<String>,
+    name: String,
+    value: Value,
+    done: WriteCallback,
+}
+
+enum Request {
+    Read(ReadRequest),
+    Write(WriteRequest),
+}
+
+/// Reads and writes parameters on behalf of any number of threads. The reads arriving
+/// within one window are sent to the instrument as a single read of all their
+/// parameters.
+///
+/// Requests are carried out in the order they were submitted: a write ends the read
+/// window, so reads submitted before it don't see its value and reads submitted after
+/// it do. Queued writes are sent together, except for repeated writes of a parameter,
+/// which are sent one after the other.
+///
+/// Writes go through the [`WriteGuard`], like those of a guarded transaction, for its
+/// user or the client given to [`ReadCoalescer::write_as`]. The connection, the SDB and
+/// the guard live on a worker thread, which stops when the coalescer is dropped.
+pub struct ReadCoalescer {
+    requests: Sender<Request>,
+    events: ConnectionEvents,
+}
+
+/// The outcome of a write queued with [`ReadCoalescer::write`].
+#[derive(Debug)]
+pub struct PendingWrite {
+    result: Receiver<Result<()>>,
+}
+
+impl PendingWrite {
+    /// Blocks until the write is done.
+    pub fn wait(self) -> Result<()> {
+        self.result.recv().context("The worker has stopped.")?
+    }
+}
+
+impl ReadCoalescer {
+    pub fn start(
+        sdb: PathBuf,
+        window: Duration,
+        connect: Arc<ConnectFn>,
+        guard: WriteGuard,
+    ) -> Self {
+        let (requests, rx) = mpsc::channel();
+        let events = ConnectionEvents::new();
+        let link = events.clone();
+        std::thread::spawn(move || worker(rx, &sdb, window, &*connect, guard, link));
+        Self { requests, events }
+    }
+
+    /// The events of the worker's connection. It connects on the first request, and
+    /// reconnects on the request after a failure.
+    pub fn events(&self) -> &ConnectionEvents {
+        &self.events
+    }
+
+    /// Reads the named parameters, together with those other threads read meanwhile.
+    pub fn read(&self, names: &[String]) -> Result<Vec<Value>> {
+        let (reply, response) = mpsc::channel();
+        self.requests
+            .send(Request::Read(ReadRequest {
+                names: names.to_vec(),
+                reply,
+            }))
+            .map_err(|_| anyhow!("The worker has stopped."))?;
+        response.recv().context("The worker has stopped.")?
+    }
+
+    /// Queues a write of the named parameter, without waiting for it.
+    pub fn write(&self, name: &str, value: Value) -> PendingWrite {
+        let (tx, result) = mpsc::channel();
+        self.write_with(name, value, move |r| {
+            let _ = tx.send(r);
+        });
+        PendingWrite { result }
+    }
+
+    /// Like [`ReadCoalescer::write`], for `client` of a server. The access policy and the
+    /// audit log see the write as made by `client` rather than the user of the guard.
+    pub fn write_as(&self, client: &str, name: &str, value: Value) -> PendingWrite {
+        let (tx, result) = mpsc::channel();
+        self.queue_write(
+            Some(client),
+            name,
+            value,
+            Box::new(move |r| {
+                let _ = tx.send(r);
+            }),
+        );
+        PendingWrite { result }
+    }
+
+    /// Queues a write of the named parameter, calling `done` with its outcome. `done`
+    /// runs on the worker thread, and holds up the following requests until it returns.
+    pub fn write_with(
+        &self,
+        name: &str,
+        value: Value,
+        done: impl FnOnce(Result<()>) + Send + 'static,
+    ) {
+        self.queue_write(None, name, value, Box::new(done));
+    }
+
+    fn queue_write(&self, client: Option<&str>, name: &str, value: Value, done: WriteCallback) {
+        let request = WriteRequest {
+            client: client.map(str::to_string),
+            name: name.to_string(),
+            value,
+            done,
+        };
+        if let Err(mpsc::SendError(Request::Write(w))) = self.requests.send(Request::Write(request))
+        {
+            (w.done)(Err(anyhow!("The worker has stopped.")));
+        }
+    }
+}
+
+fn worker(
+    requests: Receiver<Request>,
+    sdb: &std::path::Path,
+    window: Duration,
+    connect: &ConnectFn,
+    guard: WriteGuard,
+    events: ConnectionEvents,
+) {
+    let store = SdbStore::new(sdb)
+        .with_parse_mode(ParseMode::Lazy)
+        .with_events(events.clone());
+    let sdb = match store.load() {
+        Ok(sdb) => sdb,
+        Err(e) => {
+            // Fail every request rather than leaving the callers hanging.
+            for r in requests {
+                match r {
+                    Request::Read(r) => fail(vec![r], &e),
+                    Request::Write(w) => (w.done)(Err(anyhow!("{e:#}"))),
+                }
+            }
+            return;
+        }
+    };
+    let mut worker = Worker {
+        sdb: &sdb,
+        connect,
+        client: None,
+        guard,
+        events,
+        failures: 0,
+    };
+    // A request received while collecting a batch, which belongs to the next one.
+    let mut next = None;
+    loop {
+        let first = match next.take() {
+            Some(r) => r,
+            None => match requests.recv() {
+                Ok(r) => r,
+                Err(_) => return,
+            },
+        };
+        match first {
+            Request::Read(first) => {
+                let mut batch = vec![first];
+                let end = Instant::now() + window;
+                while let Ok(r) =
+                    requests.recv_timeout(end.saturating_duration_since(Instant::now()))
+                {
+                    match r {
+                        Request::Read(r) => batch.push(r),
+                        write => {
+                            next = Some(write);
+                            break;
+                        }
+                    }
+                }
+                worker.read(batch);
+            }
+            Request::Write(first) => {
+                let mut writes = vec![first];
+                while let Ok(r) = requests.try_recv() {
+                    match r {
+                        // A batch is checked for one client.
+                        Request::Write(w)
+                            if w.client == writes[0].client
+                                && !writes.iter().any(|x| same_param(&x.name, &w.name)) =>
+                        {
+                            writes.push(w)
+                        }
+                        r => {
+                            next = Some(r);
+                            break;
+                        }
+                    }
+                }
+                worker.write(writes);
+            }
+        }
+    }
+}
+
+fn same_param(a: &str, b: &str) -> bool {
+    normalize_param_path(a) == normalize_param_path(b)
+}
+
+struct Worker<'sdb> {
+    sdb: &'sdb Sdb,
+    connect: &'sdb ConnectFn,
+    client: Option<Client<'sdb>>,
+    guard: WriteGuard,
+    events: ConnectionEvents,
+    /// Failures since the last successful request.
+    failures: u32,
+}
+
+impl<'sdb> Worker<'sdb> {
+    fn client(&mut self) -> Result<&mut Client<'sdb>> {
+        if self.client.is_none() {
+            if self.failures > 0 {
+                self.events.emit(ConnectionEvent::Reconnecting {
+                    attempt: self.failures,
+                });
+            }
+            let connected = (self.connect)().and_then(|mut conn| {
+                conn.set_events(self.events.clone());
+                Client::new(conn, self.sdb)
+            });
+            match connected {
+                Ok(client) => self.client = Some(client),
+                Err(e) => {
+                    self.failures += 1;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Drops the connection after a failed request, to reconnect for the next one.
+    fn failed(&mut self) {
+        self.client = None;
+        self.failures += 1;
+    }
+
+    fn read(&mut self, mut batch: Vec<ReadRequest>) {
+        let sdb = self.sdb;
+        // Requests with unknown names fail on their own, without failing the batch.
+        batch.retain(
+            |r| match r.names.iter().find_map(|n| sdb.param_by_name(n).err()) {
+                Some(e) => {
+                    let _ = r.reply.send(Err(e));
+                    false
+                }
+                None => true,
+            },
+        );
+        let names = union_of(batch.iter().map(|r| &r.names[..]));
+        if names.is_empty() {
+            return;
+        }
+        debug!(
+            "Reading {} parameters for {} requests.",
+            names.len(),
+            batch.len()
+        );
+        let params: Vec<_> = names
+            .iter()
+            .map(|n| sdb.param_by_name(n).unwrap())
+            .collect();
+        let values = match self.client().and_then(|c| c.read_cached(&params)) {
+            Ok(values) => values,
+            Err(e) => {
+                // Reconnect for the next batch, the stream may be out of step.
+                warn!("Coalesced read failed: {e:#}");
+                self.failed();
+                fail(batch, &e);
+                return;
+            }
+        };
+        self.failures = 0;
+        for r in batch {
+            let picked = r
+                .names
+                .iter()
+                .map(|n| values[names.iter().position(|m| m == n).unwrap()].clone())
+                .collect();
+            let _ = r.reply.send(Ok(picked));
+        }
+    }
+
+    /// Writes the values in one guarded transaction, for the client of the first write.
+    /// The names are expected to be distinct. Writes the guard refuses fail on their own,
+    /// without failing the batch.
+    fn write(&mut self, writes: Vec<WriteRequest>) {
+        let Some(client) = writes.first().and_then(|w| w.client.clone()) else {
+            return self.write_checked(writes);
+        };
+        let user = self.guard.user().to_string();
+        self.guard.set_user(client);
+        self.write_checked(writes);
+        self.guard.set_user(user);
+    }
+
+    fn write_checked(&mut self, writes: Vec<WriteRequest>) {
+        let mut params = Vec::with_capacity(writes.len());
+        let mut done = Vec::with_capacity(writes.len());
+        for w in writes {
+            let checked = self.sdb.param_by_name(&w.name).and_then(|param| {
+                self.guard
+                    .before_write(param.name(), &w.value)
+                    .with_context(|| WriteRejected {
+                        param: param.name().to_string(),
+                    })?;
+                Ok(param)
+            });
+            match checked {
+                Ok(param) => {
+                    params.push((param, w.value));
+                    done.push(w.done);
+                }
+                Err(e) => (w.done)(Err(e)),
+            }
+        }
+        if params.is_empty() {
+            return;
+        }
+        debug!("Writing {} parameters.", params.len());
+        let written = match self.client() {
+            Ok(_) => {
+                let client = self.client.as_mut().unwrap();
+                let mut transaction = client.transaction().guarded(&mut self.guard);
+                for (param, value) in params {
+                    transaction = transaction.write(param, value);
+                }
+                transaction.execute()
+            }
+            Err(e) => Err(e),
+        };
+        match written {
+            Ok(result) => {
+                self.failures = 0;
+                let results = result.results.into_iter().filter_map(|r| match r {
+                    OpResult::Write(w) => Some(w),
+                    OpResult::Read(..) => None,
+                });
+                for (r, done) in results.zip(done) {
+                    done(match r.is_ok() {
+                        true => Ok(()),
+                        false => Err(anyhow!(
+                            "Writing {} failed with error code {}.",
+                            r.param.name(),
+                            ErrorCode(r.error_code)
+                        )),
+                    });
+                }
+            }
+            Err(e) => {
+                warn!("Queued write failed: {e:#}");
+                self.failed();
+                for done in done {
+                    done(Err(anyhow!("{e:#}")));
+                }
+            }
+        }
+    }
+}
+
+fn fail(batch: Vec<ReadRequest>, e: &anyhow::Error) {
+    for r in batch {
+        let _ = r.reply.send(Err(anyhow!("{e:#}")));
+    }
+}
+
+/// The names of all requests, each once, in the order first requested.
+fn union_of<'a>(requests: impl Iterator<Item = &'a [String]>) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in requests.flatten() {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+/// How to read a set of parameters holding compound parameters together with some of
+/// their members. Either the compound parameter is read and the members are taken out
+/// of its value, or, when that is cheaper on the link or the compound parameter
+/// exceeds the response size limit, all its members are read and its value is put
+/// together from them.
+#[derive(Clone, Debug)]
+pub struct ReadPlan<'sdb> {
+    /// The parameters to read from the instrument, each once.
+    pub fetched: Vec<Parameter<'sdb>>,
+    /// Where the value of each parameter of the set comes from.
+    sources: Vec<Source>,
+}
+
+#[derive(Clone, Debug)]
+enum Source {
+    Fetched(usize),
+    /// A member or element within a fetched value, by its positions in the nested
+    /// structs and arrays.
+    Part(usize, Vec<usize>),
+    Struct(Vec<(String, usize)>),
+    Array(Vec<usize>),
+}
+
+impl<'sdb> ReadPlan<'sdb> {
+    /// Plans the reads of `params`, with responses of at most `max_response_len` bytes.
+    pub fn new(params: &[Parameter<'sdb>], max_response_len: usize) -> Self {
+        let by_name: HashMap<&str, &Parameter<'sdb>> =
+            params.iter().map(|p| (p.name(), p)).collect();
+        // The outermost compound parameter in the set containing each parameter.
+        let root_of = |name| {
+            let mut root = name;
+            let mut ancestor = name;
+            while let Some(parent) = parent_name(ancestor) {
+                if by_name.contains_key(parent) {
+                    root = parent;
+                }
+                ancestor = parent;
+            }
+            root
+        };
+        let mut groups: Vec<(&str, Vec<&Parameter<'sdb>>)> = vec![];
+        for param in params {
+            let root = root_of(param.name());
+            match groups.iter_mut().find(|(r, _)| *r == root) {
+                Some((_, members)) if root == param.name() || members.contains(&param) => {}
+                Some((_, members)) => members.push(param),
+                None if root == param.name() => groups.push((root, vec![])),
+                None => groups.push((root, vec![param])),
+            }
+        }
+
+        let mut plan = Planner::default();
+        for (root, members) in groups {
+            let root = by_name[root];
+            if members.is_empty() {
+                plan.fetch(root);
+                continue;
+            }
+            let too_large = |p: &Parameter| p.wire_cost().response > max_response_len;
+            let children = root
+                .element_names()
+                .and_then(|names| names.iter().map(|n| by_name.get(&**n).copied()).collect())
+                .filter(|children: &Vec<_>| !children.iter().any(|c| too_large(c)));
+            let split = children.filter(|children| {
+                let cost: usize = children.iter().map(|c| c.wire_cost().total()).sum();
+                too_large(root) || cost < root.wire_cost().total()
+            });
+            match split {
+                Some(children) => {
+                    let fetched: Vec<_> = children.iter().map(|c| plan.fetch(c)).collect();
+                    let source = match root.value_kind() {
+                        TypeKind::Data => {
+                            let names = children.iter().map(|c| member_name(root, c));
+                            Source::Struct(names.zip(fetched.iter().copied()).collect())
+                        }
+                        _ => Source::Array(fetched.clone()),
+                    };
+                    plan.sources.insert(root.name(), source);
+                    for member in members {
+                        let child = children.iter().zip(&fetched).find(|(c, _)| {
+                            member.name() == c.name() || is_within(member.name(), c.name())
+                        });
+                        match child {
+                            Some((c, _)) if c.name() == member.name() => {}
+                            Some((c, &i)) => plan.part(member, c, i),
+                            None => {
+                                plan.fetch(member);
+                            }
+                        }
+                    }
+                }
+                None if too_large(root) => {
+                    plan.fetch(root);
+                    for member in members {
+                        plan.fetch(member);
+                    }
+                }
+                None => {
+                    let i = plan.fetch(root);
+                    for member in members {
+                        plan.part(member, root, i);
+                    }
+                }
+            }
+        }
+        let sources = params.iter().map(|p| plan.sources[p.name()].clone());
+        Self {
+            sources: sources.collect(),
+            fetched: plan.fetched,
+        }
+    }
+
+    /// The values of the parameters planned for, from the values of [`Self::fetched`].
+    pub fn values(&self, fetched: Vec<Value>) -> Result<Vec<Value>> {
+        if fetched.len() != self.fetched.len() {
+            bail!(
+                "Got {} values for {} parameters read.",
+                fetched.len(),
+                self.fetched.len()
+            );
+        }
+        let part = |i: usize, path: &[usize]| {
+            let mut value = &fetched[i];
+            for &pos in path {
+                value = match value {
+                    Value::Struct(members) => members.get(pos).map(|(_, v)| v),
+                    Value::Array(elements) => elements.get(pos),
+                    _ => None,
+                }
+                .with_context(|| format!("{} has no member at {path:?}.", self.fetched[i]))?;
+            }
+            anyhow::Ok(value.clone())
+        };
+        self.sources
+            .iter()
+            .map(|source| match source {
+                Source::Fetched(i) => Ok(fetched[*i].clone()),
+                Source::Part(i, path) => part(*i, path),
+                Source::Struct(members) => Ok(Value::Struct(
+                    members
+                        .iter()
+                        .map(|(name, i)| (name.clone(), fetched[*i].clone()))
+                        .collect(),
+                )),
+                Source::Array(elements) => Ok(Value::Array(
+                    elements.iter().map(|i| fetched[*i].clone()).collect(),
+                )),
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Planner<'p, 'sdb> {
+    fetched: Vec<Parameter<'sdb>>,
+    sources: HashMap<&'p str, Source>,
+}
+
+impl<'p, 'sdb> Planner<'p, 'sdb> {
+    fn fetch(&mut self, param: &'p Parameter<'sdb>) -> usize {
+        if let Some(Source::Fetched(i)) = self.sources.get(param.name()) {
+            return *i;
+        }
+        self.fetched.push(param.clone());
+        let i = self.fetched.len() - 1;
+        self.sources.insert(param.name(), Source::Fetched(i));
+        i
+    }
+
+    /// Takes `member` out of the value of `container`, fetched as the `i`th parameter.
+    fn part(&mut self, member: &'p Parameter<'sdb>, container: &Parameter<'sdb>, i: usize) {
+        let rest = &member.name()[container.name().len()..];
+        match value_path(container.sdb(), container.type_info().index(), rest) {
+            Some(path) => {
+                self.sources.insert(member.name(), Source::Part(i, path));
+            }
+            None => {
+                self.fetch(member);
+            }
+        }
+    }
+}
+
+/// The name of the struct or array containing the parameter, if it is a member.
+fn parent_name(name: &str) -> Option<&str> {
+    name.rfind(['.', '['])
+        .filter(|&i| i > 0)
+        .map(|i| &name[..i])
+}
+
+/// Whether `ancestor` contains the parameter `name`.
+fn is_within(name: &str, ancestor: &str) -> bool {
+    name.strip_prefix(ancestor)
+        .is_some_and(|rest| rest.starts_with(['.', '[']))
+}
+
+fn member_name(parent: &Parameter, member: &Parameter) -> String {
+    member.name()[parent.name().len() + 1..].to_string()
+}
+
+/// The positions of the member or element named by `rest`, e.g. `[2].Value`, within
+/// values of the type with index `ty`.
+fn value_path(sdb: &Sdb, mut ty: usize, mut rest: &str) -> Option<Vec<usize>> {
+    let mut path = vec![];
+    while !rest.is_empty() {
+        let info = sdb.type_by_index(ty)?;
+        if let Some(after) = rest.strip_prefix('[') {
+            let (index, after) = after.split_once(']')?;
+            let [(lower, _)] = info.array_bounds()? else {
+                return None;
+            };
+            let index: u32 = index.parse().ok()?;
+            path.push(index.checked_sub(*lower)? as usize);
+            ty = info.array_info()?.0.index();
+            rest = after;
+        } else {
+            let after = rest.strip_prefix('.')?;
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            let members = info.struct_info()?;
+            let pos = members.iter().position(|m| m.name == &after[..end])?;
+            path.push(pos);
+            ty = members[pos].type_info.index();
+            rest = &after[end..];
+        }
+    }
+    Some(path)
+}
+
+#[test]
+fn test_read_plan() {
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let params = |names: &[&str]| -> Vec<_> {
+        names
+            .iter()
+            .map(|n| sdb.param_by_name(n).unwrap())
+            .collect()
+    };
+    let names = |plan: &ReadPlan| -> Vec<String> {
+        plan.fetched.iter().map(|p| p.name().to_string()).collect()
+    };
+    let timer = params(&[
+        ".Gauge[1].DegasTimer.ET",
+        ".Gauge[1].DegasTimer",
+        ".Gauge[1].DegasTimer.Q",
+        ".HostRemote",
+    ]);
+    let plan = ReadPlan::new(&timer, 768);
+    assert_eq!(names(&plan), [".Gauge[1].DegasTimer", ".HostRemote"]);
+    let frequencies = params(&[
+        ".OPCPumpFrequency",
+        ".OPCPumpFrequency[1]",
+        ".OPCPumpFrequency[2]",
+        ".OPCPumpFrequency[3]",
+    ]);
+    assert_eq!(
+        names(&ReadPlan::new(&frequencies, 768)),
+        [".OPCPumpFrequency"]
+    );
+    // Too large to read whole, so put together from its elements.
+    let plan = ReadPlan::new(&frequencies, 10);
+    assert_eq!(
+        names(&plan),
+        &[
+            ".OPCPumpFrequency[1]",
+            ".OPCPumpFrequency[2]",
+            ".OPCPumpFrequency[3]"
+        ]
+    );
+
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    sim.set(&frequencies[2], &Value::Float(50.0)).unwrap();
+    sim.set(&timer[0], &Value::Int(1500)).unwrap();
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    for set in [&timer, &frequencies] {
+        let separately: Vec<_> = set
+            .iter()
+            .map(|p| client.read(std::slice::from_ref(p)).unwrap().remove(0))
+            .collect();
+        assert_eq!(client.read(set).unwrap(), separately);
+        let plan = ReadPlan::new(set, 10);
+        let fetched = client.read(&plan.fetched).unwrap();
+        assert_eq!(plan.values(fetched).unwrap(), separately);
+    }
+}
+
+#[test]
+fn test_union_of() {
+    let a = [".A".to_string(), ".B".to_string()];
+    let b = [".B".to_string(), ".C".to_string()];
+    assert_eq!(union_of([&a[..], &b[..]].into_iter()), [".A", ".B", ".C"]);
+}
+
+#[test]
+fn test_write_order() {
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let addr = sim.addr();
+    let mut config = crate::config::Config::default();
+    config.access.deny = vec![".HostRemote".into()];
+    let operator = crate::access::ClientAccess {
+        allow: vec![".CockpitUser".into()],
+        ..Default::default()
+    };
+    config.access.clients.insert("operator".into(), operator);
+    let file = crate::sdb_builder::fixture_file();
+    let coalescer = ReadCoalescer::start(
+        file.path().into(),
+        Duration::from_millis(20),
+        Arc::new(move || Connection::connect_addr(addr)),
+        WriteGuard::new(&config, "test").unwrap(),
+    );
+    let events = coalescer.events().channel();
+    let name = ".Gauge[1].Parameter[1].Name".to_string();
+    let first = coalescer.write(&name, Value::String("A".into()));
+    let second = coalescer.write(&name, Value::String("B".into()));
+    let read = coalescer.read(std::slice::from_ref(&name)).unwrap();
+    assert_eq!(read, [Value::String("B".into())]);
+    first.wait().unwrap();
+    second.wait().unwrap();
+    assert!(coalescer
+        .write(".No.Such.Param", Value::Int(1))
+        .wait()
+        .is_err());
+    let denied = coalescer.write(".HostRemote", Value::Bool(true)).wait();
+    assert!(denied.unwrap_err().is::<WriteRejected>());
+    // Checked for the client, not for the user of the guard.
+    let operator = coalescer.write_as("operator", &name, Value::String("C".into()));
+    assert!(operator.wait().unwrap_err().is::<WriteRejected>());
+    let user = Value::String("operator".into());
+    coalescer
+        .write_as("operator", ".CockpitUser", user)
+        .wait()
+        .unwrap();
+    coalescer
+        .write(&name, Value::String("D".into()))
+        .wait()
+        .unwrap();
+    let connected = ConnectionEvent::Connected { addr };
+    assert!(events.try_iter().any(|e| e == connected));
+}
@@ -0,0 +1,81 @@
+//! ANSI colors for the command line output.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::IsTerminal;
+
+use leybold_opc_rs::opc_values::Value;
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default)]
+pub enum ColorChoice {
+    /// Color when printing to a terminal, unless NO_COLOR is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Wraps text in escape codes, or passes it through when colors are off.
+#[derive(Copy, Clone, Debug)]
+pub struct Palette {
+    enabled: bool,
+}
+
+impl Palette {
+    /// Decides on colors for stdout. Errors go to stderr, which is almost always
+    /// redirected together with stdout, so that isn't checked separately.
+    pub fn new(choice: ColorChoice) -> Self {
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
+        };
+        Self { enabled }
+    }
+
+    fn paint(&self, code: &str, text: impl Display) -> String {
+        match self.enabled {
+            true => format!("\x1b[{code}m{text}\x1b[0m"),
+            false => text.to_string(),
+        }
+    }
+
+    pub fn error(&self, text: impl Display) -> String {
+        self.paint("31", text)
+    }
+
+    pub fn changed(&self, text: impl Display) -> String {
+        self.paint("1;33", text)
+    }
+
+    pub fn dim(&self, text: impl Display) -> String {
+        self.paint("2", text)
+    }
+}
+
+/// Remembers the values of the previous poll, to highlight the ones that changed.
+#[derive(Debug)]
+pub struct ChangeTracker {
+    pub palette: Palette,
+    previous: HashMap<String, Value>,
+}
+
+impl ChangeTracker {
+    pub fn new(palette: Palette) -> Self {
+        Self {
+            palette,
+            previous: HashMap::new(),
+        }
+    }
+
+    /// Whether the value differs from the previous one seen for `label`. The first
+    /// value isn't a change.
+    pub fn changed(&mut self, label: &str, value: &Value) -> bool {
+        self.previous
+            .insert(label.to_string(), value.clone())
+            .is_some_and(|prev| prev != *value)
+    }
+}
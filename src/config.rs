@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "config")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::access::AccessPolicy;
+use crate::alerts::{AlertRule, NotifyConfig};
+use crate::devices::DeviceConfig;
+use crate::metadata::MetadataOverlay;
+use crate::sdb::{Parameter, Sdb};
+
+/// The config file used when no other file is given.
+pub const DEFAULT_CONFIG_FILE: &str = "leybold-opc.toml";
+
+/// User configuration, read from a TOML file.
+///
+/// ```toml
+/// [groups.pressures]
+/// params = [".Gauge[1].Parameter[1].Value", ".Gauge[2].Parameter[1].Value"]
+///
+/// [writes]
+/// audit_log = "writes.log"
+/// min_interval_secs = 5
+///
+/// [access]
+/// allow = [".CockpitUser"]
+///
+/// [renames]
+/// ".Gauge[1].Parameter[1].Value" = ".Gauge[1].Pressure"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Named parameter sets, referred to as `@name` on the command line.
+    pub groups: BTreeMap<String, ParamGroup>,
+    pub writes: WriteConfig,
+    /// Which parameters may be written, and by whom.
+    pub access: AccessPolicy,
+    /// Checked against the values read while polling, see [`AlertRule`].
+    pub alerts: Vec<AlertRule>,
+    pub notify: NotifyConfig,
+    /// Instruments polled together by `poll-devices`, see [`DeviceConfig`].
+    pub devices: Vec<DeviceConfig>,
+    /// File with units, descriptions and limits of parameters, see [`MetadataOverlay`].
+    pub metadata: Option<PathBuf>,
+    /// Parameters renamed by firmware updates, from the old to the new name, see
+    /// [`Config::resolve_param`]. They apply to the alert rules and metadata too.
+    pub renames: BTreeMap<String, String>,
+}
+
+/// Settings which apply to all parameter writes.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteConfig {
+    /// Every write is appended to this file, together with the previous value.
+    pub audit_log: Option<PathBuf>,
+    /// The minimum time between two writes to the same parameter.
+    #[serde(deserialize_with = "seconds")]
+    pub min_interval_secs: f64,
+}
+
+impl WriteConfig {
+    /// The minimum interval, none if `min_interval_secs` was set to an invalid time
+    /// other than by reading the config.
+    pub fn min_interval(&self) -> Duration {
+        Duration::try_from_secs_f64(self.min_interval_secs).unwrap_or_default()
+    }
+}
+
+/// A number of seconds, which must be finite and not negative.
+fn seconds<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(_) => Ok(secs),
+        Err(e) => Err(serde::de::Error::custom(format!("{secs} seconds: {e}"))),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamGroup {
+    pub params: Vec<String>,
+}
+
+impl Config {
+    #[cfg(feature = "config")]
+    pub fn from_file(file: impl AsRef<Path>) -> Result<Self> {
+        let file = file.as_ref();
+        let text = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read config file {}", file.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {}", file.display()))
+    }
+
+    /// Reads the given config file, or the default config file if it exists.
+    #[cfg(feature = "config")]
+    pub fn load(file: Option<&Path>) -> Result<Self> {
+        match file {
+            Some(file) => Self::from_file(file),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(DEFAULT_CONFIG_FILE),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The metadata file, or an empty overlay if none is configured. The metadata of a
+    /// parameter is also found by the names it was renamed to.
+    pub fn metadata(&self) -> Result<MetadataOverlay> {
+        let metadata = match &self.metadata {
+            #[cfg(feature = "config")]
+            Some(file) => MetadataOverlay::from_file(file)?,
+            #[cfg(not(feature = "config"))]
+            Some(file) => anyhow::bail!(
+                "Reading metadata file {} needs the `config` feature",
+                file.display()
+            ),
+            None => MetadataOverlay::default(),
+        };
+        Ok(metadata.with_renames(&self.renames))
+    }
+
+    /// The alert rules, with the parameters looked up in the SDB like
+    /// [`Config::resolve_param`] does. Rules of parameters missing from the SDB are
+    /// left as they are.
+    pub fn alert_rules(&self, sdb: &Sdb) -> Vec<AlertRule> {
+        let mut rules = self.alerts.clone();
+        for rule in &mut rules {
+            if let Ok(param) = self.resolve_param(sdb, &rule.param) {
+                rule.param = param.name().to_string();
+            }
+        }
+        rules
+    }
+
+    pub fn group(&self, name: &str) -> Result<&ParamGroup> {
+        self.groups
+            .get(name)
+            .with_context(|| format!("Parameter group '{name}' not found in config"))
+    }
+
+    /// Expands a `@group` reference into the parameter names of the group.
+    /// Other names are returned unchanged.
+    pub fn expand_param<'a>(&'a self, name: &'a str) -> Result<Vec<&'a str>> {
+        match name.strip_prefix('@') {
+            Some(group) => Ok(self
+                .group(group)?
+                .params
+                .iter()
+                .map(|p| p.as_str())
+                .collect()),
+            None => Ok(vec![name]),
+        }
+    }
+
+    /// Looks up a parameter in the SDB. A name missing from the SDB is looked up by its
+    /// new name from `[renames]` instead, with a warning, so that groups written for
+    /// older firmware keep working.
+    pub fn resolve_param<'sdb>(&self, sdb: &'sdb Sdb, name: &str) -> Result<Parameter<'sdb>> {
+        resolve_renamed(&self.renames, sdb, name)
+    }
+}
+
+/// Looks up a parameter in the SDB by its name, or else by the names it was renamed
+/// to in `renames`, see [`Config::resolve_param`].
+pub fn resolve_renamed<'sdb>(
+    renames: &BTreeMap<String, String>,
+    sdb: &'sdb Sdb,
+    name: &str,
+) -> Result<Parameter<'sdb>> {
+    let missing = match sdb.param_by_name(name) {
+        Ok(param) => return Ok(param),
+        Err(e) => e,
+    };
+    for renamed in rename_chain(renames, name) {
+        if let Ok(param) = sdb.param_by_name(renamed) {
+            warn!("{name} is called {renamed} in this SDB, as given in [renames].");
+            return Ok(param);
+        }
+    }
+    Err(missing)
+}
+
+/// The names `name` was renamed to, in order.
+pub(crate) fn rename_chain<'a>(
+    renames: &'a BTreeMap<String, String>,
+    name: &'a str,
+) -> impl Iterator<Item = &'a str> {
+    let mut renamed = name;
+    // Bounded, in case the renames form a cycle.
+    (0..renames.len()).map_while(move |_| {
+        renamed = renames.get(renamed)?;
+        Some(renamed)
+    })
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn test_expand_group() {
+    let cfg: Config = toml::from_str(
+        r#"
+        [groups.pressures]
+        params = [".Gauge[1].Parameter[1].Value", ".Gauge[2].Parameter[1].Value"]
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        cfg.expand_param("@pressures").unwrap(),
+        [
+            ".Gauge[1].Parameter[1].Value",
+            ".Gauge[2].Parameter[1].Value"
+        ]
+    );
+    assert_eq!(cfg.expand_param(".CockpitUser").unwrap(), [".CockpitUser"]);
+    assert!(cfg.expand_param("@missing").is_err());
+
+    let cfg: Config = toml::from_str("[writes]\nmin_interval_secs = 2.5\n").unwrap();
+    assert_eq!(cfg.writes.min_interval(), Duration::from_millis(2500));
+    assert!(toml::from_str::<Config>("[writes]\nmin_interval_secs = inf\n").is_err());
+    assert!(toml::from_str::<Config>("[writes]\nmin_interval_secs = -1.0\n").is_err());
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn test_resolve_renamed() {
+    let cfg: Config = toml::from_str(
+        r#"
+        [renames]
+        ".Pressure1" = ".Gauge1.Pressure"
+        ".Gauge1.Pressure" = ".Gauge[1].Parameter[1].Value"
+        ".Loop" = ".Loop"
+        "#,
+    )
+    .unwrap();
+    let sdb = crate::sdb_builder::test_sdb();
+    let param = cfg.resolve_param(&sdb, ".Pressure1").unwrap();
+    assert_eq!(param.name(), ".Gauge[1].Parameter[1].Value");
+    assert_eq!(
+        cfg.resolve_param(&sdb, ".HostRemote").unwrap().name(),
+        ".HostRemote"
+    );
+    assert!(cfg.resolve_param(&sdb, ".Loop").is_err());
+
+    let cfg: Config = toml::from_str(
+        r#"
+        [renames]
+        ".Pressure1" = ".Gauge[1].Parameter[1].Value"
+
+        [[alerts]]
+        name = "pressure"
+        param = ".Pressure1"
+        above = 1e-3
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        cfg.alert_rules(&sdb)[0].param,
+        ".Gauge[1].Parameter[1].Value"
+    );
+    let metadata = MetadataOverlay::from_toml("[\".Pressure1\"]\nunit = \"mbar\"\n")
+        .unwrap()
+        .with_renames(&cfg.renames);
+    assert!(metadata.get(".Gauge[1].Parameter[1].Value").is_some());
+    assert!(metadata.get(".Pressure1").is_some());
+}
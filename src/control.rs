@@ -0,0 +1,176 @@
+//! A control socket for long-running commands, for switching packet dumps and the log
+//! level and fetching statistics without a restart.
+//!
+//! Clients connect to the Unix socket, send one command line and read the reply until
+//! the socket is closed, e.g. with `echo stats | nc -U <socket>`. The commands are
+//! `dump on`, `dump off`, `level <level>` and `stats`.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, warn};
+
+type SetLevel = dyn Fn(LevelFilter) -> Result<()> + Send + Sync;
+
+/// The settings and state reachable through a [`ControlSocket`].
+pub struct Controls {
+    packet_dump: Arc<AtomicBool>,
+    stats: Mutex<Option<String>>,
+    set_level: Option<Box<SetLevel>>,
+}
+
+impl Controls {
+    /// Controls with packet dumps on or off to begin with.
+    pub fn new(packet_dump: bool) -> Self {
+        Self {
+            packet_dump: Arc::new(AtomicBool::new(packet_dump)),
+            stats: Mutex::new(None),
+            set_level: None,
+        }
+    }
+
+    /// Switches the log level with `set_level`, e.g. through a reload handle of
+    /// `tracing_subscriber`.
+    pub fn with_level_switch(
+        mut self,
+        set_level: impl Fn(LevelFilter) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.set_level = Some(Box::new(set_level));
+        self
+    }
+
+    /// Whether packets should be dumped, for sharing with the packet observers.
+    pub fn packet_dump(&self) -> Arc<AtomicBool> {
+        self.packet_dump.clone()
+    }
+
+    /// Sets the statistics returned by the `stats` command.
+    pub fn set_stats(&self, stats: impl Into<String>) {
+        *self.stats.lock().unwrap() = Some(stats.into());
+    }
+
+    /// Runs one command and returns its reply.
+    pub fn execute(&self, command: &str) -> Result<String> {
+        let words: Vec<_> = command.split_whitespace().collect();
+        match words[..] {
+            ["dump", on @ ("on" | "off")] => {
+                self.packet_dump.store(on == "on", SeqCst);
+                Ok(format!("Packet dump {on}."))
+            }
+            ["level", level] => {
+                let Some(set_level) = &self.set_level else {
+                    bail!("The log level can't be changed here.");
+                };
+                let level: LevelFilter = level
+                    .parse()
+                    .with_context(|| format!("Unknown log level '{level}'"))?;
+                set_level(level)?;
+                Ok(format!("Log level {level}."))
+            }
+            ["stats"] => Ok(self
+                .stats
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_else(|| "No statistics yet.".into())),
+            _ => bail!(
+                "Unknown command '{command}', expected 'dump on', 'dump off', 'level <level>' \
+                 or 'stats'."
+            ),
+        }
+    }
+}
+
+/// Serves [`Controls`] on a Unix socket. The socket file is removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Listens on `path`, replacing a socket file left by a process no longer running.
+    /// Fails if `path` is anything else than a socket.
+    pub fn start(path: impl Into<PathBuf>, controls: Arc<Controls>) -> Result<Self> {
+        let path = path.into();
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                bail!("{} exists and is not a socket.", path.display());
+            }
+            if UnixStream::connect(&path).is_ok() {
+                bail!("Another process is listening on {}.", path.display());
+            }
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to listen on {}", path.display()))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = serve(stream, &controls) {
+                    warn!("Control socket client failed: {e:#}");
+                }
+            }
+        });
+        debug!("Control socket listening on {}", path.display());
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, controls: &Controls) -> Result<()> {
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    let reply = match controls.execute(command.trim()) {
+        Ok(reply) => reply,
+        Err(e) => format!("Error: {e:#}"),
+    };
+    debug!("Control command '{}': {reply}", command.trim());
+    (&stream).write_all(format!("{reply}\n").as_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn test_control_socket() {
+    use std::io::Read;
+
+    let controls = Arc::new(Controls::new(false));
+    let path = std::env::temp_dir().join(format!("control-{}.sock", std::process::id()));
+    let socket = ControlSocket::start(&path, controls.clone()).unwrap();
+    let send = |command: &str| {
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(format!("{command}\n").as_bytes()).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    };
+    assert_eq!(send("dump on"), "Packet dump on.\n");
+    assert!(controls.packet_dump().load(SeqCst));
+    assert_eq!(send("stats"), "No statistics yet.\n");
+    controls.set_stats("3 samples");
+    assert_eq!(send("stats"), "3 samples\n");
+    assert!(send("level debug").starts_with("Error: "));
+    assert!(send("reboot").starts_with("Error: Unknown command"));
+    drop(socket);
+    assert!(!path.exists());
+
+    // Other files are left alone.
+    std::fs::write(&path, "data").unwrap();
+    assert!(ControlSocket::start(&path, controls).is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,198 @@
+//! Polls several instruments at once, each on its own thread and connection.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::client::Client;
+use crate::config::resolve_renamed;
+use crate::events::{ConnectionEvent, ConnectionEvents};
+use crate::host::Host;
+use crate::opc_values::Value;
+use crate::plc_connection::Connection;
+use crate::sdb::ParseMode;
+use crate::sdb_store::SdbStore;
+
+/// An instrument in the config file.
+///
+/// ```toml
+/// [[devices]]
+/// name = "chamber-a"
+/// ip = "192.168.1.10"
+/// sdb = "chamber-a.sdb"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    /// An IP address or host name.
+    pub ip: Host,
+    /// The SDB of this instrument, if it differs from the one given on the command line.
+    pub sdb: Option<PathBuf>,
+}
+
+/// What the polling threads report, in the order it happened.
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    Sample {
+        device: String,
+        time: DateTime<Utc>,
+        values: Vec<(String, Value)>,
+    },
+    /// The device failed and is retried after a delay, the others keep polling.
+    Failed { device: String, error: String },
+    /// A change of the device's connection.
+    Link {
+        device: String,
+        event: ConnectionEvent,
+    },
+}
+
+/// Opens the connection to a device, configured like the caller wants it.
+pub type ConnectFn = dyn Fn(&DeviceConfig) -> Result<Connection> + Send + Sync;
+
+/// The polling threads, which stop when this is dropped.
+pub struct DevicePoller {
+    stop: Vec<Sender<()>>,
+    threads: Vec<JoinHandle<()>>,
+    events: Receiver<DeviceEvent>,
+}
+
+impl DevicePoller {
+    /// Starts reading `params` from every device each `interval`. Devices which fail
+    /// are reconnected after `retry_delay`. Parameters missing from the SDB of a device
+    /// are looked up by their new names in `renames`, see [`Config::resolve_param`].
+    ///
+    /// [`Config::resolve_param`]: crate::config::Config::resolve_param
+    pub fn start(
+        devices: &[DeviceConfig],
+        default_sdb: &Path,
+        params: &[String],
+        renames: &BTreeMap<String, String>,
+        interval: Duration,
+        retry_delay: Duration,
+        connect: Arc<ConnectFn>,
+    ) -> Self {
+        let (tx, events) = mpsc::channel();
+        let mut stop = Vec::new();
+        let mut threads = Vec::new();
+        for device in devices {
+            let (stop_tx, stop_rx) = mpsc::channel();
+            let poller = Poller {
+                sdb: device.sdb.clone().unwrap_or_else(|| default_sdb.into()),
+                device: device.clone(),
+                params: params.to_vec(),
+                renames: renames.clone(),
+                interval,
+                connect: connect.clone(),
+                events: tx.clone(),
+                stop: stop_rx,
+            };
+            stop.push(stop_tx);
+            threads.push(std::thread::spawn(move || poller.run(retry_delay)));
+        }
+        Self {
+            stop,
+            threads,
+            events,
+        }
+    }
+
+    /// The samples and failures of all devices.
+    pub fn events(&self) -> &Receiver<DeviceEvent> {
+        &self.events
+    }
+
+    /// Stops the threads and waits for them, which takes up to one response timeout.
+    pub fn stop(mut self) {
+        self.stop.clear();
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+struct Poller {
+    device: DeviceConfig,
+    sdb: PathBuf,
+    params: Vec<String>,
+    renames: BTreeMap<String, String>,
+    interval: Duration,
+    connect: Arc<ConnectFn>,
+    events: Sender<DeviceEvent>,
+    stop: Receiver<()>,
+}
+
+impl Poller {
+    fn run(self, retry_delay: Duration) {
+        let link = ConnectionEvents::new();
+        let (events, device) = (self.events.clone(), self.device.name.clone());
+        link.subscribe(move |event| {
+            let _ = events.send(DeviceEvent::Link {
+                device: device.clone(),
+                event: event.clone(),
+            });
+        });
+        for attempt in 1.. {
+            let error = match self.poll(&link) {
+                Ok(()) => return,
+                Err(e) => format!("{e:#}"),
+            };
+            let failed = DeviceEvent::Failed {
+                device: self.device.name.clone(),
+                error,
+            };
+            if self.events.send(failed).is_err() || self.stopped_within(retry_delay) {
+                return;
+            }
+            link.emit(ConnectionEvent::Reconnecting { attempt });
+        }
+    }
+
+    /// Polls until stopped, or until the device fails.
+    fn poll(&self, link: &ConnectionEvents) -> Result<()> {
+        // The SDB isn't Send, every thread parses its own.
+        let sdb = SdbStore::new(&self.sdb)
+            .with_parse_mode(ParseMode::Lazy)
+            .load()?;
+        let params = self
+            .params
+            .iter()
+            .map(|name| resolve_renamed(&self.renames, &sdb, name))
+            .collect::<Result<Vec<_>>>()?;
+        let mut conn = (self.connect)(&self.device)?;
+        conn.set_events(link.clone());
+        let mut client = Client::new(conn, &sdb)?;
+        debug!("Polling {}", self.device.name);
+        loop {
+            let values = client.read_cached(&params)?;
+            let sample = DeviceEvent::Sample {
+                device: self.device.name.clone(),
+                time: Utc::now(),
+                values: params
+                    .iter()
+                    .map(|p| p.name().to_string())
+                    .zip(values)
+                    .collect(),
+            };
+            if self.events.send(sample).is_err() || self.stopped_within(self.interval) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn stopped_within(&self, timeout: Duration) -> bool {
+        !matches!(
+            self.stop.recv_timeout(timeout),
+            Err(RecvTimeoutError::Timeout)
+        )
+    }
+}
@@ -0,0 +1,3 @@
+//! Errors reported by the instruments.
+
+pub mod device;
@@ -0,0 +1,59 @@
+//! The error codes instruments answer queries with, and what they mean.
+//!
+//! The protocol is undocumented, so the meanings are guesses from how instruments
+//! behave when they answer with a code. Codes without a guess are shown as bare
+//! numbers.
+
+use std::fmt::{self, Display, Formatter};
+
+/// A device error code with a guessed meaning.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceError {
+    pub code: u16,
+    pub message: &'static str,
+    /// What to do about it.
+    pub hint: Option<&'static str>,
+}
+
+const KNOWN: &[DeviceError] = &[DeviceError {
+    code: 0x0001,
+    message: "query refused",
+    hint: Some(
+        "possibly a read of an unknown address or a response too large, check that the \
+         SDB matches the instrument",
+    ),
+}];
+
+/// The guessed meaning of this code, if there is one.
+pub fn lookup(code: u16) -> Option<&'static DeviceError> {
+    KNOWN.iter().find(|e| e.code == code)
+}
+
+/// Shows an error code with its guessed message and hint, if any, e.g.
+/// `0x0001 (query refused: ...)`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ErrorCode(pub u16);
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}", self.0)?;
+        match lookup(self.0) {
+            Some(DeviceError {
+                message,
+                hint: Some(hint),
+                ..
+            }) => write!(f, " ({message}: {hint})"),
+            Some(DeviceError { message, .. }) => write!(f, " ({message})"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_error_code() {
+    assert_eq!(ErrorCode(0x0102).to_string(), "0x0102");
+    assert!(ErrorCode(crate::sim::SIM_ERROR_CODE)
+        .to_string()
+        .starts_with("0x0001 (query refused: "));
+    assert_eq!(lookup(0x0001).unwrap().message, "query refused");
+}
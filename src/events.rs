@@ -0,0 +1,99 @@
+//! Changes of the link to an instrument, for showing its status without parsing logs.
+
+use std::fmt::{self, Debug, Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected {
+        addr: SocketAddr,
+    },
+    /// The connection failed or was closed.
+    Disconnected {
+        reason: String,
+    },
+    /// Connecting again after a failure, `attempt` counts from one.
+    Reconnecting {
+        attempt: u32,
+    },
+    /// An SDB was parsed, at startup or because the file changed.
+    SdbRefreshed {
+        sdb_id: u32,
+    },
+}
+
+impl Display for ConnectionEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connected { addr } => write!(f, "Connected to {addr}."),
+            Self::Disconnected { reason } => write!(f, "Disconnected: {reason}"),
+            Self::Reconnecting { attempt } => write!(f, "Reconnecting, attempt {attempt}."),
+            Self::SdbRefreshed { sdb_id } => write!(f, "Loaded SDB {sdb_id:#010x}."),
+        }
+    }
+}
+
+type Subscriber = Arc<dyn Fn(&ConnectionEvent) + Send + Sync>;
+
+/// Passes the events to the subscribers. Clones share the subscribers, so a clone can
+/// be handed to every emitter.
+#[derive(Clone, Default)]
+pub struct ConnectionEvents {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl ConnectionEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `f` with every event from now on, on the thread emitting it.
+    pub fn subscribe(&self, f: impl Fn(&ConnectionEvent) + Send + Sync + 'static) {
+        self.subscribers.lock().unwrap().push(Arc::new(f));
+    }
+
+    /// The events from now on, as a channel.
+    pub fn channel(&self) -> Receiver<ConnectionEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribe(move |e| {
+            let _ = tx.send(e.clone());
+        });
+        rx
+    }
+
+    /// Calls the subscribers without holding the lock, so that they may subscribe or
+    /// emit themselves.
+    pub fn emit(&self, event: ConnectionEvent) {
+        let subscribers = self.subscribers.lock().unwrap().clone();
+        for subscriber in subscribers {
+            subscriber(&event);
+        }
+    }
+}
+
+impl Debug for ConnectionEvents {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let n = self.subscribers.lock().unwrap().len();
+        write!(f, "ConnectionEvents({n} subscribers)")
+    }
+}
+
+#[test]
+fn test_emit_from_subscriber() {
+    let events = ConnectionEvents::new();
+    let rx = events.channel();
+    let inner = events.clone();
+    events.subscribe(move |e| {
+        if let ConnectionEvent::Reconnecting { attempt: 1 } = e {
+            inner.emit(ConnectionEvent::Reconnecting { attempt: 2 });
+        }
+    });
+    events.emit(ConnectionEvent::Reconnecting { attempt: 1 });
+    let attempts: Vec<_> = rx.try_iter().collect();
+    assert_eq!(
+        attempts,
+        [1, 2].map(|attempt| ConnectionEvent::Reconnecting { attempt })
+    );
+}
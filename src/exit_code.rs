@@ -0,0 +1,168 @@
+//! Exit codes and error reports that scripts can branch on.
+
+use std::io::ErrorKind;
+use std::process::ExitCode;
+
+use serde::Serialize;
+
+use leybold_opc_rs::client::WriteRejected;
+use leybold_opc_rs::metadata::OutOfRange;
+use leybold_opc_rs::plc_connection::{DeviceBusy, SdbDownloadFailed, SdbDownloadMisbehavior};
+use leybold_opc_rs::sdb::UnknownParameter;
+use leybold_opc_rs::sdb_store::SdbNotFound;
+use leybold_opc_rs::session::SessionBusy;
+
+/// The kinds of failure with their own exit code. Usage errors exit with 2, from clap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Failure {
+    Other = 1,
+    Connection = 3,
+    UnknownParameter = 4,
+    WriteRejected = 5,
+    Parse = 6,
+    /// Some of the writes failed, the others were written.
+    PartialSuccess = 7,
+    /// The SDB file is missing and couldn't be downloaded.
+    Sdb = 8,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default)]
+pub enum ErrorFormat {
+    #[default]
+    Text,
+    /// One JSON object with the failure kind, exit code and messages.
+    Json,
+}
+
+/// Writes answered with an error code by the instrument.
+#[derive(Clone, Debug)]
+pub struct WritesFailed {
+    pub failed: usize,
+    pub total: usize,
+}
+
+impl std::fmt::Display for WritesFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} writes failed.", self.failed, self.total)
+    }
+}
+
+impl std::error::Error for WritesFailed {}
+
+/// A value given on the command line which doesn't parse as the parameter type.
+#[derive(Clone, Debug)]
+pub struct InvalidValue {
+    pub param: String,
+    pub value: String,
+}
+
+impl std::fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to parse '{}' as valid value for {}.",
+            self.value, self.param
+        )
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
+/// Finds `T` as either a context or a source of the error.
+fn find<T: std::error::Error + Send + Sync + 'static>(e: &anyhow::Error) -> Option<&T> {
+    e.downcast_ref::<T>()
+        .or_else(|| e.chain().find_map(|c| c.downcast_ref::<T>()))
+}
+
+pub fn classify(e: &anyhow::Error) -> Failure {
+    if let Some(w) = find::<WritesFailed>(e) {
+        return match w.failed < w.total {
+            true => Failure::PartialSuccess,
+            false => Failure::WriteRejected,
+        };
+    }
+    if find::<WriteRejected>(e).is_some() || find::<OutOfRange>(e).is_some() {
+        return Failure::WriteRejected;
+    }
+    if find::<UnknownParameter>(e).is_some() {
+        return Failure::UnknownParameter;
+    }
+    if find::<InvalidValue>(e).is_some() {
+        return Failure::Parse;
+    }
+    if find::<DeviceBusy>(e).is_some_and(|busy| busy.error_code.is_none())
+        || find::<SessionBusy>(e).is_some()
+    {
+        return Failure::Connection;
+    }
+    // The socket errors, as opposed to e.g. a missing SDB file.
+    let network = |kind| {
+        matches!(
+            kind,
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::AddrNotAvailable
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::WouldBlock
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+        )
+    };
+    if find::<std::io::Error>(e).is_some_and(|io| network(io.kind())) {
+        return Failure::Connection;
+    }
+    if find::<SdbNotFound>(e).is_some()
+        || find::<SdbDownloadFailed>(e).is_some()
+        || find::<SdbDownloadMisbehavior>(e).is_some()
+    {
+        return Failure::Sdb;
+    }
+    Failure::Other
+}
+
+/// Prints the error to stderr in the requested format, and returns its exit code.
+pub fn report(e: &anyhow::Error, format: ErrorFormat) -> ExitCode {
+    let failure = classify(e);
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {e:?}"),
+        ErrorFormat::Json => eprintln!(
+            "{}",
+            serde_json::json!({
+                "error": failure,
+                "exit_code": failure as u8,
+                "message": format!("{e:#}"),
+                "causes": e.chain().map(|c| c.to_string()).collect::<Vec<_>>(),
+            })
+        ),
+    }
+    ExitCode::from(failure as u8)
+}
+
+#[test]
+fn test_classify() {
+    use anyhow::Context;
+    let partial = anyhow::Error::from(WritesFailed {
+        failed: 1,
+        total: 2,
+    });
+    assert_eq!(classify(&partial), Failure::PartialSuccess);
+    let refused = std::io::Error::from(ErrorKind::ConnectionRefused);
+    let e = Err::<(), _>(refused).context("Failed to connect to PLC");
+    assert_eq!(classify(&e.unwrap_err()), Failure::Connection);
+    let e = anyhow::Error::from(std::io::Error::from(ErrorKind::NotFound));
+    assert_eq!(classify(&e), Failure::Other);
+    let e = Err::<(), _>(e).context(SdbNotFound {
+        path: "sdb.dat".into(),
+    });
+    assert_eq!(classify(&e.unwrap_err()), Failure::Sdb);
+    let e = Err::<(), _>(anyhow::anyhow!("bad float")).context(InvalidValue {
+        param: ".P".into(),
+        value: "x".into(),
+    });
+    assert_eq!(classify(&e.unwrap_err()), Failure::Parse);
+}
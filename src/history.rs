@@ -0,0 +1,84 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::opc_values::Value;
+use crate::sdb::Parameter;
+
+/// One polled value of a parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// Host time when the response was received.
+    pub time: DateTime<Utc>,
+    /// The instrument clock of the response.
+    pub device_time: Option<Duration>,
+    pub value: Value,
+}
+
+/// The most recent samples of each parameter, in a ring buffer of fixed capacity.
+#[derive(Clone, Debug)]
+pub struct History<'sdb> {
+    capacity: usize,
+    buffers: HashMap<Parameter<'sdb>, VecDeque<Sample>>,
+}
+
+impl<'sdb> History<'sdb> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            buffers: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Adds a sample, dropping the oldest one of the parameter if its buffer is full.
+    #[allow(clippy::mutable_key_type)]
+    pub fn record(&mut self, param: &Parameter<'sdb>, sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        let buffer = self.buffers.entry(param.clone()).or_default();
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(sample);
+    }
+
+    /// The samples of the parameter, oldest first.
+    pub fn get(&self, param: &Parameter<'sdb>) -> impl DoubleEndedIterator<Item = &Sample> + '_ {
+        self.buffers.get(param).into_iter().flatten()
+    }
+
+    /// The most recent sample of the parameter.
+    pub fn latest(&self, param: &Parameter<'sdb>) -> Option<&Sample> {
+        self.buffers.get(param)?.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+    }
+}
+
+#[test]
+fn test_history_ring_buffer() {
+    let sdb = crate::sdb_builder::test_sdb();
+    let param = sdb.param_by_name(".CockpitUser").unwrap();
+    let mut history = History::new(3);
+    for i in 0..5 {
+        history.record(
+            &param,
+            Sample {
+                time: Utc::now(),
+                device_time: None,
+                value: Value::Int(i),
+            },
+        );
+    }
+    let values: Vec<_> = history.get(&param).map(|s| s.value.clone()).collect();
+    assert_eq!(values, [Value::Int(2), Value::Int(3), Value::Int(4)]);
+    assert_eq!(history.latest(&param).unwrap().value, Value::Int(4));
+}
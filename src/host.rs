@@ -0,0 +1,322 @@
+//! Instrument addresses given as IP addresses or host names.
+//!
+//! Names are looked up with the system resolver. Names ending in `.local` which the
+//! system can't resolve are asked for with a one-shot mDNS query, since many hosts
+//! have no mDNS support in their resolver.
+//!
+//! [`Bind`] selects the local end of connections, on hosts with several networks.
+
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tracing::debug;
+
+const MDNS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+const MDNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The address of an instrument: an IP address or a host name.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Host {
+    Ip(IpAddr),
+    /// An IPv6 address with the index of the interface it is on, as in `fe80::1%eth0`.
+    Scoped(Ipv6Addr, u32),
+    Name(String),
+}
+
+impl Host {
+    /// The IP address, looking up names.
+    pub fn resolve(&self) -> Result<IpAddr> {
+        let name = match self {
+            Host::Ip(ip) => return Ok(*ip),
+            Host::Scoped(ip, _) => return Ok((*ip).into()),
+            Host::Name(name) => name,
+        };
+        let system = (name.as_str(), 0)
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.next().map(|a| a.ip()));
+        match system {
+            Ok(Some(ip)) => Ok(ip),
+            _ if is_mdns_name(name) => {
+                debug!("Resolving {name} with mDNS");
+                mdns_resolve(name).with_context(|| format!("Failed to resolve {name}"))
+            }
+            Ok(None) => bail!("No address found for {name}."),
+            Err(e) => Err(e).with_context(|| format!("Failed to resolve {name}")),
+        }
+    }
+
+    /// The address of `port` on the host, looking up names.
+    pub fn socket_addr(&self, port: u16) -> Result<SocketAddr> {
+        Ok(match self {
+            Host::Scoped(ip, scope) => SocketAddrV6::new(*ip, port, 0, *scope).into(),
+            host => SocketAddr::new(host.resolve()?, port),
+        })
+    }
+}
+
+impl From<IpAddr> for Host {
+    fn from(ip: IpAddr) -> Self {
+        Host::Ip(ip)
+    }
+}
+
+impl FromStr for Host {
+    type Err = anyhow::Error;
+
+    /// IPv6 addresses may be given in brackets, as in URLs, and with a zone.
+    fn from_str(s: &str) -> Result<Self> {
+        let unbracketed = s.strip_prefix('[').and_then(|s| s.strip_suffix(']'));
+        let ip = unbracketed.unwrap_or(s);
+        if let Ok(ip) = ip.parse() {
+            return Ok(Host::Ip(ip));
+        }
+        if let Some((ip, scope)) = parse_scoped(ip)? {
+            return Ok(Host::Scoped(ip, scope));
+        }
+        let valid = |label: &str| {
+            !label.is_empty()
+                && label.len() < 64
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        if s.len() > 253 || !s.trim_end_matches('.').split('.').all(valid) {
+            bail!("Invalid IP address or host name '{s}'.");
+        }
+        Ok(Host::Name(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for Host {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Ip(ip) => ip.fmt(f),
+            Host::Scoped(ip, scope) => write!(f, "{ip}%{scope}"),
+            Host::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// The local address or network interface to connect from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Bind {
+    Addr(IpAddr),
+    /// An IPv6 address with the index of its interface, see [`Host::Scoped`].
+    Scoped(Ipv6Addr, u32),
+    /// Only supported on Linux.
+    Interface(String),
+}
+
+impl FromStr for Bind {
+    type Err = anyhow::Error;
+
+    /// An IP address, or else an interface name such as `eth1`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse() {
+            Ok(Host::Ip(ip)) => return Ok(Bind::Addr(ip)),
+            Ok(Host::Scoped(ip, scope)) => return Ok(Bind::Scoped(ip, scope)),
+            _ => {}
+        }
+        if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '/') {
+            bail!("Invalid address or interface '{s}'.");
+        }
+        Ok(Bind::Interface(s.to_string()))
+    }
+}
+
+impl Display for Bind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Addr(ip) => ip.fmt(f),
+            Bind::Scoped(ip, scope) => write!(f, "{ip}%{scope}"),
+            Bind::Interface(name) => f.write_str(name),
+        }
+    }
+}
+
+/// An IPv6 address with a zone, given as an interface name or index.
+fn parse_scoped(s: &str) -> Result<Option<(Ipv6Addr, u32)>> {
+    let Some((ip, zone)) = s.split_once('%') else {
+        return Ok(None);
+    };
+    let Ok(ip) = ip.parse() else {
+        return Ok(None);
+    };
+    if let Ok(index) = zone.parse() {
+        return Ok(Some((ip, index)));
+    }
+    #[cfg(unix)]
+    if let Ok(name) = std::ffi::CString::new(zone) {
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index != 0 {
+            return Ok(Some((ip, index)));
+        }
+    }
+    bail!("Unknown network interface '{zone}' in {s}.")
+}
+
+fn is_mdns_name(name: &str) -> bool {
+    name.trim_end_matches('.')
+        .to_ascii_lowercase()
+        .ends_with(".local")
+}
+
+/// Asks for the A and AAAA records of `name` with a one-shot mDNS query, which
+/// responders answer by unicast to the port it was sent from.
+fn mdns_resolve(name: &str) -> Result<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    for qtype in [1, 28] {
+        socket.send_to(&mdns_query(name, qtype), MDNS_ADDR)?;
+    }
+    let deadline = Instant::now() + MDNS_TIMEOUT;
+    let mut buf = [0; 1500];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            bail!("No mDNS response.");
+        }
+        socket.set_read_timeout(Some(left))?;
+        let Ok(len) = socket.recv(&mut buf) else {
+            bail!("No mDNS response.");
+        };
+        if let Some(ip) = mdns_answer(&buf[..len], name) {
+            return Ok(ip);
+        }
+    }
+}
+
+fn mdns_query(name: &str, qtype: u16) -> Vec<u8> {
+    // ID, flags, one question and no records.
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    // Class IN, with the unicast response bit set.
+    packet.extend_from_slice(&0x8001u16.to_be_bytes());
+    packet
+}
+
+/// The first address record for `name` in a DNS response.
+fn mdns_answer(packet: &[u8], name: &str) -> Option<IpAddr> {
+    let u16_at = |pos: usize| {
+        Some(u16::from_be_bytes(
+            packet.get(pos..pos + 2)?.try_into().ok()?,
+        ))
+    };
+    let questions = u16_at(4)?;
+    let records = u16_at(6)? as usize + u16_at(8)? as usize + u16_at(10)? as usize;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos, &mut String::new())? + 4;
+    }
+    for _ in 0..records {
+        let mut owner = String::new();
+        pos = read_name(packet, pos, &mut owner)?;
+        let rtype = u16_at(pos)?;
+        let len = u16_at(pos + 8)? as usize;
+        let data = packet.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+        if !owner.eq_ignore_ascii_case(name.trim_end_matches('.')) {
+            continue;
+        }
+        match (rtype, data.len()) {
+            (1, 4) => return Some(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (28, 16) => return Some(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?).into()),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Appends the possibly compressed name at `pos` to `out`, and returns the position
+/// after it.
+fn read_name(packet: &[u8], mut pos: usize, out: &mut String) -> Option<usize> {
+    let mut end = None;
+    // Bounds the pointers followed, against pointer loops.
+    for _ in 0..64 {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => return Some(end.unwrap_or(pos + 1)),
+            0xc0.. => {
+                let target = u16::from_be_bytes([len as u8 & 0x3f, *packet.get(pos + 1)?]);
+                end.get_or_insert(pos + 2);
+                pos = target as usize;
+            }
+            _ => {
+                let label = packet.get(pos + 1..pos + 1 + len)?;
+                if !out.is_empty() {
+                    out.push('.');
+                }
+                out.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn test_parse_host() {
+    assert_eq!(
+        "192.168.1.10".parse::<Host>().unwrap(),
+        Host::Ip([192, 168, 1, 10].into())
+    );
+    assert_eq!(
+        "vacvision-1.local".parse::<Host>().unwrap(),
+        Host::Name("vacvision-1.local".into())
+    );
+    assert_eq!(
+        "[fe80::1]".parse::<Host>().unwrap(),
+        Host::Ip("fe80::1".parse().unwrap())
+    );
+    let scoped = "[fe80::1%lo]".parse::<Host>().unwrap();
+    let SocketAddr::V6(addr) = scoped.socket_addr(1202).unwrap() else {
+        panic!("{scoped:?} is not IPv6");
+    };
+    assert_eq!(*addr.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+    assert_ne!(addr.scope_id(), 0);
+    assert_eq!(scoped.to_string().parse::<Host>().unwrap(), scoped);
+    assert_eq!(
+        "fe80::1%3".parse::<Bind>().unwrap(),
+        Bind::Scoped("fe80::1".parse().unwrap(), 3)
+    );
+    assert!("fe80::1%no-such-interface".parse::<Host>().is_err());
+    assert!("bad name".parse::<Host>().is_err());
+    assert_eq!(
+        "::1".parse::<Bind>().unwrap(),
+        Bind::Addr("::1".parse().unwrap())
+    );
+    assert_eq!(
+        "eth1".parse::<Bind>().unwrap(),
+        Bind::Interface("eth1".into())
+    );
+    assert!("a..b".parse::<Host>().is_err());
+}
+
+#[test]
+fn test_mdns_answer() {
+    // A response echoing the question, with the answer name compressed to point at it.
+    let mut packet = mdns_query("plc.local", 1);
+    packet[2] = 0x84;
+    packet[7] = 1;
+    packet.extend_from_slice(&[0xc0, 12, 0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 10, 0, 0, 7]);
+    assert_eq!(
+        mdns_answer(&packet, "PLC.local"),
+        Some([10, 0, 0, 7].into())
+    );
+    assert_eq!(mdns_answer(&packet, "other.local"), None);
+}
@@ -1,4 +1,54 @@
+//! Reading and writing parameters of Leybold vacuum instruments over their OPC protocol.
+//!
+//! Most programs only need the [`prelude`]. The packet layer is exported as
+//! [`packets`] with the `unstable` feature only, since its types change as more of the
+//! protocol is understood.
+
+pub mod access;
+pub mod alerts;
+pub mod audit;
+#[cfg(unix)]
+pub mod broker;
+#[cfg(feature = "exporters")]
+pub mod bundle;
+pub mod capture;
+pub mod client;
+pub mod clock;
+pub mod coalesce;
+pub mod config;
+#[cfg(unix)]
+pub mod control;
+pub mod devices;
+pub mod errors;
+pub mod events;
+pub mod history;
+pub mod host;
+pub mod metadata;
+#[cfg(feature = "exporters")]
+pub mod monitoring;
 pub mod opc_values;
+#[cfg(feature = "unstable")]
 pub mod packets;
+#[cfg(not(feature = "unstable"))]
+mod packets;
 pub mod plc_connection;
+pub mod pool;
+pub mod prelude;
+pub mod pressure;
+#[cfg(feature = "config")]
+pub mod recipe;
+pub mod recording;
+pub mod schema;
 pub mod sdb;
+pub mod sdb_builder;
+pub mod sdb_store;
+pub mod session;
+pub mod sim;
+pub mod stats;
+pub mod template;
+pub mod transcript;
+pub mod tunnel;
+pub mod value_tree;
+pub mod vendor;
+
+pub use packets::{Dialect, ParamQuerySet, ParamQuerySetBuilder, ParamWrite};
@@ -1,52 +1,219 @@
 #![allow(dead_code, unused_mut)]
 
-use std::net::IpAddr;
+use std::io::Write;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
 
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
 use clap::{
-    error::ErrorKind as ClapError, Arg, ArgAction, ArgMatches, Args, Command, CommandFactory,
-    FromArgMatches, Parser, Subcommand,
+    error::ErrorKind as ClapError, Arg, ArgAction, ArgGroup, ArgMatches, Args, Command,
+    CommandFactory, FromArgMatches, Parser, Subcommand,
 };
 use rhexdump::hexdump;
 use serde::ser::*;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
-use leybold_opc_rs::opc_values::Value;
-use leybold_opc_rs::packets::{PacketCC, ParamQuerySetBuilder, ParamWrite, PayloadParamWrite};
-use leybold_opc_rs::plc_connection::{self, Connection};
-use leybold_opc_rs::sdb;
+use leybold_opc_rs::alerts::{Alerts, Notifier};
+use leybold_opc_rs::audit::{self, WriteGuard};
+#[cfg(unix)]
+use leybold_opc_rs::broker::{Broker, BrokerAccess};
+use leybold_opc_rs::bundle::Bundle;
+use leybold_opc_rs::capture;
+use leybold_opc_rs::client::{
+    AdaptiveBatching, AdaptiveInterval, Capabilities, Client, OpResult, PollSchedule, Transaction,
+    TransactionResult, WritePolicy, DEFAULT_MAX_RESPONSE_LEN,
+};
+use leybold_opc_rs::clock::{Clock, SystemClock};
+use leybold_opc_rs::config::{self, Config};
+#[cfg(unix)]
+use leybold_opc_rs::control::{ControlSocket, Controls};
+use leybold_opc_rs::devices::{DeviceConfig, DeviceEvent, DevicePoller};
+use leybold_opc_rs::errors::device::ErrorCode;
+use leybold_opc_rs::host::{Bind, Host};
+use leybold_opc_rs::metadata::MetadataOverlay;
+use leybold_opc_rs::monitoring;
+use leybold_opc_rs::opc_values::{Pretty, RawValue, StringEncoding, TimeFormat, Value};
+use leybold_opc_rs::packets::{
+    Dialect, PacketCC, ParamQuerySetBuilder, ParamWrite, PayloadParamWrite, PayloadUnknown,
+    RawReadQuery,
+};
+use leybold_opc_rs::plc_connection::{Connection, DeviceBusy, PacketObserver, RetryPolicy};
+use leybold_opc_rs::pressure::{self, PressureUnit, RateOfChange};
+use leybold_opc_rs::recipe::Recipe;
+use leybold_opc_rs::recording::{Recorder, Recording, ReplayServer};
+use leybold_opc_rs::schema;
+use leybold_opc_rs::sdb::{self, ParseMode};
+use leybold_opc_rs::sdb_store::{versioned_file_name, SdbStore, DEFAULT_SDB_FILE};
+#[cfg(unix)]
+use leybold_opc_rs::session::{SessionBusy, SessionLock};
+use leybold_opc_rs::sim::SimulatedPlc;
+use leybold_opc_rs::stats::{self, AggregateWindow, Downsampler, PollStats};
+use leybold_opc_rs::template::{Fields, Template};
+use leybold_opc_rs::transcript::Transcript;
+use leybold_opc_rs::tunnel::Via;
+use leybold_opc_rs::vendor::{ImportedList, ListFormat};
+
+mod color;
+mod exit_code;
+#[cfg(feature = "tui")]
+mod tui;
+
+use color::{ChangeTracker, ColorChoice, Palette};
+use exit_code::{ErrorFormat, InvalidValue, WritesFailed};
 
 fn hex<H: Deref<Target = [u8]>>(hex: &H) {
     println!("{}", hexdump(hex.as_ref()));
 }
 
-fn poll_pressure(conn: &mut Connection) -> Result<()> {
-    let sdb = sdb::read_sdb_file()?;
-    let mut param_set = ParamQuerySetBuilder::new(&sdb);
-    param_set.add(".Gauge[1].Parameter[1].Value")?;
+/// Prints the raw packets for `--hexdump`, while the flag is set. The control socket
+/// switches it.
+struct HexDumper(Arc<AtomicBool>);
 
-    let pkt = param_set.into_query_packet();
-    loop {
-        let pre_query_time = std::time::Instant::now();
-        let r = conn.query(&pkt)?;
-        let response = &r.payload.data;
-        let datetime = DateTime::<Utc>::from(std::time::SystemTime::now());
-        let Value::Float(pressure) = response[0] else {
+impl HexDumper {
+    fn on() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl PacketObserver for HexDumper {
+    fn on_send(&mut self, raw: &[u8], _decoded: Option<&dyn std::fmt::Debug>) {
+        if self.0.load(SeqCst) {
+            eprintln!(">>> {} bytes\n{}", raw.len(), hexdump(raw));
+        }
+    }
+
+    fn on_receive(&mut self, raw: &[u8], _decoded: Option<&dyn std::fmt::Debug>) {
+        if self.0.load(SeqCst) {
+            eprintln!("<<< {} bytes\n{}", raw.len(), hexdump(raw));
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct PressureArgs {
+    /// The gauge to read.
+    #[clap(long, default_value_t = 1)]
+    gauge: u32,
+    /// Time between readings, in seconds.
+    #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, default_value_t = 1.0)]
+    interval: f32,
+    /// Pressure unit: mbar, Pa or Torr.
+    #[clap(long, default_value = "mbar")]
+    unit: PressureUnit,
+    /// Show a log scale bar next to the reading, in text output.
+    #[clap(long)]
+    log_scale: bool,
+    #[clap(long, value_enum, default_value_t = PressureFormat::Text)]
+    format: PressureFormat,
+    /// Also show the rate of change dP/dt, over a window of this many seconds.
+    #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds)]
+    rate_window: Option<f32>,
+    /// Chamber volume in liters, to show the leak-up rate with --rate-window.
+    #[clap(long, value_name = "LITERS", requires = "rate_window")]
+    volume: Option<f64>,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug)]
+enum PressureFormat {
+    Text,
+    Csv,
+    /// One JSON object per line.
+    Json,
+}
+
+fn cmd_pressure(
+    conn: Connection,
+    store: &SdbStore,
+    opts: &PressureArgs,
+    time_format: TimeFormat,
+    palette: Palette,
+) -> Result<()> {
+    let sdb = store.load()?;
+    let param = sdb.param_by_name(&format!(".Gauge[{}].Parameter[1].Value", opts.gauge))?;
+    let mut client = Client::new(conn, &sdb)?;
+    install_ctrl_c_handler()?;
+
+    let unit = opts.unit;
+    let interval = std::time::Duration::from_secs_f32(opts.interval);
+    if let PressureFormat::Csv = opts.format {
+        println!("time,device_time,gauge,pressure,unit,rate,leak_rate");
+    }
+    let mut roc = opts
+        .rate_window
+        .map(|w| RateOfChange::new(std::time::Duration::from_secs_f32(w)));
+    let clock = client.connection().clock().clone();
+    let mut next = clock.now();
+    while !CTRL_C_PRESSED.load(SeqCst) {
+        let (values, device_time) = client.read_cached_timed(std::slice::from_ref(&param))?;
+        let Value::Float(mbar) = values[0] else {
             bail!("Pressure parameter isn't a float.")
         };
-        println!("{datetime}, {pressure:.2e} mbar");
-        std::thread::sleep(
-            std::time::Duration::from_secs(1)
-                - std::time::Instant::now().duration_since(pre_query_time),
-        );
+        let pressure = unit.from_mbar(mbar.into());
+        let time = DateTime::<Utc>::from(std::time::SystemTime::now());
+        // The rate and leak rate, converted to the output unit.
+        let (rate, leak) = match &mut roc {
+            Some(roc) => {
+                roc.push(mbar.into(), clock.now());
+                let rate = roc.rate().map(|r| unit.from_mbar(r));
+                (rate, rate.zip(opts.volume).map(|(r, v)| r * v))
+            }
+            None => (None, None),
+        };
+        match opts.format {
+            PressureFormat::Text => {
+                let dim = |unit: String| palette.dim(unit);
+                let mut line = format!("{time}, {pressure:9.2e} {}", dim(unit.to_string()));
+                if opts.log_scale {
+                    line += &format!("  |{}|", pressure::log_bar(mbar.into(), 40));
+                }
+                if let Some(rate) = rate {
+                    line += &format!("  dP/dt {rate:9.2e} {}", dim(format!("{unit}/s")));
+                }
+                if let Some(leak) = leak {
+                    line += &format!("  leak {leak:9.2e} {}", dim(format!("{unit}·l/s")));
+                }
+                println!("{line}");
+            }
+            PressureFormat::Csv => println!(
+                "{},{},{},{pressure:e},{unit},{},{}",
+                time.to_rfc3339(),
+                device_time.map(|d| time_format.text(d)).unwrap_or_default(),
+                opts.gauge,
+                rate.map(|r| format!("{r:e}")).unwrap_or_default(),
+                leak.map(|r| format!("{r:e}")).unwrap_or_default(),
+            ),
+            PressureFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "time": time.to_rfc3339(),
+                    "device_time": device_time.map(|d| time_format.json(d)),
+                    "gauge": opts.gauge,
+                    "pressure": pressure,
+                    "unit": unit.symbol(),
+                    "rate": rate,
+                    "leak_rate": leak,
+                })
+            ),
+        }
+        next += interval;
+        let now = clock.now();
+        if next < now {
+            // Fell behind, e.g. due to a slow response, don't try to catch up.
+            next = now;
+        }
+        clock.park_timeout(next - now);
     }
+    Ok(())
 }
 
-fn read_dyn_params(conn: &mut Connection) -> Result<()> {
-    let sdb = sdb::read_sdb_file()?;
+fn read_dyn_params(conn: &mut Connection, store: &SdbStore) -> Result<()> {
+    let sdb = store.load()?;
     let mut param_set = ParamQuerySetBuilder::new(&sdb);
     param_set.add(".CockpitUser")?;
     // param_set.add_param(sdb.param_by_name(".Gauge[1].Parameter[1].Value")?);
@@ -63,8 +230,8 @@ fn read_dyn_params(conn: &mut Connection) -> Result<()> {
     Ok(())
 }
 
-fn write_param(conn: &mut Connection) -> Result<()> {
-    let sdb = sdb::read_sdb_file()?;
+fn write_param(conn: &mut Connection, store: &SdbStore) -> Result<()> {
+    let sdb = store.load()?;
     let param = sdb.param_by_name(".CockpitUser")?;
 
     let packet = PacketCC::new(PayloadParamWrite::new(
@@ -78,19 +245,205 @@ fn write_param(conn: &mut Connection) -> Result<()> {
 
 #[derive(Parser, Debug)]
 #[clap(author = "Lukas Sandström", version, about)]
+#[clap(group(ArgGroup::new("target").args(["ip", "via", "simulate", "replay", "broker"]).multiple(true)))]
 struct CmdlineArgs {
-    /// The IP address of the Vacvision unit.
-    #[clap(global = true, long = "ip")]
-    ip: Option<IpAddr>,
+    /// The IP address or host name of the Vacvision unit. Names ending in .local are
+    /// looked up with mDNS when the system resolver doesn't know them.
+    #[clap(global = true, long = "ip", value_name = "HOST")]
+    ip: Option<Host>,
+    /// Run against a simulated instrument with the parameters of the SDB file, instead
+    /// of a real one. All parameters start out zero.
+    #[clap(global = true, long, conflicts_with_all = ["ip", "via", "replay", "broker"])]
+    simulate: bool,
+    /// Set the simulated parameters from this recipe file before running the command.
+    #[clap(global = true, long, value_name = "RECIPE", requires = "simulate")]
+    simulate_seed: Option<std::path::PathBuf>,
+    /// Record every request and response to this file, for answering them later with
+    /// --replay.
+    #[clap(global = true, long, value_name = "FILE")]
+    record: Option<std::path::PathBuf>,
+    /// Write every request and response, decoded and raw, with their timing to this
+    /// file, for support cases. Markdown, or JSON Lines for files ending in .json.
+    #[clap(global = true, long, value_name = "FILE")]
+    transcript: Option<std::path::PathBuf>,
+    /// Answer queries from a file written with --record instead of an instrument.
+    #[clap(global = true, long, value_name = "FILE", conflicts_with_all = ["ip", "via", "broker"])]
+    replay: Option<std::path::PathBuf>,
+    /// Connect through the broker listening on this Unix socket, see the broker command.
+    #[cfg(unix)]
+    #[clap(global = true, long, value_name = "SOCKET", conflicts_with_all = ["ip", "via"])]
+    broker: Option<std::path::PathBuf>,
+    /// Reach the instrument through a tunnel: ssh://[user@]gateway[:port] forwards the
+    /// connection through an SSH gateway, tcp://host:port connects to an existing
+    /// forward such as a local stunnel endpoint, and needs no --ip.
+    #[clap(global = true, long, value_name = "URL")]
+    via: Option<Via>,
+    /// Connect from this local address or network interface (interfaces on Linux only),
+    /// for when the route to the instrument isn't the default one.
+    #[clap(
+        global = true,
+        long,
+        value_name = "ADDR-OR-IFACE",
+        conflicts_with = "via"
+    )]
+    bind: Option<Bind>,
+    /// Protocol dialect of the controller runtime: vacvision or little-endian.
+    #[clap(global = true, long, default_value = "vacvision")]
+    dialect: Dialect,
+    /// The SDB file describing the parameters of the instrument.
+    #[clap(global = true, long, value_name = "FILE", default_value = DEFAULT_SDB_FILE)]
+    sdb: std::path::PathBuf,
+    /// Config file with parameter groups [default: leybold-opc.toml, if present]
+    #[clap(global = true, long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
     #[clap(flatten)]
     readwrite: RwCmds<String, String>,
     /// Read out the values continuously
-    #[clap(long, value_name = "SECONDS")]
+    #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds)]
     poll: Option<f32>,
+    /// Also read the parameters listed in the file, one name per line, with `#`
+    /// comments.
+    #[clap(long, value_name = "FILE", requires = "target")]
+    params_from: Option<std::path::PathBuf>,
+    /// Write values outside the min/max given for the parameter in the metadata file.
+    #[clap(long)]
+    force: bool,
+    /// Stop polling after this many polls.
+    #[clap(long, value_name = "N", requires = "poll")]
+    count: Option<u64>,
+    /// Stop polling after this long, e.g. 30s or 2h.
+    #[clap(long, value_name = "TIME", requires = "poll", value_parser = stats::parse_duration)]
+    duration: Option<std::time::Duration>,
+    /// For pointer parameters, also read the parameter pointed to.
+    #[clap(long)]
+    follow_pointers: bool,
+    /// Print every value read with this template instead, e.g. '{ts} {param}={value}'.
+    /// The placeholders are {param}, {value}, {unit} from the metadata file, and {ts}.
+    #[clap(long, value_name = "TEMPLATE")]
+    template: Option<Template>,
+    /// Print min/max/mean/stddev of the values read over a window instead of every
+    /// sample: a number of polls, or a time such as 10s or 5m.
+    #[clap(long, value_name = "WINDOW", requires = "poll")]
+    aggregate: Option<AggregateWindow>,
+    /// Tune the size of the read queries to the link while polling, instead of using a
+    /// fixed response size limit, up to the largest read the instrument accepts as
+    /// probed first.
+    #[clap(long, requires = "poll")]
+    adaptive_batching: bool,
+    /// Vary the time between polls randomly by up to this many seconds either way, so
+    /// that instances started together don't poll in step.
+    #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, requires = "poll")]
+    jitter: Option<f32>,
+    /// Check this often whether the SDB changed on the instrument while polling. A changed
+    /// SDB is downloaded, and polling goes on with the parameters still in it.
+    #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, requires = "poll")]
+    sdb_check_interval: Option<f32>,
+    /// Print clock skew and poll jitter statistics when polling ends.
+    #[clap(long, requires = "poll")]
+    stats: bool,
+    /// Write the poll statistics in Prometheus text format to this file after every poll,
+    /// for use with the node_exporter textfile collector.
+    #[clap(long, value_name = "FILE", requires = "poll")]
+    stats_file: Option<std::path::PathBuf>,
+    /// Send a keep-alive query when the connection has been idle this long, for
+    /// firmwares which drop idle connections.
+    #[clap(global = true, long, value_name = "SECONDS", value_parser = stats::parse_seconds)]
+    keep_alive: Option<f32>,
+    /// Wait at least this long between consecutive queries to the instrument, to
+    /// spread the load on a shared network.
+    #[clap(global = true, long, value_name = "SECONDS", value_parser = stats::parse_seconds)]
+    min_gap: Option<f32>,
+    /// Hex dump every packet sent and received to stderr.
+    #[clap(global = true, long)]
+    hexdump: bool,
+    /// Listen for commands on this Unix socket while running, to switch the hex dump
+    /// and the log level and to print statistics, see the control command.
+    #[cfg(unix)]
+    #[clap(global = true, long, value_name = "SOCKET")]
+    control_socket: Option<std::path::PathBuf>,
+    /// Fail on responses which deviate from the known protocol, instead of warning, and
+    /// on responses with payload left over after parsing.
+    #[clap(global = true, long)]
+    strict: bool,
+    /// Take a lock file for the instrument while connected, so that other processes
+    /// run with this flag fail instead of interleaving their queries, or connect
+    /// through the broker of this one.
+    #[clap(global = true, long)]
+    session_lock: bool,
+    /// How to write values of String parameters: strict fails on characters missing
+    /// from the CP1252 code page, lossy replaces them with '?', and hex takes the raw
+    /// bytes in hex.
+    #[clap(global = true, long, value_name = "ENCODING", default_value = "strict")]
+    string_encoding: StringEncoding,
+    /// What writes do when the instrument doesn't answer in time: fail, at-most-once
+    /// reads the values back and fails those not written, at-least-once writes those
+    /// again.
+    #[clap(global = true, long, value_name = "POLICY", default_value = "fail")]
+    write_policy: WritePolicy,
+    /// How to print TIME values and instrument timestamps: ms, seconds, or iso8601
+    /// durations such as PT1.5S.
+    #[clap(global = true, long, value_name = "FORMAT", default_value = "ms")]
+    time_format: TimeFormat,
+    /// Color the output: values changed since the previous poll are highlighted and
+    /// errors red. Auto respects NO_COLOR.
+    #[clap(global = true, long, value_enum, value_name = "WHEN", default_value_t)]
+    color: ColorChoice,
+    /// How to report errors on stderr. Either way the exit code tells the kind of
+    /// failure: 3 connection, 4 unknown parameter, 5 write rejected, 6 invalid value,
+    /// 7 some writes failed, 8 SDB missing, 1 anything else.
+    #[clap(
+        global = true,
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t
+    )]
+    error_format: ErrorFormat,
+    #[clap(flatten)]
+    retry: RetryArgs,
     #[clap(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(Args, Debug)]
+struct RetryArgs {
+    /// Number of times to re-send a query answered with a transient error code.
+    #[clap(global = true, long, default_value_t = 0)]
+    retries: u32,
+    /// Delay before re-sending a query, in milliseconds.
+    #[clap(global = true, long, value_name = "MS", default_value_t = 100)]
+    retry_delay: u64,
+    /// Device error code to treat as transient, e.g. 0x0102. Can be given several times.
+    #[clap(global = true, long, value_name = "CODE", value_parser = parse_u16)]
+    retry_on: Vec<u16>,
+}
+
+impl RetryArgs {
+    fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            delay: std::time::Duration::from_millis(self.retry_delay),
+            transient_codes: self.retry_on.clone(),
+        }
+    }
+}
+
+impl CmdlineArgs {
+    /// The instrument given with `--ip`, or else by a `tcp://` tunnel.
+    fn target_host(&self) -> Option<Host> {
+        self.ip
+            .clone()
+            .or_else(|| self.via.as_ref().and_then(Via::host))
+    }
+}
+
+fn parse_u16(s: &str) -> Result<u16, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
 #[test]
 fn verify_cli() {
     use clap::CommandFactory;
@@ -99,11 +452,218 @@ fn verify_cli() {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    PollPressure,
-    SdbDownload,
-    SdbPrint,
+    /// Show the values of parameters live, with a plot of the selected one.
+    #[cfg(feature = "tui")]
+    Watch {
+        #[clap(required = true)]
+        params: Vec<String>,
+        /// Time between reads, in seconds.
+        #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, default_value_t = 1.0)]
+        interval: f32,
+        /// Number of values kept for the plot.
+        #[clap(long, default_value_t = 600)]
+        history: usize,
+    },
+    /// Read parameters periodically and print only the ones whose value changed, to
+    /// find out which parameters react to something done on the instrument or its HMI.
+    WatchChanges {
+        /// Parameters, @groups, or name prefixes ending in `*`, e.g. `.Gauge[1].*`, which
+        /// take every scalar parameter starting with the prefix, hidden ones included.
+        #[clap(required = true)]
+        params: Vec<String>,
+        /// Time between reads, in seconds.
+        #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, default_value_t = 1.0)]
+        interval: f32,
+    },
+    /// Read parameters periodically while something is done on the instrument, then rank
+    /// them by how closely they follow a reference parameter.
+    Correlate {
+        /// The parameter the others are compared with.
+        #[clap(long, value_name = "PARAM")]
+        reference: String,
+        /// Parameters, @groups, or name prefixes ending in `*`, as for watch-changes.
+        #[clap(required = true)]
+        params: Vec<String>,
+        /// Time between reads, in seconds.
+        #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, default_value_t = 1.0)]
+        interval: f32,
+        /// How long to sample, e.g. `2m`. [default: until ctrl-c]
+        #[clap(long, value_name = "TIME", value_parser = stats::parse_duration)]
+        duration: Option<std::time::Duration>,
+        /// Number of parameters listed.
+        #[clap(long, default_value_t = 20)]
+        top: usize,
+    },
+    /// Share the connection to the instrument with other invocations, which connect
+    /// with --broker instead of --ip. Writes are checked against the [access] policy of
+    /// the config for the user running each invocation. Runs until ctrl-c.
+    #[cfg(unix)]
+    Broker {
+        /// The Unix socket to listen on.
+        #[clap(long, value_name = "PATH")]
+        socket: std::path::PathBuf,
+    },
+    /// Send a command to the --control-socket of a running invocation and print the
+    /// reply: "dump on", "dump off", "level <level>" or "stats".
+    #[cfg(unix)]
+    Control {
+        /// The Unix socket of the running invocation.
+        #[clap(long, value_name = "PATH")]
+        socket: std::path::PathBuf,
+        #[clap(required = true)]
+        command: Vec<String>,
+    },
+    /// Log the pressure of a gauge continuously.
+    #[clap(alias = "poll-pressure")]
+    Pressure(PressureArgs),
+    /// Download the SDB from the instrument.
+    SdbDownload {
+        /// Number of times to reconnect and resume an interrupted download.
+        #[clap(long, default_value_t = SDB_DOWNLOAD_ATTEMPTS)]
+        attempts: u32,
+        /// Where to store the SDB, by default the --sdb file. A directory stores it
+        /// named by its version.
+        #[clap(long, value_name = "PATH")]
+        output: Option<std::path::PathBuf>,
+    },
+    /// Print the types and parameters of the SDB.
+    SdbPrint {
+        /// Write to this file instead of stdout.
+        #[clap(long, value_name = "FILE")]
+        output: Option<std::path::PathBuf>,
+    },
     ReadAllParams,
+    /// Write a tar archive with the SDB, its types and parameters as JSON, the
+    /// instrument identification and the values of all parameters, for attaching to
+    /// service tickets.
+    ExportBundle {
+        /// The archive to write.
+        #[clap(value_name = "FILE")]
+        output: std::path::PathBuf,
+    },
+    /// Print a config group with the parameters of a list exported from the vendor's
+    /// HMI or engineering tool, mapped onto the SDB. Names matching no parameter are
+    /// listed on stderr.
+    ImportParams {
+        /// A CSV file with a Name, Variable or Symbol column, or an XML file.
+        file: std::path::PathBuf,
+        /// The name of the group.
+        #[clap(long, default_value = "imported")]
+        group: String,
+        /// csv or xml. [default: from the file extension]
+        #[clap(long)]
+        format: Option<ListFormat>,
+    },
+    /// Print the type descriptions as a Graphviz DOT graph.
+    SdbGraph,
+    /// List the parameters in the SDB.
+    List {
+        /// Only list parameters whose name starts with this.
+        prefix: Option<String>,
+        /// Include function block internals, which are hidden by default.
+        #[clap(long)]
+        hidden: bool,
+    },
+    /// Describe a type from the SDB: its layout and the parameters using it.
+    Type {
+        /// Type index, type name (e.g. DATA) or a parameter name.
+        #[clap(value_name = "INDEX-OR-NAME")]
+        ty: String,
+    },
+    /// Describe a parameter: its type and access, with the metadata from the config.
+    Info {
+        param: String,
+    },
+    /// Print a JSON Schema for the value of a parameter.
+    Schema {
+        param: String,
+    },
+    /// Work with recipe files, see the recipe module for the format.
+    #[clap(subcommand)]
+    Recipe(RecipeCmd),
+    /// Print the runtime version and the capabilities derived from it.
+    Identify {
+        /// Find the largest accepted response size by trial reads.
+        #[clap(long)]
+        probe: bool,
+    },
     Test,
+    /// Send a hand-crafted payload, then hexdump and decode the response.
+    RawQuery {
+        /// The payload in hex, without the packet header, e.g. `11` for the version query.
+        #[clap(long)]
+        hex: String,
+        /// Set the header flag which read queries have.
+        #[clap(long)]
+        poll_flag: bool,
+        /// Required, since raw payloads can write anything, past the access rules
+        /// and the audit log.
+        #[clap(long = "i-know-what-i-am-doing")]
+        confirmed: bool,
+    },
+    /// Poll the [[devices]] of the config file at the same time, each on its own
+    /// connection, so that one unit going offline doesn't stall the others.
+    PollDevices {
+        /// The parameters, or @groups, to read from every device.
+        #[clap(required = true)]
+        params: Vec<String>,
+        /// Only poll the named device. Can be given several times. [default: all]
+        #[clap(long = "device", value_name = "NAME")]
+        devices: Vec<String>,
+        /// Time between reads, in seconds.
+        #[clap(long, value_name = "SECONDS", value_parser = stats::parse_seconds, default_value_t = 1.0)]
+        interval: f32,
+    },
+    /// Print a Telegraf config which reads a parameter group with this program, using
+    /// the --ip, --sdb and --config given.
+    GenTelegraf {
+        /// The parameter group from the config, with or without the @.
+        group: String,
+        /// Time between reads, in seconds.
+        #[clap(long, value_name = "SECONDS", default_value_t = 10)]
+        interval: u32,
+    },
+    /// Print a Grafana dashboard plotting a parameter group, from the InfluxDB data
+    /// written by the gen-telegraf config.
+    GenGrafanaDashboard {
+        /// The parameter group from the config, with or without the @.
+        group: String,
+        /// The name of the InfluxDB data source in Grafana.
+        #[clap(long, value_name = "NAME", default_value = "InfluxDB")]
+        datasource: String,
+    },
+    /// Decode the traffic between an HMI and the instrument from a packet capture,
+    /// e.g. `tcpdump -i eth1 -U -w - port 1202 | leybold-opc-rs analyze -`.
+    Analyze {
+        /// A pcap file, or - to read from stdin.
+        #[clap(value_name = "PCAP")]
+        input: std::path::PathBuf,
+        /// The TCP port of the instrument.
+        #[clap(long, default_value_t = capture::PLC_PORT)]
+        port: u16,
+        /// Also show the "66 66" acknowledgements.
+        #[clap(long)]
+        acks: bool,
+    },
+    /// Write a JSON report of the requests and responses in a packet capture, with
+    /// the header fields and payload bytes which are not understood yet.
+    AnnotateCapture {
+        /// A pcap file, or - to read from stdin.
+        #[clap(value_name = "PCAP")]
+        input: std::path::PathBuf,
+        /// The TCP port of the instrument.
+        #[clap(long, default_value_t = capture::PLC_PORT)]
+        port: u16,
+        /// Where to write the report, instead of stdout.
+        #[clap(long, value_name = "FILE")]
+        output: Option<std::path::PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RecipeCmd {
+    /// Check every entry of the recipe against the SDB, without writing anything.
+    Validate { file: std::path::PathBuf },
 }
 
 #[derive(Debug)]
@@ -122,39 +682,81 @@ impl<Param, Value> Deref for RwCmds<Param, Value> {
 }
 
 impl RwCmds<String, String> {
+    /// Looks up the parameters and parses the values. A read of `-` reads the names
+    /// from stdin, and the names in `params_from` are read after the others.
     pub fn try_to_param_value<'sdb>(
         &self,
         sdb: &'sdb sdb::Sdb,
+        config: &Config,
+        encoding: StringEncoding,
+        params_from: Option<&std::path::Path>,
     ) -> Result<RwCmds<sdb::Parameter<'sdb>, Value>> {
-        let inner: Result<Vec<_>> = self
-            .0
-            .iter()
-            .map(|rw| match rw {
-                Rw::Read(param) => Ok(Rw::Read(sdb.param_by_name(param)?)),
+        let mut inner = Vec::with_capacity(self.0.len());
+        let mut push_reads = |inner: &mut Vec<_>, param: &str| -> Result<()> {
+            for name in config.expand_param(param)? {
+                inner.push(Rw::Read(config.resolve_param(sdb, name)?));
+            }
+            Ok(())
+        };
+        for rw in &self.0 {
+            match rw {
+                Rw::Read(param) if param == "-" => {
+                    for name in read_param_list(std::io::stdin().lock())? {
+                        push_reads(&mut inner, &name)?;
+                    }
+                }
+                Rw::Read(param) => push_reads(&mut inner, param)?,
                 Rw::Write(param, value) => {
-                    let param = sdb.param_by_name(param)?;
-                    let value = param.value_from_str(value).with_context(|| {
-                        format!(
-                            "Failed to parse '{}' as valid value for {}.",
-                            value,
-                            param.name()
-                        )
-                    })?;
-                    Ok(Rw::Write(param, value))
+                    let param = config.resolve_param(sdb, param)?;
+                    let value = Value::from_str_with(value, &param.type_info(), encoding)
+                        .with_context(|| InvalidValue {
+                            param: param.name().to_string(),
+                            value: value.clone(),
+                        })?;
+                    inner.push(Rw::Write(param, value));
                 }
-            })
-            .collect();
-        Ok(RwCmds(inner?))
+            }
+        }
+        if let Some(path) = params_from {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            for name in read_param_list(std::io::BufReader::new(file))? {
+                push_reads(&mut inner, &name)?;
+            }
+        }
+        Ok(RwCmds(inner))
     }
 }
 
+/// Reads one parameter name per line. Blank lines and `#` comments are skipped.
+fn read_param_list(reader: impl std::io::BufRead) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let name = line.split('#').next().unwrap_or_default().trim();
+        if !name.is_empty() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+#[test]
+fn test_read_param_list() {
+    let list = "# pressures\n.Gauge[1].Parameter[1].Value\n\n  @temps  # group\n";
+    assert_eq!(
+        read_param_list(list.as_bytes()).unwrap(),
+        [".Gauge[1].Parameter[1].Value", "@temps"]
+    );
+}
+
 impl Args for RwCmds<String, String> {
     fn augment_args(cmd: Command) -> Command {
         let read = Arg::new("read")
             .short('r')
-            .help("Read the parameter from the instrument")
+            .help("Read the parameter from the instrument, or all parameters in a '@group' from the config. - reads a list of names from stdin")
             .action(ArgAction::Append)
-            .requires("ip")
+            .requires("target")
             .display_order(10);
         let write = read
             .clone()
@@ -210,39 +812,759 @@ impl FromArgMatches for RwCmds<String, String> {
     }
 }
 
+/// How often a download is resumed by default.
+const SDB_DOWNLOAD_ATTEMPTS: u32 = 3;
+
 static CTRL_C_PRESSED: AtomicBool = AtomicBool::new(false);
 
-fn cmd_read_all(conn: &mut Connection) -> Result<()> {
-    let sdb = sdb::read_sdb_file()?;
-    let mut serializer = serde_json::Serializer::pretty(std::io::stdout());
+struct DevicePollOptions<'a> {
+    params: &'a [String],
+    devices: &'a [String],
+    interval: std::time::Duration,
+    sdb: &'a std::path::Path,
+}
+
+fn cmd_poll_devices(
+    config: &Config,
+    opts: DevicePollOptions,
+    connect: ConnectOptions,
+    palette: Palette,
+) -> Result<()> {
+    for name in opts.devices {
+        if !config.devices.iter().any(|d| &d.name == name) {
+            bail!("Device '{name}' not found in config.");
+        }
+    }
+    let devices: Vec<_> = config
+        .devices
+        .iter()
+        .filter(|d| opts.devices.is_empty() || opts.devices.contains(&d.name))
+        .cloned()
+        .collect();
+    if devices.is_empty() {
+        bail!("No [[devices]] in the config file.");
+    }
+    let mut params = Vec::new();
+    for p in opts.params {
+        params.extend(config.expand_param(p)?.into_iter().map(String::from));
+    }
+    install_ctrl_c_handler()?;
+
+    let poller = DevicePoller::start(
+        &devices,
+        opts.sdb,
+        &params,
+        &config.renames,
+        opts.interval,
+        opts.interval.max(std::time::Duration::from_secs(5)),
+        Arc::new(move |device: &DeviceConfig| connect.connect(&device.ip)),
+    );
+    let mut tracker = ChangeTracker::new(palette);
+    while !CTRL_C_PRESSED.load(SeqCst) {
+        match poller
+            .events()
+            .recv_timeout(std::time::Duration::from_millis(200))
+        {
+            Ok(DeviceEvent::Sample { device, values, .. }) => {
+                for (param, value) in values {
+                    print_value(
+                        &format!("{device} {param}"),
+                        &value,
+                        value.pretty(),
+                        &mut tracker,
+                    );
+                }
+            }
+            Ok(DeviceEvent::Failed { device, error }) => {
+                eprintln!("{}", palette.error(format_args!("{device}: {error}")));
+            }
+            Ok(DeviceEvent::Link { device, event }) => eprintln!("{device}: {event}"),
+            Err(_) => {}
+        }
+    }
+    poller.stop();
+    Ok(())
+}
+
+/// The parameters named by `params`: names, @groups, and prefixes ending in `*`, which
+/// take the scalar parameters starting with them, hidden ones included.
+fn expand_watched<'sdb>(
+    sdb: &'sdb sdb::Sdb,
+    config: &Config,
+    params: &[String],
+) -> Result<Vec<sdb::Parameter<'sdb>>> {
+    let mut watched = Vec::new();
+    for p in params {
+        if let Some(prefix) = p.strip_suffix('*') {
+            let before = watched.len();
+            watched.extend(
+                sdb.parameters()
+                    .filter(|p| p.name().starts_with(prefix) && p.value_kind().is_scalar()),
+            );
+            if watched.len() == before {
+                bail!("No scalar parameters start with '{prefix}'.");
+            }
+            continue;
+        }
+        for name in config.expand_param(p)? {
+            watched.push(config.resolve_param(sdb, name)?);
+        }
+    }
+    Ok(watched)
+}
+
+fn cmd_watch_changes(
+    conn: Connection,
+    store: &SdbStore,
+    config: &Config,
+    params: &[String],
+    interval: std::time::Duration,
+    palette: Palette,
+) -> Result<()> {
+    let sdb = store.load()?;
+    let watched = expand_watched(&sdb, config, params)?;
+    let mut client = Client::new(conn, &sdb)?;
+    install_ctrl_c_handler()?;
+    eprintln!(
+        "Watching {} parameters, press ctrl-c to stop.",
+        watched.len()
+    );
+    watch_changes(
+        &mut client,
+        &watched,
+        interval,
+        palette,
+        &mut std::io::stdout(),
+        || CTRL_C_PRESSED.load(SeqCst),
+    )
+}
+
+/// Reads `watched` every `interval` until `stop` returns true, writing the changed values.
+/// The connection is kept alive between the reads.
+fn watch_changes<'sdb>(
+    client: &mut Client<'sdb>,
+    watched: &[sdb::Parameter<'sdb>],
+    interval: std::time::Duration,
+    palette: Palette,
+    out: &mut impl Write,
+    mut stop: impl FnMut() -> bool,
+) -> Result<()> {
+    let mut previous = client.read_cached(watched)?;
+    let mut next = std::time::Instant::now() + interval;
+    while !stop() {
+        let wait = next.saturating_duration_since(std::time::Instant::now());
+        client.connection().idle(wait)?;
+        next += interval;
+        let values = client.read_cached(watched)?;
+        let time = chrono::Local::now().format("%H:%M:%S");
+        for ((param, before), after) in watched.iter().zip(&previous).zip(&values) {
+            if before != after {
+                let after = palette.changed(after.pretty().to_string());
+                writeln!(
+                    out,
+                    "{time} {}: {} -> {after}",
+                    param.name(),
+                    before.pretty()
+                )?;
+            }
+        }
+        previous = values;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_watch_changes() {
+    use std::sync::atomic::AtomicUsize;
+
+    struct CountSent(Arc<AtomicUsize>);
+    impl PacketObserver for CountSent {
+        fn on_send(&mut self, _raw: &[u8], _decoded: Option<&dyn std::fmt::Debug>) {
+            self.0.fetch_add(1, SeqCst);
+        }
+    }
+
+    let sdb =
+        sdb::Sdb::from_bytes(&leybold_opc_rs::sdb_builder::fixture(), ParseMode::Full).unwrap();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    let sent = Arc::new(AtomicUsize::new(0));
+    client.connection().set_observer(CountSent(sent.clone()));
+    client
+        .connection()
+        .set_keep_alive(Some(std::time::Duration::from_millis(20)));
+    let counter = sdb.param_by_name(".OPCCounter").unwrap();
+    let mut out = vec![];
+    let mut polls = 0;
+    let stop = || {
+        polls += 1;
+        sim.set(&counter, &Value::Int(5)).unwrap();
+        polls > 1
+    };
+    let interval = std::time::Duration::from_millis(100);
+    let palette = Palette::new(ColorChoice::Never);
+    watch_changes(
+        &mut client,
+        std::slice::from_ref(&counter),
+        interval,
+        palette,
+        &mut out,
+        stop,
+    )
+    .unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.ends_with(" .OPCCounter: 0 -> 5\n"), "{out}");
+    // Two reads, and keep-alive queries while waiting in between.
+    assert!(sent.load(SeqCst) > 2);
+}
+
+struct CorrelateOptions<'a> {
+    reference: &'a str,
+    params: &'a [String],
+    interval: std::time::Duration,
+    duration: Option<std::time::Duration>,
+    top: usize,
+}
+
+fn cmd_correlate(
+    conn: Connection,
+    store: &SdbStore,
+    config: &Config,
+    opts: CorrelateOptions,
+) -> Result<()> {
+    let sdb = store.load()?;
+    let reference = sdb.param_by_name(opts.reference)?;
+    let mut params = expand_watched(&sdb, config, opts.params)?;
+    params.retain(|p| *p != reference);
+    // The reference is read along with the others, as the first parameter.
+    params.insert(0, reference.clone());
+    let mut client = Client::new(conn, &sdb)?;
+    install_ctrl_c_handler()?;
+
+    eprintln!(
+        "Sampling {} parameters, press ctrl-c to stop.",
+        params.len() - 1
+    );
+    let mut correlations = vec![stats::Correlation::default(); params.len() - 1];
+    let clock = client.connection().clock().clone();
+    let started = clock.now();
+    let mut next = started;
+    while !CTRL_C_PRESSED.load(SeqCst) && opts.duration.is_none_or(|d| clock.now() - started < d) {
+        clock.sleep(next.saturating_duration_since(clock.now()));
+        next += opts.interval;
+        let values = client.read_cached(&params)?;
+        let Some(x) = values[0].as_f64() else {
+            bail!("The reference {} isn't a number.", reference.name());
+        };
+        for (corr, value) in correlations.iter_mut().zip(&values[1..]) {
+            if let Some(y) = value.as_f64() {
+                corr.push(x, y);
+            }
+        }
+    }
+
+    let mut ranked: Vec<_> = params[1..]
+        .iter()
+        .zip(&correlations)
+        .filter_map(|(p, c)| Some((p, c.coefficient()?)))
+        .collect();
+    ranked.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+    if ranked.is_empty() {
+        println!("No parameter changed together with {}.", reference.name());
+    }
+    for (param, r) in ranked.iter().take(opts.top) {
+        println!("{r:+.3}  {}", param.name());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn cmd_broker(
+    socket: &std::path::Path,
+    config: &Config,
+    store: &SdbStore,
+    options: ConnectOptions,
+    host: Host,
+) -> Result<()> {
+    let access = match config.access.is_open() {
+        true => None,
+        false => Some(BrokerAccess::new(config.access.clone(), &*store.load()?)),
+    };
+    let broker = Broker::start(socket, move || options.connect(&host), access)?;
+    install_ctrl_c_handler()?;
+    eprintln!(
+        "Sharing the connection on {}, press ctrl-c to stop.",
+        broker.path().display()
+    );
+    while !CTRL_C_PRESSED.load(SeqCst) {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        if let Err(e) = broker.keep_alive() {
+            tracing::warn!("{e:#}");
+        }
+    }
+    Ok(())
+}
+
+fn cmd_read_all(conn: Connection, store: &SdbStore, time_format: TimeFormat) -> Result<()> {
+    let sdb = store.load()?;
+    let mut client = Client::new(conn, &sdb)?;
+    write_all_values(&mut client, &sdb, time_format, std::io::stdout())?;
+    println!(); // newline after json output
+    Ok(())
+}
+
+fn cmd_export_bundle(
+    conn: Connection,
+    store: &SdbStore,
+    output: &std::path::Path,
+    time_format: TimeFormat,
+) -> Result<()> {
+    let sdb = store.load()?;
+    let sdb_file = std::fs::read(store.path())
+        .with_context(|| format!("Failed to read {}", store.path().display()))?;
+    let mut client = Client::new(conn, &sdb)?;
+    let caps = client.capabilities();
+    let identification = serde_json::json!({
+        "runtime": caps.runtime,
+        "sdb_version": caps.sdb_version,
+        "version_word": caps.version_word,
+        "dialect": format!("{:?}", caps.dialect),
+        "max_response_len": caps.max_response_len,
+        "features": format!("{:?}", caps.features),
+        "exported_at": Utc::now().to_rfc3339(),
+        "exported_by": concat!("leybold-opc-rs ", env!("CARGO_PKG_VERSION")),
+    });
+    let mut dump = vec![];
+    sdb::write_sdb_dump(&sdb, &mut dump)?;
+    let mut values = vec![];
+    write_all_values(&mut client, &sdb, time_format, &mut values)?;
+
+    let mut bundle = Bundle::new();
+    bundle
+        .add(
+            "identification.json",
+            serde_json::to_vec_pretty(&identification)?,
+        )?
+        .add("sdb.dat", sdb_file)?
+        .add("sdb.txt", dump)?
+        .add("sdb.json", serde_json::to_vec_pretty(&sdb::sdb_json(&sdb))?)?
+        .add("values.json", values)?;
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("Failed to create {}", output.display()))?;
+    let mut out = std::io::BufWriter::new(file);
+    bundle.write_tar(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads every parameter, and writes them as one JSON object by name. Parameters too
+/// large to read at once are left out.
+fn write_all_values(
+    client: &mut Client,
+    sdb: &sdb::Sdb,
+    time_format: TimeFormat,
+    out: impl std::io::Write,
+) -> Result<()> {
+    let max_response_len = client.max_response_len();
+    let mut serializer = serde_json::Serializer::pretty(out);
     let mut json_map = serializer.serialize_map(None)?;
 
-    let mut param_iter = sdb.parameters();
+    let endian = client.connection().dialect().endian();
+    // Their elements are parameters of their own, and are read instead.
+    let mut param_iter = sdb
+        .parameters()
+        .filter(|p| p.wire_cost().response <= max_response_len)
+        .peekable();
     loop {
-        let mut query_set = ParamQuerySetBuilder::new(&sdb);
+        let mut params = vec![];
         let mut response_len = 0;
-        while let Some(param) = param_iter.next() {
-            response_len += param.type_info().response_len();
-            query_set.add_param(param);
-            if response_len >= 0x300 {
-                break;
-            }
+        // Checked before adding a parameter, so that the response stays within the limit.
+        while let Some(param) =
+            param_iter.next_if(|p| response_len + p.wire_cost().response <= max_response_len)
+        {
+            response_len += param.wire_cost().response;
+            params.push(param);
         }
-        if query_set.is_empty() {
+        if params.is_empty() {
             break;
         }
-        let r = conn.query(&query_set.into_query_packet())?;
-
-        for (param, value) in r.payload.iter() {
-            json_map.serialize_entry(param.name(), value)?;
+        // The values are serialized from the response bytes, without parsing them first.
+        let reads: Vec<_> = params
+            .iter()
+            .map(|p| (p.id(), p.type_info().response_len() as u32))
+            .collect();
+        let r = client.connection().query(&RawReadQuery::new(sdb, &reads))?;
+        if r.payload.error_code != 0 {
+            bail!(
+                "Reading the values failed with error code {}.",
+                ErrorCode(r.payload.error_code)
+            );
+        }
+        for (param, data) in params.iter().zip(&r.payload.chunks) {
+            let ty = param.type_info();
+            let value = RawValue::new(data, &ty)
+                .with_endian(endian)
+                .with_time_format(time_format);
+            json_map.serialize_entry(param.name(), &value)?;
         }
     }
 
     SerializeMap::end(json_map)?;
-    println!(); // newline after json output
     Ok(())
 }
 
+#[test]
+fn test_write_all_values() {
+    use leybold_opc_rs::sim::SIM_MAX_RESPONSE_LEN;
+
+    let sdb =
+        sdb::Sdb::from_bytes(&leybold_opc_rs::sdb_builder::fixture(), ParseMode::Full).unwrap();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    // The simulator refuses responses beyond its limit, so no batch may exceed it.
+    client.set_max_response_len(SIM_MAX_RESPONSE_LEN);
+    let mut out = vec![];
+    write_all_values(&mut client, &sdb, TimeFormat::Millis, &mut out).unwrap();
+    let values: serde_json::Map<_, _> = serde_json::from_slice(&out).unwrap();
+    assert!(values.contains_key(".OPCCounter"));
+}
+
+fn cmd_import_params(
+    store: &SdbStore,
+    file: &std::path::Path,
+    group: &str,
+    format: Option<ListFormat>,
+) -> Result<()> {
+    let sdb = store.load()?;
+    let text = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let format = format.unwrap_or_else(|| ListFormat::from_path(file));
+    let list = ImportedList::import(&text, format, &sdb);
+    for name in &list.unmatched {
+        eprintln!("No parameter found for {name}");
+    }
+    if list.params.is_empty() {
+        bail!("No parameters of the SDB in {}.", file.display());
+    }
+    print!("{}", list.group_toml(group));
+    Ok(())
+}
+
+fn cmd_list(
+    store: &SdbStore,
+    metadata: &MetadataOverlay,
+    prefix: Option<&str>,
+    hidden: bool,
+) -> Result<()> {
+    let sdb = store.load()?;
+    let params = sdb
+        .parameters()
+        .filter(|p| hidden || !p.is_hidden())
+        .filter(|p| prefix.is_none_or(|prefix| p.name().starts_with(prefix)));
+    for p in params {
+        let mut line = format!("{:60} {:?}", p.to_string(), p.flags());
+        if let Some(meta) = metadata.get(p.name()) {
+            if let Some(name) = &meta.display_name {
+                line += &format!("  \"{name}\"");
+            }
+            if let Some(unit) = &meta.unit {
+                line += &format!(" [{unit}]");
+            }
+        }
+        println!("{line}");
+    }
+    Ok(())
+}
+
+fn cmd_info(store: &SdbStore, metadata: &MetadataOverlay, name: &str) -> Result<()> {
+    let sdb = store.load()?;
+    let param = sdb.param_by_name(name)?;
+    let ty = param.type_info();
+    println!("{}", param.name());
+    println!("  type:         {ty} ({} bytes)", ty.response_len());
+    println!("  access:       {}", param.access());
+    println!("  flags:        {:?}", param.flags());
+    let cost = param.wire_cost();
+    println!(
+        "  read cost:    {} bytes query, {} bytes response",
+        cost.request, cost.response
+    );
+    let Some(meta) = metadata.get(param.name()) else {
+        return Ok(());
+    };
+    let fields = [
+        ("display name", meta.display_name.clone()),
+        ("description", meta.description.clone()),
+        ("unit", meta.unit.clone()),
+        ("decimals", meta.decimals.map(|d| d.to_string())),
+        ("min", meta.min.map(|v| v.to_string())),
+        ("max", meta.max.map(|v| v.to_string())),
+    ];
+    for (label, value) in fields {
+        if let Some(value) = value {
+            println!("  {:13} {value}", format!("{label}:"));
+        }
+    }
+    Ok(())
+}
+
+/// Packets can still be decoded without an SDB, with the parameters shown by id.
+fn optional_sdb(store: &SdbStore) -> Option<std::rc::Rc<sdb::Sdb>> {
+    store
+        .load()
+        .inspect_err(|e| eprintln!("Parameter names not available: {e:#}"))
+        .ok()
+}
+
+/// Hexdumps the exchange, and keeps the last response for decoding.
+struct RawExchange(Arc<Mutex<Vec<u8>>>);
+
+impl PacketObserver for RawExchange {
+    fn on_send(&mut self, raw: &[u8], decoded: Option<&dyn std::fmt::Debug>) {
+        HexDumper::on().on_send(raw, decoded);
+    }
+
+    fn on_receive(&mut self, raw: &[u8], decoded: Option<&dyn std::fmt::Debug>) {
+        HexDumper::on().on_receive(raw, decoded);
+        if !raw.starts_with(&[0x66, 0x66]) {
+            *self.0.lock().unwrap() = raw.to_vec();
+        }
+    }
+}
+
+fn cmd_raw_query(mut conn: Connection, store: &SdbStore, hex: &str, poll_flag: bool) -> Result<()> {
+    let mut pkt = PacketCC::new(PayloadUnknown::from_hex(hex)?);
+    pkt.hdr.one_if_data_poll_maybe = poll_flag.into();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    conn.set_observer(RawExchange(received.clone()));
+    let query = conn.encode(pkt)?;
+    let response = conn.query_encoded(&query);
+
+    let sdb = optional_sdb(store);
+    let mut analyzer = capture::Analyzer::new(sdb.as_deref());
+    let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let frame = |direction, bytes: Vec<u8>| capture::Frame {
+        time,
+        client: conn
+            .local_addr()
+            .unwrap_or_else(|_| ([0, 0, 0, 0], 0).into()),
+        direction,
+        bytes,
+    };
+    println!(
+        "{}",
+        analyzer.decode(&frame(capture::Direction::ToPlc, query.bytes().to_vec()))
+    );
+    let received = std::mem::take(&mut *received.lock().unwrap());
+    if !received.is_empty() {
+        println!(
+            "{}",
+            analyzer.decode(&frame(capture::Direction::FromPlc, received))
+        );
+    }
+    response?;
+    Ok(())
+}
+
+fn open_capture(input: &std::path::Path) -> Result<capture::PcapReader<Box<dyn std::io::Read>>> {
+    let reader: Box<dyn std::io::Read> = if input.as_os_str() == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        let file = std::fs::File::open(input)
+            .with_context(|| format!("Failed to open {}", input.display()))?;
+        Box::new(std::io::BufReader::new(file))
+    };
+    capture::PcapReader::new(reader)
+}
+
+fn cmd_analyze(store: &SdbStore, input: &std::path::Path, port: u16, acks: bool) -> Result<()> {
+    let sdb = optional_sdb(store);
+    let mut reader = open_capture(input)?;
+    let mut reassembler = capture::Reassembler::new(reader.link_type(), port);
+    let mut analyzer = capture::Analyzer::new(sdb.as_deref());
+    let mut out = std::io::stdout().lock();
+    while let Some(record) = reader.next_record()? {
+        for frame in reassembler.push(&record) {
+            if frame.is_ack() && !acks {
+                continue;
+            }
+            writeln!(out, "{}", analyzer.decode(&frame))?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn cmd_annotate_capture(
+    store: &SdbStore,
+    input: &std::path::Path,
+    port: u16,
+    output: Option<&std::path::Path>,
+) -> Result<()> {
+    let sdb = optional_sdb(store);
+    let mut reader = open_capture(input)?;
+    let mut reassembler = capture::Reassembler::new(reader.link_type(), port);
+    let mut annotator = capture::Annotator::new(sdb.as_deref());
+    while let Some(record) = reader.next_record()? {
+        for frame in reassembler.push(&record) {
+            annotator.push(&frame)?;
+        }
+    }
+    let report = annotator.finish();
+    match output {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            serde_json::to_writer_pretty(std::io::BufWriter::new(file), &report)?;
+        }
+        None => serde_json::to_writer_pretty(std::io::stdout().lock(), &report)?,
+    }
+    Ok(())
+}
+
+fn cmd_type(store: &SdbStore, name: &str) -> Result<()> {
+    let sdb = store.load()?;
+    let types: Vec<_> = if let Ok(idx) = name.parse() {
+        sdb.type_by_index(idx).into_iter().collect()
+    } else {
+        let by_name: Vec<_> = sdb
+            .types()
+            .filter(|t| t.name().trim_end_matches('\0').eq_ignore_ascii_case(name))
+            .collect();
+        if by_name.is_empty() {
+            vec![sdb.param_by_name(name)?.type_info()]
+        } else {
+            by_name
+        }
+    };
+    if types.is_empty() {
+        bail!("No type with index {name}.");
+    }
+    for ty in types {
+        println!(
+            "Type #{} {}: {:?}, {} bytes, alignment {}",
+            ty.index(),
+            ty.name(),
+            ty.kind(),
+            ty.response_len(),
+            ty.alignment()
+        );
+        print_type_layout(&ty, 1);
+        let params: Vec<_> = ty.parameters().collect();
+        println!("Used by {} parameters", params.len());
+        for p in params.iter().take(10) {
+            println!("  {}", p.name());
+        }
+        if params.len() > 10 {
+            println!("  ...");
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn print_type_layout(ty: &sdb::TypeInfo, depth: usize) {
+    let indent = "  ".repeat(depth);
+    if let Some(members) = ty.struct_info() {
+        for m in members {
+            let t = &m.type_info;
+            println!(
+                "{indent}{:>5} {:24} #{} {} ({:?}, {} bytes)",
+                m.offset,
+                m.name,
+                t.index(),
+                t.name(),
+                t.kind(),
+                m.size
+            );
+            print_type_layout(t, depth + 1);
+        }
+    } else if let (Some((elem, dims)), Some(stride)) = (ty.array_info(), ty.array_stride()) {
+        println!(
+            "{indent}{dims:?} x #{} {} ({:?}), stride {stride}",
+            elem.index(),
+            elem.name(),
+            elem.kind()
+        );
+        print_type_layout(&elem, depth + 1);
+    } else if let Some(target) = ty.pointer_target() {
+        println!(
+            "{indent}-> #{} {} ({:?})",
+            target.index(),
+            target.name(),
+            target.kind()
+        );
+    }
+}
+
+fn cmd_identify(conn: Connection, store: &SdbStore, probe: bool) -> Result<()> {
+    let sdb = store.load()?;
+    let mut client = Client::new(conn, &sdb)?;
+    if probe {
+        client.probe_max_response_len()?;
+    }
+    let caps = client.capabilities();
+    println!("Runtime:           {}", caps.runtime);
+    println!("SDB version:       {:#010x}", caps.sdb_version);
+    println!("Version word:      {:#010x}", caps.version_word);
+    println!("Dialect:           {:?}", caps.dialect);
+    println!("Max response len:  {:#x}", caps.max_response_len);
+    println!("Features:          {:?}", caps.features);
+    Ok(())
+}
+
+/// Downloads the SDB to `target`, or into the directory `target` named by its version.
+fn cmd_sdb_download(
+    mut connect: impl FnMut() -> Result<Connection>,
+    target: &std::path::Path,
+    attempts: u32,
+) -> Result<()> {
+    // The first connection asks for the version, and is then used for the download.
+    let mut first = None;
+    let path = match target.is_dir() {
+        true => {
+            let mut conn = connect()?;
+            let sdb_id = Capabilities::negotiate(&mut conn)?.sdb_version;
+            first = Some(conn);
+            target.join(versioned_file_name(sdb_id))
+        }
+        false => target.to_path_buf(),
+    };
+    let store = SdbStore::new(&path).with_parse_mode(ParseMode::Lazy);
+    let sdb = store.download(|| first.take().map_or_else(&mut connect, Ok), attempts)?;
+    println!(
+        "Downloaded {} bytes to {}.",
+        sdb.total_size(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Makes the store download a missing SDB file from the instrument given with `--ip`
+/// or `--broker`.
+fn with_auto_download(store: SdbStore, args: &CmdlineArgs, options: &ConnectOptions) -> SdbStore {
+    let (host, broker) = (args.target_host(), args.broker.clone());
+    if host.is_none() && broker.is_none() {
+        return store;
+    }
+    let options = options.clone();
+    store.with_auto_download(move |path| {
+        tracing::info!("{} not found, downloading the SDB.", path.display());
+        let connect = || {
+            #[cfg(unix)]
+            if let Some(socket) = &broker {
+                return options.configure(Connection::connect_unix(socket)?);
+            }
+            match &host {
+                Some(host) => options.connect(host),
+                None => bail!("Brokers are only supported on Unix."),
+            }
+        };
+        SdbStore::new(path).download(connect, SDB_DOWNLOAD_ATTEMPTS)?;
+        Ok(())
+    })
+}
+
 fn test_cmd(connect: impl FnOnce() -> Result<Connection>) -> Result<()> {
     let _conn = &mut connect()?;
 
@@ -256,108 +1578,743 @@ fn test_cmd(connect: impl FnOnce() -> Result<Connection>) -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::TRACE)
-        .with_target(false)
-        .init();
+/// How the values read are printed.
+struct ReadOutput {
+    time_format: TimeFormat,
+    /// Replaces the labelled output, with the units of the metadata.
+    template: Option<(Template, MetadataOverlay)>,
+}
 
-    let args: CmdlineArgs = Parser::parse();
+/// Like [`print_value`], with TIME values in the given format, or with the template.
+fn print_read(
+    param: &sdb::Parameter,
+    label: &str,
+    value: &Value,
+    output: &ReadOutput,
+    tracker: &mut ChangeTracker,
+) {
+    let ty = param.type_info();
+    let pretty = value.pretty_times(&ty, output.time_format);
+    if let Some((template, metadata)) = &output.template {
+        let fields = Fields {
+            param: param.name(),
+            value: &pretty.to_string(),
+            unit: metadata.get(param.name()).and_then(|m| m.unit.as_deref()),
+            ts: Utc::now(),
+        };
+        println!("{}", template.render(&fields));
+        return;
+    }
+    print_value(label, value, pretty, tracker);
+}
 
-    let connect = || {
-        let ip = args.ip.unwrap_or_else(|| {
-            CmdlineArgs::command()
-                .error(ClapError::MissingRequiredArgument, "Missing IP address.")
-                .exit()
-        });
-        Connection::connect(ip)
+/// Prints scalars after the label, and tables of compound values indented below it,
+/// as formatted by `pretty`. Values changed since they were last printed are
+/// highlighted.
+fn print_value(label: &str, value: &Value, pretty: Pretty, tracker: &mut ChangeTracker) {
+    let changed = tracker.changed(label, value);
+    let paint = |text: String| match changed {
+        true => tracker.palette.changed(text),
+        false => text,
     };
+    if value.is_scalar() {
+        println!("{label}: {}", paint(pretty.to_string()));
+        return;
+    }
+    println!("{}:", paint(label.to_string()));
+    let indent = label.len() - label.trim_start().len() + 2;
+    for line in pretty.to_string().lines() {
+        println!("{:indent$}{line}", "");
+    }
+}
 
-    if let Some(command) = &args.command {
-        return match command {
-            Commands::PollPressure => poll_pressure(&mut connect()?),
-            Commands::SdbDownload => plc_connection::download_sbd(&mut connect()?),
-            Commands::SdbPrint => sdb::print_sdb_file(),
-            Commands::ReadAllParams => cmd_read_all(&mut connect()?),
-            Commands::Test => test_cmd(connect),
+/// The connection settings of the global flags, owned so that connections can be
+/// made on other threads.
+#[derive(Clone, Debug)]
+struct ConnectOptions {
+    via: Option<Via>,
+    bind: Option<Bind>,
+    dialect: Dialect,
+    retry: RetryPolicy,
+    strict: bool,
+    session_lock: bool,
+    /// Whether to hex dump the packets, when set.
+    hexdump: Option<Arc<AtomicBool>>,
+    keep_alive: Option<std::time::Duration>,
+    min_gap: Option<std::time::Duration>,
+    /// Connect to this simulated or replaying instrument instead of the given address.
+    local: Option<SocketAddr>,
+    recorder: Option<Recorder>,
+    transcript: Option<Transcript>,
+}
+
+impl ConnectOptions {
+    fn new(args: &CmdlineArgs) -> Self {
+        Self {
+            via: args.via.clone(),
+            bind: args.bind.clone(),
+            dialect: args.dialect,
+            retry: args.retry.policy(),
+            strict: args.strict,
+            session_lock: args.session_lock,
+            hexdump: args.hexdump.then(|| Arc::new(AtomicBool::new(true))),
+            keep_alive: args.keep_alive.map(std::time::Duration::from_secs_f32),
+            min_gap: args.min_gap.map(std::time::Duration::from_secs_f32),
+            local: None,
+            recorder: None,
+            transcript: None,
+        }
+    }
+
+    fn connect(&self, host: &Host) -> Result<Connection> {
+        let conn = self.open(host);
+        // Hand off to the broker of the process already connected, if it runs one.
+        #[cfg(unix)]
+        let conn = match conn
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<SessionBusy>())
+        {
+            Some(SessionBusy {
+                broker: Some(socket),
+                ..
+            }) => {
+                tracing::info!(
+                    "{host} is in use, connecting through the broker at {}",
+                    socket.display()
+                );
+                Connection::connect_unix(socket)
+            }
+            _ => conn,
         };
+        self.configure(conn?)
     }
-    if args.readwrite.is_empty() {
-        return Ok(());
+
+    fn open(&self, host: &Host) -> Result<Connection> {
+        let session = match self.session_lock && self.local.is_none() {
+            true => Some(SessionLock::acquire(&host.to_string())?),
+            false => None,
+        };
+        let mut conn = match (self.local, &self.via) {
+            (Some(addr), _) => Connection::connect_addr(addr)?,
+            (None, Some(via)) => Connection::connect_via(host, via)?,
+            (None, None) => match &self.bind {
+                Some(bind) => Connection::connect_bound(host, bind)?,
+                None => Connection::connect(host)?,
+            },
+        };
+        if let Some(session) = session {
+            conn.set_session_lock(session);
+        }
+        Ok(conn)
     }
-    let sdb = sdb::read_sdb_file()?;
-    let readwrite = args.readwrite.try_to_param_value(&sdb)?;
 
-    // install signal handler for ctrl-c
+    /// Applies the settings which don't concern reaching the instrument.
+    fn configure(&self, mut conn: Connection) -> Result<Connection> {
+        conn.set_retry_policy(self.retry.clone());
+        conn.set_dialect(self.dialect);
+        conn.set_strict(self.strict);
+        let dump = self.hexdump.clone().map(HexDumper);
+        match (dump, self.recorder.clone(), self.transcript.clone()) {
+            (None, None, None) => {}
+            (dump, recorder, transcript) => conn.set_observer((dump, (recorder, transcript))),
+        }
+        conn.set_keep_alive(self.keep_alive);
+        conn.set_min_gap(self.min_gap);
+        Ok(conn)
+    }
+}
+
+/// Sets [`CTRL_C_PRESSED`] on the first ctrl-c, and exits on the second.
+fn install_ctrl_c_handler() -> Result<()> {
     ctrlc::set_handler(|| {
         let again = CTRL_C_PRESSED.fetch_or(true, SeqCst);
         if again {
             std::process::exit(1);
         }
     })
-    .context("Failed to set signal handler.")?;
+    .context("Failed to set signal handler.")
+}
 
-    let mut conn = connect()?;
+/// Switches the log level, for the control socket.
+type LogLevel = tracing_subscriber::reload::Handle<LevelFilter, tracing_subscriber::Registry>;
 
-    loop {
-        // Poll loop
-        execute_queries(&sdb, &readwrite, &mut conn)?;
+fn main() -> std::process::ExitCode {
+    let (level, log_level) = tracing_subscriber::reload::Layer::new(LevelFilter::TRACE);
+    tracing_subscriber::registry()
+        .with(level)
+        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .init();
 
-        if CTRL_C_PRESSED.load(SeqCst) {
-            break;
-        }
+    let args: CmdlineArgs = Parser::parse();
+    match run(&args, log_level) {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => exit_code::report(&e, args.error_format),
+    }
+}
 
-        if let Some(delay) = args.poll {
-            let d = std::time::Duration::from_secs_f32(delay);
-            std::thread::park_timeout(d);
-        } else {
-            break;
+fn cmd_gen_telegraf(args: &CmdlineArgs, group: &str, interval: u32) -> Result<()> {
+    let group = group.trim_start_matches('@');
+    let Some(ip) = &args.ip else {
+        bail!("gen-telegraf needs the --ip of the instrument to read.");
+    };
+    // Checked now, rather than by Telegraf on every read.
+    Config::load(args.config.as_deref())?.group(group)?;
+    let absolute = |path: &std::path::Path| {
+        std::fs::canonicalize(path).with_context(|| format!("Failed to find {}", path.display()))
+    };
+    let exe = std::env::current_exe()?;
+    let mut command = vec![exe.display().to_string(), "--ip".into(), ip.to_string()];
+    command.extend(["--sdb".into(), absolute(&args.sdb)?.display().to_string()]);
+    let config = args.config.clone().or_else(|| {
+        let default = std::path::PathBuf::from(config::DEFAULT_CONFIG_FILE);
+        default.exists().then_some(default)
+    });
+    if let Some(config) = config {
+        command.extend(["--config".into(), absolute(&config)?.display().to_string()]);
+    }
+    command.extend(["--color", "never", "-r"].map(String::from));
+    command.push(format!("@{group}"));
+    let input = monitoring::TelegrafInput {
+        command,
+        interval_secs: interval,
+    };
+    print!("{}", monitoring::telegraf_config(group, &input));
+    Ok(())
+}
+
+/// Starts a simulated instrument for `--simulate`, seeded from `--simulate-seed`.
+fn start_simulator(args: &CmdlineArgs, store: &SdbStore) -> Result<SimulatedPlc> {
+    if args.dialect != Dialect::Vacvision {
+        bail!("The simulated instrument only speaks the vacvision dialect.");
+    }
+    let sdb = store.load()?;
+    let file = std::fs::read(store.path())
+        .with_context(|| format!("Failed to read {}", store.path().display()))?;
+    let sim = SimulatedPlc::start(&sdb, file)?;
+    if let Some(seed) = &args.simulate_seed {
+        for w in Recipe::from_file(seed)?.values(&sdb)? {
+            sim.set(&w.param, &w.value)?;
         }
     }
+    Ok(sim)
+}
+
+/// Starts the `--control-socket`, which takes over switching the hex dump.
+#[cfg(unix)]
+fn start_control_socket(
+    socket: &std::path::Path,
+    options: &mut ConnectOptions,
+    log_level: LogLevel,
+) -> Result<(ControlSocket, Arc<Controls>)> {
+    let controls = Controls::new(options.hexdump.is_some()).with_level_switch(move |level| {
+        log_level
+            .reload(level)
+            .context("Failed to change the log level")
+    });
+    let controls = Arc::new(controls);
+    options.hexdump = Some(controls.packet_dump());
+    Ok((ControlSocket::start(socket, controls.clone())?, controls))
+}
+
+#[cfg(unix)]
+fn cmd_control(socket: &std::path::Path, command: &[String]) -> Result<()> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    writeln!(stream, "{}", command.join(" "))?;
+    let mut reply = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut reply)?;
+    match reply.strip_prefix("Error: ") {
+        Some(e) => bail!("{}", e.trim_end()),
+        None => print!("{reply}"),
+    }
     Ok(())
 }
 
-fn execute_queries(
-    sdb: &sdb::Sdb,
-    readwrite: &RwCmds<sdb::Parameter, Value>,
-    conn: &mut Connection,
-) -> Result<()> {
-    let mut parm_iter = readwrite.iter();
-    let mut query_builder = ParamQuerySetBuilder::new(sdb);
-    loop {
-        if CTRL_C_PRESSED.load(SeqCst) {
-            break;
+fn run(args: &CmdlineArgs, log_level: LogLevel) -> Result<()> {
+    let palette = Palette::new(args.color);
+    // Most invocations only touch a few parameters, so only parse the types on demand.
+    let store = SdbStore::new(&args.sdb).with_parse_mode(ParseMode::Lazy);
+
+    let mut connect_options = ConnectOptions::new(args);
+    // Kept until the command is done, the socket is removed when dropped.
+    #[cfg(unix)]
+    let (_control_socket, controls) = match &args.control_socket {
+        Some(socket) => {
+            let (socket, controls) = start_control_socket(socket, &mut connect_options, log_level)?;
+            (Some(socket), Some(controls))
         }
-        let param = parm_iter.next();
-        // build read set
-        if let Some(Rw::Read(param)) = param {
-            query_builder.add_param(param.clone());
-            continue; // Batch consecutive parameter reads into one read request.
+        None => (None, None),
+    };
+    #[cfg(not(unix))]
+    let _ = log_level;
+    // Kept until the command is done, the simulator serves its connections.
+    let simulator = match args.simulate {
+        true => Some(start_simulator(args, &store)?),
+        false => None,
+    };
+    let replay = match &args.replay {
+        Some(file) => Some(ReplayServer::start(Recording::from_file(file)?)?),
+        None => None,
+    };
+    connect_options.local = simulator
+        .as_ref()
+        .map(SimulatedPlc::addr)
+        .or(replay.as_ref().map(ReplayServer::addr));
+    if let Some(file) = &args.record {
+        connect_options.recorder = Some(Recorder::create(file)?);
+    }
+    if let Some(file) = &args.transcript {
+        let command: Vec<_> = std::env::args().collect();
+        connect_options.transcript = Some(Transcript::create(file, &command.join(" "))?);
+    }
+    let store = with_auto_download(store, args, &connect_options);
+    let local = connect_options.local.map(|a| Host::from(a.ip()));
+    let host = || {
+        args.target_host().or(local.clone()).unwrap_or_else(|| {
+            CmdlineArgs::command()
+                .error(ClapError::MissingRequiredArgument, "Missing IP address.")
+                .exit()
+        })
+    };
+    let connect = || {
+        #[cfg(unix)]
+        if let Some(socket) = &args.broker {
+            return connect_options.configure(Connection::connect_unix(socket)?);
         }
-        // perform read query
-        if !query_builder.is_empty() {
-            let packet = query_builder.into_query_packet();
-            let r = conn.query(&packet)?;
-            for (param, value) in r.payload.iter() {
-                println!("{}: {value:?}", param.name());
+        connect_options.connect(&host())
+    };
+
+    if let Some(command) = &args.command {
+        return match command {
+            Commands::Pressure(opts) => {
+                cmd_pressure(connect()?, &store, opts, args.time_format, palette)
+            }
+            #[cfg(unix)]
+            Commands::Broker { socket } => {
+                let config = Config::load(args.config.as_deref())?;
+                cmd_broker(socket, &config, &store, connect_options.clone(), host())
+            }
+            #[cfg(unix)]
+            Commands::Control { socket, command } => cmd_control(socket, command),
+            #[cfg(feature = "tui")]
+            Commands::Watch {
+                params,
+                interval,
+                history,
+            } => {
+                let sdb = store.load()?;
+                let config = Config::load(args.config.as_deref())?;
+                let mut watched = Vec::new();
+                for p in params {
+                    for name in config.expand_param(p)? {
+                        watched.push(config.resolve_param(&sdb, name)?);
+                    }
+                }
+                let mut client = Client::new(connect()?, &sdb)?;
+                let interval = std::time::Duration::from_secs_f32(*interval);
+                tui::watch(&mut client, watched, interval, *history)
+            }
+            Commands::WatchChanges { params, interval } => {
+                let config = Config::load(args.config.as_deref())?;
+                let interval = std::time::Duration::from_secs_f32(*interval);
+                cmd_watch_changes(connect()?, &store, &config, params, interval, palette)
+            }
+            Commands::Correlate {
+                reference,
+                params,
+                interval,
+                duration,
+                top,
+            } => {
+                let config = Config::load(args.config.as_deref())?;
+                let opts = CorrelateOptions {
+                    reference,
+                    params,
+                    interval: std::time::Duration::from_secs_f32(*interval),
+                    duration: *duration,
+                    top: *top,
+                };
+                cmd_correlate(connect()?, &store, &config, opts)
+            }
+            Commands::SdbDownload { attempts, output } => {
+                cmd_sdb_download(connect, output.as_ref().unwrap_or(&args.sdb), *attempts)
+            }
+            Commands::SdbPrint { output } => {
+                let sdb = store.load()?;
+                match output {
+                    Some(path) => {
+                        let file = std::fs::File::create(path)
+                            .with_context(|| format!("Failed to create {}", path.display()))?;
+                        let mut out = std::io::BufWriter::new(file);
+                        sdb::write_sdb_dump(&sdb, &mut out)?;
+                        out.flush()?;
+                    }
+                    None => sdb::write_sdb_dump(&sdb, &mut std::io::stdout().lock())?,
+                }
+                Ok(())
+            }
+            Commands::SdbGraph => {
+                sdb::write_type_graph(&*store.load()?, &mut std::io::stdout().lock())?;
+                Ok(())
+            }
+            Commands::ReadAllParams => cmd_read_all(connect()?, &store, args.time_format),
+            Commands::ImportParams {
+                file,
+                group,
+                format,
+            } => cmd_import_params(&store, file, group, *format),
+            Commands::ExportBundle { output } => {
+                cmd_export_bundle(connect()?, &store, output, args.time_format)
+            }
+            Commands::Type { ty } => cmd_type(&store, ty),
+            Commands::Schema { param } => {
+                let sdb = store.load()?;
+                let param = sdb.param_by_name(param)?;
+                let mut schema = schema::param_schema(&param);
+                let metadata = Config::load(args.config.as_deref())?.metadata()?;
+                if let Some(meta) = metadata.get(param.name()) {
+                    schema::apply_metadata(&mut schema, meta);
+                }
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+                Ok(())
+            }
+            Commands::Recipe(RecipeCmd::Validate { file }) => {
+                let sdb = store.load()?;
+                let writes = Recipe::from_file(file)?.validate(&sdb)?;
+                let metadata = Config::load(args.config.as_deref())?.metadata()?;
+                for w in &writes {
+                    if let Some(meta) = metadata.get(w.param.name()) {
+                        meta.check_range(w.param.name(), &w.value)?;
+                    }
+                    println!("{}: {:?}", w.param.name(), w.value);
+                }
+                println!("{} entries OK.", writes.len());
+                Ok(())
+            }
+            Commands::Identify { probe } => cmd_identify(connect()?, &store, *probe),
+            Commands::List { prefix, hidden } => {
+                let metadata = Config::load(args.config.as_deref())?.metadata()?;
+                cmd_list(&store, &metadata, prefix.as_deref(), *hidden)
+            }
+            Commands::Info { param } => {
+                let metadata = Config::load(args.config.as_deref())?.metadata()?;
+                cmd_info(&store, &metadata, param)
+            }
+            Commands::Test => test_cmd(connect),
+            Commands::RawQuery {
+                hex,
+                poll_flag,
+                confirmed,
+            } => {
+                if !confirmed {
+                    bail!(
+                        "raw-query sends the payload unchecked, which can change the \
+                         instrument configuration. Pass --i-know-what-i-am-doing to send it."
+                    );
+                }
+                cmd_raw_query(connect()?, &store, hex, *poll_flag)
+            }
+            Commands::PollDevices {
+                params,
+                devices,
+                interval,
+            } => {
+                let config = Config::load(args.config.as_deref())?;
+                let options = DevicePollOptions {
+                    params,
+                    devices,
+                    interval: std::time::Duration::from_secs_f32(*interval),
+                    sdb: &args.sdb,
+                };
+                cmd_poll_devices(&config, options, connect_options.clone(), palette)
+            }
+            Commands::GenTelegraf { group, interval } => cmd_gen_telegraf(args, group, *interval),
+            Commands::GenGrafanaDashboard { group, datasource } => {
+                let config = Config::load(args.config.as_deref())?;
+                let group = group.trim_start_matches('@');
+                let params: Vec<&str> = config
+                    .group(group)?
+                    .params
+                    .iter()
+                    .map(|p| p.as_str())
+                    .collect();
+                let dashboard =
+                    monitoring::grafana_dashboard(group, &params, &config.metadata()?, datasource);
+                println!("{}", serde_json::to_string_pretty(&dashboard)?);
+                Ok(())
+            }
+            Commands::Analyze { input, port, acks } => cmd_analyze(&store, input, *port, *acks),
+            Commands::AnnotateCapture {
+                input,
+                port,
+                output,
+            } => cmd_annotate_capture(&store, input, *port, output.as_deref()),
+        };
+    }
+    if args.readwrite.is_empty() && args.params_from.is_none() {
+        return Ok(());
+    }
+    let config = Config::load(args.config.as_deref())?;
+    let sdb = store.load()?;
+    let readwrite = args.readwrite.try_to_param_value(
+        &sdb,
+        &config,
+        args.string_encoding,
+        args.params_from.as_deref(),
+    )?;
+    let mut writes = WriteGuard::new(&config, audit::current_user())?.with_limits(!args.force);
+    // Checked before connecting too, for the hint.
+    for rw in readwrite.iter() {
+        if let Rw::Write(param, value) = rw {
+            if let Err(e) = writes.check_limits(param.name(), value) {
+                eprintln!("Pass --force to write values outside the limits.");
+                return Err(e.into());
             }
-            query_builder = ParamQuerySetBuilder::new(sdb);
         }
+    }
 
-        if CTRL_C_PRESSED.load(SeqCst) {
-            break;
+    // By name, for finding the parameters again when the SDB changes.
+    let mut readwrite: Vec<Rw<String, Value>> = readwrite
+        .iter()
+        .map(|rw| match rw {
+            Rw::Read(param) => Rw::Read(param.name().to_string()),
+            Rw::Write(param, value) => Rw::Write(param.name().to_string(), value.clone()),
+        })
+        .collect();
+
+    install_ctrl_c_handler()?;
+
+    let mut stats = PollStats::new();
+    let mut downsampler = args.aggregate.map(Downsampler::new);
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let mut alerts =
+        Alerts::new(config.alert_rules(&sdb), &config.notify).with_clock(clock.clone());
+    alerts.check_params(
+        &sdb,
+        readwrite.iter().filter_map(|rw| match rw {
+            Rw::Read(param) => Some(param.as_str()),
+            Rw::Write(..) => None,
+        }),
+    )?;
+    let notifier = Notifier::start(config.notify.clone());
+    let mut tracker = ChangeTracker::new(palette);
+    let output = ReadOutput {
+        time_format: args.time_format,
+        template: match &args.template {
+            Some(template) => Some((template.clone(), config.metadata()?)),
+            None => None,
+        },
+    };
+    // Polling slows down while the device reports being busy.
+    let interval = args.poll.map(|p| {
+        let base = std::time::Duration::from_secs_f32(p);
+        AdaptiveInterval::new(base, base * 16)
+    });
+    let mut schedule = PollSchedule::new(clock.clone(), interval, args.count, args.duration);
+    if let Some(jitter) = args.jitter {
+        schedule = schedule.with_jitter(std::time::Duration::from_secs_f32(jitter));
+    }
+    let sdb_check = args
+        .sdb_check_interval
+        .map(std::time::Duration::from_secs_f32);
+    let mut sdb_checked = clock.now();
+    let mut sdb = sdb;
+    let mut conn = connect()?;
+    // The largest read the instrument accepts, probed once for adaptive batching.
+    let mut batch_limit = None;
+
+    // Runs once per SDB, the inner loop polls until done or the SDB changes.
+    loop {
+        let mut client = Client::new(conn, &sdb)?;
+        client.set_write_policy(args.write_policy);
+        if args.adaptive_batching {
+            let limit = match batch_limit {
+                Some(limit) => limit,
+                None => *batch_limit.insert(client.probe_max_response_len()?),
+            };
+            let batching = AdaptiveBatching::new(DEFAULT_MAX_RESPONSE_LEN / 4, limit);
+            client.set_adaptive_batching(Some(batching));
+        }
+        let mut transaction = client.transaction().guarded(&mut writes);
+        for rw in &readwrite {
+            transaction = match rw {
+                Rw::Read(name) => transaction.read(sdb.param_by_name(name)?),
+                Rw::Write(name, value) => {
+                    transaction.write(sdb.param_by_name(name)?, value.clone())
+                }
+            };
         }
+        let mut changed = None;
 
-        // perform write
-        if let Some(Rw::Write(param, value)) = param {
-            let x = ParamWrite::new(param, value)?;
-            let r = conn.query(&PacketCC::new(PayloadParamWrite::new(sdb, &[x])))?;
-            dbg!(r);
+        while !CTRL_C_PRESSED.load(SeqCst) && !schedule.is_done() {
+            // Poll loop
+            let result = match execute_queries(
+                &mut transaction,
+                args.follow_pointers,
+                &output,
+                downsampler.as_mut(),
+                &mut tracker,
+            ) {
+                Ok(result) => result,
+                Err(e) if is_busy_error(&e) && schedule.is_polling() => {
+                    let next = schedule.busy();
+                    let e = palette.error(format_args!("{e:#}"));
+                    eprintln!("{e} Slowing down polling to {next:?}.");
+                    transaction.client().connection().idle(next)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(device_ts) = result.timestamp {
+                stats.record(device_ts);
+            }
+            #[cfg(unix)]
+            if let Some(controls) = &controls {
+                controls.set_stats(format!(
+                    "{} polls, {} answered busy. {stats}",
+                    schedule.polls() + 1,
+                    schedule.busy_polls()
+                ));
+            }
+            for r in &result.results {
+                if let OpResult::Read(param, value) = r {
+                    let events = value.as_f64().map(|v| alerts.check(param.name(), v));
+                    for event in events.unwrap_or_default() {
+                        notifier.notify(event);
+                    }
+                }
+            }
+            if let Some(file) = &args.stats_file {
+                std::fs::write(file, stats.prometheus_text())
+                    .with_context(|| format!("Failed to write {}", file.display()))?;
+            }
+            if sdb_check.is_some_and(|every| clock.now() - sdb_checked >= every) {
+                sdb_checked = clock.now();
+                changed = store.refresh_or_keep(transaction.client().connection());
+                if changed.is_some() {
+                    break;
+                }
+            }
+
+            match schedule.polled() {
+                Some(wait) if !CTRL_C_PRESSED.load(SeqCst) => {
+                    transaction.client().connection().idle(wait)?
+                }
+                _ => break,
+            }
         }
-        // repeat until iterator empty
-        if param.is_none() {
+        drop(transaction);
+        let Some(new) = changed else {
             break;
+        };
+        conn = client.into_connection();
+        eprintln!(
+            "The SDB changed from {:#010x} to {:#010x}.",
+            sdb.sdb_id(),
+            new.sdb_id()
+        );
+        readwrite.retain_mut(|rw| {
+            let (Rw::Read(name) | Rw::Write(name, _)) = rw;
+            match config.resolve_param(&new, name) {
+                Ok(param) => {
+                    *name = param.name().to_string();
+                    true
+                }
+                Err(_) => {
+                    let e = format_args!("{name} is not in the new SDB, leaving it out.");
+                    eprintln!("{}", palette.error(e));
+                    false
+                }
+            }
+        });
+        if readwrite.is_empty() {
+            bail!("None of the parameters are in the new SDB.");
         }
+        sdb = new;
+    }
+    if args.count.is_some() || args.duration.is_some() {
+        eprintln!(
+            "{} polls in {:.1?}, {} answered busy.",
+            schedule.polls(),
+            schedule.elapsed(),
+            schedule.busy_polls()
+        );
+    }
+    if args.stats {
+        eprintln!("{stats}");
     }
     Ok(())
 }
+
+/// A busy error code, as opposed to a timeout which leaves the connection unusable.
+fn is_busy_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<DeviceBusy>()
+        .is_some_and(|busy| busy.error_code.is_some())
+}
+
+fn execute_queries<'sdb>(
+    transaction: &mut Transaction<'_, 'sdb>,
+    follow_pointers: bool,
+    output: &ReadOutput,
+    downsampler: Option<&mut Downsampler>,
+    tracker: &mut ChangeTracker,
+) -> Result<TransactionResult<'sdb>> {
+    let result = transaction.execute()?;
+
+    let aggregated = downsampler.is_some();
+    if let Some(downsampler) = downsampler {
+        let reads: Vec<_> = result
+            .results
+            .iter()
+            .filter_map(|r| match r {
+                OpResult::Read(param, value) => Some((param, value.as_f64())),
+                OpResult::Write(_) => None,
+            })
+            .collect();
+        let values: Vec<_> = reads.iter().map(|(_, v)| *v).collect();
+        if let Some(stats) = downsampler.push(&values) {
+            for ((param, _), stats) in reads.iter().zip(stats) {
+                println!("{}: {stats}", param.name());
+            }
+        }
+    }
+    let mut targets = Vec::new();
+    for r in &result.results {
+        match r {
+            OpResult::Read(..) if aggregated => {}
+            OpResult::Read(param, value) => {
+                print_read(param, param.name(), value, output, tracker);
+                if let Some(target) = param.resolve_pointer(value).filter(|_| follow_pointers) {
+                    targets.push(target);
+                }
+            }
+            OpResult::Write(w) if w.is_ok() => println!("{} written", w.param.name()),
+            OpResult::Write(w) => eprintln!(
+                "{}",
+                tracker.palette.error(format_args!(
+                    "{}: write failed with error code {}",
+                    w.param.name(),
+                    ErrorCode(w.error_code)
+                ))
+            ),
+        }
+    }
+    if !targets.is_empty() {
+        let values = transaction.client().read_cached(&targets)?;
+        for (param, value) in targets.iter().zip(values) {
+            let label = format!("  -> {}", param.name());
+            print_read(param, &label, &value, output, tracker);
+        }
+    }
+    let failed = result.failed_writes().count();
+    if failed > 0 {
+        let total = result
+            .results
+            .iter()
+            .filter(|r| matches!(r, OpResult::Write(_)))
+            .count();
+        return Err(WritesFailed { failed, total }.into());
+    }
+    Ok(result)
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "config")]
+use std::path::Path;
+
+#[cfg(feature = "config")]
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::opc_values::Value;
+use crate::sdb::normalize_param_path;
+
+/// What the user knows about a parameter, beyond the SDB's name and type.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParamMetadata {
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub unit: Option<String>,
+    /// Decimal places to show the value with.
+    pub decimals: Option<usize>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl ParamMetadata {
+    /// Fails if the value is a number outside of `min..=max`, or not finite while there
+    /// are limits.
+    pub fn check_range(&self, param: &str, value: &Value) -> Result<(), OutOfRange> {
+        let Some(v) = value.as_f64() else {
+            return Ok(());
+        };
+        let limited = self.min.is_some() || self.max.is_some();
+        if (limited && !v.is_finite())
+            || self.min.is_some_and(|min| v < min)
+            || self.max.is_some_and(|max| v > max)
+        {
+            return Err(OutOfRange {
+                param: param.to_string(),
+                value: v,
+                min: self.min,
+                max: self.max,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A value outside the limits the metadata gives for the parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutOfRange {
+    pub param: String,
+    pub value: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl std::fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bound = |b: Option<f64>| b.map(|b| b.to_string()).unwrap_or_default();
+        write!(
+            f,
+            "{} is outside the range {}..{} of {}.",
+            self.value,
+            bound(self.min),
+            bound(self.max),
+            self.param
+        )
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+/// A user-maintained file of [`ParamMetadata`], keyed by parameter path. The paths are
+/// matched like [`Sdb::param_by_name`] does.
+///
+/// ```toml
+/// [".Gauge[1].Parameter[1].Value"]
+/// display_name = "Chamber pressure"
+/// unit = "mbar"
+/// decimals = 2
+/// min = 0.0
+/// max = 1100.0
+/// ```
+///
+/// [`Sdb::param_by_name`]: crate::sdb::Sdb::param_by_name
+#[derive(Clone, Debug, Default)]
+pub struct MetadataOverlay {
+    params: BTreeMap<String, ParamMetadata>,
+}
+
+impl MetadataOverlay {
+    #[cfg(feature = "config")]
+    pub fn from_file(file: impl AsRef<Path>) -> Result<Self> {
+        let file = file.as_ref();
+        let text = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read metadata file {}", file.display()))?;
+        Self::from_toml(&text).with_context(|| format!("Invalid metadata file {}", file.display()))
+    }
+
+    #[cfg(feature = "config")]
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let params: BTreeMap<String, ParamMetadata> = toml::from_str(text)?;
+        Ok(Self {
+            params: params
+                .into_iter()
+                .map(|(name, meta)| (normalize_param_path(&name), meta))
+                .collect(),
+        })
+    }
+
+    /// Also files the metadata of renamed parameters under their new names, see
+    /// [`Config::renames`](crate::config::Config::renames). Metadata given for a new
+    /// name is kept.
+    pub fn with_renames(mut self, renames: &BTreeMap<String, String>) -> Self {
+        let renames: BTreeMap<_, _> = renames
+            .iter()
+            .map(|(old, new)| (normalize_param_path(old), normalize_param_path(new)))
+            .collect();
+        let names: Vec<_> = self.params.keys().cloned().collect();
+        for name in names {
+            for renamed in crate::config::rename_chain(&renames, &name) {
+                if !self.params.contains_key(renamed) {
+                    let meta = self.params[&name].clone();
+                    self.params.insert(renamed.to_string(), meta);
+                }
+            }
+        }
+        self
+    }
+
+    pub fn get(&self, param: &str) -> Option<&ParamMetadata> {
+        self.params.get(&normalize_param_path(param))
+    }
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn test_metadata_overlay() {
+    let overlay = MetadataOverlay::from_toml(
+        r#"
+        [".Gauge[1].Parameter[1].Value"]
+        unit = "mbar"
+        max = 1100.0
+        "#,
+    )
+    .unwrap();
+    let meta = overlay.get(".gauge.1.parameter.1.value").unwrap();
+    assert_eq!(meta.unit.as_deref(), Some("mbar"));
+    assert_eq!(meta.max, Some(1100.0));
+    assert!(overlay.get(".Gauge[2].Parameter[1].Value").is_none());
+    assert!(meta.check_range(".P", &Value::Float(1000.0)).is_ok());
+    assert!(meta.check_range(".P", &Value::Float(1200.0)).is_err());
+    assert!(meta.check_range(".P", &Value::Float(f32::NAN)).is_err());
+    assert!(meta
+        .check_range(".P", &Value::Float(f32::NEG_INFINITY))
+        .is_err());
+}
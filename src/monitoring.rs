@@ -0,0 +1,149 @@
+//! Configs for monitoring setups: a Telegraf input running this program to read a
+//! parameter group, and a Grafana dashboard plotting what it collects.
+
+use serde_json::{json, Value as Json};
+
+use crate::metadata::MetadataOverlay;
+
+/// The measurement the Telegraf input writes, with the parameter name as the `param`
+/// tag and the value as the `value` field.
+pub const MEASUREMENT: &str = "leybold_opc";
+
+/// How Telegraf runs this program, see [`telegraf_config`].
+#[derive(Clone, Debug)]
+pub struct TelegrafInput {
+    /// The command line reading the group, printing `name: value` lines.
+    pub command: Vec<String>,
+    pub interval_secs: u32,
+}
+
+/// A float as printed for REAL values, also in exponent notation such as `1.5e-7`, which
+/// grok's `NUMBER` doesn't match.
+const FLOAT_PATTERN: &str = r"[-+]?(?:\d+(?:\.\d*)?|\.\d+)(?:[eE][-+]?\d+)?";
+
+/// An `inputs.exec` section for Telegraf. Values are parsed from the `name: value`
+/// lines of the command's output; lines with non-numeric values are skipped.
+pub fn telegraf_config(group: &str, input: &TelegrafInput) -> String {
+    let command = input
+        .command
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        r#"# Reads the parameter group @{group} from the instrument.
+[[inputs.exec]]
+  commands = [{command}]
+  interval = "{interval}s"
+  timeout = "{interval}s"
+  name_override = "{MEASUREMENT}"
+  data_format = "grok"
+  grok_patterns = ['^%{{NOTSPACE:param:tag}}: %{{OPC_FLOAT:value:float}}$']
+  grok_custom_patterns = {patterns}
+"#,
+        command = toml_string(&command),
+        patterns = toml_string(&format!("OPC_FLOAT {FLOAT_PATTERN}")),
+        interval = input.interval_secs,
+    )
+}
+
+/// A Grafana dashboard with a time series panel per parameter, querying the InfluxDB
+/// data source written to by the [`telegraf_config`] input. Titles and units are taken
+/// from the metadata, where given.
+pub fn grafana_dashboard(
+    group: &str,
+    params: &[&str],
+    metadata: &MetadataOverlay,
+    datasource: &str,
+) -> Json {
+    let panels: Vec<Json> = params
+        .iter()
+        .enumerate()
+        .map(|(i, &param)| {
+            let meta = metadata.get(param);
+            let title = meta
+                .and_then(|m| m.display_name.as_deref())
+                .unwrap_or(param);
+            let unit = meta
+                .and_then(|m| m.unit.as_ref())
+                .map(|u| format!("suffix:{u}"));
+            let query = format!(
+                "SELECT mean(\"value\") FROM \"{MEASUREMENT}\" WHERE \"param\" = '{}' \
+                 AND $timeFilter GROUP BY time($__interval) fill(null)",
+                param.replace('\'', "\\'")
+            );
+            json!({
+                "id": i + 1,
+                "type": "timeseries",
+                "title": title,
+                "description": meta.and_then(|m| m.description.clone()),
+                "datasource": datasource,
+                "gridPos": { "x": (i % 2) * 12, "y": (i / 2) * 8, "w": 12, "h": 8 },
+                "fieldConfig": {
+                    "defaults": { "unit": unit, "decimals": meta.and_then(|m| m.decimals) },
+                    "overrides": [],
+                },
+                "targets": [{ "refId": "A", "rawQuery": true, "query": query }],
+            })
+        })
+        .collect();
+    json!({
+        "title": format!("Leybold @{group}"),
+        "tags": ["leybold-opc"],
+        "timezone": "browser",
+        "refresh": "30s",
+        "time": { "from": "now-6h", "to": "now" },
+        "schemaVersion": 36,
+        "panels": panels,
+    })
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@".contains(c);
+    match !arg.is_empty() && arg.chars().all(plain) {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', r"'\''")),
+    }
+}
+
+fn toml_string(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+#[test]
+fn test_telegraf_config() {
+    let input = TelegrafInput {
+        command: ["leybold-opc-rs", "-r", "@pressures", "--sdb", "my sdb.dat"]
+            .map(String::from)
+            .to_vec(),
+        interval_secs: 10,
+    };
+    let config = telegraf_config("pressures", &input);
+    let parsed: toml::Table = toml::from_str(&config).unwrap();
+    let exec = &parsed["inputs"]["exec"][0];
+    assert_eq!(
+        exec["commands"][0].as_str(),
+        Some("leybold-opc-rs -r @pressures --sdb 'my sdb.dat'")
+    );
+    assert_eq!(exec["interval"].as_str(), Some("10s"));
+    let patterns = exec["grok_custom_patterns"].as_str().unwrap();
+    let float = regex::Regex::new(&format!(
+        "^{}$",
+        patterns.strip_prefix("OPC_FLOAT ").unwrap()
+    ))
+    .unwrap();
+    for v in [1e-7, 1.5e-7, 2.5, -3.0, 1100.0] {
+        let printed = crate::opc_values::Value::Float(v).pretty().to_string();
+        assert!(float.is_match(&printed), "{printed}");
+    }
+}
+
+#[test]
+fn test_grafana_dashboard() {
+    let metadata = MetadataOverlay::from_toml("[\".P\"]\nunit = \"mbar\"\n").unwrap();
+    let dashboard = grafana_dashboard("pressures", &[".P"], &metadata, "InfluxDB");
+    assert_eq!(
+        dashboard["panels"][0]["fieldConfig"]["defaults"]["unit"],
+        "suffix:mbar"
+    );
+}
@@ -1,5 +1,7 @@
-use std::fmt::{Debug, Formatter};
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
 use std::io::{Cursor, Read, Seek};
+use std::time::Duration;
 
 use anyhow::{anyhow, bail, Result};
 use binrw::meta::{EndianKind, ReadEndian};
@@ -11,7 +13,7 @@ use crate::sdb::{TypeInfo, TypeKind};
 
 /// Used when parsing the response from the instrument,
 /// for converting OPC types to native Rust types.
-#[derive(Clone, Serialize)]
+#[derive(Clone, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum Value {
     /// A Vec with Values
@@ -27,7 +29,7 @@ pub enum Value {
 
 #[test]
 fn test_value_serialize() {
-    let v = Value::Struct(vec![("field_1".to_string(), Value::Int(4))]);
+    let v = Value::Struct(vec![("field_1".into(), Value::Int(4))]);
     let j = serde_json::ser::to_string(&v).unwrap();
     assert_eq!(j, "{\"field_1\":4}");
 }
@@ -56,41 +58,264 @@ impl Debug for Value {
     }
 }
 
+/// Human friendly formatting of a value, see [`Value::pretty`].
+pub struct Pretty<'a> {
+    value: &'a Value,
+    /// The type of the value, and the format of the TIME values in it.
+    times: Option<(&'a TypeInfo<'a>, TimeFormat)>,
+}
+
+impl Display for Pretty<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ty = self.times.map(|(ty, _)| ty.clone());
+        let format = self.times.map(|(_, format)| format).unwrap_or_default();
+        if self.value.is_scalar() {
+            return f.write_str(&scalar_text(self.value, ty.as_ref(), format));
+        }
+        let mut lines = Vec::new();
+        table_lines(self.value, ty.as_ref(), format, 0, &mut lines);
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+/// A scalar value, with TIME values of type `ty` in `format`.
+fn scalar_text(value: &Value, ty: Option<&TypeInfo>, format: TimeFormat) -> String {
+    match (value, ty.map(TypeInfo::kind)) {
+        (Value::Int(ms), Some(TypeKind::Time)) => format.text(Duration::from_millis(*ms as u64)),
+        _ => format!("{value:?}"),
+    }
+}
+
+/// Lists the members or elements of the value as aligned rows, with nested values
+/// indented below their row.
+fn table_lines(
+    value: &Value,
+    ty: Option<&TypeInfo>,
+    format: TimeFormat,
+    depth: usize,
+    lines: &mut Vec<String>,
+) {
+    let members = ty.and_then(TypeInfo::struct_info).unwrap_or_default();
+    let elem = ty.and_then(TypeInfo::array_info).map(|(elem, _)| elem);
+    let rows: Vec<(String, &Value, Option<&TypeInfo>)> = match value {
+        Value::Struct(fields) => fields
+            .iter()
+            .enumerate()
+            .map(|(i, (n, v))| {
+                let ty = members.get(i).map(|m| &m.type_info);
+                (n.trim_end_matches('\0').to_string(), v, ty)
+            })
+            .collect(),
+        Value::Array(v) => v
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("[{i}]"), v, elem.as_ref()))
+            .collect(),
+        Value::Matrix(m) => m
+            .iter()
+            .enumerate()
+            .flat_map(|(i, row)| {
+                let elem = elem.as_ref();
+                row.iter()
+                    .enumerate()
+                    .map(move |(j, v)| (format!("[{i},{j}]"), v, elem))
+            })
+            .collect(),
+        _ => return,
+    };
+    let width = rows
+        .iter()
+        .map(|(label, ..)| label.len())
+        .max()
+        .unwrap_or(0);
+    let indent = "  ".repeat(depth);
+    for (label, v, ty) in rows {
+        if v.is_scalar() {
+            let text = scalar_text(v, ty, format);
+            lines.push(format!("{indent}{label:width$}  {text}"));
+        } else {
+            lines.push(format!("{indent}{label}"));
+            table_lines(v, ty, format, depth + 1, lines);
+        }
+    }
+}
+
+#[test]
+fn test_pretty() {
+    let v = Value::Struct(vec![
+        ("Name\0".into(), Value::String("pump".into())),
+        ("On".into(), Value::Bool(true)),
+        (
+            "Values".into(),
+            Value::Array(vec![Value::Int(1), Value::Int(2)]),
+        ),
+    ]);
+    assert_eq!(
+        v.pretty().to_string(),
+        "Name    \"pump\"\nOn      true\nValues\n  [0]  1\n  [1]  2"
+    );
+    assert_eq!(Value::Float(1.5).pretty().to_string(), "1.5");
+}
+
 impl ReadEndian for Value {
     const ENDIAN: EndianKind = EndianKind::Endian(Endian::Big);
 }
 
 impl Value {
     pub fn parse(data: &[u8], param: &TypeInfo) -> BinResult<Self> {
-        let mut cur = Cursor::new(data);
-        Self::parse_param(&mut cur, param)
-    }
-
-    fn parse_param(cur: &mut Cursor<&[u8]>, param: &TypeInfo) -> BinResult<Self> {
-        let start_pos = cur.position();
-        macro_rules! int {
-            ($ty:ty) => {{
-                let read_len = param.response_len() as usize;
-                assert_eq!(
-                    read_len,
-                    std::mem::size_of::<$ty>(),
-                    "Type size and specified size are unequal."
-                );
-                if read_len > 1 && start_pos & 1 == 1 {
-                    // adjust alignment to 2 bytes
-                    cur.set_position(start_pos + 1);
-                }
-                Value::Int(cur.read_be::<$ty>()? as i64)
-            }};
+        Self::parse_endian(data, param, Endian::Big)
+    }
+
+    /// The value as a number, for scalar numeric and boolean values.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Bool(b) => Some(*b as u8 as f64),
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f as f64),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer, for integral values and floats without a fractional part.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Bool(b) => Some(*b as i64),
+            Value::Int(i) => Some(*i),
+            Value::Float(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f32 => Some(*f as i64),
+            _ => None,
+        }
+    }
+
+    /// Like [`Value::as_i64`], for values which are not negative.
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_i64().and_then(|i| i.try_into().ok())
+    }
+
+    /// Orders numeric and boolean values by number, whatever their variants, and strings
+    /// with strings. `None` for other values.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (Value::Float(_), _) | (_, Value::Float(_)) => {
+                self.as_f64()?.partial_cmp(&other.as_f64()?)
+            }
+            _ => Some(self.as_i64()?.cmp(&other.as_i64()?)),
         }
+    }
+
+    /// Whether the values are equal as numbers, so that `Int(1)` equals `Float(1.0)`.
+    /// Other values are compared as with `==`.
+    pub fn num_eq(&self, other: &Value) -> bool {
+        match self.compare(other) {
+            Some(ordering) => ordering.is_eq(),
+            None => self == other,
+        }
+    }
+
+    /// Formats structs as aligned member tables and arrays as indexed rows, one per line.
+    pub fn pretty(&self) -> Pretty<'_> {
+        Pretty {
+            value: self,
+            times: None,
+        }
+    }
+
+    /// Like [`Value::pretty`], with the TIME values in the value of type `ty` in `format`.
+    pub fn pretty_times<'a>(&'a self, ty: &'a TypeInfo<'a>, format: TimeFormat) -> Pretty<'a> {
+        Pretty {
+            value: self,
+            times: Some((ty, format)),
+        }
+    }
+
+    /// Whether the value is a single number, boolean or string.
+    pub fn is_scalar(&self) -> bool {
+        !matches!(self, Value::Struct(_) | Value::Array(_) | Value::Matrix(_))
+    }
+
+    /// The member of a struct value, with the name matched ignoring case.
+    pub fn get_field(&self, name: &str) -> Option<&Value> {
+        self.fields()
+            .find(|(n, _)| n.trim_end_matches('\0').eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// The members of a struct value, nothing for other values.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &Value)> {
+        let members: &[(String, Value)] = match self {
+            Value::Struct(members) => members,
+            _ => &[],
+        };
+        members.iter().map(|(n, v)| (n.as_str(), v))
+    }
+
+    /// The elements of an array value, or of a matrix row by row, nothing for other values.
+    pub fn elements(&self) -> impl Iterator<Item = &Value> {
+        let (array, rows): (&[Value], &[Vec<Value>]) = match self {
+            Value::Array(v) => (v, &[]),
+            Value::Matrix(m) => (&[], m),
+            _ => (&[], &[]),
+        };
+        array.iter().chain(rows.iter().flatten())
+    }
+
+    /// Follows a path of struct members and array indices, e.g. `.a.b[2]` or `.m[1,0]`
+    /// for a matrix. Indices count from zero, whatever the bounds of the PLC array are.
+    pub fn field_path(&self, path: &str) -> Result<&Value> {
+        let mut value = self;
+        let mut rest = path;
+        while !rest.is_empty() {
+            let done = &path[..path.len() - rest.len()];
+            if let Some(after) = rest.strip_prefix('[') {
+                let (index, after) = after
+                    .split_once(']')
+                    .ok_or_else(|| anyhow!("Missing ']' after {done} in {path}."))?;
+                let index = index
+                    .split(',')
+                    .map(|i| i.trim().parse::<usize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| anyhow!("Invalid index [{index}] after {done} in {path}."))?;
+                let element = match (value, index.as_slice()) {
+                    (Value::Array(v), [i]) => v.get(*i),
+                    (Value::Matrix(m), [i, j]) => m.get(*i).and_then(|row| row.get(*j)),
+                    _ => None,
+                };
+                value = element.ok_or_else(|| {
+                    anyhow!("{done}[{}] is not in the value.", index_list(&index))
+                })?;
+                rest = after;
+            } else {
+                let after = rest.strip_prefix('.').unwrap_or(rest);
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let name = &after[..end];
+                value = value
+                    .get_field(name)
+                    .ok_or_else(|| anyhow!("{done} has no member {name}."))?;
+                rest = &after[end..];
+            }
+        }
+        Ok(value)
+    }
+
+    /// Like [`Value::parse`], for protocol dialects with other byte orders.
+    pub fn parse_endian(data: &[u8], param: &TypeInfo, endian: Endian) -> BinResult<Self> {
+        let mut cur = Cursor::new(data);
+        Self::parse_param(&mut cur, param, endian)
+    }
+
+    fn parse_param(cur: &mut Cursor<&[u8]>, param: &TypeInfo, endian: Endian) -> BinResult<Self> {
         let value = match param.kind() {
             TypeKind::Array => {
-                let (ty, dims) = param.array_info().unwrap();
+                let pos = cur.position();
+                let (ty, dims) = param.array_info().ok_or_else(|| binrw::Error::AssertFail {
+                    pos,
+                    message: format!("No array description for {}", param.name()),
+                })?;
                 match dims {
                     [len, 0] => {
                         let mut v = Vec::with_capacity(len);
                         for _ in 0..len {
-                            v.push(Self::parse_param(cur, &ty)?);
+                            v.push(Self::parse_param(cur, &ty, endian)?);
                         }
                         Value::Array(v)
                     }
@@ -99,7 +324,7 @@ impl Value {
                         for _ in 0..a {
                             let mut inner = Vec::with_capacity(b);
                             for _ in 0..b {
-                                inner.push(Self::parse_param(cur, &ty)?);
+                                inner.push(Self::parse_param(cur, &ty, endian)?);
                             }
                             outer.push(inner);
                         }
@@ -108,49 +333,45 @@ impl Value {
                 }
             }
             TypeKind::Data => {
-                let info = param.struct_info().unwrap();
-                let mut ret = Vec::with_capacity(info.len());
-                for m in info {
-                    let name = m.name.to_string();
-                    let value = Self::parse_param(cur, &m.type_info)?;
-                    ret.push((name, value));
+                let members = param.members();
+                let mut ret = Vec::with_capacity(members.len());
+                for (name, ty) in members {
+                    let value = Self::parse_param(cur, &ty, endian)?;
+                    ret.push((name.to_string(), value));
                 }
                 Value::Struct(ret)
             }
-            TypeKind::Bool => Value::Bool(cur.read_be::<u8>()? != 0),
-            TypeKind::Int => int!(i16),
-            TypeKind::Byte => int!(u8),
-            TypeKind::Word | TypeKind::Uint => int!(u16),
-            TypeKind::Dword | TypeKind::Udint | TypeKind::Pointer => int!(u32),
-            TypeKind::Real => {
-                if start_pos & 1 == 1 {
-                    // Adjust alignment
-                    cur.set_position(start_pos + 1);
-                }
-                Value::Float(cur.read_be::<f32>()?)
-            }
-            TypeKind::Time => int!(u32), // TODO: use better representation?
-            TypeKind::String => {
-                let mut v = vec![0; param.response_len()];
-                cur.read_exact(v.as_mut_slice())?;
-                if let Some(nul_pos) = v.iter().position(|&b| b == 0) {
-                    v.truncate(nul_pos);
-                }
-                Value::String(CP1252.decode(&v).to_string())
-            }
+            _ => match read_scalar(cur, param, endian)? {
+                Scalar::Bool(b) => Value::Bool(b),
+                Scalar::Int(i) => Value::Int(i),
+                Scalar::Float(f) => Value::Float(f),
+                Scalar::String(s) => Value::String(s.into_owned()),
+            },
         };
         Ok(value)
     }
 
     pub fn from_str(val: &str, desc: &TypeInfo) -> Result<Self> {
+        Self::from_str_with(val, desc, StringEncoding::Strict)
+    }
+
+    /// Like [`Value::from_str`], with strings for String parameters converted as given.
+    pub fn from_str_with(val: &str, desc: &TypeInfo, encoding: StringEncoding) -> Result<Self> {
         let val = match desc.kind() {
             TypeKind::Bool => Value::Bool(val.parse()?),
             TypeKind::Real => Value::Float(val.parse()?),
-            TypeKind::Time => unimplemented!(),
-            TypeKind::String => Value::String(val.to_string()),
+            // Milliseconds, or a time with a unit such as 1.5s.
+            TypeKind::Time => match val.parse() {
+                Ok(ms) => Value::Int(ms),
+                Err(_) => Value::Int(crate::stats::parse_duration(val)?.as_millis() as i64),
+            },
+            TypeKind::String => Value::String(encoding.to_cp1252_str(val)?),
             TypeKind::Array => unimplemented!(),
             TypeKind::Data => unimplemented!(),
-            TypeKind::Pointer => unimplemented!(),
+            TypeKind::Pointer => match val.strip_prefix("0x") {
+                Some(hex) => Value::Int(i64::from_str_radix(hex, 16)?),
+                None => Value::Int(val.parse()?),
+            },
             _ => Value::Int(val.parse()?),
         };
         // Check that the value can be encoded into the type
@@ -159,20 +380,563 @@ impl Value {
     }
 }
 
+/// A value of a type other than arrays and structs, strings borrowed from the data.
+pub(crate) enum Scalar<'d> {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    String(std::borrow::Cow<'d, str>),
+}
+
+/// Reads a value of a type other than arrays and structs, aligning numbers wider than
+/// a byte to 2 bytes.
+pub(crate) fn read_scalar<'d>(
+    cur: &mut Cursor<&'d [u8]>,
+    param: &TypeInfo,
+    endian: Endian,
+) -> BinResult<Scalar<'d>> {
+    let start_pos = cur.position();
+    let scalar = match param.kind() {
+        TypeKind::Bool => Scalar::Bool(cur.read_type::<u8>(endian)? != 0),
+        TypeKind::Real => {
+            if start_pos & 1 == 1 {
+                // Adjust alignment
+                cur.set_position(start_pos + 1);
+            }
+            Scalar::Float(cur.read_type::<f32>(endian)?)
+        }
+        TypeKind::String => {
+            let data: &'d [u8] = cur.get_ref();
+            let start = start_pos as usize;
+            let Some(v) = data.get(start..start + param.response_len()) else {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            };
+            cur.set_position((start + v.len()) as u64);
+            let end = v.iter().position(|&b| b == 0).unwrap_or(v.len());
+            Scalar::String(CP1252.decode(&v[..end]))
+        }
+        // The integer kinds, TIME being milliseconds.
+        kind => {
+            let read_len = param.response_len();
+            assert_eq!(
+                Some(read_len),
+                kind.wire_size(),
+                "Type size and specified size are unequal."
+            );
+            if read_len > 1 && start_pos & 1 == 1 {
+                // adjust alignment to 2 bytes
+                cur.set_position(start_pos + 1);
+            }
+            let mut b = [0; 8];
+            cur.read_exact(&mut b[..read_len])?;
+            Scalar::Int(kind.decode_int(&b[..read_len], endian).unwrap())
+        }
+    };
+    Ok(scalar)
+}
+
+fn index_list(index: &[usize]) -> String {
+    let index: Vec<_> = index.iter().map(usize::to_string).collect();
+    index.join(",")
+}
+
+/// Compares numeric values with thresholds, as in `value > 1e-3`.
+impl PartialEq<f64> for Value {
+    fn eq(&self, other: &f64) -> bool {
+        self.as_f64() == Some(*other)
+    }
+}
+
+impl PartialOrd<f64> for Value {
+    fn partial_cmp(&self, other: &f64) -> Option<Ordering> {
+        self.as_f64()?.partial_cmp(other)
+    }
+}
+
+impl PartialEq<i64> for Value {
+    fn eq(&self, other: &i64) -> bool {
+        self.num_eq(&Value::Int(*other))
+    }
+}
+
+impl PartialOrd<i64> for Value {
+    fn partial_cmp(&self, other: &i64) -> Option<Ordering> {
+        self.compare(&Value::Int(*other))
+    }
+}
+
+#[test]
+fn test_value_compare() {
+    assert!(Value::Int(2) > 1.5);
+    assert!(Value::Float(1e-3) < 1e-2);
+    assert!(Value::Float(3.0) == 3);
+    assert!(Value::Bool(true) >= 1);
+    assert!(!(Value::String("1".into()) < 2.0));
+    assert_eq!(
+        Value::Int(1).compare(&Value::Float(1.5)),
+        Some(Ordering::Less)
+    );
+    assert_eq!(Value::Int(-1).compare(&Value::String("a".into())), None);
+    assert!(Value::Int(1).num_eq(&Value::Float(1.0)));
+    assert_eq!(Value::Float(2.5).as_i64(), None);
+    assert_eq!(Value::Int(-2).as_u64(), None);
+    assert_eq!(Value::Float(7.0).as_u64(), Some(7));
+}
+
+#[test]
+fn test_field_path() {
+    let v = Value::Struct(vec![
+        ("Name".into(), Value::String("pump".into())),
+        (
+            "Data".into(),
+            Value::Struct(vec![(
+                "Values".into(),
+                Value::Array(vec![Value::Int(1), Value::Int(2), Value::Int(3)]),
+            )]),
+        ),
+        (
+            "M".into(),
+            Value::Matrix(vec![vec![Value::Int(4), Value::Int(5)]]),
+        ),
+    ]);
+    assert_eq!(v.get_field("name"), Some(&Value::String("pump".into())));
+    assert_eq!(v.field_path(".Data.Values[2]").unwrap(), &Value::Int(3));
+    assert_eq!(v.field_path("M[0,1]").unwrap(), &Value::Int(5));
+    assert_eq!(v.field_path("").unwrap(), &v);
+    let err = v.field_path(".Data.Values[3]").unwrap_err();
+    assert_eq!(err.to_string(), ".Data.Values[3] is not in the value.");
+    assert!(v.field_path(".Data.Nope").is_err());
+    assert_eq!(v.fields().count(), 3);
+    assert_eq!(v.field_path(".M").unwrap().elements().count(), 2);
+}
+
+/// How text is turned into the CP1252 strings of the instrument.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum StringEncoding {
+    /// Characters which CP1252 doesn't have are an error.
+    #[default]
+    Strict,
+    /// Characters which CP1252 doesn't have are replaced with '?'.
+    Lossy,
+    /// The text is the hex encoded bytes to write, e.g. `48690a`.
+    Hex,
+}
+
+impl StringEncoding {
+    /// Returns a string which encodes to CP1252 without errors.
+    pub fn to_cp1252_str(self, val: &str) -> Result<String> {
+        match self {
+            Self::Strict => {
+                check_cp1252(val)?;
+                Ok(val.to_string())
+            }
+            Self::Lossy => Ok(CP1252.decode(&CP1252.encode_lossy(val, b'?')).into_owned()),
+            Self::Hex => {
+                let bytes = crate::packets::PayloadUnknown::from_hex(val)?.data;
+                // Every byte decodes to a character which encodes back to the same byte.
+                Ok(CP1252.decode(&bytes).into_owned())
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for StringEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "lossy" => Ok(Self::Lossy),
+            "hex" => Ok(Self::Hex),
+            _ => bail!("Unknown string encoding '{s}', expected strict, lossy or hex."),
+        }
+    }
+}
+
+/// How TIME values and instrument timestamps, which are both durations, are written.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// Integer milliseconds, as the instrument has them.
+    #[default]
+    Millis,
+    /// Seconds with a fraction.
+    Seconds,
+    /// ISO 8601 durations, e.g. `PT1.5S`.
+    Iso8601,
+}
+
+impl TimeFormat {
+    pub fn json(self, d: Duration) -> serde_json::Value {
+        match self {
+            Self::Millis => (d.as_millis() as u64).into(),
+            Self::Seconds => d.as_secs_f64().into(),
+            Self::Iso8601 => self.text(d).into(),
+        }
+    }
+
+    /// The duration as text, for CSV and text output.
+    pub fn text(self, d: Duration) -> String {
+        match self {
+            Self::Millis => d.as_millis().to_string(),
+            Self::Seconds => d.as_secs_f64().to_string(),
+            Self::Iso8601 => match d.subsec_millis() {
+                0 => format!("PT{}S", d.as_secs()),
+                ms => {
+                    let fraction = format!("{ms:03}");
+                    format!("PT{}.{}S", d.as_secs(), fraction.trim_end_matches('0'))
+                }
+            },
+        }
+    }
+
+    /// The value as JSON, with the TIME values in it in this format.
+    pub fn value_json(self, value: &Value, ty: &TypeInfo) -> serde_json::Value {
+        match (value, ty.kind()) {
+            (Value::Int(ms), TypeKind::Time) => self.json(Duration::from_millis(*ms as u64)),
+            (Value::Array(items), TypeKind::Array) => self.elements_json(items, ty),
+            (Value::Matrix(rows), TypeKind::Array) => {
+                rows.iter().map(|r| self.elements_json(r, ty)).collect()
+            }
+            (Value::Struct(fields), TypeKind::Data) => {
+                let members = ty.struct_info().unwrap_or_default();
+                let mut map = serde_json::Map::new();
+                for (i, (name, v)) in fields.iter().enumerate() {
+                    let v = match members.get(i) {
+                        Some(member) => self.value_json(v, &member.type_info),
+                        None => serde_json::to_value(v).unwrap_or_default(),
+                    };
+                    map.insert(name.clone(), v);
+                }
+                map.into()
+            }
+            _ => serde_json::to_value(value).unwrap_or_default(),
+        }
+    }
+
+    /// The elements of an array value of type `ty`.
+    fn elements_json(self, items: &[Value], ty: &TypeInfo) -> serde_json::Value {
+        let elem = ty.array_info().map(|(elem, _)| elem);
+        items
+            .iter()
+            .map(|v| match &elem {
+                Some(elem) => self.value_json(v, elem),
+                None => serde_json::to_value(v).unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+impl std::str::FromStr for TimeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ms" => Ok(Self::Millis),
+            "seconds" => Ok(Self::Seconds),
+            "iso8601" => Ok(Self::Iso8601),
+            _ => bail!("Unknown time format '{s}', expected ms, seconds or iso8601."),
+        }
+    }
+}
+
+#[test]
+fn test_time_format() {
+    let d = Duration::from_millis(61_500);
+    assert_eq!(TimeFormat::Millis.json(d), serde_json::json!(61500));
+    assert_eq!(TimeFormat::Seconds.json(d), serde_json::json!(61.5));
+    assert_eq!(TimeFormat::Iso8601.text(d), "PT61.5S");
+    assert_eq!(TimeFormat::Iso8601.text(Duration::from_secs(2)), "PT2S");
+    assert_eq!(
+        TimeFormat::Iso8601.text(Duration::from_millis(20)),
+        "PT0.02S"
+    );
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let ty = sdb
+        .param_by_name(".Gauge[1].DegasTimer")
+        .unwrap()
+        .type_info();
+    let timer = Value::Struct(
+        ["M", "StartTime", "IN", "PT", "Q", "ET"]
+            .into_iter()
+            .map(|name| (name.to_string(), Value::Int(1500)))
+            .collect(),
+    );
+    let text = timer.pretty_times(&ty, TimeFormat::Iso8601).to_string();
+    assert!(text.contains("PT         PT1.5S"), "{text}");
+    assert!(text.contains("IN         1500"), "{text}");
+}
+
+/// Fails with the characters CP1252 doesn't have, if there are any.
+fn check_cp1252(s: &str) -> Result<()> {
+    if CP1252.encode(s).is_ok() {
+        return Ok(());
+    }
+    let mut missing: Vec<char> = s
+        .chars()
+        .filter(|c| CP1252.encode(c.encode_utf8(&mut [0; 4])).is_err())
+        .collect();
+    missing.dedup();
+    let missing: Vec<_> = missing
+        .iter()
+        .map(|c| format!("'{c}' (U+{:04X})", *c as u32))
+        .collect();
+    bail!(
+        "The instrument's code page CP1252 has no {}. Use lossy string encoding to replace them with '?'.",
+        missing.join(", ")
+    )
+}
+
+#[test]
+fn test_string_encoding() {
+    assert!(StringEncoding::Strict.to_cp1252_str("Grüße €").is_ok());
+    let err = StringEncoding::Strict.to_cp1252_str("1 µΩ").unwrap_err();
+    assert!(err.to_string().contains("'Ω' (U+03A9)"), "{err}");
+    assert_eq!(StringEncoding::Lossy.to_cp1252_str("1 µΩ").unwrap(), "1 µ?");
+    let bytes: Vec<u8> = (1..=255).collect();
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    let s = StringEncoding::Hex.to_cp1252_str(&hex).unwrap();
+    assert_eq!(CP1252.encode(&s).unwrap(), bytes);
+}
+
 impl BinRead for Value {
     type Args<'a> = TypeInfo<'a>;
 
     fn read_options<R: Read + Seek>(
         reader: &mut R,
-        _endian: Endian,
+        endian: Endian,
         args: Self::Args<'_>,
     ) -> BinResult<Self> {
         let mut buf = vec![0; args.response_len()];
         reader.read_exact(buf.as_mut_slice())?;
-        Self::parse(&buf, &args)
+        Self::parse_endian(&buf, &args, endian)
+    }
+}
+
+/// Serializes the bytes of a parameter as its [`Value`] would be, decoding them while
+/// serializing, without building the value. For exporting many values.
+#[derive(Clone)]
+pub struct RawValue<'a> {
+    data: &'a [u8],
+    ty: &'a TypeInfo<'a>,
+    endian: Endian,
+    time_format: TimeFormat,
+}
+
+impl<'a> RawValue<'a> {
+    /// The value in `data` of type `ty`, in big endian like [`Value::parse`].
+    pub fn new(data: &'a [u8], ty: &'a TypeInfo<'a>) -> Self {
+        Self {
+            data,
+            ty,
+            endian: Endian::Big,
+            time_format: TimeFormat::Millis,
+        }
+    }
+
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    /// Writes TIME values in `time_format`, as [`TimeFormat::value_json`] does.
+    pub fn with_time_format(mut self, time_format: TimeFormat) -> Self {
+        self.time_format = time_format;
+        self
+    }
+}
+
+impl Serialize for RawValue<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pos = std::cell::Cell::new(0);
+        let node = RawNode {
+            raw: self,
+            ty: self.ty.clone(),
+            pos: &pos,
+        };
+        node.serialize(serializer)
     }
 }
 
+/// A part of a [`RawValue`], read from the shared position onwards.
+struct RawNode<'r, 'a> {
+    raw: &'r RawValue<'a>,
+    ty: TypeInfo<'a>,
+    pos: &'r std::cell::Cell<usize>,
+}
+
+impl RawNode<'_, '_> {
+    /// Takes `len` bytes, first skipping a byte to align to 2 bytes if `align` is set.
+    fn take<E: serde::ser::Error>(&self, len: usize, align: bool) -> Result<&[u8], E> {
+        let mut start = self.pos.get();
+        if align && start & 1 == 1 {
+            start += 1;
+        }
+        let bytes = self.raw.data.get(start..start + len).ok_or_else(|| {
+            E::custom(format!(
+                "{} bytes are too few for {}.",
+                self.raw.data.len(),
+                self.raw.ty.name()
+            ))
+        })?;
+        self.pos.set(start + len);
+        Ok(bytes)
+    }
+
+    fn int<E: serde::ser::Error>(&self) -> Result<i64, E> {
+        let len = self.ty.response_len();
+        let b = self.take(len, len > 1)?;
+        self.ty
+            .kind()
+            .decode_int(b, self.raw.endian)
+            .ok_or_else(|| E::custom(format!("Unexpected size {len} of {}.", self.ty.name())))
+    }
+
+    fn child<'r>(&'r self, ty: TypeInfo<'r>) -> RawNode<'r, 'r> {
+        RawNode {
+            raw: self.raw,
+            ty,
+            pos: self.pos,
+        }
+    }
+}
+
+impl Serialize for RawNode<'_, '_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{SerializeMap, SerializeSeq};
+
+        match self.ty.kind() {
+            TypeKind::Array => {
+                let (elem, dims) = self.ty.array_info().ok_or_else(|| {
+                    serde::ser::Error::custom(format!(
+                        "No array description for {}.",
+                        self.ty.name()
+                    ))
+                })?;
+                let mut seq = serializer.serialize_seq(Some(dims[0]))?;
+                for _ in 0..dims[0] {
+                    match dims[1] {
+                        0 => seq.serialize_element(&self.child(elem.clone()))?,
+                        len => seq.serialize_element(&RawRow {
+                            node: self.child(elem.clone()),
+                            len,
+                        })?,
+                    }
+                }
+                seq.end()
+            }
+            TypeKind::Data => {
+                let members = self.ty.members();
+                let mut map = serializer.serialize_map(Some(members.len()))?;
+                for (name, ty) in members {
+                    map.serialize_entry(name, &self.child(ty))?;
+                }
+                map.end()
+            }
+            TypeKind::Bool => serializer.serialize_bool(self.take(1, false)?[0] != 0),
+            TypeKind::Real => {
+                let b = self.take(4, true)?;
+                let b = [b[0], b[1], b[2], b[3]];
+                serializer.serialize_f32(match self.raw.endian {
+                    Endian::Big => f32::from_be_bytes(b),
+                    Endian::Little => f32::from_le_bytes(b),
+                })
+            }
+            TypeKind::Time => {
+                let d = Duration::from_millis(self.int()? as u64);
+                match self.raw.time_format {
+                    TimeFormat::Millis => serializer.serialize_u64(d.as_millis() as u64),
+                    TimeFormat::Seconds => serializer.serialize_f64(d.as_secs_f64()),
+                    TimeFormat::Iso8601 => serializer.serialize_str(&TimeFormat::Iso8601.text(d)),
+                }
+            }
+            TypeKind::String => {
+                let b = self.take(self.ty.response_len(), false)?;
+                let end = b.iter().position(|&b| b == 0).unwrap_or(b.len());
+                serializer.serialize_str(&CP1252.decode(&b[..end]))
+            }
+            _ => serializer.serialize_i64(self.int()?),
+        }
+    }
+}
+
+/// A matrix row of a [`RawValue`].
+struct RawRow<'r, 'a> {
+    node: RawNode<'r, 'a>,
+    len: usize,
+}
+
+impl Serialize for RawRow<'_, '_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for _ in 0..self.len {
+            seq.serialize_element(&self.node)?;
+        }
+        seq.end()
+    }
+}
+
+#[test]
+fn test_raw_value() {
+    let sdb = crate::sdb_builder::test_sdb();
+    for param in sdb.parameters() {
+        let ty = param.type_info();
+        let data: Vec<u8> = (0..ty.response_len())
+            .map(|i| (i * 7 + 0x41) as u8)
+            .collect();
+        let value = Value::parse(&data, &ty).unwrap();
+        for time_format in [TimeFormat::Millis, TimeFormat::Iso8601] {
+            let raw = RawValue::new(&data, &ty).with_time_format(time_format);
+            assert_eq!(
+                serde_json::to_value(&raw).unwrap(),
+                time_format.value_json(&value, &ty),
+                "{}",
+                param.name()
+            );
+        }
+    }
+    let ty = sdb.param_by_name(".OPCCounter").unwrap().type_info();
+    assert!(serde_json::to_string(&RawValue::new(&[], &ty)).is_err());
+
+    use crate::sdb::{AccessMode, ParamFlags, ParseMode, Sdb};
+    let mut b = crate::sdb_builder::SdbBuilder::new(1);
+    let byte = b.add_type(TypeKind::Byte, 1, "BYTE");
+    let int = b.add_type(TypeKind::Int, 2, "INT");
+    let matrix = b.add_array("ARRAY [1..2, 1..2] OF INT", int, &[(1, 2), (1, 2)]);
+    let data = b.add_struct(
+        "DATA",
+        vec![
+            crate::sdb_builder::Member::new("B", byte),
+            crate::sdb_builder::Member::new("M", matrix),
+        ],
+    );
+    b.add_variable(".D", data, ParamFlags::GLOBAL, AccessMode::Read, 0x100);
+    let sdb = Sdb::from_bytes(&b.build(), ParseMode::Full).unwrap();
+    let ty = sdb.param_by_name(".D").unwrap().type_info();
+    let data = [7, 0, 0, 1, 0, 2, 0xff, 0xfd, 0, 4];
+    let raw = RawValue::new(&data, &ty).with_endian(Endian::Big);
+    let json = serde_json::to_string(&raw).unwrap();
+    assert_eq!(json, r#"{"B":7,"M":[[1,2],[-3,4]]}"#);
+    let value = Value::parse(&data, &ty).unwrap();
+    assert_eq!(json, serde_json::to_string(&value).unwrap());
+
+    // An array type with an invalid description, reached without checking it in a lazily
+    // parsed SDB, is an error rather than a panic.
+    let mut bytes = b.build();
+    let name = b"ARRAY [1..2, 1..2] OF INT";
+    let pos = bytes.windows(name.len()).position(|w| w == name).unwrap();
+    bytes[pos - 2..pos].copy_from_slice(&0xffffu16.to_le_bytes());
+    let sdb = Sdb::from_bytes(&bytes, ParseMode::Lazy).unwrap();
+    let param = sdb.parameters().find(|p| p.name() == ".D").unwrap();
+    let (_, ty) = param.type_info().members().nth(1).unwrap();
+    assert_eq!(ty.kind(), TypeKind::Array);
+    assert!(ty.array_info().is_none());
+    assert!(Value::parse(&data[2..], &ty).is_err());
+    assert!(serde_json::to_string(&RawValue::new(&data[2..], &ty)).is_err());
+}
+
 pub trait EncodeOpcValue {
     fn opc_encode(self, desc: &TypeInfo) -> Result<Vec<u8>>;
 }
@@ -182,8 +946,13 @@ impl EncodeOpcValue for &Value {
         match self {
             Value::Bool(b) if desc.kind() == TypeKind::Bool => return Ok(vec![*b as u8]),
             Value::Int(i) => return i.opc_encode(desc),
-            Value::Float(_) => todo!("Implement OPC value encoding for f32."),
-            Value::String(s) => return CP1252.encode(s)?.opc_encode(desc),
+            Value::Float(f) if desc.kind() == TypeKind::Real => {
+                return Ok(f.to_be_bytes().to_vec())
+            }
+            Value::String(s) => {
+                check_cp1252(s)?;
+                return CP1252.encode(s)?.opc_encode(desc);
+            }
             _ => {}
         }
         bail!("Can't encode value {:?} as {:?}", self, desc.kind())
@@ -194,23 +963,13 @@ macro_rules! impl_enc_int {
     ($($int:ty),+) => {$(
         impl EncodeOpcValue for $int {
             fn opc_encode(self, desc: &TypeInfo) -> Result<Vec<u8>> {
-                let mut ret = Vec::with_capacity(desc.response_len());
-                macro_rules! try_into {
-                    ($ty:ty) => {{
-                        let x: $ty = self
-                            .try_into()
-                            .map_err(|_| anyhow!("Int didn't fit in OPC size."))?;
-                        ret.extend_from_slice(&x.to_be_bytes());
-                    }};
-                }
-                match desc.kind() {
-                    TypeKind::Byte => try_into!(u8),
-                    TypeKind::Int => try_into!(i16),
-                    TypeKind::Word | TypeKind::Uint => try_into!(u16),
-                    TypeKind::Dword | TypeKind::Udint => try_into!(u32),
-                    _ => bail!("Can't encode value"),
+                if desc.kind().int_range().is_none() {
+                    bail!("Can't encode value");
                 }
-                Ok(ret)
+                i64::try_from(self)
+                    .ok()
+                    .and_then(|x| desc.kind().encode_int(x))
+                    .ok_or_else(|| anyhow!("Int didn't fit in OPC size."))
             }
         })+
     };
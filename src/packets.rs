@@ -1,8 +1,15 @@
-#![allow(dead_code, clippy::new_without_default)]
+// binrw's `count` expansion calls try_into() on the u16 length fields.
+#![allow(
+    dead_code,
+    clippy::new_without_default,
+    clippy::unnecessary_fallible_conversions
+)]
 
 use anyhow::{anyhow, Result};
 use binrw::{binread, binrw, binwrite, BinRead, BinResult, BinWrite, Endian};
 use rhexdump::hexdump;
+use serde::Serialize;
+use tracing::warn;
 
 use crate::opc_values::{EncodeOpcValue, Value};
 use crate::sdb;
@@ -15,9 +22,9 @@ use std::rc::Rc;
 use std::time::Duration;
 
 #[binrw]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
-#[br(big, magic = 0xCCCC0001u32)]
-#[bw(big, magic = 0xCCCC0001u32, import (payload_len_wr: u16))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, Serialize)]
+#[br(magic = 0xCCCC0001u32)]
+#[bw(magic = 0xCCCC0001u32, import (payload_len_wr: u16))]
 pub struct PacketCCHeader {
     pub u16_zero: u16,
     #[bw(map =|_| payload_len_wr)]
@@ -29,17 +36,158 @@ pub struct PacketCCHeader {
     #[bw(map =|_| payload_len_wr)]
     /// received len in response, payload_len in command
     pub len2: u16,
-    /// 0x23 in command, 0x27 in response
+    /// 0x23 in command, 0x27 in response, see [`Dialect`]
     pub b17: u8,
 }
 
 impl PacketCCHeader {
+    /// Size of the header on the wire.
+    pub const LEN: usize = 24;
+    /// Offset of the `b17` command/response marker byte.
+    pub const MARKER_OFFSET: usize = 23;
+
     pub fn new_cmd() -> Self {
         Self {
-            b17: 0x23,
+            b17: Dialect::default().command_marker(),
             ..Self::default()
         }
     }
+
+    /// Describes the fields of a response header which differ from what the
+    /// instrument has been seen to send.
+    pub fn response_anomalies(&self, dialect: Dialect) -> Vec<String> {
+        let mut anomalies = Vec::new();
+        if self.b17 != dialect.response_marker() {
+            anomalies.push(format!(
+                "marker {:#04x}, expected {:#04x}",
+                self.b17,
+                dialect.response_marker()
+            ));
+        }
+        if self.u16_zero != 0 {
+            anomalies.push(format!("u16_zero is {:#06x}", self.u16_zero));
+        }
+        anomalies
+    }
+}
+
+/// Variations of the protocol between controller runtimes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// Big-endian, as spoken by the Vacvision.
+    #[default]
+    Vacvision,
+    /// Runtimes which encode all packet fields little-endian.
+    LittleEndian,
+    /// Any other combination of byte order and header markers.
+    Custom {
+        endian: Endian,
+        command_marker: u8,
+        response_marker: u8,
+    },
+}
+
+impl Dialect {
+    pub fn endian(&self) -> Endian {
+        match self {
+            Self::Vacvision => Endian::Big,
+            Self::LittleEndian => Endian::Little,
+            Self::Custom { endian, .. } => *endian,
+        }
+    }
+
+    /// The `b17` header byte in commands.
+    pub fn command_marker(&self) -> u8 {
+        match self {
+            Self::Custom { command_marker, .. } => *command_marker,
+            _ => 0x23,
+        }
+    }
+
+    /// The `b17` header byte in responses.
+    pub fn response_marker(&self) -> u8 {
+        match self {
+            Self::Custom {
+                response_marker, ..
+            } => *response_marker,
+            _ => 0x27,
+        }
+    }
+}
+
+impl std::str::FromStr for Dialect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "vacvision" | "big-endian" => Ok(Self::Vacvision),
+            "little-endian" => Ok(Self::LittleEndian),
+            _ => Err(anyhow!(
+                "Unknown dialect '{s}', expected 'vacvision' or 'little-endian'."
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_dialect_header_encoding() {
+    use hex_literal::hex;
+    use std::io::Cursor;
+
+    let pkt = cc_payloads::SdbDownloadContinue::pkt();
+    let mut be = Vec::new();
+    pkt.write_options(&mut Cursor::new(&mut be), Dialect::Vacvision.endian(), ())
+        .unwrap();
+    assert_eq!(
+        be,
+        hex!("cccc0001 0000 0001 0000000000000000 00000000 00 0001 23 32")
+    );
+    let mut le = Vec::new();
+    pkt.write_options(
+        &mut Cursor::new(&mut le),
+        Dialect::LittleEndian.endian(),
+        (),
+    )
+    .unwrap();
+    assert_eq!(
+        le,
+        hex!("0100cccc 0000 0100 0000000000000000 00000000 00 0100 23 32")
+    );
+
+    // Decoding and encoding again gives the same bytes, including any tail.
+    for endian in [Endian::Big, Endian::Little] {
+        let mut pkt = PacketCC::new(PayloadUnknown::from([0x32]));
+        pkt.tail = vec![1, 2, 3];
+        let mut encoded = Vec::new();
+        pkt.write_options(&mut Cursor::new(&mut encoded), endian, ())
+            .unwrap();
+        assert_eq!(encoded.len(), PacketCCHeader::LEN + 4);
+        let decoded =
+            PacketCC::<PayloadUnknown>::read_options(&mut Cursor::new(&encoded), endian, ())
+                .unwrap();
+        let mut again = Vec::new();
+        decoded
+            .write_options(&mut Cursor::new(&mut again), endian, ())
+            .unwrap();
+        assert_eq!(again, encoded);
+    }
+}
+
+#[test]
+fn test_response_anomalies() {
+    let mut hdr = PacketCCHeader {
+        payload_len: 10,
+        len2: 10,
+        b17: 0x27,
+        ..Default::default()
+    };
+    assert!(hdr.response_anomalies(Dialect::Vacvision).is_empty());
+    // len2 of a response is the length of the request it answers.
+    hdr.len2 = 4;
+    assert!(hdr.response_anomalies(Dialect::Vacvision).is_empty());
+    hdr.b17 = 0x23;
+    hdr.u16_zero = 1;
+    assert_eq!(hdr.response_anomalies(Dialect::Vacvision).len(), 2);
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -55,10 +203,51 @@ where
     PacketCC<'p, Self::Response<'p>>: BinRead + 'p,
 {
     /// The type used for decoding the query response
-    type Response<'r>: BinRead;
+    type Response<'r>: BinRead + DeviceStatus;
     fn get_response_read_arg(&self) -> <PacketCC<'p, Self::Response<'p>> as BinRead>::Args<'p>;
 }
 
+/// Access to the status code reported by the instrument in a response payload.
+pub trait DeviceStatus {
+    /// The error code in the response, if the payload has one. Zero means success.
+    fn error_code(&self) -> Option<u16> {
+        None
+    }
+
+    /// The status bytes in front of each value read, for responses with values.
+    fn param_statuses(&self) -> &[ParamStatus] {
+        &[]
+    }
+}
+
+/// The byte in front of each value in a read response.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamStatus {
+    /// `1`, the value follows. The only status the Vacvision sends.
+    Ok,
+    /// Sent by some firmware variants. Whether a value follows, and how long it is, isn't
+    /// known, so a response isn't decoded past it.
+    Unknown(u8),
+}
+
+impl From<u8> for ParamStatus {
+    fn from(b: u8) -> Self {
+        match b {
+            1 => Self::Ok,
+            b => Self::Unknown(b),
+        }
+    }
+}
+
+impl fmt::Display for ParamStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => f.write_str("0x01 (ok)"),
+            Self::Unknown(b) => write!(f, "{b:#04x} (unknown)"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ReadArgs<T: Clone> {
     hdr: PacketCCHeader,
@@ -104,6 +293,8 @@ impl<'a, P: BinWrite<Args<'a> = ()>> BinWrite for PacketCC<'_, P> {
         self.hdr.write_options(writer, options, (0,))?;
         let payload_start = writer.stream_position()?;
         self.payload.write_options(writer, options, ())?;
+        // The tail holds whatever the payload didn't parse, it counts towards the length.
+        writer.write_all(&self.tail)?;
         let len: u16 = (writer.stream_position()? - payload_start)
             .try_into()
             .expect("Payload length too big.");
@@ -125,12 +316,38 @@ impl<P: BinWrite> PacketCC<'_, P> {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[binrw]
-#[br(big, import_raw(arg: ReadArgs<()>))]
+#[br(import_raw(arg: ReadArgs<()>))]
 pub struct PayloadUnknown {
     #[br(count = arg.hdr.payload_len)]
     pub data: Vec<u8>,
 }
 
+impl DeviceStatus for PayloadUnknown {}
+
+/// Sends payload bytes as they are, for exploring the protocol.
+impl QueryPacket<'static> for PayloadUnknown {
+    type Response<'p> = PayloadUnknown;
+    fn get_response_read_arg(&self) -> <PacketCC<'_, Self::Response<'_>> as BinRead>::Args<'_> {}
+}
+
+impl PayloadUnknown {
+    /// Parses hex digits, ignoring whitespace, e.g. `"2e00 0000 0001"`.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(anyhow!("Odd number of hex digits."));
+        }
+        let data = digits
+            .chunks(2)
+            .map(|pair| {
+                let pair = std::str::from_utf8(pair)?;
+                u8::from_str_radix(pair, 16).map_err(|_| anyhow!("Invalid hex byte '{pair}'."))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { data })
+    }
+}
+
 impl<T: AsRef<[u8]>> From<T> for PayloadUnknown {
     fn from(d: T) -> Self {
         Self {
@@ -142,7 +359,7 @@ impl<T: AsRef<[u8]>> From<T> for PayloadUnknown {
 /// Encodes a parameter read command.
 #[binwrite]
 #[derive(Clone, Debug)]
-#[bw(big, magic = 0x2e00u16)]
+#[bw(magic = 0x2e00u16)]
 pub struct ParamsReadQuery<'sdb> {
     #[bw(ignore)]
     query_set: ParamQuerySet<'sdb>,
@@ -158,7 +375,9 @@ pub struct ParamsReadQuery<'sdb> {
 impl<'sdb> QueryPacket<'sdb> for ParamsReadQuery<'sdb> {
     type Response<'r> = ParamReadDynResponse<'sdb>;
 
-    fn get_response_read_arg(&self) -> <PacketCC<Self::Response<'sdb>> as BinRead>::Args<'sdb> {
+    fn get_response_read_arg(
+        &self,
+    ) -> <PacketCC<'sdb, Self::Response<'sdb>> as BinRead>::Args<'sdb> {
         self.query_set.clone()
     }
 }
@@ -173,7 +392,7 @@ impl<'sdb> ParamsReadQuery<'sdb> {
         Self {
             query_set,
             params,
-            sdb_id: sdb.sdb_id,
+            sdb_id: sdb.sdb_id(),
         }
     }
 }
@@ -181,7 +400,7 @@ impl<'sdb> ParamsReadQuery<'sdb> {
 /// Instructs the instrument to change the value of the given parameters.
 #[binwrite]
 #[derive(Clone, Debug)]
-#[bw(big, magic = 0x3c00u16)]
+#[bw(magic = 0x3c00u16)]
 pub struct PayloadParamWrite {
     #[bw(calc = params.len() as u32)]
     param_count: u32,
@@ -190,41 +409,103 @@ pub struct PayloadParamWrite {
 }
 
 impl QueryPacket<'static> for PayloadParamWrite {
-    type Response<'p> = PayloadUnknown;
-    fn get_response_read_arg(&self) -> <PacketCC<Self::Response<'_>> as BinRead>::Args<'_> {}
+    type Response<'p> = ParamWriteResponse;
+    fn get_response_read_arg(&self) -> <PacketCC<'_, Self::Response<'_>> as BinRead>::Args<'_> {}
 }
 
 impl PayloadParamWrite {
     pub fn new(sdb: &sdb::Sdb, params: &[ParamWrite]) -> Self {
         Self {
             params: params.to_vec(),
-            sdb_id: sdb.sdb_id,
+            sdb_id: sdb.sdb_id(),
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+/// The response to a parameter write. Only the leading error code is understood,
+/// it applies to the packet as a whole.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[binread]
+#[br(import_raw(arg: ReadArgs<()>))]
+pub struct ParamWriteResponse {
+    #[br(if(arg.hdr.payload_len >= 2))]
+    pub error_code: Option<u16>,
+    #[br(count = arg.hdr.payload_len.saturating_sub(2))]
+    pub data: Vec<u8>,
+}
+
+impl DeviceStatus for ParamWriteResponse {
+    fn error_code(&self) -> Option<u16> {
+        self.error_code
+    }
 }
 
 #[binwrite]
 #[derive(Clone, Debug)]
-#[bw(big, magic = 3u16)]
+#[bw(magic = 3u16)]
 pub struct ParamWrite {
+    /// The data is a single multi-byte number, which must follow the byte order of the packet.
+    #[bw(ignore)]
+    scalar: bool,
     param_id: u32,
     #[bw(calc = data.len() as u32)]
     data_len: u32,
+    #[bw(write_with = |data: &Vec<u8>, writer, endian, ()| write_param_data(data, writer, endian, *scalar))]
     data: Vec<u8>,
 }
 
 impl ParamWrite {
+    /// Writes bytes at an address, which is what parameter ids are. Used to write
+    /// values in chunks.
+    pub fn raw(address: u32, data: Vec<u8>) -> Self {
+        Self {
+            scalar: false,
+            param_id: address,
+            data,
+        }
+    }
+
     pub fn new<T: EncodeOpcValue>(param: &sdb::Parameter, data: T) -> Result<Self> {
+        use sdb::TypeKind::*;
+        let scalar = matches!(
+            param.value_kind(),
+            Int | Word | Uint | Dword | Udint | Real | Time | Pointer
+        );
         Ok(Self {
+            scalar,
             param_id: param.id(),
             data: data.opc_encode(&param.type_info())?,
         })
     }
 }
 
+/// Values are encoded big-endian, swap scalars if the packet is little-endian.
+fn write_param_data<W: Write + Seek>(
+    data: &[u8],
+    writer: &mut W,
+    endian: Endian,
+    scalar: bool,
+) -> BinResult<()> {
+    if scalar && endian == Endian::Little {
+        let swapped: Vec<u8> = data.iter().rev().copied().collect();
+        writer.write_all(&swapped)?;
+    } else {
+        writer.write_all(data)?;
+    }
+    Ok(())
+}
+
 #[binrw]
 #[derive(Copy, Clone, Debug)]
-#[bw(big, magic = 0x03u16)]
+#[bw(magic = 0x03u16)]
 pub struct ParamRead {
     param_id: u32,
     response_len: u32,
@@ -239,36 +520,219 @@ impl ParamRead {
     }
 }
 
+/// Reads bytes at addresses, which is what parameter ids are. Parameters too
+/// large for one response are read in chunks at their id plus an offset.
+#[binwrite]
+#[derive(Clone, Debug)]
+#[bw(magic = 0x2e00u16)]
+pub struct RawReadQuery {
+    #[bw(calc = reads.len() as u32)]
+    read_count: u32,
+    reads: Vec<ParamRead>,
+    sdb_id: u32,
+}
+
+impl QueryPacket<'static> for RawReadQuery {
+    type Response<'p> = RawReadResponse;
+
+    fn get_response_read_arg(&self) -> <PacketCC<'_, Self::Response<'_>> as BinRead>::Args<'_> {
+        self.reads.iter().map(|r| r.response_len).collect()
+    }
+}
+
+impl RawReadQuery {
+    /// Reads `len` bytes at each address.
+    pub fn new(sdb: &sdb::Sdb, reads: &[(u32, u32)]) -> PacketCC<'static, Self> {
+        let reads = reads
+            .iter()
+            .map(|&(address, len)| ParamRead::new(address, len))
+            .collect();
+        let mut p = PacketCC::new(Self {
+            reads,
+            sdb_id: sdb.sdb_id(),
+        });
+        p.hdr.one_if_data_poll_maybe = 1;
+        p
+    }
+}
+
+#[binread]
+#[derive(Clone, Debug)]
+#[br(import_raw(read_args: ReadArgs<Vec<u32>>))]
+pub struct RawReadResponse {
+    pub error_code: u16,
+    #[br(if(error_code == 0), map(|d:u32| Duration::from_millis(d as u64)))]
+    pub timestamp: Duration,
+    #[br(temp, if(error_code == 0), parse_with = |reader, _, ()| parse_raw_chunks(reader, &read_args.args))]
+    read: (Vec<ParamStatus>, Vec<Vec<u8>>),
+    /// The status byte in front of each chunk, up to and including the first unknown one.
+    #[br(calc = read.0)]
+    pub statuses: Vec<ParamStatus>,
+    /// The bytes read at each address, empty if the read failed, and from the first
+    /// unknown status on.
+    #[br(calc = read.1)]
+    pub chunks: Vec<Vec<u8>>,
+}
+
+fn parse_raw_chunks<R: Read + Seek>(
+    reader: &mut R,
+    lens: &[u32],
+) -> BinResult<(Vec<ParamStatus>, Vec<Vec<u8>>)> {
+    let mut statuses = Vec::with_capacity(lens.len());
+    let mut chunks = vec![Vec::new(); lens.len()];
+    for (&len, chunk) in lens.iter().zip(&mut chunks) {
+        let status = ParamStatus::from(u8::read(reader)?);
+        statuses.push(status);
+        if status != ParamStatus::Ok {
+            break;
+        }
+        chunk.resize(len as usize, 0);
+        reader.read_exact(chunk)?;
+    }
+    Ok((statuses, chunks))
+}
+
+impl DeviceStatus for RawReadResponse {
+    fn error_code(&self) -> Option<u16> {
+        Some(self.error_code)
+    }
+
+    fn param_statuses(&self) -> &[ParamStatus] {
+        &self.statuses
+    }
+}
+
+#[test]
+fn test_raw_read() {
+    use std::io::Cursor;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let query = RawReadQuery::new(&sdb, &[(0x1000, 3), (0x1003, 1)]);
+    let mut encoded = Vec::new();
+    query
+        .write_options(&mut Cursor::new(&mut encoded), Endian::Big, ())
+        .unwrap();
+    assert_eq!(encoded.len(), PacketCCHeader::LEN + 2 + 4 + 2 * 10 + 4);
+
+    let response = PacketCCHeader {
+        payload_len: 2 + 4 + 4 + 2,
+        len2: 2 + 4 + 4 + 2,
+        b17: 0x27,
+        ..Default::default()
+    };
+    let mut bytes = Vec::new();
+    response
+        .write_options(
+            &mut Cursor::new(&mut bytes),
+            Endian::Big,
+            (response.payload_len,),
+        )
+        .unwrap();
+    // The byte after the unknown status isn't taken for its value.
+    bytes.extend([0, 0, 0, 0, 0, 9, 1, b'a', b'b', b'c', 3, b'd']);
+    let r = PacketCC::<RawReadResponse>::read_options(
+        &mut Cursor::new(&bytes),
+        Endian::Big,
+        query.payload.get_response_read_arg(),
+    )
+    .unwrap();
+    assert_eq!(r.payload.chunks, [b"abc".to_vec(), vec![]]);
+    assert_eq!(
+        r.payload.param_statuses(),
+        [ParamStatus::Ok, ParamStatus::Unknown(3)]
+    );
+}
+
 #[binread]
 #[derive(Clone)]
-#[br(big, import_raw(read_args: ReadArgs<ParamQuerySet<'sdb>>))]
+#[br(import_raw(read_args: ReadArgs<ParamQuerySet<'sdb>>))]
 pub struct ParamReadDynResponse<'sdb> {
     pub error_code: u16,
-    #[br(map(|d:u32| Duration::from_millis(d as u64)))]
+    /// Refused queries are answered with the error code only.
+    #[br(if(error_code == 0), map(|d:u32| Duration::from_millis(d as u64)))]
     pub timestamp: Duration,
-    #[br(parse_with = |reader,_,()| parse_dyn_payload(reader, &read_args.args.0))]
-    pub data: Vec<Value>,
+    #[br(temp, if(error_code == 0))]
+    #[br(parse_with = |reader, endian, ()| parse_dyn_payload(reader, endian, &read_args.args.0))]
+    read: (Vec<ParamStatus>, Vec<Option<Value>>),
+    /// The status byte in front of each value, up to and including the first unknown one.
+    #[br(calc = read.0)]
+    pub statuses: Vec<ParamStatus>,
+    /// The values, `None` from the first unknown status on.
+    #[br(calc = read.1)]
+    pub data: Vec<Option<Value>>,
     #[br(calc = read_args.args)]
     pub query_set: ParamQuerySet<'sdb>,
 }
 
+#[test]
+fn test_refused_read() {
+    use std::io::Cursor;
+
+    let response = PacketCCHeader {
+        payload_len: 2,
+        len2: 2,
+        b17: 0x27,
+        ..Default::default()
+    };
+    let mut bytes = Vec::new();
+    response
+        .write_options(&mut Cursor::new(&mut bytes), Endian::Big, (2,))
+        .unwrap();
+    bytes.extend([0x00, 0x01]);
+    let r = PacketCC::<ParamReadDynResponse>::read_options(
+        &mut Cursor::new(&bytes),
+        Endian::Big,
+        ParamQuerySet(Rc::from([])),
+    )
+    .unwrap();
+    assert_eq!(r.payload.error_code(), Some(1));
+    assert!(r.payload.data.is_empty());
+
+    // Version responses too short for their fields are parse errors, not panics.
+    let version = |error_code: u16| {
+        let mut bytes = bytes.clone();
+        bytes[24..].copy_from_slice(&error_code.to_be_bytes());
+        PacketCC::<cc_payloads::InstrumentVersionResponse>::read_options(
+            &mut Cursor::new(&bytes),
+            Endian::Big,
+            (),
+        )
+    };
+    assert_eq!(version(1).unwrap().payload.error_code(), Some(1));
+    assert!(version(0).is_err());
+}
+
 fn parse_dyn_payload<R: Read + Seek>(
     reader: &mut R,
+    endian: Endian,
     params: &[sdb::Parameter],
-) -> BinResult<Vec<Value>> {
-    params
-        .iter()
-        .map(|param| {
-            let one = u8::read(reader)?;
-            assert_eq!(one, 1, "Bad magic at start of parameter response payload.");
-            Value::read_args(reader, param.type_info())
-        })
-        .collect()
+) -> BinResult<(Vec<ParamStatus>, Vec<Option<Value>>)> {
+    let mut statuses = Vec::with_capacity(params.len());
+    let mut values = vec![None; params.len()];
+    for (param, value) in params.iter().zip(&mut values) {
+        let status = ParamStatus::from(u8::read(reader)?);
+        statuses.push(status);
+        if status != ParamStatus::Ok {
+            break;
+        }
+        *value = Some(Value::read_options(reader, endian, param.type_info())?);
+    }
+    Ok((statuses, values))
+}
+
+impl DeviceStatus for ParamReadDynResponse<'_> {
+    fn error_code(&self) -> Option<u16> {
+        Some(self.error_code)
+    }
+
+    fn param_statuses(&self) -> &[ParamStatus] {
+        &self.statuses
+    }
 }
 
 impl Debug for ParamReadDynResponse<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        struct DbgMapHelper<'a>(&'a ParamQuerySet<'a>, &'a [Value]);
+        struct DbgMapHelper<'a>(&'a ParamQuerySet<'a>, &'a [Option<Value>]);
         impl Debug for DbgMapHelper<'_> {
             fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
                 let mut m = f.debug_map();
@@ -288,22 +752,54 @@ impl Debug for ParamReadDynResponse<'_> {
 }
 
 impl<'sdb> ParamReadDynResponse<'sdb> {
+    // Parameters hash by index and SDB address, the lazily parsed types don't matter.
+    #[allow(clippy::mutable_key_type)]
     pub fn into_hashmap(self) -> HashMap<sdb::Parameter<'sdb>, Value> {
-        self.query_set
-            .0
-            .iter()
-            .cloned()
-            .zip(self.data.into_iter())
-            .collect()
+        let values = self.query_set.0.iter().cloned().zip(self.data);
+        values.filter_map(|(p, v)| Some((p, v?))).collect()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&sdb::Parameter, &Value)> {
-        self.query_set.0.iter().zip(self.data.iter())
+    /// The parameters with their values, skipping those without one.
+    pub fn iter(&self) -> impl Iterator<Item = (&sdb::Parameter<'_>, &Value)> {
+        let values = self.query_set.0.iter().zip(self.data.iter());
+        values.filter_map(|(p, v)| Some((p, v.as_ref()?)))
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct ParamQuerySetBuilder<'sdb>(Vec<sdb::Parameter<'sdb>>, &'sdb sdb::Sdb);
+pub struct ParamQuerySetBuilder<'sdb> {
+    params: Vec<sdb::Parameter<'sdb>>,
+    /// Only `None` for a builder collected from an empty iterator.
+    sdb: Option<&'sdb sdb::Sdb>,
+    dedup: bool,
+    max_response_len: Option<usize>,
+}
+
+/// A parameter whose value alone is longer than the response size limit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParamTooLarge {
+    pub name: String,
+    pub response_len: usize,
+    pub max_response_len: usize,
+    /// The first of its array elements or struct members, if it has any.
+    pub first_element: Option<String>,
+}
+
+impl fmt::Display for ParamTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has {} bytes, more than the response size limit of {}.",
+            self.name, self.response_len, self.max_response_len
+        )?;
+        match &self.first_element {
+            Some(element) => write!(f, " Read its elements instead, e.g. {element}."),
+            None => f.write_str(" It can't be read in one query."),
+        }
+    }
+}
+
+impl std::error::Error for ParamTooLarge {}
 
 #[derive(Debug, Clone)]
 // Use Rc instead of Box, since Clone is required
@@ -311,27 +807,184 @@ pub struct ParamQuerySet<'sdb>(pub Rc<[sdb::Parameter<'sdb>]>);
 
 impl<'sdb> ParamQuerySetBuilder<'sdb> {
     pub fn new(sdb: &'sdb sdb::Sdb) -> Self {
-        Self(vec![], sdb.get_ref())
+        Self {
+            params: vec![],
+            sdb: Some(sdb.get_ref()),
+            dedup: false,
+            max_response_len: None,
+        }
+    }
+
+    /// Skip parameters which are already in the set, with a warning. The instrument
+    /// sends, and counts towards the response size limit, every copy of a parameter.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
     }
+
+    /// Rejects parameters longer than `len` bytes of response, see [`Self::add_param`].
+    /// The limit applies to each parameter, a set of them is split by the
+    /// [`Client`](crate::client::Client).
+    pub fn max_response_len(mut self, len: usize) -> Self {
+        self.max_response_len = Some(len);
+        self
+    }
+
     pub fn add(&mut self, name: &str) -> Result<()> {
-        self.0.push(self.1.param_by_name(name)?);
-        Ok(())
+        let sdb = self
+            .sdb
+            .ok_or_else(|| anyhow!("No SDB to look up {name} in."))?;
+        self.add_param(sdb.param_by_name(name)?)
     }
-    pub fn add_param(&mut self, param: sdb::Parameter<'sdb>) {
-        self.0.push(param);
+
+    /// Adds the parameter, failing with [`ParamTooLarge`] if it exceeds the response
+    /// size limit.
+    pub fn add_param(&mut self, param: sdb::Parameter<'sdb>) -> Result<()> {
+        let response_len = param.wire_cost().response;
+        match self.max_response_len {
+            Some(max_response_len) if response_len > max_response_len => Err(ParamTooLarge {
+                name: param.name().to_string(),
+                response_len,
+                max_response_len,
+                first_element: param.elements().first().map(|e| e.name().to_string()),
+            }
+            .into()),
+            _ => {
+                self.push(param);
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds the parameter, or if it exceeds the response size limit its array elements
+    /// or struct members, split further as required. Returns the number of parameters
+    /// added. Fails for parameters without elements, such as long strings.
+    pub fn add_split(&mut self, param: sdb::Parameter<'sdb>) -> Result<usize> {
+        let elements = match self.add_param(param.clone()) {
+            Ok(()) => return Ok(1),
+            Err(e) => match param.elements() {
+                elements if elements.is_empty() => return Err(e),
+                elements => elements,
+            },
+        };
+        let mut added = 0;
+        for element in elements {
+            added += self.add_split(element)?;
+        }
+        Ok(added)
+    }
+
+    fn push(&mut self, param: sdb::Parameter<'sdb>) {
+        if self.dedup && self.params.contains(&param) {
+            warn!(
+                "Parameter {} is already in the query, skipping it.",
+                param.name()
+            );
+            return;
+        }
+        self.sdb.get_or_insert(param.sdb());
+        self.params.push(param);
+    }
+
+    /// Adds all visible parameters with names starting with `prefix`. Only scalar and
+    /// string values are added, since arrays and structs overlap their own elements.
+    /// Returns the number of parameters added.
+    pub fn add_all(&mut self, prefix: &str) -> Result<usize> {
+        let sdb = self
+            .sdb
+            .ok_or_else(|| anyhow!("No SDB to look up {prefix} in."))?;
+        let before = self.params.len();
+        for param in sdb.scalar_parameters() {
+            if param.name().starts_with(prefix) {
+                self.add_param(param)?;
+            }
+        }
+        match self.params.len() - before {
+            0 => Err(anyhow!("No parameters start with {prefix}.")),
+            n => Ok(n),
+        }
     }
 
+    /// # Panics
+    /// If the builder was collected from an empty iterator, and so has no SDB.
     pub fn into_query_packet(self) -> PacketCC<'sdb, ParamsReadQuery<'sdb>> {
-        let mut p = PacketCC::new(ParamsReadQuery::new(self.1, ParamQuerySet(self.0.into())));
+        let sdb = self.sdb.expect("query set without SDB");
+        let mut p = PacketCC::new(ParamsReadQuery::new(sdb, ParamQuerySet(self.params.into())));
         p.hdr.one_if_data_poll_maybe = 1;
         p
     }
 
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn params(&self) -> &[sdb::Parameter<'sdb>] {
+        &self.params
+    }
+
+    /// The response length of the query, as counted by the instrument's response size
+    /// limit.
+    pub fn estimated_response_len(&self) -> usize {
+        self.params.iter().map(|p| p.wire_cost().response).sum()
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.params.is_empty()
+    }
+}
+
+/// Adds the parameters without checking the response size limit.
+impl<'sdb> Extend<sdb::Parameter<'sdb>> for ParamQuerySetBuilder<'sdb> {
+    fn extend<T: IntoIterator<Item = sdb::Parameter<'sdb>>>(&mut self, iter: T) {
+        for param in iter {
+            self.push(param);
+        }
+    }
+}
+
+impl<'sdb> FromIterator<sdb::Parameter<'sdb>> for ParamQuerySetBuilder<'sdb> {
+    fn from_iter<T: IntoIterator<Item = sdb::Parameter<'sdb>>>(iter: T) -> Self {
+        let mut builder = Self {
+            params: vec![],
+            sdb: None,
+            dedup: false,
+            max_response_len: None,
+        };
+        builder.extend(iter);
+        builder
     }
 }
 
+#[test]
+fn test_query_set_builder() {
+    let sdb = crate::sdb_builder::test_sdb();
+    let mut builder = ParamQuerySetBuilder::new(&sdb).dedup(true);
+    builder.add(".CockpitUser").unwrap();
+    builder.add(".CockpitUser").unwrap();
+    assert_eq!(builder.len(), 1);
+    let cockpit_user = sdb.param_by_name(".CockpitUser").unwrap();
+    assert_eq!(
+        builder.estimated_response_len(),
+        cockpit_user.wire_cost().response
+    );
+    let n = builder.add_all(".Gauge[1].Parameter[1].").unwrap();
+    assert_eq!(builder.len(), 1 + n);
+    assert!(builder.add_all(".NoSuchParameter").is_err());
+
+    let mut limited = ParamQuerySetBuilder::new(&sdb).max_response_len(200);
+    let table = sdb.param_by_name(".Gauge[1].Parameter").unwrap();
+    let e = limited.add_param(table.clone()).unwrap_err();
+    let e = e.downcast_ref::<ParamTooLarge>().unwrap();
+    assert_eq!(e.first_element.as_deref(), Some(".Gauge[1].Parameter[1]"));
+    assert_eq!(limited.add_split(table).unwrap(), 5);
+    let mut tiny = ParamQuerySetBuilder::new(&sdb).max_response_len(50);
+    assert!(tiny.add_split(cockpit_user).is_err());
+
+    let collected: ParamQuerySetBuilder = sdb.parameters().take(3).collect();
+    assert_eq!(collected.len(), 3);
+    collected.into_query_packet();
+}
+
 pub mod cc_payloads {
     /// Specific command-reply CC packet payloads for various purposes,
     /// reconstructed from Wireshark captures.
@@ -339,28 +992,59 @@ pub mod cc_payloads {
 
     #[binwrite]
     #[derive(Clone, Debug)]
-    #[bw(big, magic = 0x11u8)]
+    #[bw(magic = 0x11u8)]
     pub struct InstrumentVersionQuery;
 
+    impl InstrumentVersionQuery {
+        pub fn pkt() -> PacketCC<'static, Self> {
+            PacketCC::new(Self)
+        }
+    }
+
     impl QueryPacket<'static> for InstrumentVersionQuery {
         type Response<'p> = InstrumentVersionResponse;
-        fn get_response_read_arg(&self) -> <PacketCC<Self::Response<'_>> as BinRead>::Args<'_> {}
+        fn get_response_read_arg(&self) -> <PacketCC<'_, Self::Response<'_>> as BinRead>::Args<'_> {
+        }
     }
 
     #[binread]
     #[derive(Clone, Debug)]
-    #[br(big, import_raw(args: ReadArgs<()>))]
+    #[br(import_raw(args: ReadArgs<()>))]
     pub struct InstrumentVersionResponse {
-        error_code: u16,  // ??
-        sdb_version: u32, // 0x 00 02 53 34
-        u32_0: u32,       // 0x 57 db e3 ce
-        #[br(count = args.hdr.payload_len - (2+4+4))]
-        str_descr: Vec<u8>,
+        #[br(assert(
+            error_code != 0 || args.hdr.payload_len >= 2 + 4 + 4,
+            "Version response of {} bytes too short.", args.hdr.payload_len
+        ))]
+        pub error_code: u16, // ??
+        #[br(if(error_code == 0))]
+        pub sdb_version: u32, // 0x 00 02 53 34
+        #[br(if(error_code == 0))]
+        pub u32_0: u32, // 0x 57 db e3 ce
+        #[br(if(error_code == 0), count = args.hdr.payload_len.saturating_sub(2 + 4 + 4))]
+        pub str_descr: Vec<u8>,
+    }
+
+    impl InstrumentVersionResponse {
+        /// The runtime description text, without NUL padding.
+        pub fn description(&self) -> String {
+            let end = self
+                .str_descr
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(self.str_descr.len());
+            String::from_utf8_lossy(&self.str_descr[..end]).into_owned()
+        }
+    }
+
+    impl DeviceStatus for InstrumentVersionResponse {
+        fn error_code(&self) -> Option<u16> {
+            Some(self.error_code)
+        }
     }
 
     #[binwrite]
     #[derive(Clone, Debug)]
-    #[bw(big, magic = 0x34u8)]
+    #[bw(magic = 0x34u8)]
     pub struct SdbVersionQuery {
         x: &'static [u8],
     }
@@ -380,21 +1064,30 @@ pub mod cc_payloads {
 
     impl QueryPacket<'static> for SdbVersionQuery {
         type Response<'p> = SdbVersionResponse;
-        fn get_response_read_arg(&self) -> <PacketCC<Self::Response<'_>> as BinRead>::Args<'_> {}
+        fn get_response_read_arg(&self) -> <PacketCC<'_, Self::Response<'_>> as BinRead>::Args<'_> {
+        }
     }
 
     #[binread]
     #[derive(Clone, Debug)]
-    #[br(big, import_raw(_hdr:ReadArgs<()>))]
+    #[br(import_raw(_hdr:ReadArgs<()>))]
     pub struct SdbVersionResponse {
         pub error_code: u16,
+        #[br(if(error_code == 0))]
         pub sbd_size: u32,
+        #[br(if(error_code == 0))]
         pub data: [u8; 4 * 4],
     }
 
+    impl DeviceStatus for SdbVersionResponse {
+        fn error_code(&self) -> Option<u16> {
+            Some(self.error_code)
+        }
+    }
+
     #[binwrite]
     #[derive(Clone, Debug)]
-    #[bw(big, magic = 0x31u8)]
+    #[bw(magic = 0x31u8)]
     pub struct SdbDownloadRequest {
         x: &'static [u8],
     }
@@ -414,12 +1107,13 @@ pub mod cc_payloads {
 
     impl QueryPacket<'static> for SdbDownloadRequest {
         type Response<'p> = SdbDownload;
-        fn get_response_read_arg(&self) -> <PacketCC<Self::Response<'_>> as BinRead>::Args<'_> {}
+        fn get_response_read_arg(&self) -> <PacketCC<'_, Self::Response<'_>> as BinRead>::Args<'_> {
+        }
     }
 
     #[binwrite]
     #[derive(Clone, Debug)]
-    #[bw(big, magic = 0x32u8)]
+    #[bw(magic = 0x32u8)]
     pub struct SdbDownloadContinue;
 
     impl SdbDownloadContinue {
@@ -430,12 +1124,13 @@ pub mod cc_payloads {
 
     impl QueryPacket<'static> for SdbDownloadContinue {
         type Response<'p> = SdbDownload;
-        fn get_response_read_arg(&self) -> <PacketCC<Self::Response<'_>> as BinRead>::Args<'_> {}
+        fn get_response_read_arg(&self) -> <PacketCC<'_, Self::Response<'_>> as BinRead>::Args<'_> {
+        }
     }
 
     #[binread]
     #[derive(Clone)]
-    #[br(big, import_raw(_hdr: ReadArgs<()>))]
+    #[br(import_raw(_hdr: ReadArgs<()>))]
     pub struct SdbDownload {
         #[br(try_map(|x:u32|match x {0 => Ok(false), 1 => Ok(true), _ => Err(anyhow!("Unexpected in continues field."))}))]
         pub continues: bool, // 0 if this is the last packet, 1 otherwise
@@ -444,13 +1139,15 @@ pub mod cc_payloads {
         pub sdb_part: Vec<u8>,
     }
 
+    impl DeviceStatus for SdbDownload {}
+
     impl Debug for SdbDownload {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
             write!(
                 f,
                 "PayloadSdbDownload {{\n continues: {},\n{}}}",
                 self.continues,
-                hexdump(&self.sdb_part[0..100]),
+                hexdump(&self.sdb_part[..self.sdb_part.len().min(100)]),
             )
         }
     }
@@ -1,53 +1,740 @@
-use std::io::{Cursor, Read, Write};
-use std::net::{IpAddr, TcpStream};
-use std::time::Duration;
+use std::fmt::Debug;
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::net::{SocketAddr, SocketAddrV6, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use binrw::{BinRead, BinReaderExt, BinWrite};
-use tracing::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::{debug, info, warn};
 
+use crate::clock::{Clock, SystemClock};
+use crate::errors::device::ErrorCode;
+use crate::events::{ConnectionEvent, ConnectionEvents};
+use crate::host::{Bind, Host};
 use crate::packets::cc_payloads::*;
-use crate::packets::{PacketCC, PacketCCHeader, QueryPacket};
+use crate::packets::{
+    DeviceStatus, Dialect, PacketCC, PacketCCHeader, ParamStatus, PayloadUnknown, QueryPacket,
+};
+use crate::sdb::{ParseMode, Sdb, SdbHeader};
+use crate::sdb_store::{versioned_file_name, write_atomic};
+use crate::session::{SessionLock, SessionRefused};
+use crate::tunnel::{Tunnel, Via};
+
+/// The TCP port the PLC listens on.
+pub const PLC_PORT: u16 = 1202;
+
+/// The longest one attempt to download the SDB may take, however steadily the
+/// instrument answers.
+pub const SDB_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The number of SDB parts without data in a row after which a download is given up.
+const MAX_EMPTY_SDB_PARTS: u32 = 3;
+
+/// The instrument's answer to the "66 66" acknowledgement following each query.
+pub(crate) const ACK_RESPONSE: [u8; 24] =
+    hex_literal::hex!("66 66 00 00 00 00 00 00  00 00 00 00 00 00 00 19  00 00 00 00 00 00 00 04");
+
+/// The socket to the PLC: TCP, or a Unix socket to a broker.
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Decides which device error codes are worth retrying, and how often.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Number of times a query is re-sent after a transient error code.
+    pub retries: u32,
+    /// Time to wait before re-sending a query.
+    pub delay: Duration,
+    /// Error codes which indicate a transient condition, such as the device being busy.
+    pub transient_codes: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            delay: Duration::from_millis(100),
+            transient_codes: vec![],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_transient(&self, error_code: u16) -> bool {
+        self.transient_codes.contains(&error_code)
+    }
+}
+
+/// Sees every packet sent and received on a [`Connection`], e.g. for logging,
+/// metrics, captures or assertions in tests. See [`Connection::set_observer`].
+///
+/// The decoded packet is `None` for the protocol acknowledgements, and for
+/// responses that failed to parse.
+pub trait PacketObserver: Send {
+    fn on_send(&mut self, _raw: &[u8], _decoded: Option<&dyn Debug>) {}
+    fn on_receive(&mut self, _raw: &[u8], _decoded: Option<&dyn Debug>) {}
+}
+
+/// Passes the packets to both observers.
+impl<A: PacketObserver, B: PacketObserver> PacketObserver for (A, B) {
+    fn on_send(&mut self, raw: &[u8], decoded: Option<&dyn Debug>) {
+        self.0.on_send(raw, decoded);
+        self.1.on_send(raw, decoded);
+    }
+
+    fn on_receive(&mut self, raw: &[u8], decoded: Option<&dyn Debug>) {
+        self.0.on_receive(raw, decoded);
+        self.1.on_receive(raw, decoded);
+    }
+}
+
+/// Passes the packets to the observer, if there is one.
+impl<T: PacketObserver> PacketObserver for Option<T> {
+    fn on_send(&mut self, raw: &[u8], decoded: Option<&dyn Debug>) {
+        if let Some(observer) = self {
+            observer.on_send(raw, decoded);
+        }
+    }
+
+    fn on_receive(&mut self, raw: &[u8], decoded: Option<&dyn Debug>) {
+        if let Some(observer) = self {
+            observer.on_receive(raw, decoded);
+        }
+    }
+}
+
+/// A query packet serialized once for a given dialect, to be sent repeatedly
+/// without encoding it again. See [`Connection::encode`].
+#[derive(Clone, Debug)]
+pub struct EncodedQuery<'p, Cmd> {
+    packet: PacketCC<'p, Cmd>,
+    bytes: Vec<u8>,
+    dialect: Dialect,
+}
+
+impl<'p, Cmd> EncodedQuery<'p, Cmd> {
+    pub fn packet(&self) -> &PacketCC<'p, Cmd> {
+        &self.packet
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// The device is too busy to answer, either reported with a transient error code which
+/// persisted through the retries, or by not answering in time.
+///
+/// Returned inside the [`anyhow::Error`] of a query, find it with `downcast_ref`. When the
+/// response timed out it may still arrive later, so the connection should be reopened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceBusy {
+    /// The transient error code, `None` for a timeout.
+    pub error_code: Option<u16>,
+    pub retries: u32,
+}
+
+impl std::fmt::Display for DeviceBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.error_code {
+            Some(code) => write!(
+                f,
+                "Device returned transient error code {}, gave up after {} retries.",
+                ErrorCode(code),
+                self.retries
+            ),
+            None => f.write_str("Device busy, no response in time."),
+        }
+    }
+}
+
+impl std::error::Error for DeviceBusy {}
+
+/// A response with payload bytes left over after parsing. Only an error in strict mode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameLengthMismatch {
+    pub payload_len: u16,
+    /// The bytes of the payload the response was parsed from.
+    pub parsed: usize,
+}
+
+impl std::fmt::Display for FrameLengthMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Response payload has {} bytes, {} of them parsed.",
+            self.payload_len, self.parsed
+        )
+    }
+}
+
+impl std::error::Error for FrameLengthMismatch {}
+
+/// A response which ended, or stopped arriving, before the payload length in its
+/// header, as seen when frames are truncated on congested networks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TruncatedFrame {
+    pub payload_len: u16,
+    /// The bytes of the payload received.
+    pub received: usize,
+}
+
+impl std::fmt::Display for TruncatedFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Response truncated: {} of {} payload bytes received.",
+            self.received, self.payload_len
+        )
+    }
+}
+
+impl std::error::Error for TruncatedFrame {}
 
 pub struct Connection {
-    stream: TcpStream,
+    stream: Stream,
+    retry: RetryPolicy,
+    dialect: Dialect,
+    /// Kept alive for as long as the connection uses it.
+    tunnel: Option<Tunnel>,
+    /// Held by connections to an instrument, not by those to simulators or brokers.
+    session: Option<SessionLock>,
+    /// Whether connected to an instrument, rather than to a broker or simulator.
+    direct: bool,
+    /// Whether any query has been answered, to tell refused sessions from lost ones.
+    answered: bool,
+    /// Reused between packets, so that polling doesn't allocate for every query.
+    send_buf: Vec<u8>,
+    recv_buf: Vec<u8>,
+    observer: Option<Box<dyn PacketObserver>>,
+    keep_alive: Option<Duration>,
+    min_gap: Option<Duration>,
+    last_activity: Instant,
+    strict: bool,
+    clock: Arc<dyn Clock>,
+    /// Taken when `Disconnected` is emitted, so that it's emitted once.
+    events: Option<ConnectionEvents>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.disconnected("Connection closed.".into());
+    }
 }
 
 impl Connection {
-    pub fn connect(ip: IpAddr) -> anyhow::Result<Self> {
-        debug!("Connecting to PLC at {}:1202", ip);
-        let stream = TcpStream::connect_timeout(&(ip, 1202).into(), Duration::from_secs(1))
+    /// Connects to the PLC at `host`, looking up host names. See
+    /// [`set_session_lock`](Self::set_session_lock) to keep other processes out.
+    pub fn connect(host: &Host) -> anyhow::Result<Self> {
+        let mut conn = Self::connect_addr(host.socket_addr(PLC_PORT)?)?;
+        conn.direct = true;
+        Ok(conn)
+    }
+
+    /// Connects to the PLC at `host` through a tunnel. Host names are looked up at the
+    /// other end of SSH tunnels.
+    pub fn connect_via(host: &Host, via: &Via) -> anyhow::Result<Self> {
+        let tunnel = Tunnel::open(via, host, PLC_PORT)?;
+        let mut conn = Self::connect_addr(tunnel.local_addr())?;
+        conn.tunnel = Some(tunnel);
+        conn.direct = true;
+        Ok(conn)
+    }
+
+    /// Connects to the PLC at `host` from the given local address or interface.
+    pub fn connect_bound(host: &Host, bind: &Bind) -> anyhow::Result<Self> {
+        let addr = host.socket_addr(PLC_PORT)?;
+        debug!("Connecting to PLC at {addr} from {bind}");
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        match bind {
+            Bind::Addr(ip) => socket
+                .bind(&SocketAddr::new(*ip, 0).into())
+                .with_context(|| format!("Failed to bind to {ip}"))?,
+            Bind::Scoped(ip, scope) => socket
+                .bind(&SocketAddrV6::new(*ip, 0, 0, *scope).into())
+                .with_context(|| format!("Failed to bind to {bind}"))?,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Bind::Interface(name) => socket
+                .bind_device(Some(name.as_bytes()))
+                .with_context(|| format!("Failed to bind to interface {name}"))?,
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            Bind::Interface(name) => {
+                bail!("Binding to interface {name} is only supported on Linux, give its address instead.")
+            }
+        }
+        socket
+            .connect_timeout(&addr.into(), Duration::from_secs(1))
+            .context("Failed to connect to PLC")?;
+        let mut conn = Self::from_stream(Stream::Tcp(socket.into()))?;
+        conn.direct = true;
+        Ok(conn)
+    }
+
+    pub fn connect_addr(addr: SocketAddr) -> anyhow::Result<Self> {
+        debug!("Connecting to PLC at {}", addr);
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(1))
             .context("Failed to connect to PLC")?;
+        Self::from_stream(Stream::Tcp(stream))
+    }
+
+    /// Connects to a [`Broker`](crate::broker::Broker) sharing its connection to the PLC.
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        debug!("Connecting to broker at {}", path.display());
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to broker at {}", path.display()))?;
+        Self::from_stream(Stream::Unix(stream))
+    }
+
+    fn from_stream(stream: Stream) -> anyhow::Result<Self> {
         stream.set_read_timeout(Some(Duration::from_secs(2)))?;
-        Ok(Self { stream })
+        Ok(Self {
+            stream,
+            retry: RetryPolicy::default(),
+            dialect: Dialect::default(),
+            tunnel: None,
+            session: None,
+            direct: false,
+            answered: false,
+            send_buf: Vec::new(),
+            recv_buf: Vec::new(),
+            observer: None,
+            keep_alive: None,
+            min_gap: None,
+            last_activity: Instant::now(),
+            strict: false,
+            clock: Arc::new(SystemClock),
+            events: None,
+        })
+    }
+
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// In strict mode, responses which deviate from the known protocol are errors,
+    /// otherwise they are only logged. Responses with payload left over after parsing
+    /// fail with [`FrameLengthMismatch`].
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// How long to wait for a response before the query fails. Defaults to two seconds.
+    pub fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        Ok(())
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// The clock of [`Connection::idle`], the keep-alive timing and the waits between
+    /// retries.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.last_activity = clock.now();
+        self.clock = clock;
     }
 
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Some firmwares drop idle connections. With a keep-alive interval set, [`Connection::idle`]
+    /// sends a version query whenever the connection has been idle for that long.
+    pub fn set_keep_alive(&mut self, interval: Option<Duration>) {
+        self.keep_alive = interval;
+    }
+
+    /// Waits at least `gap` after each response before sending the next query, to
+    /// spread the load on links shared with other clients.
+    pub fn set_min_gap(&mut self, gap: Option<Duration>) {
+        self.min_gap = gap;
+    }
+
+    fn wait_min_gap(&self) {
+        let Some(gap) = self.min_gap else {
+            return;
+        };
+        let next = self.last_activity + gap;
+        loop {
+            let wait = next.saturating_duration_since(self.clock.now());
+            if wait.is_zero() {
+                return;
+            }
+            self.clock.park_timeout(wait);
+        }
+    }
+
+    /// Sends a keep-alive query if the keep-alive interval has passed since the last query.
+    /// Returns whether a query was sent.
+    pub fn keep_alive(&mut self) -> Result<bool> {
+        match self.keep_alive {
+            Some(interval) if self.idle_time() >= interval => {
+                debug!("Sending keep-alive query.");
+                self.query(&InstrumentVersionQuery::pkt())
+                    .context("Keep-alive query failed")?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// The time since the last query.
+    pub fn idle_time(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.last_activity)
+    }
+
+    /// Waits for `duration`, keeping the connection alive meanwhile.
+    pub fn idle(&mut self, duration: Duration) -> Result<()> {
+        let end = self.clock.now() + duration;
+        loop {
+            let now = self.clock.now();
+            if now >= end {
+                return Ok(());
+            }
+            let wait = match self.keep_alive {
+                Some(interval) => (self.last_activity + interval).saturating_duration_since(now),
+                None => end - now,
+            };
+            self.clock.park_timeout(wait.min(end - now));
+            self.keep_alive()?;
+        }
+    }
+
+    /// The local end of the TCP connection, or of the tunnel. Fails for broker
+    /// connections.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match &self.stream {
+            Stream::Tcp(stream) => Ok(stream.local_addr()?),
+            #[cfg(unix)]
+            Stream::Unix(_) => bail!("Connected through a broker, not TCP."),
+        }
+    }
+
+    /// Passes every packet sent and received from now on to the observer,
+    /// replacing any previous one.
+    pub fn set_observer(&mut self, observer: impl PacketObserver + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    /// Emits `Connected` now for TCP connections, and `Disconnected` when a query fails on the socket or
+    /// the connection is dropped.
+    pub fn set_events(&mut self, events: ConnectionEvents) {
+        // Broker connections have no address to tell.
+        let addr = match &self.stream {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        };
+        if let Some(addr) = addr {
+            events.emit(ConnectionEvent::Connected { addr });
+        }
+        self.events = Some(events);
+    }
+
+    /// Sends the query and returns the response. Queries answered with a transient
+    /// error code are re-sent according to the connection's [`RetryPolicy`].
     pub fn query<'a, Cmd>(&mut self, pkt: &PacketCC<Cmd>) -> Result<PacketCC<'a, Cmd::Response<'a>>>
     where
-        Cmd: QueryPacket<'a> + BinWrite<Args<'a> = ()>,
-        PacketCC<'a, Cmd::Response<'a>>: BinRead,
+        Cmd: QueryPacket<'a> + BinWrite<Args<'a> = ()> + Debug,
+        PacketCC<'a, Cmd::Response<'a>>: BinRead + Debug,
         <PacketCC<'a, <Cmd as QueryPacket<'a>>::Response<'a>> as BinRead>::Args<'a>: Clone,
     {
-        self.send(pkt)?;
+        self.query_with(pkt, None)
+    }
+
+    /// Serializes the query for the current dialect, for use with [`Connection::query_encoded`].
+    pub fn encode<'p, Cmd>(&self, packet: PacketCC<'p, Cmd>) -> Result<EncodedQuery<'p, Cmd>>
+    where
+        Cmd: BinWrite<Args<'static> = ()>,
+    {
+        let mut bytes = Vec::new();
+        self.encode_into(&packet, &mut bytes)?;
+        Ok(EncodedQuery {
+            packet,
+            bytes,
+            dialect: self.dialect,
+        })
+    }
+
+    /// Like [`Connection::query`], but sends the pre-encoded bytes as they are. The
+    /// query is encoded again if the dialect has changed since it was encoded.
+    pub fn query_encoded<'a, Cmd>(
+        &mut self,
+        query: &EncodedQuery<Cmd>,
+    ) -> Result<PacketCC<'a, Cmd::Response<'a>>>
+    where
+        Cmd: QueryPacket<'a> + BinWrite<Args<'a> = ()> + Debug,
+        PacketCC<'a, Cmd::Response<'a>>: BinRead + Debug,
+        <PacketCC<'a, <Cmd as QueryPacket<'a>>::Response<'a>> as BinRead>::Args<'a>: Clone,
+    {
+        let bytes = (query.dialect == self.dialect).then_some(query.bytes.as_slice());
+        self.query_with(&query.packet, bytes)
+    }
+
+    fn query_with<'a, Cmd>(
+        &mut self,
+        pkt: &PacketCC<Cmd>,
+        encoded: Option<&[u8]>,
+    ) -> Result<PacketCC<'a, Cmd::Response<'a>>>
+    where
+        Cmd: QueryPacket<'a> + BinWrite<Args<'a> = ()> + Debug,
+        PacketCC<'a, Cmd::Response<'a>>: BinRead + Debug,
+        <PacketCC<'a, <Cmd as QueryPacket<'a>>::Response<'a>> as BinRead>::Args<'a>: Clone,
+    {
+        let mut attempt = 0;
+        loop {
+            let r = match self.query_once(pkt, encoded) {
+                Ok(r) => r,
+                Err(e) => return Err(self.failed(e)),
+            };
+            let code = r.payload.error_code().unwrap_or(0);
+            if !self.retry.is_transient(code) {
+                return Ok(r);
+            }
+            if attempt == self.retry.retries {
+                return Err(DeviceBusy {
+                    error_code: Some(code),
+                    retries: attempt,
+                }
+                .into());
+            }
+            attempt += 1;
+            warn!(
+                "Device returned transient error code {}, retry {attempt}/{}.",
+                ErrorCode(code),
+                self.retry.retries
+            );
+            self.clock.sleep(self.retry.delay);
+        }
+    }
+
+    fn query_once<'a, Cmd>(
+        &mut self,
+        pkt: &PacketCC<Cmd>,
+        encoded: Option<&[u8]>,
+    ) -> Result<PacketCC<'a, Cmd::Response<'a>>>
+    where
+        Cmd: QueryPacket<'a> + BinWrite<Args<'a> = ()> + Debug,
+        PacketCC<'a, Cmd::Response<'a>>: BinRead + Debug,
+        <PacketCC<'a, <Cmd as QueryPacket<'a>>::Response<'a>> as BinRead>::Args<'a>: Clone,
+    {
+        self.wait_min_gap();
+        match encoded {
+            Some(bytes) => {
+                if let Some(observer) = &mut self.observer {
+                    observer.on_send(bytes, Some(pkt));
+                }
+                self.stream
+                    .write_all(bytes)
+                    .context("Write to TCP stream failed.")?
+            }
+            None => self.send(pkt)?,
+        }
         let args = pkt.payload.get_response_read_arg();
         let r = self.receive_response_args(args);
+        if r.as_ref().is_err_and(|e| e.is::<TruncatedFrame>()) {
+            // The rest of the frame may still come, it isn't acknowledged.
+            return r;
+        }
         self.send_66_ack()?;
-        r
+        self.last_activity = self.clock.now();
+        self.answered |= r.is_ok();
+        let r = r?;
+        self.check_param_statuses(r.payload.param_statuses())?;
+        Ok(r)
+    }
+
+    /// Fails in strict mode on values with another status byte than `1`, otherwise warns.
+    fn check_param_statuses(&self, statuses: &[ParamStatus]) -> Result<()> {
+        let unknown: Vec<_> = statuses
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s != ParamStatus::Ok)
+            .map(|(i, s)| format!("{s} for value {}", i + 1))
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        let unknown = unknown.join(", ");
+        if self.strict {
+            bail!("Unexpected parameter status: {unknown}.");
+        }
+        warn!("Unexpected parameter status: {unknown}.");
+        Ok(())
+    }
+
+    /// Sends an encoded query as it is and returns the raw response, for passing on
+    /// queries of other clients. The "66 66" acknowledgement is sent here, and
+    /// transient error codes are not retried.
+    pub fn query_raw(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.wait_min_gap();
+        if let Some(observer) = &mut self.observer {
+            observer.on_send(request, None);
+        }
+        let r = self
+            .stream
+            .write_all(request)
+            .context("Write to TCP stream failed.")
+            .and_then(|_| self.receive_response_args::<PayloadUnknown, _>(()))
+            .map(|_| self.recv_buf.clone())
+            .and_then(|response| self.send_66_ack().map(|_| response));
+        self.last_activity = self.clock.now();
+        let response = r.map_err(|e| self.failed(e))?;
+        self.answered = true;
+        Ok(response)
+    }
+
+    /// After a query timed out, waits up to another read timeout for its late response
+    /// and discards it, so that the next query doesn't read it as its own. Returns
+    /// whether a late response came.
+    pub fn resync(&mut self) -> Result<bool> {
+        match self.receive_response_args::<PayloadUnknown, _>(()) {
+            Ok(late) => {
+                debug!("Discarded late response {late:?}");
+                self.send_66_ack()?;
+                Ok(true)
+            }
+            Err(e) if e.is::<DeviceBusy>() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Emits `Disconnected` for socket errors, and tells refused sessions apart.
+    fn failed(&mut self, e: anyhow::Error) -> anyhow::Error {
+        // Timeouts and garbled responses leave the socket open.
+        let Some(io) = e.chain().find_map(|e| e.downcast_ref::<std::io::Error>()) else {
+            return e;
+        };
+        let closed = matches!(
+            io.kind(),
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+        );
+        self.disconnected(format!("{e:#}"));
+        match closed && !self.answered && self.direct {
+            true => e.context(SessionRefused),
+            false => e,
+        }
+    }
+
+    /// Holds `lock` for as long as the connection is open. Take it before connecting,
+    /// from [`SessionLock::acquire`] with the host as given to the `connect` methods.
+    pub fn set_session_lock(&mut self, lock: SessionLock) {
+        self.session = Some(lock);
+    }
+
+    /// The session lock of a connection to an instrument, if one was set.
+    pub fn session(&self) -> Option<&SessionLock> {
+        self.session.as_ref()
+    }
+
+    fn disconnected(&mut self, reason: String) {
+        if let Some(events) = self.events.take() {
+            events.emit(ConnectionEvent::Disconnected { reason });
+        }
     }
 
     fn send<'a, P>(&mut self, pkt: &P) -> anyhow::Result<()>
+    where
+        P: BinWrite + Debug,
+        <P as BinWrite>::Args<'a>: Default,
+        for<'b> <P as BinWrite>::Args<'b>: binrw::__private::Required,
+    {
+        let mut buf = std::mem::take(&mut self.send_buf);
+        let r = self.encode_into(pkt, &mut buf).and_then(|_| {
+            // hex(&buf);
+            if let Some(observer) = &mut self.observer {
+                observer.on_send(&buf, Some(pkt));
+            }
+            self.stream
+                .write_all(buf.as_slice())
+                .context("Write to TCP stream failed.")
+        });
+        self.send_buf = buf;
+        r
+    }
+
+    fn encode_into<'a, P>(&self, pkt: &P, buf: &mut Vec<u8>) -> anyhow::Result<()>
     where
         P: BinWrite,
         <P as BinWrite>::Args<'a>: Default,
         for<'b> <P as BinWrite>::Args<'b>: binrw::__private::Required,
     {
-        let mut buf = Vec::with_capacity(0);
-        pkt.write_be(&mut Cursor::new(&mut buf))
-            .context("Writing packet to send buffer.")?;
-        // hex(&buf);
-        self.stream
-            .write_all(buf.as_slice())
-            .context("Write to TCP stream failed.")
+        buf.clear();
+        pkt.write_options(
+            &mut Cursor::new(&mut *buf),
+            self.dialect.endian(),
+            Default::default(),
+        )
+        .context("Writing packet to send buffer.")?;
+        if buf.len() >= PacketCCHeader::LEN {
+            buf[PacketCCHeader::MARKER_OFFSET] = self.dialect.command_marker();
+        }
+        Ok(())
     }
 
     fn receive_response_args<'a, P: 'a, Args>(
@@ -55,68 +742,557 @@ impl Connection {
         args: Args,
     ) -> anyhow::Result<PacketCC<'a, P>>
     where
-        PacketCC<'a, P>: BinRead<Args<'a> = Args>,
+        PacketCC<'a, P>: BinRead<Args<'a> = Args> + Debug,
         Args: Clone,
     {
-        let mut buf = vec![0; 24];
-        self.stream.read_exact(buf.as_mut_slice())?;
-        let hdr =
-            PacketCCHeader::read(&mut Cursor::new(&buf)).context("Response header parse error")?;
-        buf.resize(hdr.payload_len as usize + 24, 0);
-        self.stream.read_exact(&mut buf[24..])?;
-        // hex(&buf);
-        Cursor::new(buf)
-            .read_be_args(args)
-            .context("Response parse error.")
+        let endian = self.dialect.endian();
+        let buf = &mut self.recv_buf;
+        buf.resize(PacketCCHeader::LEN, 0);
+        match self.stream.read_exact(buf.as_mut_slice()) {
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(DeviceBusy {
+                    error_code: None,
+                    retries: 0,
+                }
+                .into())
+            }
+            r => r?,
+        }
+        let hdr = PacketCCHeader::read_options(&mut Cursor::new(&*buf), endian, ())
+            .context("Response header parse error")?;
+        buf.resize(hdr.payload_len as usize + PacketCCHeader::LEN, 0);
+        let mut received = 0;
+        while PacketCCHeader::LEN + received < buf.len() {
+            match self.stream.read(&mut buf[PacketCCHeader::LEN + received..]) {
+                Ok(0) => break,
+                Ok(n) => received += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if received < hdr.payload_len as usize {
+            return Err(TruncatedFrame {
+                payload_len: hdr.payload_len,
+                received,
+            }
+            .into());
+        }
+        let r: Result<PacketCC<'a, P>> = Cursor::new(&*buf)
+            .read_type_args(endian, args)
+            .context("Response parse error.");
+        if let Some(observer) = &mut self.observer {
+            let decoded = r.as_ref().ok().map(|p| p as &dyn Debug);
+            observer.on_receive(buf, decoded);
+        }
+        // Checked after reading the payload, so that the stream stays in sync.
+        if let (true, Ok(p)) = (self.strict, &r) {
+            if !p.tail.is_empty() {
+                return Err(FrameLengthMismatch {
+                    payload_len: hdr.payload_len,
+                    parsed: hdr.payload_len as usize - p.tail.len(),
+                }
+                .into());
+            }
+        }
+        let anomalies = hdr.response_anomalies(self.dialect);
+        if !anomalies.is_empty() {
+            let anomalies = anomalies.join(", ");
+            if self.strict {
+                bail!("Unexpected response header: {anomalies}.");
+            }
+            warn!("Unexpected response header: {anomalies}.");
+        }
+        r
     }
 
     fn send_66_ack(&mut self) -> anyhow::Result<()> {
-        self.stream.write_all(
-            hex_literal::hex!(
-                "66 66 00 01 00 00 00 00  00 00 00 00 00 00 00 00  00 00 00 01 02 00 00 04"
-            )
-            .as_slice(),
-        )?;
+        let ack = hex_literal::hex!(
+            "66 66 00 01 00 00 00 00  00 00 00 00 00 00 00 00  00 00 00 01 02 00 00 04"
+        );
+        if let Some(observer) = &mut self.observer {
+            observer.on_send(&ack, None);
+        }
+        self.stream.write_all(ack.as_slice())?;
         let mut rbuf = [0; 24];
         self.stream
             .read_exact(&mut rbuf)
             .context("Reading 66 ack response")?;
-        if rbuf
-            != hex_literal::hex!(
-                "66 66 00 00 00 00 00 00  00 00 00 00 00 00 00 19  00 00 00 00 00 00 00 04"
-            )
-            .as_slice()
-        {
+        if let Some(observer) = &mut self.observer {
+            observer.on_receive(&rbuf, None);
+        }
+        if rbuf != ACK_RESPONSE {
             // bail!("Unexpected 66 ack response {:x?}", rbuf);
         }
         Ok(())
     }
 }
 
-pub fn download_sbd(conn: &mut Connection) -> anyhow::Result<()> {
+/// Progress of an SDB download, kept by the caller so that an interrupted
+/// download can be resumed with [`download_sdb_resume`].
+#[derive(Clone, Debug, Default)]
+pub struct DownloadState {
+    /// The SDB size announced by the instrument.
+    pub expected_len: usize,
+    /// The SDB id reported in the instrument version response.
+    pub expected_id: Option<u32>,
+    /// The header of an SDB file already at hand. The checksum can't be computed from
+    /// the contents, but a download of the same SDB id must have the same checksum.
+    pub known: Option<SdbHeader>,
+    /// The SDB bytes received so far.
+    pub received: Vec<u8>,
+    pub complete: bool,
+    /// Number of times the download was (re)started.
+    pub attempts: u32,
+}
+
+impl DownloadState {
+    /// The fraction of the SDB received so far.
+    pub fn progress(&self) -> f32 {
+        if self.expected_len == 0 {
+            return 0.0;
+        }
+        (self.received.len() as f32 / self.expected_len as f32).min(1.0)
+    }
+
+    /// Parses the downloaded SDB and checks it against what the instrument announced.
+    pub fn verify(&self) -> Result<Rc<Sdb>> {
+        if !self.complete {
+            bail!("The SDB download is incomplete.");
+        }
+        let len = self.received.len();
+        if len != self.expected_len {
+            bail!(
+                "Received {len} SDB bytes, the instrument announced {}.",
+                self.expected_len
+            );
+        }
+        let sdb = Sdb::from_bytes(&self.received, ParseMode::Full)?;
+        if sdb.total_size() != len {
+            bail!(
+                "The SDB header gives a size of {} bytes, received {len}.",
+                sdb.total_size()
+            );
+        }
+        if let Some(id) = self.expected_id.filter(|id| *id != sdb.sdb_id()) {
+            bail!(
+                "Downloaded SDB has id {:#010x}, the instrument reports {id:#010x}.",
+                sdb.sdb_id()
+            );
+        }
+        if let Some(known) = self.known.filter(|h| h.sdb_id == sdb.sdb_id()) {
+            if sdb.header().checksum != known.checksum {
+                bail!(
+                    "Downloaded SDB {:#010x} has checksum {:#010x}, the SDB file of that id has {:#010x}.",
+                    sdb.sdb_id(),
+                    sdb.header().checksum,
+                    known.checksum
+                );
+            }
+        }
+        Ok(sdb)
+    }
+}
+
+/// Downloads the SDB into `state`, continuing an interrupted download.
+///
+/// The protocol has no way of requesting the SDB from an offset, so the download
+/// always starts from the beginning. The parts already in `state` are compared with
+/// the ones received again instead of being appended, which detects an SDB that was
+/// changed on the instrument in between.
+pub fn download_sdb_resume(conn: &mut Connection, state: &mut DownloadState) -> Result<()> {
+    if state.complete {
+        return Ok(());
+    }
+    state.attempts += 1;
+    let version = conn.query(&InstrumentVersionQuery::pkt())?;
+    state.expected_id = Some(version.payload.sdb_version);
     let sdb_info = conn.query(&SdbVersionQuery::pkt())?;
     let sdb_len = sdb_info.payload.sbd_size as usize;
+    if state.expected_len != 0 && state.expected_len != sdb_len {
+        warn!(
+            "SDB size changed from {} to {sdb_len} bytes, restarting the download.",
+            state.expected_len
+        );
+        state.received.clear();
+    }
+    state.expected_len = sdb_len;
 
-    let mut sdb_file = std::fs::File::create("sdb_new.dat")?;
+    let mut offset = 0;
     let mut pkt_cnt = 0;
+    let mut guard = DownloadGuard::new(sdb_len, SDB_DOWNLOAD_TIMEOUT);
     let mut r = conn.query(&SdbDownloadRequest::pkt())?;
-    let tot_est = (sdb_len / r.payload.pkt_sdb_part_len as usize) + 1;
     loop {
-        sdb_file.write_all(r.payload.sdb_part.as_slice())?;
+        let part = r.payload.sdb_part.as_slice();
+        let known = state.received.len().saturating_sub(offset).min(part.len());
+        if part[..known] != state.received[offset..offset + known] {
+            state.received.clear();
+            state.expected_len = 0;
+            bail!("The SDB changed on the instrument during the download.");
+        }
+        state.received.extend_from_slice(&part[known..]);
+        offset += part.len();
 
         pkt_cnt += 1;
         conn.send_66_ack()?;
 
-        if pkt_cnt > tot_est * 2 {
-            bail!("Received more than twice the amount of expected sdb download packets.")
-        }
-        println!("Pkt cnt {pkt_cnt} / {tot_est}.");
+        guard.check(offset, part.len(), r.payload.continues)?;
+        debug!("Pkt cnt {pkt_cnt}, {offset} of {sdb_len} bytes.");
         if !r.payload.continues {
-            println!("Download complete.");
             break;
         }
         r = conn.query(&SdbDownloadContinue::pkt())?;
     }
     conn.send_66_ack()?;
+    state.received.truncate(offset);
+    state.complete = true;
     Ok(())
 }
+
+/// The instrument sent an SDB download which doesn't end as announced. Unlike link
+/// failures, these are not retried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SdbDownloadMisbehavior {
+    /// More bytes than the announced SDB size.
+    Overrun { received: usize, expected: usize },
+    /// Several parts in a row without data.
+    Stalled { received: usize, empty_parts: u32 },
+    /// The download didn't end in time, although the instrument kept answering.
+    Timeout { received: usize, elapsed: Duration },
+}
+
+impl std::fmt::Display for SdbDownloadMisbehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overrun { received, expected } => write!(
+                f,
+                "The instrument sent {received} SDB bytes, more than the {expected} it announced."
+            ),
+            Self::Stalled {
+                received,
+                empty_parts,
+            } => write!(
+                f,
+                "The instrument sent {empty_parts} SDB parts without data after {received} bytes."
+            ),
+            Self::Timeout { received, elapsed } => write!(
+                f,
+                "The SDB download didn't end within {elapsed:.0?}, after {received} bytes."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SdbDownloadMisbehavior {}
+
+/// Ends SDB downloads which would go on forever, by the bytes received and the time
+/// taken rather than by the number of parts.
+struct DownloadGuard {
+    expected_len: usize,
+    started: Instant,
+    timeout: Duration,
+    empty_parts: u32,
+}
+
+impl DownloadGuard {
+    fn new(expected_len: usize, timeout: Duration) -> Self {
+        Self {
+            expected_len,
+            started: Instant::now(),
+            timeout,
+            empty_parts: 0,
+        }
+    }
+
+    /// Checks the download after a part of `part_len` bytes, with `received` bytes in
+    /// total.
+    fn check(
+        &mut self,
+        received: usize,
+        part_len: usize,
+        continues: bool,
+    ) -> Result<(), SdbDownloadMisbehavior> {
+        if received > self.expected_len {
+            return Err(SdbDownloadMisbehavior::Overrun {
+                received,
+                expected: self.expected_len,
+            });
+        }
+        match part_len {
+            0 if continues => self.empty_parts += 1,
+            _ => self.empty_parts = 0,
+        }
+        if self.empty_parts >= MAX_EMPTY_SDB_PARTS {
+            return Err(SdbDownloadMisbehavior::Stalled {
+                received,
+                empty_parts: self.empty_parts,
+            });
+        }
+        let elapsed = self.started.elapsed();
+        if continues && elapsed >= self.timeout {
+            return Err(SdbDownloadMisbehavior::Timeout { received, elapsed });
+        }
+        Ok(())
+    }
+}
+
+/// An SDB download which was interrupted more often than it was allowed to be resumed.
+/// The last interruption is the source of the error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SdbDownloadFailed {
+    pub attempts: u32,
+    pub received: usize,
+    /// The SDB size announced by the instrument, 0 if it wasn't received.
+    pub expected: usize,
+}
+
+impl std::fmt::Display for SdbDownloadFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SDB download failed after {} attempts, with {} of {} bytes received.",
+            self.attempts, self.received, self.expected
+        )
+    }
+}
+
+impl std::error::Error for SdbDownloadFailed {}
+
+/// Downloads the SDB, reconnecting and resuming up to `attempts` times if the download
+/// is interrupted, and returns it once it passes [`DownloadState::verify`] against the
+/// `known` header of the SDB file to be replaced, if any. Downloads ended by an
+/// [`SdbDownloadMisbehavior`] are not resumed.
+pub fn download_sdb(
+    mut connect: impl FnMut() -> Result<Connection>,
+    attempts: u32,
+    known: Option<SdbHeader>,
+) -> Result<Vec<u8>> {
+    let mut state = DownloadState {
+        known,
+        ..Default::default()
+    };
+    let mut conn = connect()?;
+    loop {
+        match download_sdb_resume(&mut conn, &mut state) {
+            Ok(()) => break,
+            Err(e) if state.attempts < attempts && !e.is::<SdbDownloadMisbehavior>() => {
+                warn!(
+                    "SDB download interrupted at {:.0}%: {e:#}. Reconnecting.",
+                    state.progress() * 100.0
+                );
+                conn = connect()?;
+            }
+            Err(e) => {
+                return Err(e.context(SdbDownloadFailed {
+                    attempts: state.attempts,
+                    received: state.received.len(),
+                    expected: state.expected_len,
+                }))
+            }
+        }
+    }
+    state.verify().context("Downloaded SDB is corrupt")?;
+    Ok(state.received)
+}
+
+/// Downloads the SDB with [`download_sdb`] and stores it at `target`, or in the
+/// directory `target` named by its version, see [`versioned_file_name`]. The file is
+/// replaced atomically, and its path returned.
+#[deprecated(note = "use SdbStore::download instead")]
+pub fn download_sbd(
+    connect: impl FnMut() -> Result<Connection>,
+    attempts: u32,
+    target: &Path,
+) -> anyhow::Result<PathBuf> {
+    let known = (!target.is_dir())
+        .then(|| SdbHeader::from_file(target).ok())
+        .flatten();
+    let bytes = download_sdb(connect, attempts, known)?;
+    let path = if target.is_dir() {
+        let header = SdbHeader::read(&mut Cursor::new(&bytes))?;
+        target.join(versioned_file_name(header.sdb_id))
+    } else {
+        target.to_path_buf()
+    };
+    write_atomic(&path, &bytes)?;
+    info!("Downloaded {} bytes to {}.", bytes.len(), path.display());
+    Ok(path)
+}
+
+#[test]
+fn test_verify_download() {
+    let bytes = crate::sdb_builder::fixture();
+    let mut state = DownloadState {
+        expected_len: bytes.len(),
+        expected_id: Some(0x25334),
+        known: None,
+        received: bytes,
+        complete: true,
+        attempts: 1,
+    };
+    assert_eq!(state.verify().unwrap().sdb_id(), 0x25334);
+
+    state.expected_id = Some(1);
+    assert!(state.verify().is_err());
+    state.expected_id = None;
+    // The header of another build of the same SDB.
+    let other = crate::sdb_builder::SdbBuilder::new(0x25334)
+        .with_checksum(7)
+        .build();
+    state.known = Some(SdbHeader::read(&mut Cursor::new(&other)).unwrap());
+    assert!(state.verify().is_err());
+    state.known.as_mut().unwrap().sdb_id = 1;
+    assert!(state.verify().is_ok());
+    state.known = None;
+    state.received.truncate(1000);
+    state.expected_len = 1000;
+    assert!(state.verify().is_err());
+}
+
+#[test]
+fn test_download_guard() {
+    let mut guard = DownloadGuard::new(2048, Duration::from_secs(60));
+    assert!(guard.check(1024, 1024, true).is_ok());
+    assert!(guard.check(1024, 0, true).is_ok());
+    assert!(guard.check(1024, 0, true).is_ok());
+    assert_eq!(
+        guard.check(1024, 0, true),
+        Err(SdbDownloadMisbehavior::Stalled {
+            received: 1024,
+            empty_parts: 3
+        })
+    );
+    assert!(matches!(
+        guard.check(3072, 1024, true),
+        Err(SdbDownloadMisbehavior::Overrun { .. })
+    ));
+    let mut guard = DownloadGuard::new(2048, Duration::ZERO);
+    assert!(matches!(
+        guard.check(1024, 1024, true),
+        Err(SdbDownloadMisbehavior::Timeout { .. })
+    ));
+    assert!(guard.check(2048, 1024, false).is_ok());
+}
+
+#[test]
+fn test_frame_length_mismatch() {
+    use crate::packets::RawReadQuery;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut hdr = [0; PacketCCHeader::LEN];
+        while stream.read_exact(&mut hdr).is_ok() {
+            if hdr.starts_with(&[0x66, 0x66]) {
+                let mut ack = [0; 24];
+                ack[..2].copy_from_slice(&[0x66, 0x66]);
+                stream.write_all(&ack).unwrap();
+                continue;
+            }
+            let mut payload = vec![0; u16::from_be_bytes([hdr[6], hdr[7]]) as usize];
+            stream.read_exact(&mut payload).unwrap();
+            // A two byte read, followed by a stray byte.
+            let payload = hex_literal::hex!("00 00  00 00 00 00  01 12 34  ff");
+            let len = payload.len() as u16;
+            let mut response = Vec::new();
+            PacketCCHeader {
+                payload_len: len,
+                len2: len,
+                b17: 0x27,
+                ..Default::default()
+            }
+            .write_options(&mut Cursor::new(&mut response), binrw::Endian::Big, (len,))
+            .unwrap();
+            response.extend(payload);
+            stream.write_all(&response).unwrap();
+        }
+    });
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let query = RawReadQuery::new(&sdb, &[(0x100, 2)]);
+    let mut conn = Connection::connect_addr(addr).unwrap();
+    let r = conn.query(&query).unwrap();
+    assert_eq!(r.payload.chunks, [vec![0x12, 0x34]]);
+    conn.set_strict(true);
+    let e = conn.query(&query).unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<FrameLengthMismatch>(),
+        Some(&FrameLengthMismatch {
+            payload_len: 10,
+            parsed: 9
+        })
+    );
+}
+
+#[test]
+fn test_truncated_frame() {
+    use crate::packets::RawReadQuery;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut hdr = [0; PacketCCHeader::LEN];
+        loop {
+            stream.read_exact(&mut hdr).unwrap();
+            if !hdr.starts_with(&[0x66, 0x66]) {
+                break;
+            }
+            let mut ack = [0; 24];
+            ack[..2].copy_from_slice(&[0x66, 0x66]);
+            stream.write_all(&ack).unwrap();
+        }
+        let mut payload = vec![0; u16::from_be_bytes([hdr[6], hdr[7]]) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        // A header announcing a two byte read, then the connection drops midway.
+        let mut response = Vec::new();
+        PacketCCHeader {
+            b17: 0x27,
+            ..Default::default()
+        }
+        .write_options(&mut Cursor::new(&mut response), binrw::Endian::Big, (9,))
+        .unwrap();
+        response.extend(hex_literal::hex!("00 00  00 00 00 00"));
+        stream.write_all(&response).unwrap();
+    });
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let mut conn = Connection::connect_addr(addr).unwrap();
+    let e = conn
+        .query(&RawReadQuery::new(&sdb, &[(0x100, 2)]))
+        .unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<TruncatedFrame>(),
+        Some(&TruncatedFrame {
+            payload_len: 9,
+            received: 6
+        })
+    );
+}
+
+#[test]
+fn test_retry_delay() {
+    use crate::clock::MockClock;
+    use crate::packets::RawReadQuery;
+    use crate::sim::{SimulatedPlc, SIM_ERROR_CODE, SIM_MAX_RESPONSE_LEN};
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let mut conn = Connection::connect_addr(sim.addr()).unwrap();
+    let clock = Arc::new(MockClock::new());
+    conn.set_clock(clock.clone());
+    conn.set_retry_policy(RetryPolicy {
+        retries: 2,
+        delay: Duration::from_secs(60),
+        transient_codes: vec![SIM_ERROR_CODE],
+    });
+    // Refused as too large, and retried on the connection's clock.
+    let started = clock.now();
+    let read = (0x1000, SIM_MAX_RESPONSE_LEN as u32 + 1);
+    let e = conn.query(&RawReadQuery::new(&sdb, &[read])).unwrap_err();
+    assert_eq!(e.downcast_ref::<DeviceBusy>().unwrap().retries, 2);
+    assert_eq!(clock.now() - started, Duration::from_secs(120));
+}
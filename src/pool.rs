@@ -0,0 +1,203 @@
+//! A small pool of connections to one instrument, so that concurrent requests of a
+//! server don't all queue behind one stuck socket.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, warn};
+
+use crate::packets::cc_payloads::InstrumentVersionQuery;
+use crate::plc_connection::Connection;
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// The most connections open at once. The instrument seems to handle few clients,
+    /// so keep this small.
+    pub size: usize,
+    /// Connections idle for longer than this are checked with a version query before
+    /// being handed out.
+    pub check_after: Duration,
+    /// How long [`ConnectionPool::get`] waits for a connection to be returned.
+    pub wait: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 2,
+            check_after: Duration::from_secs(10),
+            wait: Duration::from_secs(5),
+        }
+    }
+}
+
+type ConnectFn = dyn Fn() -> Result<Connection> + Send + Sync;
+
+/// Hands out connections, replacing those which fail their health check or were
+/// returned broken.
+pub struct ConnectionPool {
+    connect: Box<ConnectFn>,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: Vec<Connection>,
+    /// Connections handed out or idle.
+    open: usize,
+}
+
+impl ConnectionPool {
+    pub fn new(
+        config: PoolConfig,
+        connect: impl Fn() -> Result<Connection> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            connect: Box::new(connect),
+            config,
+            state: Mutex::default(),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Takes an idle connection, or opens a new one if the pool isn't full. Otherwise
+    /// waits for one to be returned.
+    pub fn get(&self) -> Result<PooledConnection<'_>> {
+        let deadline = Instant::now() + self.config.wait;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            while let Some(mut conn) = state.idle.pop() {
+                // The check blocks, so it is done without holding the lock.
+                drop(state);
+                if self.is_healthy(&mut conn) {
+                    return Ok(self.pooled(conn));
+                }
+                state = self.state.lock().unwrap();
+                state.open -= 1;
+            }
+            if state.open < self.config.size {
+                state.open += 1;
+                drop(state);
+                return match (self.connect)() {
+                    Ok(conn) => Ok(self.pooled(conn)),
+                    Err(e) => {
+                        self.release(None);
+                        Err(e)
+                    }
+                };
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                bail!("All {} connections of the pool are busy.", self.config.size);
+            }
+            state = self.returned.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Runs `f` on a pooled connection. The connection is replaced if `f` fails, as
+    /// the failure may have left the stream out of step with the instrument.
+    pub fn with<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut conn = self.get()?;
+        let r = f(&mut conn);
+        if r.is_err() {
+            conn.discard();
+        }
+        r
+    }
+
+    /// The number of connections open, idle or handed out.
+    pub fn open_connections(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    fn is_healthy(&self, conn: &mut Connection) -> bool {
+        if conn.idle_time() < self.config.check_after {
+            return true;
+        }
+        debug!("Checking pooled connection.");
+        let r = conn
+            .query(&InstrumentVersionQuery::pkt())
+            .context("Health check failed");
+        if let Err(e) = &r {
+            warn!("{e:#}, replacing the connection.");
+        }
+        r.is_ok()
+    }
+
+    fn pooled(&self, conn: Connection) -> PooledConnection<'_> {
+        PooledConnection {
+            pool: self,
+            conn: Some(conn),
+        }
+    }
+
+    /// Puts the connection back, or frees its slot if it is `None`.
+    fn release(&self, conn: Option<Connection>) {
+        let mut state = self.state.lock().unwrap();
+        match conn {
+            Some(conn) => state.idle.push(conn),
+            None => state.open -= 1,
+        }
+        self.returned.notify_one();
+    }
+}
+
+/// A connection taken from the pool, returned to it when dropped.
+pub struct PooledConnection<'p> {
+    pool: &'p ConnectionPool,
+    conn: Option<Connection>,
+}
+
+impl PooledConnection<'_> {
+    /// Closes the connection instead of returning it, e.g. after a response timed out.
+    pub fn discard(mut self) {
+        self.conn = None;
+    }
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        self.pool.release(self.conn.take());
+    }
+}
+
+#[test]
+fn test_pool_limits() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = PoolConfig {
+        size: 2,
+        wait: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let pool = ConnectionPool::new(config, move || Connection::connect_addr(addr));
+    let a = pool.get().unwrap();
+    let b = pool.get().unwrap();
+    assert!(pool.get().is_err());
+    drop(a);
+    let a = pool.get().unwrap();
+    assert_eq!(pool.open_connections(), 2);
+    b.discard();
+    assert_eq!(pool.open_connections(), 1);
+    drop(a);
+    assert!(pool.with(|_| -> Result<()> { bail!("timeout") }).is_err());
+    assert_eq!(pool.open_connections(), 0);
+}
@@ -0,0 +1,26 @@
+//! The types most programs need, for a glob import.
+//!
+//! ```no_run
+//! use leybold_opc_rs::prelude::*;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let sdb = SdbStore::new("sdb.dat").load()?;
+//! let conn = Connection::connect(&"192.168.1.10".parse()?)?;
+//! let mut client = Client::new(conn, &sdb)?;
+//! let pressure = sdb.param_by_name(".Gauge[1].Parameter[1].Value")?;
+//! let values: Vec<Value> = client.read(&[pressure])?;
+//! # Ok(()) }
+//! ```
+
+pub use crate::client::{
+    Client, OpResult, ProtocolFeatures, Transaction, TransactionResult, WriteNotApplied,
+    WritePolicy, WriteRejected,
+};
+pub use crate::events::{ConnectionEvent, ConnectionEvents};
+pub use crate::host::Host;
+pub use crate::metadata::{MetadataOverlay, OutOfRange};
+pub use crate::opc_values::Value;
+pub use crate::plc_connection::{Connection, DeviceBusy};
+pub use crate::sdb::{AccessMode, Parameter, Sdb, TypeInfo, TypeKind, UnknownParameter};
+pub use crate::sdb_store::SdbStore;
+pub use crate::{Dialect, ParamQuerySetBuilder, ParamWrite};
@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+/// The gauges report pressure in mbar.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PressureUnit {
+    #[default]
+    Mbar,
+    Pa,
+    Torr,
+}
+
+impl PressureUnit {
+    /// Converts a pressure in mbar to this unit.
+    pub fn from_mbar(self, mbar: f64) -> f64 {
+        match self {
+            Self::Mbar => mbar,
+            Self::Pa => mbar * 100.0,
+            Self::Torr => mbar * (760.0 / 1013.25),
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Mbar => "mbar",
+            Self::Pa => "Pa",
+            Self::Torr => "Torr",
+        }
+    }
+}
+
+impl Display for PressureUnit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+impl FromStr for PressureUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mbar" | "hpa" => Ok(Self::Mbar),
+            "pa" => Ok(Self::Pa),
+            "torr" => Ok(Self::Torr),
+            _ => Err(anyhow!(
+                "Unknown pressure unit '{s}', expected mbar, Pa or Torr."
+            )),
+        }
+    }
+}
+
+/// The range covered by [`log_bar`], in mbar.
+pub const LOG_BAR_RANGE: (f64, f64) = (1e-10, 1e4);
+
+/// A bar of `width` characters showing the pressure on a log scale, from ultra high
+/// vacuum on the left to atmosphere on the right.
+pub fn log_bar(mbar: f64, width: usize) -> String {
+    let (lo, hi) = (LOG_BAR_RANGE.0.log10(), LOG_BAR_RANGE.1.log10());
+    let pos = if mbar > 0.0 {
+        ((mbar.log10() - lo) / (hi - lo)).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let filled = (pos * width as f64).round() as usize;
+    format!("{}{}", "#".repeat(filled), ".".repeat(width - filled))
+}
+
+/// The rate of change of the pressure over a sliding time window.
+///
+/// The rate is the least squares slope of the samples in the window, which is much
+/// less noisy than the difference of the last two samples.
+#[derive(Clone, Debug)]
+pub struct RateOfChange {
+    window: Duration,
+    samples: VecDeque<(Instant, f64)>,
+}
+
+impl RateOfChange {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, mbar: f64, at: Instant) {
+        self.samples.push_back((at, mbar));
+        while let Some((t, _)) = self.samples.front() {
+            if at.duration_since(*t) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// dP/dt in mbar/s, once there are at least two samples in the window.
+    pub fn rate(&self) -> Option<f64> {
+        let (t0, _) = *self.samples.front()?;
+        if self.samples.len() < 2 {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let points = || {
+            self.samples
+                .iter()
+                .map(move |(t, p)| (t.duration_since(t0).as_secs_f64(), *p))
+        };
+        let (sum_t, sum_p) = points().fold((0.0, 0.0), |(st, sp), (t, p)| (st + t, sp + p));
+        let (mean_t, mean_p) = (sum_t / n, sum_p / n);
+        let (cov, var) = points().fold((0.0, 0.0), |(c, v), (t, p)| {
+            (c + (t - mean_t) * (p - mean_p), v + (t - mean_t).powi(2))
+        });
+        (var > 0.0).then(|| cov / var)
+    }
+
+    /// The leak-up rate in mbar·l/s of a closed chamber with the given volume.
+    pub fn leak_rate(&self, volume_liters: f64) -> Option<f64> {
+        self.rate().map(|r| r * volume_liters)
+    }
+
+    /// The time covered by the samples in the window.
+    pub fn span(&self) -> Duration {
+        match (self.samples.front(), self.samples.back()) {
+            (Some((first, _)), Some((last, _))) => last.duration_since(*first),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[test]
+fn test_pressure_units() {
+    let unit: PressureUnit = "torr".parse().unwrap();
+    assert!((unit.from_mbar(1013.25) - 760.0).abs() < 1e-9);
+    assert_eq!("Pa".parse::<PressureUnit>().unwrap().from_mbar(2.0), 200.0);
+    assert!("psi".parse::<PressureUnit>().is_err());
+    assert_eq!(log_bar(1e-10, 14), ".".repeat(14));
+    assert_eq!(log_bar(1e-3, 14), "#######.......");
+    assert_eq!(log_bar(1e6, 14), "#".repeat(14));
+}
+
+#[test]
+fn test_rate_of_change() {
+    let start = Instant::now();
+    let mut roc = RateOfChange::new(Duration::from_secs(10));
+    roc.push(1e-3, start);
+    assert_eq!(roc.rate(), None);
+    for s in 1..=20 {
+        roc.push(1e-3 + s as f64 * 1e-5, start + Duration::from_secs(s));
+    }
+    assert_eq!(roc.span(), Duration::from_secs(10));
+    assert!((roc.rate().unwrap() - 1e-5).abs() < 1e-12);
+    assert!((roc.leak_rate(50.0).unwrap() - 5e-4).abs() < 1e-10);
+}
@@ -0,0 +1,172 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::opc_values::{EncodeOpcValue, Value};
+use crate::sdb::{AccessMode, Parameter, Sdb, TypeInfo, TypeKind};
+
+/// A set of parameter values to write, read from a TOML file.
+///
+/// Each entry names a parameter and its value. The type annotation is optional,
+/// when given it must match the SDB type, either the kind (`Real`) or the SDB type
+/// name (`REAL`). `unit` and `comment` are for the reader only.
+///
+/// ```toml
+/// description = "Pump setup"
+///
+/// [[param]]
+/// name = ".OPCPumpFrequency[1]"
+/// type = "Real"
+/// value = 750.0
+/// unit = "Hz"
+/// comment = "Nominal speed of the turbo pump"
+///
+/// [[param]]
+/// name = ".CockpitUser"
+/// value = "service"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipe {
+    pub description: Option<String>,
+    #[serde(default, rename = "param")]
+    pub params: Vec<RecipeEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: Option<String>,
+    pub value: toml::Value,
+    pub unit: Option<String>,
+    pub comment: Option<String>,
+}
+
+/// A recipe entry checked against the SDB, ready to be written.
+#[derive(Clone, Debug)]
+pub struct RecipeWrite<'sdb> {
+    pub param: Parameter<'sdb>,
+    pub value: Value,
+}
+
+impl Recipe {
+    pub fn from_file(file: impl AsRef<Path>) -> Result<Self> {
+        let file = file.as_ref();
+        let text = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read recipe {}", file.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid recipe {}", file.display()))
+    }
+
+    /// Checks every entry against the SDB. All problems are reported together,
+    /// and nothing is returned unless every entry is valid.
+    pub fn validate<'sdb>(&self, sdb: &'sdb Sdb) -> Result<Vec<RecipeWrite<'sdb>>> {
+        self.check_entries(|entry| entry.validate(sdb))
+    }
+
+    /// Like [`Recipe::validate`], but read-only parameters are allowed too. For
+    /// setting up a simulated instrument, which can be in any state.
+    pub fn values<'sdb>(&self, sdb: &'sdb Sdb) -> Result<Vec<RecipeWrite<'sdb>>> {
+        self.check_entries(|entry| entry.value(sdb))
+    }
+
+    fn check_entries<'sdb>(
+        &self,
+        check: impl Fn(&RecipeEntry) -> Result<RecipeWrite<'sdb>>,
+    ) -> Result<Vec<RecipeWrite<'sdb>>> {
+        let mut writes = Vec::with_capacity(self.params.len());
+        let mut errors = Vec::new();
+        for entry in &self.params {
+            match check(entry) {
+                Ok(w) => writes.push(w),
+                Err(e) => errors.push(format!("{}: {e:#}", entry.name)),
+            }
+        }
+        if !errors.is_empty() {
+            bail!(
+                "{} of {} recipe entries are invalid:\n  {}",
+                errors.len(),
+                self.params.len(),
+                errors.join("\n  ")
+            );
+        }
+        Ok(writes)
+    }
+}
+
+impl RecipeEntry {
+    pub fn validate<'sdb>(&self, sdb: &'sdb Sdb) -> Result<RecipeWrite<'sdb>> {
+        let write = self.value(sdb)?;
+        if write.param.access() == AccessMode::Read {
+            bail!("Parameter is read-only.");
+        }
+        Ok(write)
+    }
+
+    /// The parameter and its value, checked against the type but not the access mode.
+    pub fn value<'sdb>(&self, sdb: &'sdb Sdb) -> Result<RecipeWrite<'sdb>> {
+        let param = sdb.param_by_name(&self.name)?;
+        let ty = param.type_info();
+        if let Some(name) = &self.ty {
+            let kind = format!("{:?}", ty.kind());
+            let sdb_name = ty.name().trim_end_matches('\0');
+            if !name.eq_ignore_ascii_case(&kind) && !name.eq_ignore_ascii_case(sdb_name) {
+                bail!("Type is {kind} ({sdb_name}), not {name}.");
+            }
+        }
+        let value = recipe_value(&self.value, &ty)?;
+        (&value).opc_encode(&ty)?;
+        Ok(RecipeWrite { param, value })
+    }
+}
+
+fn recipe_value(value: &toml::Value, ty: &TypeInfo) -> Result<Value> {
+    use toml::Value as T;
+    Ok(match (value, ty.kind()) {
+        (T::Boolean(b), TypeKind::Bool) => Value::Bool(*b),
+        (T::Float(f), TypeKind::Real) => Value::Float(*f as f32),
+        (T::Integer(i), TypeKind::Real) => Value::Float(*i as f32),
+        (T::Integer(i), kind) if kind != TypeKind::Bool && kind != TypeKind::String => {
+            Value::Int(*i)
+        }
+        (T::String(s), _) => Value::from_str(s, ty)?,
+        (value, kind) => bail!("A {} can't be written to a {kind:?}.", value.type_str()),
+    })
+}
+
+#[test]
+fn test_recipe_validate() {
+    let sdb = crate::sdb_builder::test_sdb();
+    let recipe: Recipe = toml::from_str(
+        r#"
+        [[param]]
+        name = ".CockpitUser"
+        type = "STRING"
+        value = "service"
+        comment = "Logged in user"
+        "#,
+    )
+    .unwrap();
+    let writes = recipe.validate(&sdb).unwrap();
+    assert_eq!(writes[0].param.name(), ".CockpitUser");
+
+    let recipe: Recipe = toml::from_str(
+        r#"
+        [[param]]
+        name = ".CockpitUser"
+        value = 12
+        [[param]]
+        name = ".CockpitUser"
+        type = "Real"
+        value = "x"
+        "#,
+    )
+    .unwrap();
+    let err = recipe.validate(&sdb).unwrap_err().to_string();
+    assert!(
+        err.starts_with("2 of 2 recipe entries are invalid"),
+        "{err}"
+    );
+}
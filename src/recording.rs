@@ -0,0 +1,223 @@
+//! Recording the packets of live sessions, and answering queries from a recording, so
+//! that behavior seen on an instrument in the field can be reproduced without it.
+//!
+//! A recording file starts with [`RECORDING_MAGIC`], followed by the exchanges. Each is
+//! the request and the response, both as a big-endian u32 length and the raw bytes.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, warn};
+
+use crate::packets::PacketCCHeader;
+use crate::plc_connection::PacketObserver;
+
+pub const RECORDING_MAGIC: [u8; 8] = *b"LOPCREC1";
+
+/// A request sent to the instrument and the response to it, as raw packets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exchange {
+    pub request: Vec<u8>,
+    pub response: Vec<u8>,
+}
+
+/// Records the exchanges of connections to a file. Clones write to the same file, so
+/// one recorder can observe several connections.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+    /// The request waiting for its response.
+    pending: Option<Vec<u8>>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::create(path)
+            .with_context(|| format!("Failed to create recording {}", path.display()))?;
+        file.write_all(&RECORDING_MAGIC)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            pending: None,
+        })
+    }
+
+    fn write(&self, request: &[u8], response: &[u8]) -> std::io::Result<()> {
+        // Written unbuffered, so that the recording is complete however the session ends.
+        let mut bytes = Vec::with_capacity(8 + request.len() + response.len());
+        for data in [request, response] {
+            bytes.extend((data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(data);
+        }
+        self.file.lock().unwrap().write_all(&bytes)
+    }
+}
+
+impl PacketObserver for Recorder {
+    fn on_send(&mut self, raw: &[u8], _decoded: Option<&dyn std::fmt::Debug>) {
+        // A request without a response, e.g. after a timeout, is dropped.
+        self.pending = Some(raw.to_vec());
+    }
+
+    fn on_receive(&mut self, raw: &[u8], _decoded: Option<&dyn std::fmt::Debug>) {
+        let Some(request) = self.pending.take() else {
+            return;
+        };
+        if let Err(e) = self.write(&request, raw) {
+            warn!("Failed to record exchange: {e}");
+        }
+    }
+}
+
+/// The exchanges of a recording file, in the order they happened.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Recording {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open recording {}", path.display()))?;
+        Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("Invalid recording {}", path.display()))
+    }
+
+    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+        let mut magic = [0; RECORDING_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != RECORDING_MAGIC {
+            bail!("Not a recording file.");
+        }
+        let mut exchanges = Vec::new();
+        loop {
+            let request = match read_chunk(&mut reader) {
+                Ok(request) => request,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            let response = read_chunk(&mut reader).context("Truncated exchange")?;
+            exchanges.push(Exchange { request, response });
+        }
+        Ok(Self { exchanges })
+    }
+}
+
+fn read_chunk(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut data = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Answers queries from a recording on a local port, in place of the instrument.
+///
+/// A request is answered with the response recorded for an identical request. When
+/// the same request was recorded several times, e.g. while polling, the responses are
+/// given in the recorded order, and the last one is repeated after that. Connections
+/// sending a request missing from the recording are closed.
+pub struct ReplayServer {
+    addr: SocketAddr,
+}
+
+impl ReplayServer {
+    pub fn start(recording: Recording) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").context("Failed to listen for replay")?;
+        let addr = listener.local_addr()?;
+        let recording = Arc::new(recording);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let recording = recording.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = replay(stream, &recording) {
+                        warn!("Replay connection ended: {e:#}");
+                    }
+                });
+            }
+        });
+        debug!("Replaying on {addr}");
+        Ok(Self { addr })
+    }
+
+    /// The address to connect to instead of the instrument.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn replay(mut stream: TcpStream, recording: &Recording) -> Result<()> {
+    // How many times each request has been answered on this connection.
+    let mut answered: HashMap<&[u8], usize> = HashMap::new();
+    loop {
+        let mut hdr = [0; PacketCCHeader::LEN];
+        match stream.read_exact(&mut hdr) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            r => r?,
+        }
+        // The header holds the payload length, so any recorded request starting with
+        // it tells the length of this one, whatever the dialect.
+        let Some(len) = recording
+            .exchanges
+            .iter()
+            .find(|x| x.request.starts_with(&hdr))
+            .map(|x| x.request.len())
+        else {
+            bail!("Request header {hdr:02x?} isn't in the recording.");
+        };
+        let mut request = hdr.to_vec();
+        request.resize(len, 0);
+        stream.read_exact(&mut request[hdr.len()..])?;
+
+        let responses: Vec<_> = recording
+            .exchanges
+            .iter()
+            .filter(|x| x.request == request)
+            .collect();
+        let Some(last) = responses.last() else {
+            bail!("Request {request:02x?} isn't in the recording.");
+        };
+        let n = answered.entry(&last.request).or_default();
+        let response = responses.get(*n).unwrap_or(last);
+        *n += 1;
+        stream.write_all(&response.response)?;
+    }
+}
+
+#[test]
+fn test_record_and_replay() {
+    use crate::client::Client;
+    use crate::opc_values::Value;
+    use crate::plc_connection::Connection;
+    use crate::sim::SimulatedPlc;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let pressure = sdb.param_by_name(".Gauge[1].Parameter[1].Value").unwrap();
+    let file = std::env::temp_dir().join(format!("recording-{}.bin", std::process::id()));
+
+    let mut conn = Connection::connect_addr(sim.addr()).unwrap();
+    conn.set_observer(Recorder::create(&file).unwrap());
+    let mut client = Client::new(conn, &sdb).unwrap();
+    for p in [1e-3, 2e-3] {
+        sim.set(&pressure, &Value::Float(p)).unwrap();
+        client.read(std::slice::from_ref(&pressure)).unwrap();
+    }
+    drop(client);
+
+    let recording = Recording::from_file(&file).unwrap();
+    std::fs::remove_file(&file).unwrap();
+    let replay = ReplayServer::start(recording).unwrap();
+    let conn = Connection::connect_addr(replay.addr()).unwrap();
+    let mut client = Client::new(conn, &sdb).unwrap();
+    for p in [1e-3, 2e-3, 2e-3] {
+        let values = client.read(std::slice::from_ref(&pressure)).unwrap();
+        assert_eq!(values, [Value::Float(p)]);
+    }
+}
@@ -0,0 +1,108 @@
+use serde_json::{json, Map, Value as Json};
+
+use crate::metadata::ParamMetadata;
+use crate::sdb::{Parameter, TypeInfo, TypeKind};
+
+const SCHEMA_DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// JSON Schema for the value of a parameter, as produced by the JSON output
+/// and expected in write payloads.
+pub fn param_schema(param: &Parameter) -> Json {
+    let mut schema = type_schema(&param.type_info());
+    if let Json::Object(map) = &mut schema {
+        map.insert("$schema".into(), SCHEMA_DRAFT.into());
+        map.insert("title".into(), param.name().into());
+    }
+    schema
+}
+
+/// Adds the user's description, unit and limits to a parameter schema.
+pub fn apply_metadata(schema: &mut Json, meta: &ParamMetadata) {
+    let Json::Object(map) = schema else {
+        return;
+    };
+    if let Some(name) = &meta.display_name {
+        map.insert("title".into(), name.as_str().into());
+    }
+    if let Some(description) = &meta.description {
+        map.insert("description".into(), description.as_str().into());
+    }
+    if let Some(unit) = &meta.unit {
+        map.insert("x-unit".into(), unit.as_str().into());
+    }
+    // Limits only apply to numbers, not to e.g. the members of a struct.
+    if matches!(map.get("type"), Some(t) if t == "number" || t == "integer") {
+        if let Some(min) = meta.min {
+            map.insert("minimum".into(), min.into());
+        }
+        if let Some(max) = meta.max {
+            map.insert("maximum".into(), max.into());
+        }
+    }
+}
+
+/// JSON Schema for values of the given type.
+pub fn type_schema(ty: &TypeInfo) -> Json {
+    match ty.kind() {
+        TypeKind::Bool => json!({"type": "boolean"}),
+        TypeKind::Real => json!({"type": "number"}),
+        // One byte is reserved for the NUL terminator.
+        TypeKind::String => json!({
+            "type": "string",
+            "maxLength": ty.response_len().saturating_sub(1),
+        }),
+        TypeKind::Array => {
+            let Some((elem, dims)) = ty.array_info() else {
+                return json!({"type": "array"});
+            };
+            match dims {
+                [len, 0] => array_schema(type_schema(&elem), len),
+                [a, b] => array_schema(array_schema(type_schema(&elem), b), a),
+            }
+        }
+        TypeKind::Data => {
+            let members = ty.struct_info().unwrap_or_default();
+            let properties: Map<_, _> = members
+                .iter()
+                .map(|m| (m.name.to_string(), type_schema(&m.type_info)))
+                .collect();
+            let required: Vec<_> = members.iter().map(|m| m.name).collect();
+            json!({
+                "type": "object",
+                "title": ty.name(),
+                "properties": properties,
+                "required": required,
+                "additionalProperties": false,
+            })
+        }
+        // The integer kinds.
+        kind => {
+            let (min, max) = kind.int_range().unwrap();
+            let mut int = json!({"type": "integer", "minimum": min, "maximum": max});
+            if kind == TypeKind::Time {
+                int["description"] = "Time in milliseconds".into();
+            }
+            int
+        }
+    }
+}
+
+fn array_schema(items: Json, len: usize) -> Json {
+    json!({
+        "type": "array",
+        "items": items,
+        "minItems": len,
+        "maxItems": len,
+    })
+}
+
+#[test]
+fn test_param_schema() {
+    let sdb = crate::sdb_builder::test_sdb();
+    let param = sdb.param_by_name(".Gauge[1].Parameter[1]").unwrap();
+    let schema = param_schema(&param);
+    assert_eq!(schema["type"], "object");
+    assert_eq!(schema["properties"]["Value"]["type"], "number");
+    assert_eq!(schema["properties"]["Name"]["maxLength"], 80);
+    assert_eq!(schema["properties"]["AccessLevel"]["maximum"], 32767);
+}
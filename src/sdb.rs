@@ -3,9 +3,12 @@
 use anyhow::{bail, Context, Result};
 use binrw::{binread, BinRead, BinResult, Endian, VecArgs};
 use rhexdump::hexdump;
+use tracing::error;
 
-use std::fmt::{Debug, Formatter};
-use std::io::{ErrorKind, Read, Seek};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
 use std::ops::Deref;
 use std::path::Path;
 use std::rc::Rc;
@@ -14,10 +17,26 @@ pub use api::*;
 
 pub mod api {
     use super::*;
-    pub use super::{Sdb, TypeKind};
+    pub use super::{AccessMode, ParamFlags, ParseMode, Sdb, TypeKind};
     use crate::opc_values::Value;
     use std::hash::{Hash, Hasher};
 
+    /// The bytes of a read query entry: `00 03`, the id and the length.
+    const READ_ENTRY_LEN: usize = 10;
+
+    /// The bytes reading a parameter costs on the link, see [`Parameter::wire_cost`].
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct WireCost {
+        pub request: usize,
+        pub response: usize,
+    }
+
+    impl WireCost {
+        pub fn total(&self) -> usize {
+            self.request + self.response
+        }
+    }
+
     #[derive(Clone)]
     pub struct Parameter<'sdb> {
         sdb: &'sdb Sdb,
@@ -31,33 +50,133 @@ pub mod api {
         }
 
         pub fn name(&self) -> &str {
-            self.sdb.parameters[self.param].name.as_str()
+            self.sdb.parameters.name(self.param)
+        }
+
+        /// The SDB the parameter belongs to.
+        pub fn sdb(&self) -> &'sdb Sdb {
+            self.sdb
         }
 
         pub fn id(&self) -> u32 {
             self.sdb.parameters[self.param].id
         }
 
-        pub fn type_info(&self) -> TypeInfo<'_> {
+        pub fn type_info(&self) -> TypeInfo<'sdb> {
             TypeInfo {
                 sdb: self.sdb,
                 descr: self.descr,
             }
         }
 
+        /// What reading the parameter adds to a read query and to its response. The
+        /// response bytes are the value, as counted by the response size limit, without
+        /// the status byte preceding it.
+        pub fn wire_cost(&self) -> WireCost {
+            WireCost {
+                request: READ_ENTRY_LEN,
+                response: self.type_info().response_len(),
+            }
+        }
+
         /// Returns a TypeKind enum value, describing the data type of the parameter.
         pub fn value_kind(&self) -> TypeKind {
             self.sdb.type_descr[self.descr].kind
         }
 
+        pub fn flags(&self) -> ParamFlags {
+            ParamFlags::from_bits_retain(self.sdb.parameters[self.param].flags[0])
+        }
+
+        /// The second flags word. Its meaning is unknown, observed values are 0, 200, 1000
+        /// and 1200, where 1000 seems to be added for arrays and structs.
+        pub fn flags2(&self) -> u16 {
+            self.sdb.parameters[self.param].flags[1]
+        }
+
+        pub fn access(&self) -> AccessMode {
+            self.sdb.parameters[self.param].rw
+        }
+
+        /// Internal state of function blocks, which isn't meant to be accessed from outside.
+        pub fn is_hidden(&self) -> bool {
+            self.flags().is_hidden()
+        }
+
         pub fn value_from_str(&self, val: &str) -> Result<Value> {
             Value::from_str(val, &self.type_info())
         }
+
+        /// The names of the struct members or of the elements of a one-dimensional
+        /// array, from the type layout and in the order of the value.
+        pub fn element_names(&self) -> Option<Vec<String>> {
+            let ty = self.type_info();
+            let name = self.name();
+            match ty.kind() {
+                TypeKind::Data => Some(
+                    ty.struct_info()?
+                        .iter()
+                        .map(|m| format!("{name}.{}", m.name))
+                        .collect(),
+                ),
+                TypeKind::Array => match ty.array_bounds()? {
+                    [(lower, upper)] => {
+                        Some((*lower..=*upper).map(|i| format!("{name}[{i}]")).collect())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+
+        /// The array elements or struct members with parameters of their own, in the
+        /// order of the value. The elements of multi-dimensional arrays have none.
+        pub fn elements(&self) -> Vec<Parameter<'sdb>> {
+            let Some(names) = self.element_names() else {
+                return vec![];
+            };
+            let position: HashMap<&str, usize> = names
+                .iter()
+                .enumerate()
+                .map(|(i, n)| (n.as_str(), i))
+                .collect();
+            // The elements are within the value, which is quicker to check than names.
+            let end = self.id() as usize + self.type_info().response_len();
+            let mut elements: Vec<Option<Parameter<'sdb>>> = names.iter().map(|_| None).collect();
+            for param in self.sdb.parameters() {
+                if param.id() < self.id() || param.id() as usize >= end {
+                    continue;
+                }
+                if let Some(&i) = position.get(param.name()) {
+                    elements[i] = Some(param);
+                }
+            }
+            elements.into_iter().flatten().collect()
+        }
+
+        /// For pointer parameters, the type of the data pointed to.
+        pub fn pointer_target(&self) -> Option<TypeInfo<'sdb>> {
+            TypeInfo::new(self.sdb, self.descr as u32).pointer_target()
+        }
+
+        /// Finds the parameter a pointer value points to.
+        ///
+        /// This assumes that pointer values are parameter ids, which holds for the pointers
+        /// checked so far. `None` is returned if this isn't a pointer parameter, or if no
+        /// parameter of the target type has the id.
+        pub fn resolve_pointer(&self, value: &Value) -> Option<Parameter<'sdb>> {
+            let target = self.pointer_target()?;
+            let Value::Int(addr) = value else { return None };
+            let addr = u32::try_from(*addr).ok()?;
+            self.sdb
+                .params_by_id(addr)
+                .find(|p| p.descr == target.descr)
+        }
     }
 
     impl Hash for Parameter<'_> {
         fn hash<H: Hasher>(&self, state: &mut H) {
-            (&self.sdb as *const _ as u64).hash(state);
+            (self.sdb as *const Sdb as usize).hash(state);
             self.param.hash(state);
             self.descr.hash(state);
         }
@@ -67,7 +186,7 @@ pub mod api {
         fn eq(&self, other: &Self) -> bool {
             self.param == other.param
                 && self.descr == other.descr
-                && core::ptr::eq(&self.sdb, &other.sdb)
+                && core::ptr::eq(self.sdb, other.sdb)
         }
     }
     impl Eq for Parameter<'_> {}
@@ -78,6 +197,15 @@ pub mod api {
         }
     }
 
+    /// `name: KIND[size] (access)`, e.g. `.Gauge[1].Parameter[1].Value: REAL[4] (r)`.
+    impl Display for Parameter<'_> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            let size = self.type_info().response_len();
+            let (name, kind, access) = (self.name(), self.value_kind(), self.access());
+            write!(f, "{name}: {kind}[{size}] ({access})")
+        }
+    }
+
     #[derive(Clone, Debug)]
     pub struct TypeInfo<'sdb> {
         sdb: &'sdb Sdb,
@@ -85,25 +213,64 @@ pub mod api {
     }
 
     impl<'sdb> TypeInfo<'sdb> {
-        fn new(sdb: &'sdb Sdb, idx: u32) -> Self {
+        pub(crate) fn new(sdb: &'sdb Sdb, idx: u32) -> Self {
             let descr = idx as usize;
             Self { sdb, descr }
         }
 
         fn descr(&self) -> &TypeDescription {
-            &self.sdb.type_descr[self.descr]
+            self.sdb.type_descr[self.descr].get()
+        }
+
+        /// Parses the type description and those of the array elements and struct
+        /// members, failing if any is invalid. Only needed for SDBs loaded with
+        /// [`ParseMode::Lazy`], where the other accessors see invalid types as empty.
+        pub fn check(&self) -> Result<()> {
+            let mut seen = vec![false; self.sdb.type_descr.len()];
+            let mut todo = vec![self.descr];
+            while let Some(idx) = todo.pop() {
+                if std::mem::replace(&mut seen[idx], true) {
+                    continue;
+                }
+                let contained = match &self.sdb.type_descr[idx].decode()?.payload {
+                    TypeDescPayload::Array(arr) => vec![arr.type_idx],
+                    TypeDescPayload::Struct(members) => {
+                        members.iter().map(|m| m.type_descr_idx).collect()
+                    }
+                    _ => vec![],
+                };
+                for idx in contained {
+                    if idx as usize >= seen.len() {
+                        bail!("Type description #{idx} not found.");
+                    }
+                    todo.push(idx as usize);
+                }
+            }
+            Ok(())
+        }
+
+        /// The position of the type description in the SDB.
+        pub fn index(&self) -> usize {
+            self.descr
+        }
+
+        /// The type name from the SDB, e.g. `INT` or `STRING`.
+        pub fn name(&self) -> &'sdb str {
+            self.sdb.type_descr[self.descr].get().description.as_str()
         }
 
         pub fn kind(&self) -> TypeKind {
-            self.descr().kind
+            self.sdb.type_descr[self.descr].kind
         }
 
         pub fn response_len(&self) -> usize {
-            self.descr().type_size as usize
+            self.sdb.type_descr[self.descr].type_size as usize
         }
 
-        pub fn array_info(&self) -> Option<(TypeInfo, [usize; 2])> {
-            let TypeDescPayload::Array(ref arr) = self.descr().payload else { return None; };
+        pub fn array_info(&self) -> Option<(TypeInfo<'sdb>, [usize; 2])> {
+            let TypeDescPayload::Array(ref arr) = self.descr().payload else {
+                return None;
+            };
             let mut dims = [0; 2];
             for d in 0..arr.dims.len() {
                 let x = arr.dims[d];
@@ -112,54 +279,228 @@ pub mod api {
             Some((Self::new(self.sdb, arr.type_idx), dims))
         }
 
-        pub fn struct_info(&self) -> Option<Vec<StructMemberInfo>> {
-            let TypeDescPayload::Struct(ref v) = self.descr().payload else { return None };
+        /// The lower and upper bound of each dimension, for arrays.
+        pub fn array_bounds(&self) -> Option<&[(u32, u32)]> {
+            match self.descr().payload {
+                TypeDescPayload::Array(ref arr) => Some(&arr.dims),
+                _ => None,
+            }
+        }
+
+        /// The names and types of the struct members, without working out their
+        /// positions like [`TypeInfo::struct_info`] does.
+        pub fn members(&self) -> impl ExactSizeIterator<Item = (&'sdb str, TypeInfo<'sdb>)> {
+            let sdb = self.sdb;
+            let members = match sdb.type_descr[self.descr].get().payload {
+                TypeDescPayload::Struct(ref v) => &v[..],
+                _ => &[],
+            };
+            members
+                .iter()
+                .map(move |m| (m.name.as_str(), Self::new(sdb, m.type_descr_idx)))
+        }
+
+        /// Returns the struct members, with their positions within the struct.
+        pub fn struct_info(&self) -> Option<Vec<StructMemberInfo<'_>>> {
+            let TypeDescPayload::Struct(ref v) = self.descr().payload else {
+                return None;
+            };
+            let mut pos = 0;
             v.iter()
                 .map(|m| {
+                    let type_info = Self::new(self.sdb, m.type_descr_idx);
+                    let offset = align_up(pos, type_info.alignment());
+                    let size = type_info.response_len();
+                    pos = offset + size;
                     Some(StructMemberInfo {
                         name: m.name.as_str(),
-                        type_info: Self::new(self.sdb, m.type_descr_idx),
+                        offset,
+                        size,
+                        type_info,
                     })
                 })
                 .collect::<Option<Vec<_>>>()
         }
+
+        /// The alignment of the value within a response.
+        ///
+        /// Multi-byte numbers start at even offsets, everything else is byte aligned.
+        /// Arrays and structs get the largest alignment of their contents.
+        pub fn alignment(&self) -> usize {
+            match self.kind() {
+                TypeKind::Bool | TypeKind::Byte | TypeKind::String => 1,
+                TypeKind::Int
+                | TypeKind::Word
+                | TypeKind::Uint
+                | TypeKind::Dword
+                | TypeKind::Udint
+                | TypeKind::Real
+                | TypeKind::Time
+                | TypeKind::Pointer => 2,
+                TypeKind::Array => self.array_info().map_or(1, |(ty, _)| ty.alignment()),
+                TypeKind::Data => self
+                    .struct_info()
+                    .into_iter()
+                    .flatten()
+                    .map(|m| m.type_info.alignment())
+                    .max()
+                    .unwrap_or(1),
+            }
+        }
+
+        /// The distance in bytes between consecutive array elements.
+        pub fn array_stride(&self) -> Option<usize> {
+            let (ty, _) = self.array_info()?;
+            Some(align_up(ty.response_len(), ty.alignment()))
+        }
+
+        /// The parameters of this type.
+        pub fn parameters(&self) -> impl Iterator<Item = Parameter<'sdb>> + '_ {
+            self.sdb.parameters().filter(move |p| p.descr == self.descr)
+        }
+
+        /// For pointer types, the type of the data pointed to.
+        pub fn pointer_target(&self) -> Option<TypeInfo<'sdb>> {
+            let TypeDescPayload::Pointer(idx) = self.descr().payload else {
+                return None;
+            };
+            (self.sdb.type_descr.len() > idx as usize).then(|| Self::new(self.sdb, idx))
+        }
+    }
+
+    /// The type in IEC 61131-3 style, with the contents of arrays and structs, e.g.
+    /// `ARRAY[4] OF T_GAUGE { Name: STRING[81], Value: REAL }`. Pointer targets are
+    /// given by name only, since they can refer back to the containing type.
+    impl Display for TypeInfo<'_> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self.kind() {
+                TypeKind::String => write!(f, "STRING[{}]", self.response_len()),
+                TypeKind::Array => {
+                    let Some((elem, dims)) = self.array_info() else {
+                        return write!(f, "{}", self.name());
+                    };
+                    match dims {
+                        [n, 0] => write!(f, "ARRAY[{n}] OF {elem}"),
+                        [n, m] => write!(f, "ARRAY[{n},{m}] OF {elem}"),
+                    }
+                }
+                TypeKind::Data => {
+                    write!(f, "{} {{ ", self.name())?;
+                    for (i, m) in self.struct_info().into_iter().flatten().enumerate() {
+                        let sep = if i > 0 { ", " } else { "" };
+                        write!(f, "{sep}{}: {}", m.name, m.type_info)?;
+                    }
+                    write!(f, " }}")
+                }
+                TypeKind::Pointer => match self.pointer_target() {
+                    Some(target) => write!(f, "POINTER TO {}", target.name()),
+                    None => write!(f, "{}", self.name()),
+                },
+                kind => write!(f, "{kind}"),
+            }
+        }
     }
 
     #[derive(Clone, Debug)]
     pub struct StructMemberInfo<'a> {
         pub name: &'a str,
+        /// Byte offset of the member from the start of the struct.
+        pub offset: usize,
+        /// Size of the member in bytes.
+        pub size: usize,
         pub type_info: TypeInfo<'a>,
     }
 
+    fn align_up(pos: usize, align: usize) -> usize {
+        pos.next_multiple_of(align)
+    }
+
+    #[deprecated(note = "construct an SdbStore instead")]
     pub fn read_sdb_file() -> Result<Rc<Sdb>> {
-        Sdb::from_file("sdb.dat")
+        crate::sdb_store::SdbStore::default().load()
+    }
+}
+
+/// How much of the SDB is parsed up front.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Parse and validate everything while loading.
+    #[default]
+    Full,
+    /// Only parse the header and the parameter table. The type descriptions
+    /// are parsed when they are first inspected.
+    Lazy,
+}
+
+/// A parameter name missing from the SDB, returned inside the [`anyhow::Error`] of
+/// [`Sdb::param_by_name`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownParameter {
+    pub name: String,
+    /// The closest parameter name, if any is reasonably close.
+    pub suggestion: Option<String>,
+}
+
+impl std::fmt::Display for UnknownParameter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Parameter name '{}' not found", self.name)?;
+        match &self.suggestion {
+            Some(similar) => write!(f, ", did you mean '{similar}'?"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for UnknownParameter {}
+
+/// The header section of an SDB file.
+///
+/// The file is a sequence of sections, each starting with a tag and its length in
+/// bytes: 1 is this header, 2 the types, 3 the parameters and 6 a tail of unknown
+/// content.
+#[binread]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[br(little, magic = 1u32)]
+pub struct SdbHeader {
+    #[br(temp, assert(section_len == SdbHeader::LEN as u32, "SDB header length {section_len}"))]
+    section_len: u32,
+    /// Sent at the end of every parameter read packet.
+    #[br(magic = 1u32)]
+    pub sdb_id: u32,
+    /// Changes with the content of the SDB. It isn't any of the common CRC-32 variants
+    /// or sums over the file, so it can be compared but not verified.
+    pub checksum: u32,
+    /// The size of the whole file in bytes.
+    pub total_size: u32,
+}
+
+impl SdbHeader {
+    pub const LEN: usize = 24;
+
+    /// Reads only the header of an SDB file, which is much quicker than parsing it.
+    pub fn from_file(file: impl AsRef<Path>) -> Result<Self> {
+        let mut bytes = [0; Self::LEN];
+        std::fs::File::open(file)?.read_exact(&mut bytes)?;
+        Ok(Self::read(&mut std::io::Cursor::new(bytes))?)
     }
 }
 
 #[binread]
 #[derive(Clone, Debug)]
-#[br(little)]
+#[br(little, import(mode: ParseMode))]
 pub struct Sdb {
-    #[br(magic = 1u32, temp)]
-    hdr_len: u32,
-    #[br(magic = 1u32)]
-    /// Sent at the end of every parameter read packet
-    pub(crate) sdb_id: u32,
-    maybe_checksum: u32,
-    /// Total size of the SDB in bytes
-    total_sbd_size: u32,
-    hdr_data_2: [u32; 3],
-    #[br(temp)]
-    type_descr_cnt: u32,
+    header: SdbHeader,
 
-    #[br(count = type_descr_cnt, map = |mut vec: Vec<TypeDescription>| {
-        vec.iter_mut().enumerate().for_each(|(idx, t)|t.type_idx = idx as _); vec
-    })]
-    type_descr: Vec<TypeDescription>,
+    #[br(magic = 2u32)]
+    type_section_len: u32,
+    #[br(magic = 0u32, temp)]
+    type_descr_cnt: u32,
+    #[br(args(type_descr_cnt, mode))]
+    type_descr: TypeDescrTable,
 
     #[br(magic = 3u32)]
-    len_xx: u32, // maybe a length field
-    #[br(magic = 0u32, temp)] // consume four NUL bytes with magic
+    param_section_len: u32,
+    #[br(magic = 0u32, temp)]
     param_cnt: u32,
     #[br(args(param_cnt,))]
     parameters: SdbParams,
@@ -170,8 +511,59 @@ pub struct Sdb {
     tail: Vec<u8>,
 }
 
+/// Brings a parameter path to a canonical form for comparison: lower case, with a
+/// leading dot, and numeric path components as indices, so `Gauge.1.Value` and
+/// `.gauge[1].value` are equal.
+pub fn normalize_param_path(name: &str) -> String {
+    let name = name.trim_end_matches('\0').trim();
+    let mut out = String::with_capacity(name.len() + 1);
+    for part in name.split('.').filter(|p| !p.is_empty()) {
+        if part.bytes().all(|b| b.is_ascii_digit()) && !out.is_empty() {
+            out.push('[');
+            out.push_str(part);
+            out.push(']');
+        } else {
+            out.push('.');
+            out.extend(part.chars().flat_map(char::to_lowercase));
+        }
+    }
+    out
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { diag } else { diag + 1 };
+            diag = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The parameter table. The names are stored back to back in one buffer, rather than
+/// as thousands of separately allocated strings. Path prefixes shared between names
+/// aren't compressed, so that the names can be borrowed from the buffer.
 #[derive(Clone, Debug)]
-struct SdbParams(Box<[SdbParam]>);
+struct SdbParams {
+    params: Box<[SdbParam]>,
+    names: String,
+}
+
+impl SdbParams {
+    fn name(&self, idx: usize) -> &str {
+        self.params[idx].name.get(&self.names)
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.params.iter().map(|p| p.name.get(&self.names))
+    }
+}
 
 impl BinRead for SdbParams {
     type Args<'a> = (u32,);
@@ -182,38 +574,106 @@ impl BinRead for SdbParams {
         args: Self::Args<'_>,
     ) -> BinResult<Self> {
         let count = args.0 as usize;
-        let mut x = Vec::with_capacity(count);
+        let mut params = Vec::with_capacity(count);
+        let mut names = String::with_capacity(count * 32);
         for _ in 0..count {
-            x.push(SdbParam::read_options(reader, endian, ())?);
+            let pos = reader.stream_position()?;
+            let entry = SdbParamEntry::read_options(reader, endian, ())?;
+            let span = u32::try_from(names.len())
+                .ok()
+                .zip(u16::try_from(entry.name.as_str().len()).ok());
+            let Some((start, len)) = span else {
+                return Err(binrw::Error::AssertFail {
+                    pos,
+                    message: "Parameter names too long to store".into(),
+                });
+            };
+            let name = NameSpan { start, len };
+            names.push_str(entry.name.as_str());
+            params.push(SdbParam {
+                type_descr_idx: entry.type_descr_idx,
+                flags: entry.flags,
+                rw: entry.rw,
+                id: entry.id,
+                name,
+            });
         }
-        Ok(Self(x.into_boxed_slice()))
+        names.shrink_to_fit();
+        Ok(Self {
+            params: params.into_boxed_slice(),
+            names,
+        })
     }
 }
 
 impl Deref for SdbParams {
     type Target = [SdbParam];
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.params
     }
 }
 
 impl Sdb {
     pub fn from_file(file: impl AsRef<Path>) -> Result<Rc<Sdb>> {
+        Self::from_file_with(file, ParseMode::Full)
+    }
+
+    pub fn from_file_with(file: impl AsRef<Path>, mode: ParseMode) -> Result<Rc<Sdb>> {
         let mut file = std::fs::File::open(file)?;
 
         let mut reader = std::io::Cursor::new(Vec::new());
         file.read_to_end(reader.get_mut())?;
 
-        let sdb = Sdb::read(&mut reader).context("Failed to parse SDB file.")?;
+        let sdb = Sdb::read_args(&mut reader, (mode,)).context("Failed to parse SDB file.")?;
         Ok(Rc::new(sdb))
     }
 
+    /// Parses the SDB file from a memory map, without reading it into a buffer first.
+    #[cfg(feature = "mmap")]
+    pub fn from_mmap(file: impl AsRef<Path>) -> Result<Rc<Sdb>> {
+        Self::from_mmap_with(file, ParseMode::Full)
+    }
+
+    /// Like [`Sdb::from_mmap`], with the given parse mode.
+    #[cfg(feature = "mmap")]
+    pub fn from_mmap_with(file: impl AsRef<Path>, mode: ParseMode) -> Result<Rc<Sdb>> {
+        let file = std::fs::File::open(file)?;
+        // SAFETY: The map is only read while parsing, and the parsed SDB owns its data.
+        // SDB files are replaced by renaming, see `SdbStore::download`, so the mapped
+        // file isn't changed underneath, unless something else writes to it in place.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let sdb = Sdb::read_args(&mut std::io::Cursor::new(&map[..]), (mode,))
+            .context("Failed to parse SDB file.")?;
+        Ok(Rc::new(sdb))
+    }
+
+    pub fn from_bytes(bytes: &[u8], mode: ParseMode) -> Result<Rc<Sdb>> {
+        let sdb = Sdb::read_args(&mut std::io::Cursor::new(bytes), (mode,))
+            .context("Failed to parse SDB.")?;
+        Ok(Rc::new(sdb))
+    }
+
+    /// Identifies the SDB version, the instrument reports the same id in its
+    /// version response.
+    pub fn sdb_id(&self) -> u32 {
+        self.header.sdb_id
+    }
+
+    /// The size of the SDB file according to its header.
+    pub fn total_size(&self) -> usize {
+        self.header.total_size as usize
+    }
+
+    pub fn header(&self) -> &SdbHeader {
+        &self.header
+    }
+
     pub fn get_ref(&self) -> &Sdb {
         self
     }
 
     /// Returns an iterator over all the parameters in the SDB.
-    pub fn parameters(&self) -> impl Iterator<Item = Parameter> + '_ {
+    pub fn parameters(&self) -> impl Iterator<Item = Parameter<'_>> + '_ {
         self.parameters
             .iter()
             .map(|p| p.type_descr_idx)
@@ -221,24 +681,218 @@ impl Sdb {
             .map(move |(param_idx, type_idx)| Parameter::new(self, param_idx, type_idx as usize))
     }
 
-    pub fn param_by_name(&self, name: &str) -> Result<Parameter> {
-        let param = self
-            .parameters
-            .iter()
-            .position(|p| p.name == name)
-            .with_context(|| format!("Parameter name '{name}' not found"))?;
+    /// The parameters with values of the given kind.
+    pub fn parameters_of_kind(&self, kind: TypeKind) -> impl Iterator<Item = Parameter<'_>> + '_ {
+        self.parameters().filter(move |p| p.value_kind() == kind)
+    }
+
+    /// The parameters which aren't hidden.
+    pub fn visible_parameters(&self) -> impl Iterator<Item = Parameter<'_>> + '_ {
+        self.parameters().filter(|p| !p.is_hidden())
+    }
+
+    /// The visible parameters with scalar or string values. Arrays and structs are left
+    /// out, since they overlap their own elements.
+    pub fn scalar_parameters(&self) -> impl Iterator<Item = Parameter<'_>> + '_ {
+        self.visible_parameters()
+            .filter(|p| p.value_kind().is_scalar())
+    }
+
+    /// The number of parameters of each kind, hidden ones included.
+    pub fn count_by_kind(&self) -> BTreeMap<TypeKind, usize> {
+        let mut counts = BTreeMap::new();
+        for p in self.parameters() {
+            *counts.entry(p.value_kind()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Returns an iterator over all the type descriptions in the SDB.
+    pub fn types(&self) -> impl Iterator<Item = TypeInfo<'_>> + '_ {
+        (0..self.type_descr.len() as u32).map(move |idx| TypeInfo::new(self, idx))
+    }
+
+    pub fn type_by_index(&self, idx: usize) -> Option<TypeInfo<'_>> {
+        (idx < self.type_descr.len()).then(|| TypeInfo::new(self, idx as u32))
+    }
+
+    /// Looks up a parameter by name. An exact match is preferred, otherwise the names
+    /// are compared in their [normalized](normalize_param_path) form, so that e.g.
+    /// `gauge.1.parameter.1.value` finds `.Gauge[1].Parameter[1].Value`.
+    pub fn param_by_name(&self, name: &str) -> Result<Parameter<'_>> {
+        let param = match self.parameters.names().position(|n| n == name) {
+            Some(param) => param,
+            None => self.find_normalized(name)?,
+        };
 
         let type_idx = self.parameters[param].type_descr_idx as usize;
         if type_idx >= self.type_descr.len() {
             bail!("Invalid type descriptor index for parameter {}.", name)
         }
+        TypeInfo::new(self, type_idx as u32)
+            .check()
+            .with_context(|| format!("Parameter {name} has an invalid type."))?;
         Ok(Parameter::new(self, param, type_idx))
     }
 
+    fn find_normalized(&self, name: &str) -> Result<usize> {
+        let wanted = normalize_param_path(name);
+        let mut matches = self
+            .parameters
+            .names()
+            .enumerate()
+            .filter(|(_, n)| normalize_param_path(n) == wanted);
+        match (matches.next(), matches.next()) {
+            (Some((idx, _)), None) => Ok(idx),
+            (Some((_, a)), Some((_, b))) => {
+                bail!("Parameter name '{name}' is ambiguous, it matches both '{a}' and '{b}'.")
+            }
+            (None, _) => Err(UnknownParameter {
+                name: name.to_string(),
+                suggestion: self.suggest_name(&wanted).map(str::to_string),
+            }
+            .into()),
+        }
+    }
+
+    /// The parameter name closest to the normalized path, if any is reasonably close.
+    fn suggest_name(&self, wanted: &str) -> Option<&str> {
+        let max_distance = (wanted.len() / 4).max(2);
+        self.parameters
+            .names()
+            .map(|n| (edit_distance(&normalize_param_path(n), wanted), n))
+            .filter(|(d, _)| *d <= max_distance)
+            .min_by_key(|(d, _)| *d)
+            .map(|(_, n)| n)
+    }
+
+    /// The number of bytes used to store the parameter names.
+    pub fn name_storage_size(&self) -> usize {
+        self.parameters.names.capacity() + self.parameters.len() * size_of::<NameSpan>()
+    }
+
+    /// Returns the parameters with the given id. Arrays and structs share
+    /// the id with their first element, so there can be more than one.
+    pub fn params_by_id(&self, id: u32) -> impl Iterator<Item = Parameter<'_>> + '_ {
+        self.parameters().filter(move |p| p.id() == id)
+    }
+
     fn get_desc(&self, idx: u32) -> Result<&TypeDescription> {
         self.type_descr
             .get(idx as usize)
-            .context("Type descriptor not found")
+            .context("Type descriptor not found")?
+            .decode()
+    }
+}
+
+/// The type descriptions, each possibly still unparsed.
+#[derive(Clone, Debug)]
+struct TypeDescrTable(Box<[TypeDescrSlot]>);
+
+#[derive(Clone, Debug)]
+struct TypeDescrSlot {
+    idx: u32,
+    kind: TypeKind,
+    type_size: u32,
+    /// The encoded entry, kept until it is parsed.
+    raw: Box<[u8]>,
+    descr: OnceCell<TypeDescription>,
+    /// Why the entry couldn't be parsed, if it couldn't.
+    error: OnceCell<String>,
+}
+
+impl TypeDescrSlot {
+    /// The type description, parsing it if the SDB was loaded lazily.
+    fn decode(&self) -> Result<&TypeDescription> {
+        let descr = self.get();
+        match self.error.get() {
+            Some(e) => bail!("Invalid type description #{}: {e}", self.idx),
+            None => Ok(descr),
+        }
+    }
+
+    /// Like [`TypeDescrSlot::decode`], but an entry which can't be parsed is taken as
+    /// a type without contents, for the infallible accessors of [`TypeInfo`]. Lookups
+    /// check their types with [`TypeInfo::check`] first.
+    fn get(&self) -> &TypeDescription {
+        self.descr.get_or_init(|| {
+            let idx = self.idx;
+            TypeDescription::read(&mut std::io::Cursor::new(&self.raw))
+                .map(|mut t| {
+                    t.type_idx = idx;
+                    t
+                })
+                .unwrap_or_else(|e| {
+                    error!("Invalid type description #{idx}: {e}");
+                    let _ = self.error.set(e.to_string());
+                    TypeDescription {
+                        type_idx: idx,
+                        kind: self.kind,
+                        type_size: self.type_size,
+                        description: SdbStr::default(),
+                        payload: TypeDescPayload::None,
+                    }
+                })
+        })
+    }
+}
+
+impl BinRead for TypeDescrTable {
+    type Args<'a> = (u32, ParseMode);
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        (count, mode): Self::Args<'_>,
+    ) -> BinResult<Self> {
+        let mut slots = Vec::with_capacity(count as usize);
+        for idx in 0..count {
+            let slot = match mode {
+                ParseMode::Full => {
+                    let mut descr = TypeDescription::read_options(reader, endian, ())?;
+                    descr.type_idx = idx;
+                    TypeDescrSlot {
+                        idx,
+                        kind: descr.kind,
+                        type_size: descr.type_size,
+                        raw: Box::default(),
+                        descr: OnceCell::from(descr),
+                        error: OnceCell::new(),
+                    }
+                }
+                ParseMode::Lazy => {
+                    let pos = reader.stream_position()?;
+                    let (magic, len) = <(u32, u32)>::read_options(reader, endian, ())?;
+                    if magic != 4 || len < 16 {
+                        return Err(binrw::Error::AssertFail {
+                            pos,
+                            message: format!("Invalid type description header {magic} {len}"),
+                        });
+                    }
+                    let (kind, type_size) = <(TypeKind, u32)>::read_options(reader, endian, ())?;
+                    let mut raw = vec![0; len as usize];
+                    reader.seek(SeekFrom::Start(pos))?;
+                    reader.read_exact(&mut raw)?;
+                    TypeDescrSlot {
+                        idx,
+                        kind,
+                        type_size,
+                        raw: raw.into_boxed_slice(),
+                        descr: OnceCell::new(),
+                        error: OnceCell::new(),
+                    }
+                }
+            };
+            slots.push(slot);
+        }
+        Ok(Self(slots.into_boxed_slice()))
+    }
+}
+
+impl Deref for TypeDescrTable {
+    type Target = [TypeDescrSlot];
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
 
@@ -268,7 +922,7 @@ impl TypeDescription {
 }
 
 /// The various parameter data types
-#[derive(Copy, Clone, Debug, BinRead, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, BinRead, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[br(repr(u32), little)]
 pub enum TypeKind {
     Bool = 0,
@@ -294,6 +948,150 @@ pub enum TypeKind {
     Pointer = 0x17,
 }
 
+impl TypeKind {
+    /// Whether values of this kind are single values, i.e. not arrays or structs.
+    /// Strings count as scalars.
+    pub fn is_scalar(&self) -> bool {
+        !matches!(self, TypeKind::Array | TypeKind::Data)
+    }
+}
+
+/// Rust types holding the values of a scalar [`TypeKind`], see [`kind_of`].
+pub trait OpcKind {
+    /// The kind the values are read and written as.
+    const KIND: TypeKind;
+}
+
+/// The kind values of `T` are read and written as, e.g. [`TypeKind::Int`] for `i16`.
+pub const fn kind_of<T: OpcKind>() -> TypeKind {
+    T::KIND
+}
+
+/// The correspondence of kinds and Rust types, the first kind of a type being the one
+/// [`kind_of`] returns. The sizes on the wire are checked against the Rust types, and
+/// the ranges of the integer kinds are those of theirs.
+macro_rules! kind_bindings {
+    (
+        ints { $($int:ident: $isize:literal => $ikind:ident $(| $iother:ident)*),+ $(,)? }
+        $($ty:ident: $size:literal => $kind:ident $(| $other:ident)*),+ $(,)?
+    ) => {
+        kind_bindings!(@impl $($int: $isize => $ikind $(| $iother)*,)+ $($ty: $size => $kind $(| $other)*,)+);
+
+        impl TypeKind {
+            /// The smallest and largest values of an integer kind, None for other kinds.
+            pub const fn int_range(&self) -> Option<(i64, i64)> {
+                match self {
+                    $(TypeKind::$ikind $(| TypeKind::$iother)* => {
+                        Some(($int::MIN as i64, $int::MAX as i64))
+                    })+
+                    _ => None,
+                }
+            }
+        }
+    };
+    (@impl $($ty:ident: $size:literal => $kind:ident $(| $other:ident)*,)+) => {
+        $(
+            impl OpcKind for $ty {
+                const KIND: TypeKind = TypeKind::$kind;
+            }
+            const _: () = assert!(std::mem::size_of::<$ty>() == $size);
+        )+
+
+        impl TypeKind {
+            /// The Rust type holding values of this kind, e.g. `i16` for INT. TIME values
+            /// are milliseconds. None for arrays and structs.
+            pub const fn rust_type(&self) -> Option<&'static str> {
+                match self {
+                    $(TypeKind::$kind $(| TypeKind::$other)* => Some(stringify!($ty)),)+
+                    TypeKind::String => Some("String"),
+                    TypeKind::Array | TypeKind::Data => None,
+                }
+            }
+
+            /// The size of values of this kind on the wire. None for strings, whose
+            /// length is that of their type, and for arrays and structs.
+            pub const fn wire_size(&self) -> Option<usize> {
+                match self {
+                    $(TypeKind::$kind $(| TypeKind::$other)* => Some($size),)+
+                    TypeKind::String | TypeKind::Array | TypeKind::Data => None,
+                }
+            }
+        }
+    };
+}
+
+kind_bindings! {
+    ints {
+        i16: 2 => Int,
+        u8: 1 => Byte,
+        u16: 2 => Word | Uint,
+        u32: 4 => Dword | Udint | Pointer | Time,
+    }
+    bool: 1 => Bool,
+    f32: 4 => Real,
+}
+
+impl TypeKind {
+    /// The value of an integer kind from its bytes on the wire. None for other kinds,
+    /// or if `bytes` isn't of the kind's size.
+    pub fn decode_int(&self, bytes: &[u8], endian: Endian) -> Option<i64> {
+        let (min, _) = self.int_range()?;
+        if Some(bytes.len()) != self.wire_size() {
+            return None;
+        }
+        let mut raw = 0u64;
+        for i in 0..bytes.len() {
+            let b = match endian {
+                Endian::Big => bytes[i],
+                Endian::Little => bytes[bytes.len() - 1 - i],
+            };
+            raw = raw << 8 | b as u64;
+        }
+        let unused = 64 - 8 * bytes.len() as u32;
+        Some(match min < 0 {
+            // Sign extended.
+            true => ((raw << unused) as i64) >> unused,
+            false => raw as i64,
+        })
+    }
+
+    /// The big endian bytes of `value` as an integer kind. None for other kinds, or if
+    /// the value is out of the kind's range.
+    pub fn encode_int(&self, value: i64) -> Option<Vec<u8>> {
+        let (min, max) = self.int_range()?;
+        let size = self.wire_size()?;
+        (min..=max)
+            .contains(&value)
+            .then(|| value.to_be_bytes()[8 - size..].to_vec())
+    }
+}
+
+impl OpcKind for String {
+    const KIND: TypeKind = TypeKind::String;
+}
+
+/// The IEC 61131-3 name, e.g. `REAL`. Structs are `STRUCT`.
+impl Display for TypeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TypeKind::Bool => "BOOL",
+            TypeKind::Int => "INT",
+            TypeKind::Byte => "BYTE",
+            TypeKind::Word => "WORD",
+            TypeKind::Dword => "DWORD",
+            TypeKind::Real => "REAL",
+            TypeKind::Time => "TIME",
+            TypeKind::String => "STRING",
+            TypeKind::Array => "ARRAY",
+            TypeKind::Data => "STRUCT",
+            TypeKind::Uint => "UINT",
+            TypeKind::Udint => "UDINT",
+            TypeKind::Pointer => "POINTER",
+        };
+        f.write_str(name)
+    }
+}
+
 #[derive(Clone, Debug)]
 enum TypeDescPayload {
     None,
@@ -324,9 +1122,8 @@ impl BinRead for TypeDescPayload {
 }
 
 #[binread]
-#[derive(Clone, PartialEq)]
 #[br(little, magic = 0x05u32)]
-struct SdbParam {
+struct SdbParamEntry {
     #[br(temp)]
     len: u32,
     type_descr_idx: u32,
@@ -337,6 +1134,56 @@ struct SdbParam {
     name: SdbStr,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+struct SdbParam {
+    type_descr_idx: u32,
+    flags: [u16; 2],
+    rw: AccessMode,
+    id: u32,
+    name: NameSpan,
+}
+
+/// The location of a parameter name in [`SdbParams::names`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct NameSpan {
+    start: u32,
+    len: u16,
+}
+
+impl NameSpan {
+    fn get(self, names: &str) -> &str {
+        &names[self.start as usize..][..self.len as usize]
+    }
+}
+
+bitflags::bitflags! {
+    /// The first flags word of an SDB parameter entry.
+    ///
+    /// The meanings are inferred from the SDB contents, e.g. the members of the
+    /// `TON` timer block `.Gauge[1].DegasTimer` are flagged `IN`, `PT`: INPUT,
+    /// `Q`, `ET`: OUTPUT and `M`, `StartTime`: none.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub struct ParamFlags: u16 {
+        /// Can be set from outside, like a VAR_INPUT of a function block.
+        const INPUT = 0x01;
+        /// Can be read from outside, like a VAR_OUTPUT of a function block.
+        /// Ordinary struct members and array elements have both INPUT and OUTPUT.
+        const OUTPUT = 0x02;
+        /// Seen on the top-level device arrays and a few mode selectors, possibly
+        /// RETAIN variables. The exact meaning is unknown.
+        const RETAIN = 0x08;
+        /// Set on all top-level variables.
+        const GLOBAL = 0x40;
+    }
+}
+
+impl ParamFlags {
+    /// Function block internals, flagged as neither input, output nor global.
+    pub fn is_hidden(&self) -> bool {
+        !self.intersects(Self::INPUT | Self::OUTPUT | Self::GLOBAL)
+    }
+}
+
 #[derive(BinRead, Debug, Copy, Clone, PartialEq, Eq)]
 #[br(little, repr(u16))]
 pub enum AccessMode {
@@ -345,18 +1192,19 @@ pub enum AccessMode {
     ReadWrite = 0x62,
 }
 
-impl Debug for SdbParam {
+/// `r`, `w` or `rw`.
+impl Display for AccessMode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{:38?} id: {:05x}, type: {:?},\t flags: {:x?}, rw: {:?}",
-            self.name, self.id, self.type_descr_idx, self.flags, self.rw
-        )
+        f.write_str(match self {
+            AccessMode::Read => "r",
+            AccessMode::Write => "w",
+            AccessMode::ReadWrite => "rw",
+        })
     }
 }
 
 #[binread]
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Default, PartialEq)]
 #[br(little)]
 struct SdbStr {
     #[br(temp)]
@@ -367,23 +1215,36 @@ struct SdbStr {
 const SDB_STR_MAX_LEN: usize = 81;
 type SdbStrStorage = compact_str::CompactString;
 
+/// The length field of a string with `text_len` characters: the text is NUL terminated,
+/// and padded with more NULs so that the length field and the string together are a
+/// multiple of four bytes.
+pub(crate) fn padded_str_len(text_len: usize) -> usize {
+    (2 + text_len + 1).next_multiple_of(4) - 2
+}
+
 fn parse_sdbstr<R: Read + Seek>(
     reader: &mut R,
     _endian: Endian,
     args: (u16,),
 ) -> BinResult<SdbStrStorage> {
-    assert!(args.0 as usize <= SDB_STR_MAX_LEN);
-    let mut len = args.0 as usize;
+    let pos = reader.stream_position()?;
+    let invalid = |message: String| binrw::Error::AssertFail { pos, message };
+    let len = args.0 as usize;
+    if len > SDB_STR_MAX_LEN {
+        return Err(invalid(format!(
+            "String length {len} exceeds the maximum of {SDB_STR_MAX_LEN}"
+        )));
+    }
     let mut buffer = [0u8; SDB_STR_MAX_LEN];
     reader.read_exact(&mut buffer[..len])?;
-    // "len" includes 0 to 3 bytes of NUL padding
-    for _ in 0..3 {
-        if buffer[len - 1] != 0 {
-            break;
-        }
-        len -= 1;
+    let buffer = &buffer[..len];
+    let text_len = buffer.iter().position(|&b| b == 0).unwrap_or(len);
+    if buffer[text_len..].iter().any(|&b| b != 0) || len != padded_str_len(text_len) {
+        return Err(invalid(format!(
+            "String of {text_len} characters with bad padding: {buffer:02x?}"
+        )));
     }
-    SdbStrStorage::from_utf8(&buffer[..len])
+    SdbStrStorage::from_utf8(&buffer[..text_len])
         .map_err(|e| binrw::io::Error::new(ErrorKind::InvalidData, e).into())
 }
 
@@ -411,36 +1272,124 @@ impl PartialEq<&str> for SdbStr {
     }
 }
 
-pub fn print_sdb_file() -> Result<()> {
-    let sdb = read_sdb_file()?;
-    println!("{} entries in SDB.", sdb.parameters.len());
-    // entries.sort_by_key(|e| e.value_type);
-    // entries.dedup_by_key(|e| e.value_type);
+/// Writes the type descriptions as a Graphviz DOT graph, with edges from structs to
+/// their member types, from arrays to the element type and from pointers to the target.
+pub fn write_type_graph(sdb: &Sdb, out: &mut impl std::io::Write) -> std::io::Result<()> {
+    writeln!(out, "digraph sdb_types {{")?;
+    writeln!(out, "    node [shape=box, fontname=monospace];")?;
+    for ty in sdb.types() {
+        let idx = ty.index();
+        writeln!(
+            out,
+            "    t{idx} [label=\"#{idx} {}\\n{:?}, {} bytes\"];",
+            dot_escape(ty.name()),
+            ty.kind(),
+            ty.response_len()
+        )?;
+        if let Some(members) = ty.struct_info() {
+            for m in members {
+                writeln!(
+                    out,
+                    "    t{idx} -> t{} [label=\"{} @{}\"];",
+                    m.type_info.index(),
+                    dot_escape(m.name),
+                    m.offset
+                )?;
+            }
+        }
+        if let Some((elem, dims)) = ty.array_info() {
+            let dims = match dims {
+                [n, 0] => format!("{n}"),
+                [n, m] => format!("{n}x{m}"),
+            };
+            writeln!(out, "    t{idx} -> t{} [label=\"[{dims}]\"];", elem.index())?;
+        }
+        if let Some(target) = ty.pointer_target() {
+            writeln!(out, "    t{idx} -> t{} [style=dashed];", target.index())?;
+        }
+    }
+    writeln!(out, "}}")
+}
 
-    println!("Header data {:?}, {:?}", sdb.maybe_checksum, sdb.hdr_data_2);
+fn dot_escape(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_control())
+        .flat_map(|c| match c {
+            '"' | '\\' => vec!['\\', c],
+            c => vec![c],
+        })
+        .collect()
+}
 
-    for t in &sdb.type_descr {
-        // println!("{t:?}");
-        println!(
-            "Type #{:02} {:30?} {:?}, read size: {:>5}, info: {:#?}",
-            t.type_idx, t.description, t.kind, t.type_size, t.payload
-        );
+/// Writes the parameter counts, the section sizes, all types and parameters, and a
+/// hexdump of the tail.
+pub fn write_sdb_dump(sdb: &Sdb, out: &mut impl std::io::Write) -> std::io::Result<()> {
+    writeln!(out, "{} entries in SDB.", sdb.parameters.len())?;
+    for (kind, count) in sdb.count_by_kind() {
+        writeln!(out, "{count:6} {kind:?}")?;
     }
 
-    for p in &*sdb.parameters {
-        let descr = sdb.get_desc(p.type_descr_idx).expect("Invalid type idx.");
-        let name = p.name.as_str();
-        let kind = format!("{:?}~{}", descr.kind, descr.read_len());
-        if descr.kind != TypeKind::Pointer {
-            //    continue;
-        }
-        println!(
-            "{name:38} id: {:05x}, type: {kind:12} {:4x?}, {:?}",
-            p.id, p.flags, p.rw,
-        )
+    writeln!(
+        out,
+        "Header {:x?}, type section {} bytes, parameter section {} bytes",
+        sdb.header, sdb.type_section_len, sdb.param_section_len
+    )?;
+
+    for ty in sdb.types() {
+        writeln!(
+            out,
+            "Type #{:02} {}, read size: {}",
+            ty.index(),
+            ty,
+            ty.response_len()
+        )?;
+    }
+
+    for p in sdb.parameters() {
+        writeln!(out, "{p}, id: {:05x}, flags: {:?}", p.id(), p.flags())?;
     }
 
-    println!("{}", hexdump(&sdb.tail));
+    writeln!(out, "{}", hexdump(&sdb.tail))
+}
+
+/// The types and parameters as JSON, for tools which can't parse SDB files.
+pub fn sdb_json(sdb: &Sdb) -> serde_json::Value {
+    let types: Vec<_> = sdb
+        .types()
+        .map(|ty| {
+            serde_json::json!({
+                "index": ty.index(),
+                "name": ty.name(),
+                "kind": ty.kind().to_string(),
+                "size": ty.response_len(),
+                "definition": ty.to_string(),
+            })
+        })
+        .collect();
+    let parameters: Vec<_> = sdb
+        .parameters()
+        .map(|p| {
+            serde_json::json!({
+                "name": p.name(),
+                "id": p.id(),
+                "type": p.type_info().index(),
+                "access": p.access().to_string(),
+                "flags": p.flags().bits(),
+                "hidden": p.is_hidden(),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "sdb_id": sdb.sdb_id(),
+        "types": types,
+        "parameters": parameters,
+    })
+}
+
+#[deprecated(note = "use write_sdb_dump with an SdbStore instead")]
+pub fn print_sdb_file() -> Result<()> {
+    let sdb = crate::sdb_store::SdbStore::default().load()?;
+    write_sdb_dump(&sdb, &mut std::io::stdout().lock())?;
     Ok(())
 }
 
@@ -478,3 +1427,213 @@ impl Debug for StructMember {
         write!(f, "{} type: {}", self.name.as_str(), self.type_descr_idx)
     }
 }
+
+#[cfg(test)]
+use crate::sdb_builder::test_sdb;
+
+#[test]
+fn test_sdb_str_padding() {
+    let parse = |bytes: &[u8]| SdbStr::read(&mut std::io::Cursor::new(bytes)).map(|s| s.s);
+    let encode = |text: &str, len: usize| {
+        let mut bytes = (len as u16).to_le_bytes().to_vec();
+        bytes.extend(text.as_bytes());
+        bytes.resize(2 + len, 0);
+        bytes
+    };
+    // A terminator and 0 to 3 bytes of padding.
+    for text in ["", "a", "ab", "abc", "BOOL", "STRING"] {
+        let len = padded_str_len(text.len());
+        assert_eq!((2 + len) % 4, 0);
+        assert_eq!(parse(&encode(text, len)).unwrap(), text);
+        assert!(parse(&encode(text, len + 4)).is_err());
+    }
+    let longest = "x".repeat(77);
+    assert_eq!(parse(&encode(&longest, 78)).unwrap(), longest);
+    assert!(parse(&encode(&"x".repeat(80), 82)).is_err());
+    // Not NUL terminated.
+    assert!(parse(&encode("abcdef", 6)).is_err());
+}
+
+#[test]
+fn test_sdb_header() {
+    let sdb = test_sdb();
+    let file = crate::sdb_builder::fixture_file();
+    let header = SdbHeader::from_file(file.path()).unwrap();
+    assert_eq!(header, *sdb.header());
+    assert_eq!(header.sdb_id, 0x25334);
+    assert_eq!(header.total_size as usize, sdb.total_size());
+}
+
+#[test]
+fn test_parameters_of_kind() {
+    let sdb = test_sdb();
+    let counts = sdb.count_by_kind();
+    assert_eq!(counts.values().sum::<usize>(), sdb.parameters().count());
+    let reals: Vec<_> = sdb.parameters_of_kind(TypeKind::Real).collect();
+    assert_eq!(reals.len(), counts[&TypeKind::Real]);
+    assert!(reals
+        .iter()
+        .any(|p| p.name() == ".Gauge[1].Parameter[1].Value"));
+    assert!(sdb
+        .scalar_parameters()
+        .all(|p| !p.is_hidden() && p.value_kind().is_scalar()));
+}
+
+#[test]
+fn test_display() {
+    let sdb = test_sdb();
+    let value = sdb.param_by_name(".Gauge[1].Parameter[1].Value").unwrap();
+    assert_eq!(
+        value.to_string(),
+        ".Gauge[1].Parameter[1].Value: REAL[4] (r)"
+    );
+    let name = sdb.param_by_name(".Gauge[1].Parameter[1].Name").unwrap();
+    assert_eq!(name.type_info().to_string(), "STRING[81]");
+    let param = sdb.param_by_name(".Gauge[1].Parameter").unwrap();
+    let ty = param.type_info().to_string();
+    assert!(ty.starts_with("ARRAY["), "{ty}");
+    assert!(ty.contains("Value: REAL"), "{ty}");
+}
+
+#[test]
+fn test_pointer_target() {
+    use crate::opc_values::Value;
+    let sdb = test_sdb();
+    let ptr = sdb.param_by_name(".Gauge[1].AlarmOut_Ptr[1]").unwrap();
+    assert_eq!(ptr.pointer_target().unwrap().kind(), TypeKind::Dword);
+    let target = sdb.param_by_name(".Gauge[1].AlarmDWord[1]").unwrap();
+    let value = Value::Int(target.id() as i64);
+    assert_eq!(ptr.resolve_pointer(&value), Some(target));
+    assert_eq!(ptr.resolve_pointer(&Value::Int(1)), None);
+    assert!(sdb
+        .param_by_name(".CockpitUser")
+        .unwrap()
+        .pointer_target()
+        .is_none());
+}
+
+#[test]
+fn test_struct_layout() {
+    let sdb = test_sdb();
+    let param = sdb.param_by_name(".Gauge[1].Parameter").unwrap();
+    let array = param.type_info();
+    let (ty, _) = array.array_info().unwrap();
+    let members = ty.struct_info().unwrap();
+    let offsets: Vec<_> = members.iter().map(|m| m.offset).collect();
+    // "WarningValue" would start at the odd offset 159, and is moved to 160.
+    assert_eq!(
+        offsets,
+        [0, 2, 83, 94, 96, 132, 134, 138, 160, 164, 168, 172, 176]
+    );
+    let last = members.last().unwrap();
+    assert_eq!(last.offset + last.size, ty.response_len());
+    assert_eq!(ty.alignment(), 2);
+    assert_eq!(array.array_stride(), Some(178));
+}
+
+#[test]
+fn test_param_flags() {
+    let sdb = test_sdb();
+    let flags = |name| sdb.param_by_name(name).unwrap().flags();
+    assert_eq!(flags(".Gauge[1].DegasTimer.IN"), ParamFlags::INPUT);
+    assert_eq!(flags(".Gauge[1].DegasTimer.ET"), ParamFlags::OUTPUT);
+    let hidden = sdb
+        .parameters()
+        .filter(|p| p.name().starts_with(".Gauge[1].DegasTimer.") && p.is_hidden())
+        .count();
+    assert_eq!(hidden, 2); // M and StartTime
+    assert!(!flags(".Gauge[1].Active").is_hidden());
+    assert!(flags(".CockpitUser").contains(ParamFlags::GLOBAL));
+}
+
+#[test]
+fn test_param_path_matching() {
+    assert_eq!(
+        normalize_param_path("Gauge.1.Parameter.1.Value"),
+        ".gauge[1].parameter[1].value"
+    );
+    assert_eq!(normalize_param_path(".Gauge[1].Value"), ".gauge[1].value");
+
+    let sdb = test_sdb();
+    let param = sdb.param_by_name(".Gauge[1].Parameter[2].Value").unwrap();
+    assert_eq!(
+        sdb.param_by_name("gauge.1.parameter.2.value").unwrap(),
+        param
+    );
+    let err = sdb.param_by_name(".CockpitUsr").unwrap_err().to_string();
+    assert!(err.contains("did you mean '.CockpitUser'"), "{err}");
+}
+
+#[test]
+fn test_lazy_parse() {
+    let full = test_sdb();
+    let file = crate::sdb_builder::fixture_file();
+    let lazy = Sdb::from_file_with(file.path(), ParseMode::Lazy).unwrap();
+    assert!(lazy.type_descr.iter().all(|t| t.descr.get().is_none()));
+    let param = ".Gauge[1].Parameter";
+    let (a, b) = (full.param_by_name(param), lazy.param_by_name(param));
+    let (a, b) = (a.unwrap(), b.unwrap());
+    let (a, b) = (a.type_info(), b.type_info());
+    assert_eq!(a.array_stride(), b.array_stride());
+    assert_eq!(a.kind(), b.kind());
+    assert!(lazy.type_descr.iter().any(|t| t.descr.get().is_some()));
+
+    // A description string longer than allowed, in the type of the parameter.
+    let mut bytes = crate::sdb_builder::fixture();
+    let lazy = Sdb::from_bytes(&bytes, ParseMode::Lazy).unwrap();
+    let idx = lazy
+        .param_by_name(".OPCCounter")
+        .unwrap()
+        .type_info()
+        .index();
+    let raw = &lazy.type_descr[idx].raw;
+    let pos = bytes
+        .windows(raw.len())
+        .position(|w| w == &raw[..])
+        .unwrap();
+    bytes[pos + 16..pos + 18].copy_from_slice(&0xffffu16.to_le_bytes());
+    let lazy = Sdb::from_bytes(&bytes, ParseMode::Lazy).unwrap();
+    let err = lazy.param_by_name(".OPCCounter").unwrap_err();
+    assert!(
+        format!("{err:#}").contains("Invalid type description"),
+        "{err:#}"
+    );
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_from_mmap() {
+    let file = crate::sdb_builder::fixture_file();
+    let path = file.path();
+    let mapped = Sdb::from_mmap(path).unwrap();
+    let read = Sdb::from_file(path).unwrap();
+    assert_eq!(mapped.header(), read.header());
+    assert_eq!(mapped.parameters().count(), read.parameters().count());
+}
+
+#[test]
+fn test_kind_bindings() {
+    assert_eq!(kind_of::<i16>(), TypeKind::Int);
+    assert_eq!(kind_of::<u32>(), TypeKind::Dword);
+    assert_eq!(kind_of::<String>(), TypeKind::String);
+    assert_eq!(TypeKind::Udint.rust_type(), Some("u32"));
+    assert_eq!(TypeKind::Data.rust_type(), None);
+    assert_eq!(
+        TypeKind::Int.decode_int(&[0xff, 0xfe], Endian::Big),
+        Some(-2)
+    );
+    assert_eq!(
+        TypeKind::Word.decode_int(&[0xfe, 0xff], Endian::Little),
+        Some(0xfffe)
+    );
+    assert_eq!(TypeKind::Real.decode_int(&[0; 4], Endian::Big), None);
+    assert_eq!(TypeKind::Int.encode_int(-2), Some(vec![0xff, 0xfe]));
+    assert_eq!(TypeKind::Byte.encode_int(256), None);
+    let sdb = test_sdb();
+    for t in sdb.type_descr.iter() {
+        let t = t.get();
+        if let Some(size) = t.kind().wire_size() {
+            assert_eq!(size, t.read_len(), "{}", t.kind());
+        }
+    }
+}
@@ -0,0 +1,519 @@
+//! Writing SDB files.
+//!
+//! The tests run against [`fixture`], a miniature SDB laid out like the instruments' own,
+//! rather than an instrument's SDB such as the `sdb.dat` in the repository, so that they
+//! don't depend on the working directory or on one instrument's firmware. The benchmarks
+//! use `sdb.dat` where the working directory has one. See
+//! [`SdbHeader`](crate::sdb::SdbHeader) for the file sections.
+
+#[cfg(test)]
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::rc::Rc;
+
+use crate::sdb::{padded_str_len, AccessMode, ParamFlags, TypeKind};
+#[cfg(test)]
+use crate::sdb::{ParseMode, Sdb};
+
+/// The second flags word of top-level variables, and the bit added for arrays and
+/// structs, as seen in the instruments' SDBs.
+const FLAGS2_GLOBAL: u16 = 0x200;
+const FLAGS2_COMPOUND: u16 = 0x1000;
+
+/// The longest name an SDB string can hold.
+const MAX_NAME_LEN: usize = 77;
+
+/// A member of a struct type, see [`SdbBuilder::add_struct`].
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub name: String,
+    /// The index of the member type, as returned by the `add_*` methods.
+    pub ty: u32,
+    pub flags: ParamFlags,
+    pub access: AccessMode,
+}
+
+impl Member {
+    /// An ordinary member, flagged INPUT and OUTPUT and read only.
+    pub fn new(name: &str, ty: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            ty,
+            flags: ParamFlags::INPUT | ParamFlags::OUTPUT,
+            access: AccessMode::Read,
+        }
+    }
+
+    pub fn with_flags(mut self, flags: ParamFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Payload {
+    None,
+    Array {
+        elem: u32,
+        dims: Vec<(u32, u32)>,
+    },
+    /// The members with their offsets.
+    Struct(Vec<(Member, u32)>),
+    Pointer(u32),
+}
+
+#[derive(Clone, Debug)]
+struct Type {
+    kind: TypeKind,
+    size: u32,
+    align: u32,
+    name: String,
+    payload: Payload,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    name: String,
+    ty: u32,
+    flags: [u16; 2],
+    access: AccessMode,
+    id: u32,
+}
+
+/// Writes an SDB from type and variable declarations.
+///
+/// Variables are expanded like the instruments do it: every struct member and the
+/// elements of one-dimensional arrays get parameters of their own, with ids offset
+/// from the variable's by their position in it.
+///
+/// ```
+/// # use leybold_opc_rs::sdb::{AccessMode, ParamFlags, ParseMode, Sdb, TypeKind};
+/// # use leybold_opc_rs::sdb_builder::{Member, SdbBuilder};
+/// let mut builder = SdbBuilder::new(1);
+/// let real = builder.add_type(TypeKind::Real, 4, "REAL");
+/// let gauge = builder.add_struct("T_GAUGE", vec![Member::new("Value", real)]);
+/// let gauges = builder.add_array("ARRAY [1..2] OF T_GAUGE", gauge, &[(1, 2)]);
+/// builder.add_variable(".Gauge", gauges, ParamFlags::GLOBAL, AccessMode::Read, 0x100);
+/// let sdb = Sdb::from_bytes(&builder.build(), ParseMode::Full)?;
+/// assert_eq!(sdb.param_by_name(".Gauge[2].Value")?.id(), 0x104);
+/// # anyhow::Ok(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct SdbBuilder {
+    sdb_id: u32,
+    checksum: Option<u32>,
+    types: Vec<Type>,
+    params: Vec<Entry>,
+}
+
+impl SdbBuilder {
+    pub fn new(sdb_id: u32) -> Self {
+        Self {
+            sdb_id,
+            checksum: None,
+            types: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+
+    /// Sets the header checksum, which is otherwise a hash of the contents.
+    pub fn with_checksum(mut self, checksum: u32) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Adds a scalar type, e.g. `REAL` of 4 bytes or a `STRING` of 81, and returns its
+    /// index.
+    pub fn add_type(&mut self, kind: TypeKind, size: u32, name: &str) -> u32 {
+        let align = match kind.wire_size() {
+            Some(1) | None => 1,
+            _ => 2,
+        };
+        self.push(kind, size, align, name, Payload::None)
+    }
+
+    /// Adds an array type with the given lower and upper bounds for each dimension.
+    pub fn add_array(&mut self, name: &str, elem: u32, dims: &[(u32, u32)]) -> u32 {
+        let count: u32 = dims
+            .iter()
+            .map(|(lower, upper)| upper - lower + 1)
+            .product();
+        let size = self.stride(elem) * count;
+        let align = self.types[elem as usize].align;
+        let dims = dims.to_vec();
+        self.push(
+            TypeKind::Array,
+            size,
+            align,
+            name,
+            Payload::Array { elem, dims },
+        )
+    }
+
+    /// Adds a struct type, laying out the members in order with their alignment.
+    pub fn add_struct(&mut self, name: &str, members: Vec<Member>) -> u32 {
+        let mut pos = 0u32;
+        let mut align = 1;
+        let mut laid_out = Vec::with_capacity(members.len());
+        for member in members {
+            let ty = &self.types[member.ty as usize];
+            let offset = pos.next_multiple_of(ty.align);
+            pos = offset + ty.size;
+            align = align.max(ty.align);
+            laid_out.push((member, offset));
+        }
+        self.push(TypeKind::Data, pos, align, name, Payload::Struct(laid_out))
+    }
+
+    /// Adds a pointer type to the type `target`.
+    pub fn add_pointer(&mut self, target: u32) -> u32 {
+        self.push(TypeKind::Pointer, 4, 2, "POINTER", Payload::Pointer(target))
+    }
+
+    /// Adds a top-level variable, along with its struct members and array elements.
+    ///
+    /// # Panics
+    ///
+    /// If a parameter name is longer than an SDB string can hold.
+    pub fn add_variable(
+        &mut self,
+        name: &str,
+        ty: u32,
+        flags: ParamFlags,
+        access: AccessMode,
+        id: u32,
+    ) -> &mut Self {
+        let flags2 = FLAGS2_GLOBAL | self.compound_flag(ty);
+        self.add_entries(name.to_string(), ty, [flags.bits(), flags2], access, id);
+        self
+    }
+
+    /// Encodes the SDB file.
+    pub fn build(&self) -> Vec<u8> {
+        let mut types = Vec::new();
+        for ty in &self.types {
+            ty.encode(&mut types);
+        }
+        let mut params: Vec<_> = self.params.iter().collect();
+        params.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut entries = Vec::new();
+        for param in params {
+            param.encode(&mut entries);
+        }
+
+        let mut out = Vec::new();
+        for (tag, count, body) in [
+            (2, self.types.len(), types),
+            (3, self.params.len(), entries),
+        ] {
+            put_u32(&mut out, tag);
+            put_u32(&mut out, 16 + body.len() as u32);
+            put_u32(&mut out, 0);
+            put_u32(&mut out, count as u32);
+            out.extend(body);
+        }
+        // An empty tail.
+        put_u32(&mut out, 6);
+        put_u32(&mut out, 8);
+
+        let checksum = self.checksum.unwrap_or_else(|| fnv1a(&out));
+        let total_size = (24 + out.len()) as u32;
+        let mut file = Vec::with_capacity(total_size as usize);
+        for word in [1, 24, 1, self.sdb_id, checksum, total_size] {
+            put_u32(&mut file, word);
+        }
+        file.extend(out);
+        file
+    }
+
+    fn push(&mut self, kind: TypeKind, size: u32, align: u32, name: &str, payload: Payload) -> u32 {
+        self.types.push(Type {
+            kind,
+            size,
+            align,
+            name: name.to_string(),
+            payload,
+        });
+        self.types.len() as u32 - 1
+    }
+
+    fn stride(&self, ty: u32) -> u32 {
+        let ty = &self.types[ty as usize];
+        ty.size.next_multiple_of(ty.align)
+    }
+
+    fn compound_flag(&self, ty: u32) -> u16 {
+        match self.types[ty as usize].kind.is_scalar() {
+            true => 0,
+            false => FLAGS2_COMPOUND,
+        }
+    }
+
+    fn add_entries(&mut self, name: String, ty: u32, flags: [u16; 2], access: AccessMode, id: u32) {
+        assert!(
+            name.len() <= MAX_NAME_LEN,
+            "The parameter name {name} is too long for an SDB."
+        );
+        let payload = self.types[ty as usize].payload.clone();
+        self.params.push(Entry {
+            name: name.clone(),
+            ty,
+            flags,
+            access,
+            id,
+        });
+        match payload {
+            // Like the instruments, multi-dimensional arrays aren't expanded.
+            Payload::Array { elem, dims } if dims.len() == 1 => {
+                let stride = self.stride(elem);
+                for (i, index) in (dims[0].0..=dims[0].1).enumerate() {
+                    let element = format!("{name}[{index}]");
+                    self.add_entries(element, elem, flags, access, id + i as u32 * stride);
+                }
+            }
+            Payload::Struct(members) => {
+                for (member, offset) in members {
+                    let flags = [member.flags.bits(), self.compound_flag(member.ty)];
+                    let path = format!("{name}.{}", member.name);
+                    self.add_entries(path, member.ty, flags, member.access, id + offset);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Type {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        put_u32(&mut body, self.kind as u32);
+        put_u32(&mut body, self.size);
+        put_str(&mut body, &self.name);
+        match &self.payload {
+            Payload::None => {}
+            Payload::Array { elem, dims } => {
+                put_u32(&mut body, *elem);
+                put_u32(&mut body, dims.len() as u32);
+                for (lower, upper) in dims {
+                    put_u32(&mut body, *lower);
+                    put_u32(&mut body, *upper);
+                }
+            }
+            Payload::Struct(members) => {
+                put_u32(&mut body, members.len() as u32);
+                for (member, offset) in members {
+                    let mut entry = Vec::new();
+                    put_u32(&mut entry, member.ty);
+                    // The flags words and the access mode, as in parameter entries.
+                    // The upper half of the second word is unknown.
+                    put_u16(&mut entry, 0x100 | member.flags.bits());
+                    put_u16(&mut entry, 0);
+                    put_u16(&mut entry, member.access as u16);
+                    put_u16(&mut entry, 0);
+                    put_u32(&mut entry, *offset);
+                    put_str(&mut entry, &member.name);
+                    put_entry(&mut body, 5, &entry);
+                }
+            }
+            Payload::Pointer(target) => put_u32(&mut body, *target),
+        }
+        put_entry(out, 4, &body);
+    }
+}
+
+impl Entry {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        put_u32(&mut body, self.ty);
+        put_u16(&mut body, self.flags[0]);
+        put_u16(&mut body, self.flags[1]);
+        put_u16(&mut body, self.access as u16);
+        put_u16(&mut body, 3);
+        put_u32(&mut body, self.id);
+        put_str(&mut body, &self.name);
+        put_entry(out, 5, &body);
+    }
+}
+
+/// Writes an entry with its magic number and length.
+fn put_entry(out: &mut Vec<u8>, magic: u32, body: &[u8]) {
+    put_u32(out, magic);
+    put_u32(out, 8 + body.len() as u32);
+    out.extend_from_slice(body);
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend(value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, text: &str) {
+    let len = padded_str_len(text.len());
+    put_u16(out, len as u16);
+    out.extend_from_slice(text.as_bytes());
+    out.resize(out.len() + len - text.len(), 0);
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// A miniature SDB with the id of the instruments' SDB 0x25334 and the parameters the
+/// tests use: four gauges with their parameter tables, timers, pointers and alarm
+/// words, and a few global settings. The types are laid out as in the real SDB, the
+/// gauges are cut down to a few members.
+pub fn fixture() -> Vec<u8> {
+    use AccessMode::{Read, ReadWrite};
+    use TypeKind::{Bool, Dword, Int, Real, Time};
+
+    let mut b = SdbBuilder::new(0x25334);
+    let bool_ = b.add_type(Bool, 1, "BOOL");
+    let int = b.add_type(Int, 2, "INT");
+    let string = b.add_type(TypeKind::String, 81, "STRING");
+    let string11 = b.add_type(TypeKind::String, 11, "STRING");
+    let string21 = b.add_type(TypeKind::String, 21, "STRING");
+    let string36 = b.add_type(TypeKind::String, 36, "STRING");
+    let real = b.add_type(Real, 4, "REAL");
+    let dword = b.add_type(Dword, 4, "DWORD");
+    let time = b.add_type(Time, 4, "TIME");
+
+    let parameter = b.add_struct(
+        "DATA",
+        [
+            ("Number", int),
+            ("Name", string),
+            ("Unit", string11),
+            ("DecimalPoint", int),
+            ("Prefix", string36),
+            ("MaxIndex", int),
+            ("Value", real),
+            ("StringValue", string21),
+            ("WarningValue", real),
+            ("ErrorValue", real),
+            ("MinValue", real),
+            ("MaxValue", real),
+            ("AccessLevel", int),
+        ]
+        .into_iter()
+        .map(|(name, ty)| Member::new(name, ty))
+        .collect(),
+    );
+    let parameters = b.add_array("DATA", parameter, &[(1, 5)]);
+    // A TON timer function block.
+    let hidden = ParamFlags::empty();
+    let timer = b.add_struct(
+        "DATA",
+        vec![
+            Member::new("M", bool_).with_flags(hidden),
+            Member::new("StartTime", time).with_flags(hidden),
+            Member::new("IN", bool_).with_flags(ParamFlags::INPUT),
+            Member::new("PT", time).with_flags(ParamFlags::INPUT),
+            Member::new("Q", bool_).with_flags(ParamFlags::OUTPUT),
+            Member::new("ET", time).with_flags(ParamFlags::OUTPUT),
+        ],
+    );
+    let pointer = b.add_pointer(dword);
+    let pointers = b.add_array("ARRAY [1..2] OF POINTER", pointer, &[(1, 2)]);
+    let dwords = b.add_array("ARRAY [1..2] OF DWORD", dword, &[(1, 2)]);
+    let gauge = b.add_struct(
+        "DATA",
+        vec![
+            Member::new("Active", bool_),
+            Member::new("DegasTimer", timer),
+            Member::new("AlarmOut_Ptr", pointers),
+            Member::new("AlarmDWord", dwords),
+            Member::new("Parameter", parameters),
+        ],
+    );
+    let gauges = b.add_array("DATA", gauge, &[(0, 3)]);
+    let reals = b.add_array("ARRAY [1..3] OF REAL", real, &[(1, 3)]);
+
+    let global = ParamFlags::GLOBAL;
+    b.add_variable(".CockpitUser", string, global, ReadWrite, 287683)
+        .add_variable(".HostRemote", bool_, global, ReadWrite, 287764)
+        .add_variable(".Gauge", gauges, global | ParamFlags::RETAIN, Read, 290136)
+        .add_variable(".OPCPumpFrequency", reals, global, ReadWrite, 392632)
+        .add_variable(".OPCCounter", dword, global, ReadWrite, 392648);
+    b.build()
+}
+
+/// The [`fixture`] SDB.
+#[cfg(test)]
+pub(crate) fn test_sdb() -> Rc<Sdb> {
+    Sdb::from_bytes(&fixture(), ParseMode::Full).unwrap()
+}
+
+/// The [`fixture`] SDB written to a file of its own, which is removed when this is
+/// dropped.
+#[cfg(test)]
+pub(crate) struct FixtureFile(PathBuf);
+
+#[cfg(test)]
+impl FixtureFile {
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for FixtureFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn fixture_file() -> FixtureFile {
+    static FILES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let n = FILES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let name = format!("sdb-fixture-{}-{n}.dat", std::process::id());
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, fixture()).unwrap();
+    FixtureFile(path)
+}
+
+#[test]
+fn test_fixture() {
+    let bytes = fixture();
+    let sdb = Sdb::from_bytes(&bytes, ParseMode::Full).unwrap();
+    assert_eq!(sdb.total_size(), bytes.len());
+    let lazy = Sdb::from_bytes(&bytes, ParseMode::Lazy).unwrap();
+    assert_eq!(lazy.count_by_kind(), sdb.count_by_kind());
+
+    let id = |name| sdb.param_by_name(name).unwrap().id();
+    let param = |name| sdb.param_by_name(name).unwrap();
+    let gauge = param(".Gauge").type_info().array_stride().unwrap() as u32;
+    assert_eq!(id(".Gauge[1]"), id(".Gauge") + gauge);
+    assert_eq!(
+        id(".Gauge[1].Parameter[2]"),
+        id(".Gauge[1].Parameter[1]") + 178
+    );
+    assert_eq!(
+        id(".Gauge[1].Parameter[1].WarningValue"),
+        id(".Gauge[1].Parameter") + 160
+    );
+    assert_eq!(
+        id(".Gauge[1].DegasTimer.ET"),
+        id(".Gauge[1].DegasTimer") + 14
+    );
+    assert_eq!(param(".Gauge[1]").flags2(), FLAGS2_GLOBAL | FLAGS2_COMPOUND);
+    assert_eq!(param(".Gauge[1].Active").flags2(), 0);
+    assert_eq!(
+        param(".OPCPumpFrequency[3]").access(),
+        AccessMode::ReadWrite
+    );
+    let names: Vec<_> = sdb.parameters().map(|p| p.name().to_string()).collect();
+    assert!(names.is_sorted());
+
+    let checksum = |b: &SdbBuilder| Sdb::from_bytes(&b.build(), ParseMode::Full).unwrap();
+    let mut other = SdbBuilder::new(1);
+    let a = checksum(&other).header().checksum;
+    other.add_type(TypeKind::Bool, 1, "BOOL");
+    assert_ne!(checksum(&other).header().checksum, a);
+    assert_eq!(checksum(&other.with_checksum(7)).header().checksum, 7);
+}
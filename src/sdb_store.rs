@@ -0,0 +1,347 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use tracing::{debug, info, warn};
+
+use crate::events::{ConnectionEvent, ConnectionEvents};
+use crate::packets::cc_payloads::InstrumentVersionQuery;
+use crate::plc_connection::{download_sdb, download_sdb_resume, Connection, DownloadState};
+use crate::sdb::{ParseMode, Sdb, SdbHeader};
+
+/// The SDB file used when no other path is given.
+pub const DEFAULT_SDB_FILE: &str = "sdb.dat";
+
+/// The file name for an SDB with the given id, for keeping the SDBs of several
+/// instruments or versions in one directory.
+pub fn versioned_file_name(sdb_id: u32) -> String {
+    format!("sdb-{sdb_id:08x}.dat")
+}
+
+/// Replaces the file at `path` with `data`, so that readers see either the old or
+/// the new contents, never a partial file.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".part");
+    let tmp = PathBuf::from(tmp);
+    let write = || -> Result<()> {
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.context(format!("Failed to write {}", path.display())));
+    }
+    // Make the rename itself durable.
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Ok(dir) = std::fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// When [`SdbStore::load`] parses the SDB file again.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Parse the file once and keep it until [`SdbStore::invalidate`] is called.
+    #[default]
+    Keep,
+    /// Parse the file again if its modification time has changed, unless the header
+    /// still has the id and checksum of the parsed SDB, e.g. after downloading the same
+    /// SDB again.
+    ReloadIfModified,
+    /// Parse the file on every load.
+    Never,
+}
+
+/// Called with the SDB path when the file is missing. It is expected to create
+/// the file, usually by downloading the SDB from the instrument.
+pub type DownloadHook = Box<dyn Fn(&Path) -> Result<()>>;
+
+/// Owns the location of an SDB file and the parsed SDB.
+///
+/// A process talking to several instruments uses one store per instrument:
+///
+/// ```no_run
+/// # use leybold_opc_rs::sdb_store::{CachePolicy, SdbStore};
+/// let store = SdbStore::new("instruments/chamber1/sdb.dat")
+///     .with_cache_policy(CachePolicy::ReloadIfModified);
+/// let sdb = store.load()?;
+/// # anyhow::Ok(())
+/// ```
+pub struct SdbStore {
+    path: PathBuf,
+    policy: CachePolicy,
+    mode: ParseMode,
+    download: Option<DownloadHook>,
+    events: Option<ConnectionEvents>,
+    cached: RefCell<Option<(Rc<Sdb>, Option<SystemTime>)>>,
+}
+
+impl SdbStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            policy: CachePolicy::default(),
+            mode: ParseMode::default(),
+            download: None,
+            events: None,
+            cached: RefCell::new(None),
+        }
+    }
+
+    pub fn with_cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the hook used to create the SDB file when it doesn't exist.
+    pub fn with_auto_download(mut self, hook: impl Fn(&Path) -> Result<()> + 'static) -> Self {
+        self.download = Some(Box::new(hook));
+        self
+    }
+
+    /// Emits `SdbRefreshed` whenever the SDB file is parsed.
+    pub fn with_events(mut self, events: ConnectionEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the parsed SDB, reading the file if required by the cache policy.
+    pub fn load(&self) -> Result<Rc<Sdb>> {
+        if !self.path.exists() {
+            let download = self.download.as_ref().ok_or_else(|| SdbNotFound {
+                path: self.path.clone(),
+            })?;
+            debug!("Downloading missing SDB to {}", self.path.display());
+            download(&self.path)?;
+        }
+        let mtime = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if let Some((sdb, loaded_mtime)) = &mut *self.cached.borrow_mut() {
+            let fresh = match self.policy {
+                CachePolicy::Keep => true,
+                CachePolicy::ReloadIfModified if *loaded_mtime == mtime => true,
+                CachePolicy::ReloadIfModified => {
+                    let same = SdbHeader::from_file(&self.path).is_ok_and(|h| h == *sdb.header());
+                    if same {
+                        debug!("SDB {} was rewritten unchanged.", self.path.display());
+                        *loaded_mtime = mtime;
+                    }
+                    same
+                }
+                CachePolicy::Never => false,
+            };
+            if fresh {
+                return Ok(sdb.clone());
+            }
+        }
+        let sdb = Sdb::from_file_with(&self.path, self.mode)
+            .with_context(|| format!("Failed to load SDB {}", self.path.display()))?;
+        if let Some(events) = &self.events {
+            events.emit(ConnectionEvent::SdbRefreshed {
+                sdb_id: sdb.sdb_id(),
+            });
+        }
+        if self.policy != CachePolicy::Never {
+            *self.cached.borrow_mut() = Some((sdb.clone(), mtime));
+        }
+        Ok(sdb)
+    }
+
+    /// Downloads the SDB from the instrument into the file, replacing it atomically, and
+    /// returns it parsed. See [`download_sdb`] for the reconnects.
+    pub fn download(
+        &self,
+        connect: impl FnMut() -> Result<Connection>,
+        attempts: u32,
+    ) -> Result<Rc<Sdb>> {
+        let bytes = download_sdb(connect, attempts, SdbHeader::from_file(&self.path).ok())?;
+        write_atomic(&self.path, &bytes)?;
+        self.invalidate();
+        self.load()
+    }
+
+    /// Checks the SDB id reported by the instrument against the loaded SDB. If the SDB
+    /// changed on the instrument, downloads it over `conn`, replaces the file and
+    /// returns the new SDB. Returns `None` when the SDB is unchanged.
+    pub fn refresh(&self, conn: &mut Connection) -> Result<Option<Rc<Sdb>>> {
+        let current = self.load()?.sdb_id();
+        let reported = conn
+            .query(&InstrumentVersionQuery::pkt())?
+            .payload
+            .sdb_version;
+        if reported == current {
+            return Ok(None);
+        }
+        info!("The instrument reports SDB {reported:#010x}, downloading it to replace {current:#010x}.");
+        let mut state = DownloadState::default();
+        download_sdb_resume(conn, &mut state)?;
+        state.verify().context("Downloaded SDB is corrupt")?;
+        write_atomic(&self.path, &state.received)?;
+        self.invalidate();
+        self.load().map(Some)
+    }
+
+    /// Like [`SdbStore::refresh`], for checks while polling: a failed check or download
+    /// is logged and returns `None`, so that polling goes on with the current SDB and
+    /// the next check tries again.
+    pub fn refresh_or_keep(&self, conn: &mut Connection) -> Option<Rc<Sdb>> {
+        match self.refresh(conn) {
+            Ok(changed) => changed,
+            Err(e) => {
+                warn!("Failed to refresh the SDB, keeping the current one: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Drops the cached SDB, so that the next load reads the file again.
+    pub fn invalidate(&self) {
+        self.cached.take();
+    }
+}
+
+/// The SDB file doesn't exist, and the store has no way to download it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SdbNotFound {
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for SdbNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SDB file {} not found", self.path.display())
+    }
+}
+
+impl std::error::Error for SdbNotFound {}
+
+impl Default for SdbStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_SDB_FILE)
+    }
+}
+
+impl std::fmt::Debug for SdbStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SdbStore")
+            .field("path", &self.path)
+            .field("policy", &self.policy)
+            .field("mode", &self.mode)
+            .field("auto_download", &self.download.is_some())
+            .finish()
+    }
+}
+
+#[test]
+fn test_write_atomic() {
+    let dir = std::env::temp_dir().join(format!("sdb-store-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(versioned_file_name(0x25334));
+    assert!(path.ends_with("sdb-00025334.dat"));
+    write_atomic(&path, b"old").unwrap();
+    write_atomic(&path, b"new").unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sdb_store() {
+    let file = crate::sdb_builder::fixture_file();
+    let store = SdbStore::new(file.path());
+    let sdb = store.load().unwrap();
+    assert!(Rc::ptr_eq(&sdb, &store.load().unwrap()));
+    store.invalidate();
+    assert!(!Rc::ptr_eq(&sdb, &store.load().unwrap()));
+
+    let missing = SdbStore::new("no-such-dir/sdb.dat");
+    assert!(missing.load().unwrap_err().is::<SdbNotFound>());
+    let downloaded = SdbStore::new("no-such-dir/sdb.dat")
+        .with_cache_policy(CachePolicy::Never)
+        .with_auto_download(|_| anyhow::bail!("offline"));
+    let err = downloaded.load().unwrap_err().to_string();
+    assert_eq!(err, "offline");
+}
+
+#[test]
+fn test_refresh() {
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = crate::sim::SimulatedPlc::start(&sdb, crate::sdb_builder::fixture()).unwrap();
+    let path = std::env::temp_dir().join(format!("sdb-refresh-test-{}.dat", std::process::id()));
+    // An older SDB, with another id.
+    let mut old = crate::sdb_builder::fixture();
+    old[12] ^= 1;
+    std::fs::write(&path, old).unwrap();
+    let store = SdbStore::new(&path);
+    let mut conn = Connection::connect_addr(sim.addr()).unwrap();
+    assert_ne!(store.load().unwrap().sdb_id(), sdb.sdb_id());
+    let refreshed = store.refresh(&mut conn).unwrap().unwrap();
+    assert_eq!(refreshed.sdb_id(), sdb.sdb_id());
+    assert!(store.refresh(&mut conn).unwrap().is_none());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_refresh_fails() {
+    use crate::client::Client;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    // The download breaks off, as the file served is cut short.
+    let mut served = crate::sdb_builder::fixture();
+    served.truncate(served.len() / 2);
+    let sim = crate::sim::SimulatedPlc::start(&sdb, served).unwrap();
+    let path = std::env::temp_dir().join(format!("sdb-refresh-fail-{}.dat", std::process::id()));
+    let mut old = crate::sdb_builder::fixture();
+    old[12] ^= 1;
+    std::fs::write(&path, old).unwrap();
+    let store = SdbStore::new(&path);
+    let current = store.load().unwrap();
+    let mut client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &current).unwrap();
+    let counter = current.param_by_name(".OPCCounter").unwrap();
+    client.read(std::slice::from_ref(&counter)).unwrap();
+    assert!(store.refresh_or_keep(client.connection()).is_none());
+    // Polling goes on with the current SDB.
+    assert!(Rc::ptr_eq(&current, &store.load().unwrap()));
+    client.read(std::slice::from_ref(&counter)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_reload_unchanged() {
+    let path = std::env::temp_dir().join(format!("sdb-reload-test-{}.dat", std::process::id()));
+    std::fs::write(&path, crate::sdb_builder::fixture()).unwrap();
+    let store = SdbStore::new(&path).with_cache_policy(CachePolicy::ReloadIfModified);
+    let sdb = store.load().unwrap();
+    let touch = |secs| {
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        let time = SystemTime::now() + std::time::Duration::from_secs(secs);
+        file.set_modified(time).unwrap();
+    };
+    touch(10);
+    assert!(Rc::ptr_eq(&sdb, &store.load().unwrap()));
+    // A different checksum.
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[16] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    touch(20);
+    assert!(!Rc::ptr_eq(&sdb, &store.load().unwrap()));
+    std::fs::remove_file(&path).unwrap();
+}
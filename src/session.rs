@@ -0,0 +1,219 @@
+//! An advisory lock against two processes talking to one instrument at once.
+//!
+//! The instruments seem to serve one session at a time. A second client is either
+//! refused, or its queries interleave with the acknowledgements of the first one. So
+//! connections can take a lock file named after the instrument, in the runtime
+//! directory of the user. The connections of one process share the lock. A
+//! [`Broker`](crate::broker::Broker) holding it records its socket in the file, for
+//! other processes to connect through instead.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{bail, Context, Result};
+
+/// The locks held by this process, by lock file.
+static HELD: Mutex<BTreeMap<PathBuf, Weak<File>>> = Mutex::new(BTreeMap::new());
+
+/// Held for as long as a connection to the instrument is open.
+#[derive(Clone, Debug)]
+pub struct SessionLock {
+    file: Arc<File>,
+}
+
+impl SessionLock {
+    /// Takes the lock for `instrument`, the host as given by the user. Fails with
+    /// [`SessionBusy`] when another process holds it.
+    pub fn acquire(instrument: &str) -> Result<Self> {
+        let path = lock_path(instrument)?;
+        let mut held = HELD.lock().unwrap();
+        if let Some(file) = held.get(&path).and_then(Weak::upgrade) {
+            return Ok(Self { file });
+        }
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+        }
+        let mut file = options
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                // Fails where locks are mandatory, then the holder is unknown.
+                let _ = file.read_to_string(&mut holder);
+                return Err(SessionBusy::new(instrument, &holder).into());
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+            }
+        }
+        let lock = Self {
+            file: Arc::new(file),
+        };
+        lock.write_holder(None)?;
+        held.retain(|_, file| file.strong_count() > 0);
+        held.insert(path, Arc::downgrade(&lock.file));
+        Ok(lock)
+    }
+
+    /// Tells other processes to connect through the broker listening on `socket`.
+    pub fn set_broker(&self, socket: &Path) -> Result<()> {
+        self.write_holder(Some(socket))
+    }
+
+    fn write_holder(&self, broker: Option<&Path>) -> Result<()> {
+        let mut file = &*self.file;
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "pid={}", std::process::id())?;
+        if let Some(socket) = broker {
+            writeln!(file, "broker={}", socket.display())?;
+        }
+        Ok(())
+    }
+}
+
+fn lock_path(instrument: &str) -> Result<PathBuf> {
+    let name: String = instrument
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Ok(runtime_dir()?.join(format!("leybold-opc-{name}.lock")))
+}
+
+/// `$XDG_RUNTIME_DIR`, or else a directory of the user in the temp directory, which
+/// no one else can plant files in, for locks and sockets.
+#[cfg(unix)]
+pub(crate) fn runtime_dir() -> Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return Ok(dir.into());
+    }
+    // SAFETY: getuid can't fail.
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("leybold-opc-{uid}"));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+            return Err(e).with_context(|| format!("Failed to create {}", dir.display()))
+        }
+        _ => {}
+    }
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        bail!(
+            "{} is not a private directory of this user, not using it.",
+            dir.display()
+        );
+    }
+    Ok(dir)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn runtime_dir() -> Result<PathBuf> {
+    Ok(std::env::temp_dir())
+}
+
+/// Another process is connected to the instrument.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionBusy {
+    pub instrument: String,
+    pub pid: Option<u32>,
+    /// The socket of the broker the other process runs, if it is one.
+    pub broker: Option<PathBuf>,
+}
+
+impl SessionBusy {
+    fn new(instrument: &str, holder: &str) -> Self {
+        let field = |key| {
+            holder
+                .lines()
+                .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+        };
+        Self {
+            instrument: instrument.to_string(),
+            pid: field("pid").and_then(|pid| pid.parse().ok()),
+            broker: field("broker").map(PathBuf::from),
+        }
+    }
+}
+
+impl std::fmt::Display for SessionBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is in use by another process", self.instrument)?;
+        if let Some(pid) = self.pid {
+            write!(f, " (pid {pid})")?;
+        }
+        match &self.broker {
+            Some(socket) => write!(f, ", connect through its broker at {}.", socket.display()),
+            None => f.write_str(", share one connection through a broker or stop it."),
+        }
+    }
+}
+
+impl std::error::Error for SessionBusy {}
+
+/// The instrument closed a new connection before answering anything, as it does when
+/// it is already serving another client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionRefused;
+
+impl std::fmt::Display for SessionRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(
+            "The instrument closed the connection before answering, it may be serving \
+             another client such as the vendor HMI.",
+        )
+    }
+}
+
+impl std::error::Error for SessionRefused {}
+
+#[test]
+fn test_session_lock() {
+    let instrument = format!("test-{}", std::process::id());
+    let lock = SessionLock::acquire(&instrument).unwrap();
+    // Shared within the process.
+    let again = SessionLock::acquire(&instrument).unwrap();
+    assert!(Arc::ptr_eq(&lock.file, &again.file));
+    lock.set_broker(Path::new("/tmp/broker.sock")).unwrap();
+
+    // A second open file description stands in for another process.
+    let path = lock_path(&instrument).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let other = File::options().read(true).write(true).open(&path).unwrap();
+    assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+    let busy = SessionBusy::new(&instrument, &std::fs::read_to_string(&path).unwrap());
+    assert_eq!(busy.pid, Some(std::process::id()));
+    assert_eq!(busy.broker.as_deref(), Some(Path::new("/tmp/broker.sock")));
+
+    drop((lock, again));
+    assert!(other.try_lock().is_ok());
+    std::fs::remove_file(path).unwrap();
+
+    // Planted symlinks aren't followed.
+    #[cfg(unix)]
+    {
+        let target = std::env::temp_dir().join(format!("{instrument}-target"));
+        std::fs::write(&target, "keep").unwrap();
+        let link = lock_path(&format!("{instrument}-link")).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert!(SessionLock::acquire(&format!("{instrument}-link")).is_err());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "keep");
+        std::fs::remove_file(link).unwrap();
+        std::fs::remove_file(target).unwrap();
+    }
+}
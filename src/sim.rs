@@ -0,0 +1,317 @@
+//! An in-process stand-in for the instrument, for trying out commands and scripts
+//! without hardware.
+//!
+//! It speaks the Vacvision dialect on a local TCP port. Parameter values live in a
+//! byte memory addressed by parameter id, like on the instrument, so writes are read
+//! back and chunked string access works.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use binrw::{BinRead, BinWrite, Endian};
+use tracing::{debug, warn};
+
+use crate::opc_values::{EncodeOpcValue, Value};
+use crate::packets::PacketCCHeader;
+use crate::plc_connection::ACK_RESPONSE;
+use crate::sdb::{Parameter, Sdb};
+
+/// Read responses larger than this are refused, like the instrument refuses responses
+/// beyond its buffer.
+pub const SIM_MAX_RESPONSE_LEN: usize = 0x800;
+
+/// The error code of refused queries.
+pub const SIM_ERROR_CODE: u16 = 0x0001;
+
+const SDB_PART_LEN: usize = 0x400;
+
+/// Bytes by address, zero where nothing was written.
+#[derive(Debug, Default)]
+struct Memory(HashMap<u32, u8>);
+
+impl Memory {
+    fn read(&self, address: u32, len: usize) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| self.0.get(&address.wrapping_add(i)).copied().unwrap_or(0))
+            .collect()
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            self.0.insert(address.wrapping_add(i as u32), *b);
+        }
+    }
+}
+
+/// A simulated instrument answering on a local port. It keeps answering until the
+/// process exits, also after this handle is dropped.
+pub struct SimulatedPlc {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    memory: Mutex<Memory>,
+    /// The number of write packets to leave unanswered, and whether to apply them.
+    lost_writes: Mutex<(usize, bool)>,
+    /// Status bytes other than `1` to answer reads of an address with, without a value.
+    statuses: Mutex<HashMap<u32, u8>>,
+    sdb_id: u32,
+    /// The SDB file, served to SDB downloads.
+    sdb_file: Vec<u8>,
+    started: Instant,
+}
+
+impl SimulatedPlc {
+    /// Starts answering on a free local port. `sdb_file` is the file `sdb` was parsed
+    /// from, served to SDB downloads.
+    pub fn start(sdb: &Sdb, sdb_file: Vec<u8>) -> Result<Self> {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").context("Failed to listen for simulation")?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            memory: Mutex::new(Memory::default()),
+            lost_writes: Mutex::new((0, false)),
+            statuses: Mutex::default(),
+            sdb_id: sdb.sdb_id(),
+            sdb_file,
+            started: Instant::now(),
+        });
+        let serving = shared.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = serving.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve(stream, &shared) {
+                        debug!("Simulated connection ended: {e:#}");
+                    }
+                });
+            }
+        });
+        debug!("Simulated PLC listening on {addr}");
+        Ok(Self { addr, shared })
+    }
+
+    /// The address to connect to instead of the instrument.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sets the value of a parameter, as if the instrument had changed it.
+    pub fn set(&self, param: &Parameter, value: &Value) -> Result<()> {
+        let data = value.opc_encode(&param.type_info())?;
+        self.shared.memory.lock().unwrap().write(param.id(), &data);
+        Ok(())
+    }
+
+    /// Leaves the next `count` write packets unanswered, like an instrument whose
+    /// responses are lost. `apply` tells whether the writes take effect anyway.
+    pub fn lose_writes(&self, count: usize, apply: bool) {
+        *self.shared.lost_writes.lock().unwrap() = (count, apply);
+    }
+
+    /// Answers reads of the parameter with `status` in front of its value, leaving the
+    /// value out unless the status is `1`, like firmware variants may.
+    pub fn set_status(&self, param: &Parameter, status: u8) {
+        self.shared
+            .statuses
+            .lock()
+            .unwrap()
+            .insert(param.id(), status);
+    }
+
+    /// The current value of a parameter.
+    pub fn get(&self, param: &Parameter) -> Result<Value> {
+        let ty = param.type_info();
+        let data = self
+            .shared
+            .memory
+            .lock()
+            .unwrap()
+            .read(param.id(), ty.response_len());
+        Ok(Value::read_options(
+            &mut Cursor::new(data),
+            Endian::Big,
+            ty,
+        )?)
+    }
+}
+
+/// Answers queries on one connection until the client disconnects.
+fn serve(mut stream: TcpStream, shared: &Shared) -> Result<()> {
+    let mut download_offset = 0;
+    loop {
+        let mut hdr_bytes = [0; PacketCCHeader::LEN];
+        stream.read_exact(&mut hdr_bytes)?;
+        if hdr_bytes.starts_with(&[0x66, 0x66]) {
+            stream.write_all(&ACK_RESPONSE)?;
+            continue;
+        }
+        let hdr = PacketCCHeader::read_options(&mut Cursor::new(&hdr_bytes), Endian::Big, ())?;
+        let mut payload = vec![0; hdr.payload_len as usize];
+        stream.read_exact(&mut payload)?;
+        let response = match payload.first() {
+            Some(0x11) => version_response(shared),
+            // Without a file to serve, like instruments without the file API.
+            Some(0x34) if shared.sdb_file.is_empty() => refuse(&anyhow::anyhow!("No SDB file")),
+            Some(0x34) => {
+                let mut r = ok();
+                r.extend((shared.sdb_file.len() as u32).to_be_bytes());
+                r.extend([0; 16]);
+                r
+            }
+            Some(0x31) => {
+                download_offset = 0;
+                download_part(shared, &mut download_offset)
+            }
+            Some(0x32) => download_part(shared, &mut download_offset),
+            Some(0x2e) => read_response(shared, &payload).unwrap_or_else(|e| refuse(&e)),
+            Some(0x3c) => {
+                let mut lost = shared.lost_writes.lock().unwrap();
+                if lost.0 > 0 {
+                    lost.0 -= 1;
+                    if lost.1 {
+                        write_response(shared, &payload)?;
+                    }
+                    continue;
+                }
+                drop(lost);
+                write_response(shared, &payload).unwrap_or_else(|e| refuse(&e))
+            }
+            _ => refuse(&anyhow::anyhow!("Unknown command {:02x?}", &payload[..1])),
+        };
+        send(&mut stream, &response, hdr.payload_len)?;
+    }
+}
+
+fn ok() -> Vec<u8> {
+    0u16.to_be_bytes().to_vec()
+}
+
+fn refuse(e: &anyhow::Error) -> Vec<u8> {
+    warn!("Simulated PLC refused a query: {e:#}");
+    SIM_ERROR_CODE.to_be_bytes().to_vec()
+}
+
+/// Sends a response to a request with a payload of `request_len` bytes, which the
+/// instrument echoes in `len2`.
+fn send(stream: &mut TcpStream, payload: &[u8], request_len: u16) -> Result<()> {
+    let len = payload.len() as u16;
+    let hdr = PacketCCHeader {
+        b17: 0x27,
+        ..Default::default()
+    };
+    let mut bytes = Vec::with_capacity(PacketCCHeader::LEN + payload.len());
+    hdr.write_options(&mut Cursor::new(&mut bytes), Endian::Big, (len,))?;
+    bytes[21..23].copy_from_slice(&request_len.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    stream.write_all(&bytes)?;
+    Ok(())
+}
+
+fn version_response(shared: &Shared) -> Vec<u8> {
+    let mut r = ok();
+    r.extend(shared.sdb_id.to_be_bytes());
+    r.extend(0u32.to_be_bytes());
+    r.extend(b"Simulated PLC\0");
+    r
+}
+
+fn download_part(shared: &Shared, offset: &mut usize) -> Vec<u8> {
+    let end = (*offset + SDB_PART_LEN).min(shared.sdb_file.len());
+    let part = &shared.sdb_file[*offset..end];
+    *offset = end;
+    let continues = end < shared.sdb_file.len();
+    let mut r = (continues as u32).to_be_bytes().to_vec();
+    r.extend((part.len() as u16).to_be_bytes());
+    r.extend_from_slice(part);
+    r
+}
+
+fn read_u16(cur: &mut Cursor<&[u8]>) -> Result<u16> {
+    Ok(u16::read_options(cur, Endian::Big, ())?)
+}
+
+fn read_u32(cur: &mut Cursor<&[u8]>) -> Result<u32> {
+    Ok(u32::read_options(cur, Endian::Big, ())?)
+}
+
+/// Answers a read query: `2e 00`, count, then `00 03`, address and length per read.
+fn read_response(shared: &Shared, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut cur = Cursor::new(&payload[2..]);
+    let count = read_u32(&mut cur)?;
+    let mut reads = Vec::new();
+    for _ in 0..count {
+        read_u16(&mut cur)?;
+        reads.push((read_u32(&mut cur)?, read_u32(&mut cur)? as usize));
+    }
+    let total: usize = reads.iter().map(|(_, len)| len).sum();
+    anyhow::ensure!(
+        total <= SIM_MAX_RESPONSE_LEN,
+        "Response of {total} bytes too large."
+    );
+    let mut r = ok();
+    r.extend((shared.started.elapsed().as_millis() as u32).to_be_bytes());
+    let memory = shared.memory.lock().unwrap();
+    let statuses = shared.statuses.lock().unwrap();
+    for (address, len) in reads {
+        let status = statuses.get(&address).copied().unwrap_or(1);
+        r.push(status);
+        if status == 1 {
+            r.extend(memory.read(address, len));
+        }
+    }
+    Ok(r)
+}
+
+/// Applies a write packet: `3c 00`, count, then `00 03`, address, length and data.
+fn write_response(shared: &Shared, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut cur = Cursor::new(&payload[2..]);
+    let count = read_u32(&mut cur)?;
+    let mut memory = shared.memory.lock().unwrap();
+    for _ in 0..count {
+        read_u16(&mut cur)?;
+        let address = read_u32(&mut cur)?;
+        let mut data = vec![0; read_u32(&mut cur)? as usize];
+        cur.read_exact(&mut data)?;
+        memory.write(address, &data);
+    }
+    Ok(ok())
+}
+
+#[test]
+fn test_simulated_plc() {
+    use crate::client::{Client, ProtocolFeatures};
+    use crate::plc_connection::Connection;
+
+    let sdb = crate::sdb_builder::test_sdb();
+    let sim = SimulatedPlc::start(&sdb, vec![]).unwrap();
+    let pressure = sdb.param_by_name(".Gauge[1].Parameter[1].Value").unwrap();
+    sim.set(&pressure, &Value::Float(2.5e-3)).unwrap();
+
+    // Strict, as responses carry the length of their request in len2.
+    let mut conn = Connection::connect_addr(sim.addr()).unwrap();
+    conn.set_strict(true);
+    let mut client = Client::new(conn, &sdb).unwrap();
+    assert_eq!(client.capabilities().sdb_version, sdb.sdb_id());
+    // Refused file API queries leave the feature out.
+    assert_eq!(client.capabilities().features, ProtocolFeatures::empty());
+    assert_eq!(
+        client.read(std::slice::from_ref(&pressure)).unwrap(),
+        [Value::Float(2.5e-3)]
+    );
+    let name = sdb.param_by_name(".Gauge[1].Parameter[1].Name").unwrap();
+    let written = client
+        .write_many(&[(name.clone(), Value::String("Chamber".into()))])
+        .unwrap();
+    assert!(written[0].is_ok());
+    assert_eq!(sim.get(&name).unwrap(), Value::String("Chamber".into()));
+
+    let sim = SimulatedPlc::start(&sdb, crate::sdb_builder::fixture()).unwrap();
+    let client = Client::new(Connection::connect_addr(sim.addr()).unwrap(), &sdb).unwrap();
+    assert_eq!(client.capabilities().features, ProtocolFeatures::FILE_API);
+}
@@ -0,0 +1,411 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+/// Timing statistics for a polling session.
+///
+/// Every parameter read response carries the instrument's millisecond clock. By comparing
+/// how far that clock advanced against the host clock we get the accumulated clock skew,
+/// and from that the drift rate. The host side intervals between samples give the poll jitter.
+#[derive(Clone, Debug, Default)]
+pub struct PollStats {
+    start: Option<Instant>,
+    last: Option<(u32, Instant)>,
+    samples: u64,
+    /// Device time elapsed since the first sample, wrap-around corrected.
+    device_elapsed: Duration,
+    /// Device elapsed minus host elapsed, in seconds.
+    skew: f64,
+    skew_min: f64,
+    skew_max: f64,
+    interval: RunningStats,
+}
+
+impl PollStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a response timestamp from the instrument, received now.
+    pub fn record(&mut self, device_ts: Duration) {
+        self.record_at(device_ts, Instant::now())
+    }
+
+    /// Record a response timestamp from the instrument, received at `host`.
+    pub fn record_at(&mut self, device_ts: Duration, host: Instant) {
+        // The instrument sends the timestamp as u32 milliseconds, which wraps after ~49 days.
+        let device_ms = device_ts.as_millis() as u32;
+        self.samples += 1;
+        let Some((last_ms, last_host)) = self.last.replace((device_ms, host)) else {
+            self.start = Some(host);
+            return;
+        };
+        let step = device_ms.wrapping_sub(last_ms);
+        self.device_elapsed += Duration::from_millis(step as u64);
+        self.interval
+            .push(host.duration_since(last_host).as_secs_f64());
+
+        let host_elapsed = host.duration_since(self.start.unwrap());
+        self.skew = self.device_elapsed.as_secs_f64() - host_elapsed.as_secs_f64();
+        if self.samples == 2 {
+            self.skew_min = self.skew;
+            self.skew_max = self.skew;
+        } else {
+            self.skew_min = self.skew_min.min(self.skew);
+            self.skew_max = self.skew_max.max(self.skew);
+        }
+    }
+
+    /// The number of recorded samples.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// How far the instrument clock has run ahead (positive) or behind (negative)
+    /// of the host clock since the first sample, in seconds.
+    pub fn skew(&self) -> f64 {
+        self.skew
+    }
+
+    /// The smallest and largest skew seen during the session, in seconds.
+    pub fn skew_range(&self) -> (f64, f64) {
+        (self.skew_min, self.skew_max)
+    }
+
+    /// The instrument clock drift relative to the host, in parts per million.
+    pub fn drift_ppm(&self) -> f64 {
+        let host_elapsed = self.device_elapsed.as_secs_f64() - self.skew;
+        if host_elapsed <= 0.0 {
+            return 0.0;
+        }
+        self.skew / host_elapsed * 1e6
+    }
+
+    /// Mean time between samples, in seconds.
+    pub fn interval_mean(&self) -> f64 {
+        self.interval.mean()
+    }
+
+    /// Standard deviation of the time between samples, in seconds.
+    pub fn interval_jitter(&self) -> f64 {
+        self.interval.stddev()
+    }
+
+    /// Render the statistics in the Prometheus text exposition format.
+    pub fn prometheus_text(&self) -> String {
+        let mut s = String::new();
+        let mut metric = |name: &str, help: &str, ty: &str, value: f64| {
+            s += &format!("# HELP {name} {help}\n# TYPE {name} {ty}\n{name} {value}\n");
+        };
+        metric(
+            "leybold_poll_samples_total",
+            "Number of poll responses received.",
+            "counter",
+            self.samples as f64,
+        );
+        metric(
+            "leybold_clock_skew_seconds",
+            "Instrument clock minus host clock since the first sample.",
+            "gauge",
+            self.skew,
+        );
+        metric(
+            "leybold_clock_drift_ppm",
+            "Instrument clock drift relative to the host clock.",
+            "gauge",
+            self.drift_ppm(),
+        );
+        metric(
+            "leybold_poll_interval_seconds",
+            "Mean time between poll responses.",
+            "gauge",
+            self.interval_mean(),
+        );
+        metric(
+            "leybold_poll_jitter_seconds",
+            "Standard deviation of the time between poll responses.",
+            "gauge",
+            self.interval_jitter(),
+        );
+        s
+    }
+}
+
+impl Display for PollStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (min, max) = self.skew_range();
+        write!(
+            f,
+            "{} samples, interval {:.3} s ± {:.3} s, clock skew {:+.3} s (min {:+.3}, max {:+.3}), drift {:+.1} ppm",
+            self.samples,
+            self.interval_mean(),
+            self.interval_jitter(),
+            self.skew,
+            min,
+            max,
+            self.drift_ppm(),
+        )
+    }
+}
+
+/// Running mean, variance and range (Welford's algorithm).
+#[derive(Clone, Debug, Default)]
+pub struct RunningStats {
+    n: u64,
+    mean: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+}
+
+impl RunningStats {
+    pub fn push(&mut self, x: f64) {
+        if self.n == 0 {
+            (self.min, self.max) = (x, x);
+        } else {
+            self.min = self.min.min(x);
+            self.max = self.max.max(x);
+        }
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.n
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn stddev(&self) -> f64 {
+        if self.n < 2 {
+            return 0.0;
+        }
+        (self.m2 / (self.n - 1) as f64).sqrt()
+    }
+
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+}
+
+impl Display for RunningStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.n == 0 {
+            return f.write_str("no samples");
+        }
+        write!(
+            f,
+            "{} ± {} [{}, {}] ({} samples)",
+            self.mean,
+            self.stddev(),
+            self.min,
+            self.max,
+            self.n
+        )
+    }
+}
+
+/// Running Pearson correlation of paired samples.
+#[derive(Clone, Debug, Default)]
+pub struct Correlation {
+    n: u64,
+    mean_x: f64,
+    mean_y: f64,
+    m2_x: f64,
+    m2_y: f64,
+    co_moment: f64,
+}
+
+impl Correlation {
+    pub fn push(&mut self, x: f64, y: f64) {
+        self.n += 1;
+        let n = self.n as f64;
+        let dx = x - self.mean_x;
+        let dy = y - self.mean_y;
+        self.mean_x += dx / n;
+        self.mean_y += dy / n;
+        self.m2_x += dx * (x - self.mean_x);
+        self.m2_y += dy * (y - self.mean_y);
+        self.co_moment += dx * (y - self.mean_y);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.n
+    }
+
+    /// The correlation coefficient, or `None` when either series is constant.
+    pub fn coefficient(&self) -> Option<f64> {
+        let denom = (self.m2_x * self.m2_y).sqrt();
+        (denom > 0.0).then(|| (self.co_moment / denom).clamp(-1.0, 1.0))
+    }
+}
+
+/// The span of samples a [`Downsampler`] aggregates.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AggregateWindow {
+    Samples(u32),
+    Time(Duration),
+}
+
+impl FromStr for AggregateWindow {
+    type Err = anyhow::Error;
+
+    /// A plain number is a sample count, a number with a unit (`ms`, `s`, `m`, `h`) a time.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        if unit.is_empty() {
+            let n = num.parse().context("Invalid sample count")?;
+            anyhow::ensure!(n > 0, "The sample count must be at least one.");
+            return Ok(Self::Samples(n));
+        }
+        Ok(Self::Time(parse_duration(s)?))
+    }
+}
+
+/// Parses a time such as `500ms`, `10s`, `5m` or `1.5h`.
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().context("Invalid duration")?;
+    let secs = match unit {
+        "ms" => num / 1000.0,
+        "s" => num,
+        "m" => num * 60.0,
+        "h" => num * 3600.0,
+        _ => anyhow::bail!("Unknown time unit '{unit}', expected ms, s, m or h."),
+    };
+    Ok(Duration::try_from_secs_f64(secs)?)
+}
+
+/// Parses a number of seconds for the `--interval` style options, which must be finite
+/// and not negative.
+pub fn parse_seconds(s: &str) -> anyhow::Result<f32> {
+    let secs: f32 = s.parse().context("Invalid number of seconds")?;
+    Duration::try_from_secs_f32(secs).with_context(|| format!("Invalid number of seconds {s}"))?;
+    Ok(secs)
+}
+
+/// Aggregates polled values over a window, to log summaries instead of every sample.
+///
+/// Each poll pushes one value per slot, e.g. one per parameter. Values which aren't
+/// numbers are pushed as `None` and left out of the statistics.
+#[derive(Clone, Debug)]
+pub struct Downsampler {
+    window: AggregateWindow,
+    start: Option<Instant>,
+    polls: u32,
+    stats: Vec<RunningStats>,
+}
+
+impl Downsampler {
+    pub fn new(window: AggregateWindow) -> Self {
+        Self {
+            window,
+            start: None,
+            polls: 0,
+            stats: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, values: &[Option<f64>]) -> Option<Vec<RunningStats>> {
+        self.push_at(values, Instant::now())
+    }
+
+    /// Adds the values of one poll at `at`. Returns the statistics per slot when the
+    /// poll completes a window, and starts the next window.
+    pub fn push_at(&mut self, values: &[Option<f64>], at: Instant) -> Option<Vec<RunningStats>> {
+        let start = *self.start.get_or_insert(at);
+        if self.stats.len() < values.len() {
+            self.stats.resize_with(values.len(), Default::default);
+        }
+        for (stats, value) in self.stats.iter_mut().zip(values) {
+            if let Some(value) = value {
+                stats.push(*value);
+            }
+        }
+        self.polls += 1;
+        let done = match self.window {
+            AggregateWindow::Samples(n) => self.polls >= n,
+            AggregateWindow::Time(t) => at.duration_since(start) >= t,
+        };
+        if !done {
+            return None;
+        }
+        self.start = None;
+        self.polls = 0;
+        let len = self.stats.len();
+        Some(std::mem::replace(
+            &mut self.stats,
+            vec![RunningStats::default(); len],
+        ))
+    }
+}
+
+#[test]
+fn test_poll_stats_skew() {
+    let t0 = Instant::now();
+    let mut stats = PollStats::new();
+    // The device clock runs 1 ms fast per second, and wraps around between samples.
+    let dev0 = u32::MAX as u64 - 500;
+    for i in 0..=10u64 {
+        let device = Duration::from_millis((dev0 + i * 1001) & u32::MAX as u64);
+        stats.record_at(device, t0 + Duration::from_secs(i));
+    }
+    assert_eq!(stats.samples(), 11);
+    assert!((stats.skew() - 0.010).abs() < 1e-9);
+    assert!((stats.drift_ppm() - 1000.0).abs() < 1.0);
+    assert!((stats.interval_mean() - 1.0).abs() < 1e-9);
+    assert!(stats.interval_jitter() < 1e-9);
+}
+
+#[test]
+fn test_correlation() {
+    let mut up = Correlation::default();
+    let mut down = Correlation::default();
+    let mut flat = Correlation::default();
+    for i in 0..10 {
+        let x = i as f64;
+        up.push(x, 2.0 * x + 1.0);
+        down.push(x, -x * x);
+        flat.push(x, 3.0);
+    }
+    assert!((up.coefficient().unwrap() - 1.0).abs() < 1e-12);
+    assert!(down.coefficient().unwrap() < -0.9);
+    assert_eq!(flat.coefficient(), None);
+}
+
+#[test]
+fn test_downsampler() {
+    assert_eq!(
+        "500ms".parse::<AggregateWindow>().unwrap(),
+        AggregateWindow::Time(Duration::from_millis(500))
+    );
+    assert!("0".parse::<AggregateWindow>().is_err());
+    assert_eq!(parse_seconds("1.5").unwrap(), 1.5);
+    assert!(parse_seconds("-1").is_err());
+    assert!(parse_seconds("inf").is_err());
+    assert!(parse_seconds("NaN").is_err());
+
+    let mut down = Downsampler::new("3".parse().unwrap());
+    let t0 = Instant::now();
+    assert!(down.push_at(&[Some(1.0), None], t0).is_none());
+    assert!(down.push_at(&[Some(3.0), None], t0).is_none());
+    let stats = down.push_at(&[Some(2.0), None], t0).unwrap();
+    assert_eq!(
+        (stats[0].min(), stats[0].mean(), stats[0].max()),
+        (1.0, 2.0, 3.0)
+    );
+    assert_eq!(stats[1].count(), 0);
+    assert!(down.push_at(&[Some(5.0), None], t0).is_none());
+}
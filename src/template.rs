@@ -0,0 +1,148 @@
+//! Output templates, for shaping lines and message bodies for downstream systems.
+//!
+//! A template is text with the placeholders `{param}`, `{value}`, `{unit}` and `{ts}`,
+//! e.g. `{ts} {param}={value}{unit}`. Braces are written as `{{` and `}}`. Values are
+//! inserted as they are, without quoting.
+
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Field {
+    Param,
+    Value,
+    Unit,
+    Ts,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Field(Field),
+}
+
+/// A parsed template, see the [module docs](self).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+/// What a template is filled in with.
+#[derive(Clone, Debug)]
+pub struct Fields<'a> {
+    pub param: &'a str,
+    /// The value as text, formatted by the caller.
+    pub value: &'a str,
+    /// Left empty when unknown.
+    pub unit: Option<&'a str>,
+    /// Written in RFC 3339 format, with milliseconds.
+    pub ts: DateTime<Utc>,
+}
+
+impl Template {
+    pub fn render(&self, fields: &Fields) -> String {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Field(Field::Param) => out.push_str(fields.param),
+                Piece::Field(Field::Value) => out.push_str(fields.value),
+                Piece::Field(Field::Unit) => out.push_str(fields.unit.unwrap_or("")),
+                Piece::Field(Field::Ts) => {
+                    out.push_str(&fields.ts.to_rfc3339_opts(SecondsFormat::Millis, true))
+                }
+            }
+        }
+        out
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut pieces = vec![];
+        let mut text = String::new();
+        let mut rest = s;
+        while let Some(i) = rest.find(['{', '}']) {
+            text.push_str(&rest[..i]);
+            let after = &rest[i + 1..];
+            match (&rest[i..i + 1], after) {
+                ("{", after) if after.starts_with('{') => text.push('{'),
+                ("}", after) if after.starts_with('}') => text.push('}'),
+                ("{", after) => {
+                    let Some((name, after)) = after.split_once('}') else {
+                        bail!("Missing '}}' in template '{s}'.");
+                    };
+                    let field = match name {
+                        "param" => Field::Param,
+                        "value" => Field::Value,
+                        "unit" => Field::Unit,
+                        "ts" => Field::Ts,
+                        _ => bail!(
+                            "Unknown placeholder {{{name}}} in template '{s}', expected \
+                             {{param}}, {{value}}, {{unit}} or {{ts}}."
+                        ),
+                    };
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    pieces.push(Piece::Field(field));
+                    rest = after;
+                    continue;
+                }
+                _ => bail!("Unmatched '}}' in template '{s}', write '}}}}' for a brace."),
+            }
+            rest = &after[1..];
+        }
+        text.push_str(rest);
+        pieces.push(Piece::Text(text));
+        pieces.retain(|p| *p != Piece::Text(String::new()));
+        Ok(Self { pieces })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => f.write_str(&text.replace('{', "{{").replace('}', "}}"))?,
+                Piece::Field(Field::Param) => f.write_str("{param}")?,
+                Piece::Field(Field::Value) => f.write_str("{value}")?,
+                Piece::Field(Field::Unit) => f.write_str("{unit}")?,
+                Piece::Field(Field::Ts) => f.write_str("{ts}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_template() {
+    let template: Template = r#"{{"{param}": {value}}} {unit}@{ts}"#.parse().unwrap();
+    let fields = Fields {
+        param: ".Gauge[1].Parameter[1].Value",
+        value: "0.001",
+        unit: None,
+        ts: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+    };
+    assert_eq!(
+        template.render(&fields),
+        r#"{".Gauge[1].Parameter[1].Value": 0.001} @2023-11-14T22:13:20.000Z"#
+    );
+    assert_eq!(template.to_string().parse::<Template>().unwrap(), template);
+    assert!("{pressure}".parse::<Template>().is_err());
+    assert!("{value".parse::<Template>().is_err());
+    assert!("value}".parse::<Template>().is_err());
+}
@@ -0,0 +1,216 @@
+//! Transcripts of sessions for support cases: the command line, then every request
+//! and response, decoded where possible and as raw bytes, with their timing. Requests
+//! left unanswered, e.g. after a timeout, are written without a response.
+//!
+//! Transcripts are Markdown, or JSON Lines for files ending in `.json`: a first line
+//! describing the session, then one object per exchange.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use rhexdump::hexdump;
+use serde_json::json;
+use tracing::warn;
+
+use crate::plc_connection::PacketObserver;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    Markdown,
+    /// JSON Lines.
+    Json,
+}
+
+impl TranscriptFormat {
+    /// The format named by the file extension, JSON for `.json` and Markdown otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Markdown,
+        }
+    }
+}
+
+/// A packet sent, waiting for its response.
+#[derive(Clone, Debug)]
+struct Sent {
+    raw: Vec<u8>,
+    decoded: Option<String>,
+    at: Instant,
+}
+
+/// Writes the exchanges of connections to a transcript file. Clones write to the same
+/// file, so one transcript can observe several connections.
+#[derive(Clone, Debug)]
+pub struct Transcript {
+    file: Arc<Mutex<File>>,
+    format: TranscriptFormat,
+    start: Instant,
+    exchanges: Arc<AtomicUsize>,
+    pending: Option<Sent>,
+}
+
+impl Transcript {
+    /// Creates the transcript of a session run with `command`, in the format of the
+    /// file extension.
+    pub fn create(path: impl AsRef<Path>, command: &str) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::create(path)
+            .with_context(|| format!("Failed to create transcript {}", path.display()))?;
+        let format = TranscriptFormat::from_path(path);
+        let version = concat!("leybold-opc-rs ", env!("CARGO_PKG_VERSION"));
+        let started = chrono::Utc::now().to_rfc3339();
+        let header = match format {
+            TranscriptFormat::Markdown => format!(
+                "# Session transcript\n\n- Command: `{command}`\n- Version: {version}\n\
+                 - Started: {started}\n"
+            ),
+            TranscriptFormat::Json => {
+                let header = json!({"command": command, "version": version, "started": started});
+                format!("{header}\n")
+            }
+        };
+        file.write_all(header.as_bytes())?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            format,
+            start: Instant::now(),
+            exchanges: Arc::new(AtomicUsize::new(0)),
+            pending: None,
+        })
+    }
+
+    /// A decoded packet, pretty printed for Markdown and on one line for JSON.
+    fn decoded(&self, decoded: Option<&dyn std::fmt::Debug>) -> Option<String> {
+        match self.format {
+            TranscriptFormat::Markdown => decoded.map(|d| format!("{d:#?}")),
+            TranscriptFormat::Json => decoded.map(|d| format!("{d:?}")),
+        }
+    }
+
+    /// Writes the exchange of `sent`, and its response unless it went unanswered.
+    fn write(&self, sent: &Sent, response: Option<(&[u8], Option<&str>)>) -> std::io::Result<()> {
+        let n = self.exchanges.fetch_add(1, SeqCst) + 1;
+        let at = sent.at - self.start;
+        let took = sent.at.elapsed();
+        let entry = match self.format {
+            TranscriptFormat::Markdown => {
+                let outcome = match response {
+                    Some(_) => "answered in",
+                    None => "unanswered after",
+                };
+                let mut entry = format!(
+                    "\n## {n}. At {:.3} s, {outcome} {}\n",
+                    at.as_secs_f64(),
+                    millis(took)
+                );
+                let request = ("Request", &*sent.raw, sent.decoded.as_deref());
+                let response = response.map(|(raw, decoded)| ("Response", raw, decoded));
+                for (what, raw, decoded) in std::iter::once(request).chain(response) {
+                    entry += &format!("\n{what}, {} bytes:\n", raw.len());
+                    if let Some(decoded) = decoded {
+                        entry += &format!("\n```text\n{decoded}\n```\n");
+                    }
+                    entry += &format!("\n```text\n{}\n```\n", hexdump(raw).trim_end());
+                }
+                entry
+            }
+            TranscriptFormat::Json => {
+                let packet = |raw: &[u8], decoded: Option<&str>| {
+                    let hex: String = raw.iter().map(|b| format!("{b:02x}")).collect();
+                    json!({"decoded": decoded, "hex": hex})
+                };
+                let entry = json!({
+                    "exchange": n,
+                    "at_s": at.as_secs_f64(),
+                    "took_ms": took.as_secs_f64() * 1000.0,
+                    "request": packet(&sent.raw, sent.decoded.as_deref()),
+                    "response": response.map(|(raw, decoded)| packet(raw, decoded)),
+                });
+                format!("{entry}\n")
+            }
+        };
+        // Written unbuffered, so that the transcript is complete however the session ends.
+        self.file.lock().unwrap().write_all(entry.as_bytes())
+    }
+
+    /// Writes the request waiting for its response as unanswered, if there is one.
+    fn write_unanswered(&mut self) {
+        if let Some(sent) = self.pending.take() {
+            if let Err(e) = self.write(&sent, None) {
+                warn!("Failed to write transcript: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for Transcript {
+    fn drop(&mut self) {
+        self.write_unanswered();
+    }
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.1} ms", d.as_secs_f64() * 1000.0)
+}
+
+impl PacketObserver for Transcript {
+    fn on_send(&mut self, raw: &[u8], decoded: Option<&dyn std::fmt::Debug>) {
+        // The previous request got no response, e.g. after a timeout.
+        self.write_unanswered();
+        self.pending = Some(Sent {
+            raw: raw.to_vec(),
+            decoded: self.decoded(decoded),
+            at: Instant::now(),
+        });
+    }
+
+    fn on_receive(&mut self, raw: &[u8], decoded: Option<&dyn std::fmt::Debug>) {
+        let Some(sent) = self.pending.take() else {
+            return;
+        };
+        let decoded = self.decoded(decoded);
+        if let Err(e) = self.write(&sent, Some((raw, decoded.as_deref()))) {
+            warn!("Failed to write transcript: {e}");
+        }
+    }
+}
+
+#[test]
+fn test_transcript() {
+    let dir = std::env::temp_dir();
+    let md = dir.join(format!("transcript-{}.md", std::process::id()));
+    let json = md.with_extension("json");
+    for path in [&md, &json] {
+        let mut t = Transcript::create(path, "leybold-opc-rs -r .OPCCounter").unwrap();
+        t.on_send(&[0x2e, 0x00], Some(&"ParamsReadQuery"));
+        t.on_receive(&[0x00, 0x00], Some(&"ParamReadDynResponse"));
+        t.on_send(&[0x66, 0x66], None);
+        t.on_receive(&[0x66, 0x66], None);
+        t.on_send(&[0x2e, 0x01], None);
+        t.on_send(&[0x2e, 0x02], None);
+    }
+    let text = std::fs::read_to_string(&md).unwrap();
+    assert!(text.starts_with("# Session transcript\n\n- Command: `leybold-opc-rs -r .OPCCounter`"));
+    assert!(text.contains("## 2. At "));
+    assert!(text.contains("\"ParamsReadQuery\""));
+    assert_eq!(text.matches("unanswered after").count(), 2);
+    let text = std::fs::read_to_string(&json).unwrap();
+    let lines: Vec<serde_json::Value> = text
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0]["command"], "leybold-opc-rs -r .OPCCounter");
+    assert_eq!(lines[1]["request"]["hex"], "2e00");
+    assert_eq!(lines[2]["response"]["decoded"], serde_json::Value::Null);
+    assert_eq!(lines[3]["request"]["hex"], "2e01");
+    assert_eq!(lines[3]["response"], serde_json::Value::Null);
+    let _ = std::fs::remove_file(md);
+    let _ = std::fs::remove_file(json);
+}
@@ -0,0 +1,177 @@
+//! The `watch` command: a live table of parameter values with a plot of the selected one.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::symbols::Marker;
+use ratatui::text::Line;
+use ratatui::widgets::{Axis, Block, Chart, Dataset, GraphType, List, ListItem, ListState};
+use ratatui::{DefaultTerminal, Frame};
+
+use leybold_opc_rs::client::Client;
+use leybold_opc_rs::sdb::Parameter;
+
+struct Watch<'c, 'sdb> {
+    client: &'c mut Client<'sdb>,
+    params: Vec<Parameter<'sdb>>,
+    selected: ListState,
+    log_scale: bool,
+    error: Option<String>,
+}
+
+/// Polls the parameters every `interval` until the user quits, keeping `history`
+/// samples of each for the plot.
+pub fn watch<'sdb>(
+    client: &mut Client<'sdb>,
+    params: Vec<Parameter<'sdb>>,
+    interval: Duration,
+    history: usize,
+) -> Result<()> {
+    if params.is_empty() {
+        anyhow::bail!("No parameters to watch.");
+    }
+    client.set_history_capacity(history);
+    let mut watch = Watch {
+        client,
+        params,
+        selected: ListState::default().with_selected(Some(0)),
+        log_scale: false,
+        error: None,
+    };
+    let mut terminal = ratatui::init();
+    let r = watch.run(&mut terminal, interval);
+    ratatui::restore();
+    r
+}
+
+impl Watch<'_, '_> {
+    fn run(&mut self, terminal: &mut DefaultTerminal, interval: Duration) -> Result<()> {
+        let mut next = Instant::now();
+        loop {
+            if Instant::now() >= next {
+                // Show read errors instead of leaving the TUI, they are often transient.
+                self.error = self
+                    .client
+                    .read_cached(&self.params)
+                    .err()
+                    .map(|e| format!("{e:#}"));
+                next += interval;
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let timeout = next.saturating_duration_since(Instant::now());
+            if !event::poll(timeout)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('l') => self.log_scale = !self.log_scale,
+                KeyCode::Down | KeyCode::Char('j') => self.selected.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.selected.select_previous(),
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [table, plot, status] = Layout::vertical([
+            Constraint::Length(self.params.len().min(12) as u16 + 2),
+            Constraint::Min(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let items: Vec<_> = self
+            .params
+            .iter()
+            .map(|p| {
+                let value = match self.client.history(p).next_back() {
+                    Some(sample) => format!("{:?}", sample.value),
+                    None => "-".into(),
+                };
+                ListItem::new(format!("{:40} {value}", p.name()))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title("Parameters"))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, table, &mut self.selected);
+
+        let selected = self
+            .selected
+            .selected()
+            .unwrap_or(0)
+            .min(self.params.len() - 1);
+        self.draw_plot(frame, plot, &self.params[selected]);
+
+        let help = match &self.error {
+            Some(e) => Line::from(e.as_str()).red(),
+            None => Line::from("q: quit  up/down: select  l: log/linear scale"),
+        };
+        frame.render_widget(help, status);
+    }
+
+    fn draw_plot(&self, frame: &mut Frame, area: ratatui::layout::Rect, param: &Parameter) {
+        let scale = |v: f64| match self.log_scale {
+            true => v.log10(),
+            false => v,
+        };
+        // Seconds before now on the x axis.
+        let now = chrono::Utc::now();
+        let points: Vec<(f64, f64)> = self
+            .client
+            .history(param)
+            .filter_map(|s| {
+                let t = (s.time - now).num_milliseconds() as f64 / 1000.0;
+                Some((t, scale(s.value.as_f64()?)))
+            })
+            .filter(|(_, v)| v.is_finite())
+            .collect();
+        let (mut lo, mut hi) = points
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), (_, v)| {
+                (lo.min(*v), hi.max(*v))
+            });
+        if lo > hi {
+            (lo, hi) = (0.0, 1.0);
+        } else if lo == hi {
+            (lo, hi) = (lo - 0.5, hi + 0.5);
+        }
+        let oldest = points.first().map_or(-1.0, |(t, _)| t.min(-1.0));
+        let label = |v: f64| match self.log_scale {
+            true => format!("1e{v:.1}"),
+            false => format!("{v:.3e}"),
+        };
+        let dataset = Dataset::default()
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Line)
+            .data(&points);
+        let title = format!(
+            "{} ({})",
+            param.name(),
+            if self.log_scale { "log" } else { "linear" }
+        );
+        let chart = Chart::new(vec![dataset])
+            .block(Block::bordered().title(title))
+            .x_axis(
+                Axis::default()
+                    .bounds([oldest, 0.0])
+                    .labels([format!("{oldest:.0} s"), "now".into()]),
+            )
+            .y_axis(
+                Axis::default()
+                    .bounds([lo, hi])
+                    .labels([label(lo), label(hi)]),
+            );
+        frame.render_widget(chart, area);
+    }
+}
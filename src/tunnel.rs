@@ -0,0 +1,252 @@
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{debug, warn};
+
+use crate::host::Host;
+
+/// How to reach the PLC when it isn't directly routable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Via {
+    /// `ssh://[user@]host[:port]`, forward the PLC port through an SSH gateway.
+    Ssh {
+        user: Option<String>,
+        host: String,
+        port: Option<u16>,
+    },
+    /// `tcp://host:port`, connect to an existing forward instead of the PLC,
+    /// e.g. a local stunnel client endpoint.
+    Tcp(String),
+}
+
+impl Via {
+    /// The host a `tcp://` tunnel names, which stands in for the instrument's address.
+    pub fn host(&self) -> Option<Host> {
+        match self {
+            Via::Tcp(addr) => addr.rsplit_once(':')?.0.parse().ok(),
+            Via::Ssh { .. } => None,
+        }
+    }
+}
+
+impl FromStr for Via {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return Ok(Self::Tcp(addr.to_string()));
+        }
+        let rest = s
+            .strip_prefix("ssh://")
+            .ok_or_else(|| anyhow!("Unsupported tunnel '{s}', expected ssh:// or tcp://."))?;
+        let (user, host) = match rest.split_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, rest),
+        };
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().context("Invalid SSH port")?)),
+            None => (host, None),
+        };
+        if host.is_empty() {
+            bail!("Missing host name in '{s}'.");
+        }
+        Ok(Self::Ssh {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// A running tunnel. The forward is torn down when this is dropped.
+pub struct Tunnel {
+    ssh: Option<Child>,
+    local: SocketAddr,
+    /// The listener of the local end of an SSH tunnel, see [`Tunnel::open`].
+    proxy: Option<Proxy>,
+}
+
+impl Tunnel {
+    /// Sets up a forward to `target` and returns the address to connect to instead.
+    ///
+    /// SSH forwards to a Unix socket in the private runtime directory, and connections
+    /// to a local TCP port are relayed to it. The port is bound before ssh starts, so
+    /// that no other process can take it in between.
+    pub fn open(via: &Via, target: &Host, target_port: u16) -> Result<Self> {
+        match via {
+            Via::Tcp(addr) => {
+                let local = std::net::ToSocketAddrs::to_socket_addrs(addr)?
+                    .next()
+                    .with_context(|| format!("Failed to resolve {addr}"))?;
+                Ok(Self {
+                    ssh: None,
+                    local,
+                    proxy: None,
+                })
+            }
+            #[cfg(unix)]
+            Via::Ssh { user, host, port } => {
+                if let Host::Scoped(..) = target {
+                    bail!("The zone of {target} is an interface of this host, not of the SSH gateway.");
+                }
+                static TUNNELS: AtomicUsize = AtomicUsize::new(0);
+                let socket = crate::session::runtime_dir()?.join(format!(
+                    "leybold-opc-tunnel-{}-{}.sock",
+                    std::process::id(),
+                    TUNNELS.fetch_add(1, SeqCst)
+                ));
+                let listener = TcpListener::bind("127.0.0.1:0")?;
+                let local = listener.local_addr()?;
+                let destination = match user {
+                    Some(user) => format!("{user}@{host}"),
+                    None => host.clone(),
+                };
+                let mut cmd = Command::new("ssh");
+                cmd.args(["-N", "-o", "ExitOnForwardFailure=yes"])
+                    .args(["-o", "StreamLocalBindUnlink=yes", "-L"])
+                    .arg(match target {
+                        Host::Ip(IpAddr::V6(ip)) => {
+                            format!("{}:[{ip}]:{target_port}", socket.display())
+                        }
+                        _ => format!("{}:{target}:{target_port}", socket.display()),
+                    })
+                    .stdin(Stdio::null());
+                if let Some(port) = port {
+                    cmd.arg("-p").arg(port.to_string());
+                }
+                cmd.arg(destination);
+                debug!("Starting SSH tunnel: {cmd:?}");
+                let child = cmd.spawn().context("Failed to start ssh")?;
+                let mut tunnel = Self {
+                    ssh: Some(child),
+                    local,
+                    proxy: None,
+                };
+                tunnel.wait_ready(&socket, Duration::from_secs(15))?;
+                tunnel.proxy = Some(Proxy::start(listener, socket)?);
+                Ok(tunnel)
+            }
+            #[cfg(not(unix))]
+            Via::Ssh { .. } => bail!("SSH tunnels are only supported on Unix."),
+        }
+    }
+
+    /// The local end of the tunnel.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    #[cfg(unix)]
+    fn wait_ready(&mut self, socket: &std::path::Path, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.ssh.as_mut().and_then(|c| c.try_wait().transpose()) {
+                bail!("ssh exited before the tunnel was up: {}", status?);
+            }
+            if std::os::unix::net::UnixStream::connect(socket).is_ok() {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                bail!("Timed out waiting for the SSH tunnel.");
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.ssh {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if let Some(proxy) = self.proxy.take() {
+            proxy.stop(self.local);
+        }
+    }
+}
+
+/// Relays the connections to the local port of an SSH tunnel to the socket of ssh's
+/// forward.
+struct Proxy {
+    stop: Arc<AtomicBool>,
+    socket: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Proxy {
+    fn start(listener: TcpListener, socket: std::path::PathBuf) -> Result<Self> {
+        use std::os::unix::net::UnixStream;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (stopped, path) = (stop.clone(), socket.clone());
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                if stopped.load(SeqCst) {
+                    break;
+                }
+                let relayed = client.and_then(|client| {
+                    let forward = UnixStream::connect(&path)?;
+                    let (mut from_client, mut to_forward) =
+                        (client.try_clone()?, forward.try_clone()?);
+                    std::thread::spawn(move || {
+                        let _ = std::io::copy(&mut from_client, &mut to_forward);
+                        let _ = to_forward.shutdown(Shutdown::Write);
+                    });
+                    let (mut from_forward, mut to_client) = (forward, client);
+                    std::thread::spawn(move || {
+                        let _ = std::io::copy(&mut from_forward, &mut to_client);
+                        let _ = to_client.shutdown(Shutdown::Write);
+                    });
+                    Ok(())
+                });
+                if let Err(e) = relayed {
+                    warn!("Failed to relay a connection through the SSH tunnel: {e}");
+                }
+            }
+        });
+        Ok(Self { stop, socket })
+    }
+}
+
+impl Proxy {
+    /// Stops accepting connections on `local`, the address of the listener.
+    fn stop(self, local: SocketAddr) {
+        self.stop.store(true, SeqCst);
+        // Wakes up the listener, to see that it should stop.
+        let _ = TcpStream::connect_timeout(&local, Duration::from_secs(1));
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+#[test]
+fn test_parse_via() {
+    assert_eq!(
+        "ssh://lukas@gateway:2222".parse::<Via>().unwrap(),
+        Via::Ssh {
+            user: Some("lukas".into()),
+            host: "gateway".into(),
+            port: Some(2222)
+        }
+    );
+    assert_eq!(
+        "ssh://gateway".parse::<Via>().unwrap(),
+        Via::Ssh {
+            user: None,
+            host: "gateway".into(),
+            port: None
+        }
+    );
+    assert_eq!(
+        "tcp://localhost:1202".parse::<Via>().unwrap(),
+        Via::Tcp("localhost:1202".into())
+    );
+    let via = "tcp://[::1]:1202".parse::<Via>().unwrap();
+    assert_eq!(via.host(), Some(Host::Ip("::1".parse().unwrap())));
+    assert!("http://gateway".parse::<Via>().is_err());
+}
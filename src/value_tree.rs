@@ -0,0 +1,269 @@
+//! Parameter values parsed into one flat tree: the nodes of a value in one `Vec`, its
+//! strings in one buffer, and struct member names borrowed from the SDB. Parsing a
+//! deeply nested value like `.Gauge` then allocates a few times in all, rather than
+//! once per struct, array, member name and string as a [`Value`] does. See the
+//! `value_parsing` bench.
+
+use std::io::Cursor;
+
+use binrw::{BinResult, Endian};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::Serialize;
+
+use crate::opc_values::{read_scalar, Scalar, Value};
+use crate::sdb::{TypeInfo, TypeKind};
+
+/// A parsed value, read through [`ValueTree::root`].
+#[derive(Clone, Debug)]
+pub struct ValueTree<'sdb> {
+    /// In depth-first order, so that the children of a node follow it.
+    nodes: Vec<Node<'sdb>>,
+    strings: String,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Node<'sdb> {
+    /// The struct member name, empty for array elements and the root.
+    name: &'sdb str,
+    kind: NodeKind,
+    /// The index after the last node of the subtree.
+    end: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum NodeKind {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    /// The start and end in the string buffer.
+    String(u32, u32),
+    Array,
+    /// The rows and columns, elements stored row by row.
+    Matrix(u32, u32),
+    Struct,
+}
+
+impl<'sdb> ValueTree<'sdb> {
+    /// Parses a value like [`Value::parse`] does.
+    pub fn parse(data: &[u8], ty: &TypeInfo<'sdb>) -> BinResult<Self> {
+        Self::parse_endian(data, ty, Endian::Big)
+    }
+
+    /// Like [`ValueTree::parse`], for protocol dialects with other byte orders.
+    pub fn parse_endian(data: &[u8], ty: &TypeInfo<'sdb>, endian: Endian) -> BinResult<Self> {
+        let mut tree = Self {
+            nodes: Vec::new(),
+            strings: String::new(),
+        };
+        tree.parse_node(&mut Cursor::new(data), "", ty, endian)?;
+        Ok(tree)
+    }
+
+    fn parse_node(
+        &mut self,
+        cur: &mut Cursor<&[u8]>,
+        name: &'sdb str,
+        ty: &TypeInfo<'sdb>,
+        endian: Endian,
+    ) -> BinResult<()> {
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            name,
+            kind: NodeKind::Struct,
+            end: 0,
+        });
+        let kind = match ty.kind() {
+            TypeKind::Array => {
+                let pos = cur.position();
+                let (elem, dims) = ty.array_info().ok_or_else(|| binrw::Error::AssertFail {
+                    pos,
+                    message: format!("No array description for {}", ty.name()),
+                })?;
+                for _ in 0..dims[0] * dims[1].max(1) {
+                    self.parse_node(cur, "", &elem, endian)?;
+                }
+                match dims {
+                    [_, 0] => NodeKind::Array,
+                    [rows, cols] => NodeKind::Matrix(rows as u32, cols as u32),
+                }
+            }
+            TypeKind::Data => {
+                for (name, ty) in ty.members() {
+                    self.parse_node(cur, name, &ty, endian)?;
+                }
+                NodeKind::Struct
+            }
+            _ => match read_scalar(cur, ty, endian)? {
+                Scalar::Bool(b) => NodeKind::Bool(b),
+                Scalar::Int(i) => NodeKind::Int(i),
+                Scalar::Float(f) => NodeKind::Float(f),
+                Scalar::String(s) => {
+                    let start = self.strings.len() as u32;
+                    self.strings.push_str(&s);
+                    NodeKind::String(start, self.strings.len() as u32)
+                }
+            },
+        };
+        self.nodes[index].kind = kind;
+        self.nodes[index].end = self.nodes.len() as u32;
+        Ok(())
+    }
+
+    pub fn root(&self) -> ValueRef<'_, 'sdb> {
+        ValueRef {
+            tree: self,
+            index: 0,
+        }
+    }
+}
+
+impl Serialize for ValueTree<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.root().serialize(serializer)
+    }
+}
+
+/// A node of a [`ValueTree`], with the accessors of [`Value`].
+#[derive(Copy, Clone)]
+pub struct ValueRef<'t, 'sdb> {
+    tree: &'t ValueTree<'sdb>,
+    index: usize,
+}
+
+impl<'t, 'sdb> ValueRef<'t, 'sdb> {
+    fn node(&self) -> &'t Node<'sdb> {
+        &self.tree.nodes[self.index]
+    }
+
+    fn children(&self) -> impl Iterator<Item = ValueRef<'t, 'sdb>> {
+        let tree = self.tree;
+        let end = self.node().end as usize;
+        let mut next = self.index + 1;
+        std::iter::from_fn(move || {
+            let index = next;
+            (index < end).then(|| {
+                next = tree.nodes[index].end as usize;
+                ValueRef { tree, index }
+            })
+        })
+    }
+
+    /// The value as a number, for scalar numeric and boolean values.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.node().kind {
+            NodeKind::Bool(b) => Some(b as u8 as f64),
+            NodeKind::Int(i) => Some(i as f64),
+            NodeKind::Float(f) => Some(f as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'t str> {
+        match self.node().kind {
+            NodeKind::String(start, end) => Some(&self.tree.strings[start as usize..end as usize]),
+            _ => None,
+        }
+    }
+
+    /// The members of a struct value, nothing for other values.
+    pub fn fields(&self) -> impl Iterator<Item = (&'sdb str, ValueRef<'t, 'sdb>)> {
+        let is_struct = matches!(self.node().kind, NodeKind::Struct);
+        let members = self.children().take_while(move |_| is_struct);
+        members.map(|m| (m.node().name, m))
+    }
+
+    pub fn get_field(&self, name: &str) -> Option<ValueRef<'t, 'sdb>> {
+        self.fields().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    /// The elements of an array value, or of a matrix row by row, nothing for other values.
+    pub fn elements(&self) -> impl Iterator<Item = ValueRef<'t, 'sdb>> {
+        let is_array = matches!(self.node().kind, NodeKind::Array | NodeKind::Matrix(..));
+        self.children().take_while(move |_| is_array)
+    }
+
+    /// The value as a [`Value`], allocating for every node.
+    pub fn to_value(&self) -> Value {
+        match self.node().kind {
+            NodeKind::Bool(b) => Value::Bool(b),
+            NodeKind::Int(i) => Value::Int(i),
+            NodeKind::Float(f) => Value::Float(f),
+            NodeKind::String(..) => Value::String(self.as_str().unwrap().to_string()),
+            NodeKind::Array => Value::Array(self.elements().map(|e| e.to_value()).collect()),
+            NodeKind::Matrix(_, cols) => {
+                let elements: Vec<_> = self.elements().map(|e| e.to_value()).collect();
+                let rows = elements.chunks(cols as usize).map(<[Value]>::to_vec);
+                Value::Matrix(rows.collect())
+            }
+            NodeKind::Struct => Value::Struct(
+                self.fields()
+                    .map(|(n, v)| (n.to_string(), v.to_value()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Serialized as the [`Value`] would be.
+impl Serialize for ValueRef<'_, '_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.node().kind {
+            NodeKind::Bool(b) => serializer.serialize_bool(b),
+            NodeKind::Int(i) => serializer.serialize_i64(i),
+            NodeKind::Float(f) => serializer.serialize_f32(f),
+            NodeKind::String(..) => serializer.serialize_str(self.as_str().unwrap()),
+            NodeKind::Array => serializer.collect_seq(self.elements()),
+            NodeKind::Matrix(rows, cols) => {
+                let mut seq = serializer.serialize_seq(Some(rows as usize))?;
+                let mut elements = self.elements();
+                for _ in 0..rows {
+                    let row: Vec<_> = elements.by_ref().take(cols as usize).collect();
+                    seq.serialize_element(&row)?;
+                }
+                seq.end()
+            }
+            NodeKind::Struct => {
+                let mut map = serializer.serialize_map(None)?;
+                for (name, value) in self.fields() {
+                    map.serialize_entry(name, &value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+#[test]
+fn test_value_tree() {
+    let sdb = crate::sdb_builder::test_sdb();
+    for param in sdb.parameters() {
+        let ty = param.type_info();
+        let data: Vec<u8> = (0..ty.response_len())
+            .map(|i| (i * 7 + 0x41) as u8)
+            .collect();
+        let value = Value::parse(&data, &ty).unwrap();
+        let tree = ValueTree::parse(&data, &ty).unwrap();
+        // Compared as text, the data holds NaN floats.
+        let converted = tree.root().to_value();
+        assert_eq!(
+            format!("{converted:?}"),
+            format!("{value:?}"),
+            "{}",
+            param.name()
+        );
+        assert_eq!(
+            serde_json::to_value(&tree).unwrap(),
+            serde_json::to_value(&value).unwrap(),
+            "{}",
+            param.name()
+        );
+    }
+    let ty = sdb.param_by_name(".Gauge").unwrap().type_info();
+    let data = vec![0x41; ty.response_len()];
+    let tree = ValueTree::parse(&data, &ty).unwrap();
+    let gauge = tree.root().elements().nth(1).unwrap();
+    let value = Value::parse(&data, &ty).unwrap();
+    let timer = value.field_path("[1].DegasTimer.ET").unwrap();
+    let et = gauge.get_field("DegasTimer").unwrap().get_field("ET");
+    assert_eq!(et.unwrap().as_f64(), timer.as_f64());
+}
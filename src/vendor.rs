@@ -0,0 +1,238 @@
+//! Parameter lists exported from the vendor's HMI and engineering tools, as CSV or XML
+//! files, mapped onto the parameters of the SDB to seed config groups.
+//!
+//! The exports name variables in several ways, e.g. `Gauge[1].Parameter[1].Value`,
+//! `.GAUGE.1.PARAMETER.1.VALUE` or with the program or application in front, as in
+//! `PLC_PRG.Gauge[1].Parameter[1].Value`. Leading components are dropped until the
+//! rest names a parameter.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use crate::sdb::Sdb;
+
+/// The column headers and XML attributes or elements holding variable names, in
+/// lowercase.
+const NAME_KEYS: [&str; 6] = ["name", "variable", "symbol", "path", "parameter", "tag"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ListFormat {
+    Csv,
+    Xml,
+}
+
+impl ListFormat {
+    /// The format named by the file extension, XML for `.xml` and CSV otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("xml") => ListFormat::Xml,
+            _ => ListFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for ListFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(ListFormat::Csv),
+            "xml" => Ok(ListFormat::Xml),
+            _ => bail!("Unknown list format '{s}', expected csv or xml."),
+        }
+    }
+}
+
+/// The parameters of an imported list.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportedList {
+    /// The SDB names of the parameters found, each once, in the order listed.
+    pub params: Vec<String>,
+    /// The names which match no parameter.
+    pub unmatched: Vec<String>,
+}
+
+impl ImportedList {
+    /// Reads the variable names of the list and looks them up in the SDB.
+    pub fn import(text: &str, format: ListFormat, sdb: &Sdb) -> Self {
+        let names = match format {
+            ListFormat::Csv => csv_names(text),
+            ListFormat::Xml => xml_names(text),
+        };
+        let mut list = Self::default();
+        for name in names {
+            match map_name(sdb, &name) {
+                Some(param) if list.params.contains(&param) => {}
+                Some(param) => list.params.push(param),
+                None => list.unmatched.push(name),
+            }
+        }
+        list
+    }
+
+    /// The parameters as a `[groups.<name>]` table for the config file.
+    #[cfg(feature = "config")]
+    pub fn group_toml(&self, name: &str) -> String {
+        let bare = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        let key = match bare && !name.is_empty() {
+            true => name.to_string(),
+            false => toml::Value::from(name).to_string(),
+        };
+        let mut out = format!("[groups.{key}]\nparams = [\n");
+        for param in &self.params {
+            out += &format!("    {},\n", toml::Value::from(param.as_str()));
+        }
+        out + "]\n"
+    }
+}
+
+/// The SDB name of the parameter, dropping leading components of `name` as required.
+fn map_name(sdb: &Sdb, name: &str) -> Option<String> {
+    let mut rest = name.trim();
+    loop {
+        if let Ok(param) = sdb.param_by_name(rest) {
+            return Some(param.name().to_string());
+        }
+        let dot = rest.trim_start_matches('.').find('.')?;
+        rest = &rest.trim_start_matches('.')[dot..];
+    }
+}
+
+/// The names in the name column, or in the first column if no header names one. The
+/// separator is the most frequent of `;`, `,` and tab in the first line.
+fn csv_names(text: &str) -> Vec<String> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let Some(first) = lines.next() else {
+        return vec![];
+    };
+    let sep = [';', ',', '\t']
+        .into_iter()
+        .max_by_key(|&c| first.matches(c).count())
+        .unwrap();
+    let header = csv_fields(first, sep);
+    let column = header
+        .iter()
+        .position(|h| NAME_KEYS.contains(&h.trim().to_ascii_lowercase().as_str()));
+    let rows = match column {
+        Some(_) => lines.collect::<Vec<_>>(),
+        None => std::iter::once(first).chain(lines).collect(),
+    };
+    rows.into_iter()
+        .filter_map(|row| csv_fields(row, sep).into_iter().nth(column.unwrap_or(0)))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// The fields of a line, with double quotes around fields removed.
+fn csv_fields(line: &str, sep: char) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// The values of name attributes, and the text of name elements.
+fn xml_names(text: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+        if tag.starts_with(['/', '?', '!']) {
+            continue;
+        }
+        let empty = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (element, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        names.extend(
+            xml_attrs(attrs)
+                .filter(|(key, _)| NAME_KEYS.contains(&key.to_ascii_lowercase().as_str()))
+                .map(|(_, value)| xml_unescape(value)),
+        );
+        if NAME_KEYS.contains(&element.to_ascii_lowercase().as_str()) && !empty {
+            let text = &rest[..rest.find('<').unwrap_or(rest.len())];
+            if !text.trim().is_empty() {
+                names.push(xml_unescape(text.trim()));
+            }
+        }
+    }
+    names
+}
+
+fn xml_attrs(attrs: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = attrs;
+    std::iter::from_fn(move || {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let quote = after.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let (value, after) = after[1..].split_once(quote)?;
+        rest = after;
+        Some((key.trim(), value))
+    })
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[test]
+fn test_import_list() {
+    let sdb = crate::sdb_builder::test_sdb();
+    let csv = "Nr;Variable;Comment\n\
+               1;PLC_PRG.Gauge[1].Parameter[1].Value;\"Pressure; chamber\"\n\
+               2;.GAUGE.2.PARAMETER.1.VALUE;\n\
+               3;CockpitUser;\n\
+               4;Missing.Var;\n";
+    let list = ImportedList::import(csv, ListFormat::Csv, &sdb);
+    assert_eq!(
+        list.params,
+        [
+            ".Gauge[1].Parameter[1].Value",
+            ".Gauge[2].Parameter[1].Value",
+            ".CockpitUser"
+        ]
+    );
+    assert_eq!(list.unmatched, ["Missing.Var"]);
+    assert_eq!(
+        ImportedList::import(".HostRemote\n.OPCCounter\n", ListFormat::Csv, &sdb).params,
+        [".HostRemote", ".OPCCounter"]
+    );
+
+    let xml = r#"<?xml version="1.0"?>
+        <Variables>
+          <Variable Name="Application.GVL.HostRemote" Type="BOOL"/>
+          <Item><Symbol>OPCCounter</Symbol></Item>
+        </Variables>"#;
+    let list = ImportedList::import(xml, ListFormat::Xml, &sdb);
+    assert_eq!(list.params, [".HostRemote", ".OPCCounter"]);
+    #[cfg(feature = "config")]
+    assert_eq!(
+        list.group_toml("site"),
+        "[groups.site]\nparams = [\n    \".HostRemote\",\n    \".OPCCounter\",\n]\n"
+    );
+}